@@ -50,6 +50,19 @@ fn merge_test(bencher: Bencher) {
     })
 }
 
+// merge with the interleave split across threads
+#[divan::bench(args = [1, 2, 4, 8])]
+fn merge_threads_test(bencher: Bencher, threads: usize) {
+    let str0 = random_concat(N * 5, LEN, ALPHABET);
+    let str1 = random_concat(N * 5, LEN, ALPHABET);
+
+    let data0 = bwt::run_bwt(&str0);
+    let data1 = bwt::run_bwt(&str1);
+    bencher.bench_local(move || {
+        bwt::bwt_merge_threads(black_box(&data0), black_box(&data1), threads);
+    })
+}
+
 // worst case performance, where strings are duplicated
 #[divan::bench]
 fn repetitive_merge_test(bencher: Bencher) {
@@ -84,6 +97,39 @@ fn rebuild_test(bencher: Bencher) {
     })
 }
 
+// delete every hundredth line, against rebuilding the remaining text
+#[divan::bench]
+fn delete_test(bencher: Bencher) {
+    let str = random_concat(N, LEN, ALPHABET);
+    let line_ids: Vec<usize> = (0..N).step_by(100).collect();
+
+    let data = bwt::run_bwt(&str);
+    bencher.bench_local(move || {
+        bwt::bwt_delete_lines(black_box(&data), black_box(&line_ids));
+    })
+}
+
+// the same on 20 times the lines, where rebuilding costs more per character
+#[divan::bench(sample_count = 10)]
+fn delete_large_test(bencher: Bencher) {
+    let str = random_concat(N * 20, LEN, ALPHABET);
+    let line_ids: Vec<usize> = (0..N * 20).step_by(100).collect();
+
+    let data = bwt::run_bwt(&str);
+    bencher.bench_local(move || {
+        bwt::bwt_delete_lines(black_box(&data), black_box(&line_ids));
+    })
+}
+
+#[divan::bench(sample_count = 10)]
+fn rebuild_large_test(bencher: Bencher) {
+    let str = random_concat(N * 20, LEN, ALPHABET);
+
+    bencher.bench_local(move || {
+        bwt::run_bwt(black_box(&str));
+    })
+}
+
 // test on smaller alphabet
 #[divan::bench]
 fn merge_test_small(bencher: Bencher) {
@@ -127,7 +173,7 @@ fn query_test(bencher: Bencher) {
     let index = bwt::fm_index(&data);
     bencher.bench_local(move || {
         for query in query_strs.iter() {
-            bwt::get_matching_lines(&data, &index, &query);
+            bwt::get_matching_lines(&data, &index, query);
         }
     })
 }
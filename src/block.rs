@@ -0,0 +1,465 @@
+// Seekable block-compressed files, used for the .bwt and .index files of compressed segments.
+// The data is cut into blocks of a fixed size and each block is compressed into its own
+// zstd frame, so any block can be decompressed without the ones before it.
+// Layout: the frames, then a table with the offset of each frame and the end of the
+// last one as u64s, then a footer: table offset, data length, number of blocks,
+// block size, version, zstd level, CRC32 of the table, magic. All integers are little-endian.
+// Files from before the level was recorded have 0 there, which zstd takes as its default.
+// Readers fetch the footer and table once, then only the frames they need.
+
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use anyhow::{anyhow, Result};
+use opendal::Operator;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use zstd::bulk::{Compressor, Decompressor};
+
+pub const BLOCK_MAGIC: &[u8; 4] = b"BWTZ";
+pub const BLOCK_VERSION: u16 = 1;
+// table offset, data length, number of blocks, block size, version, level, checksum, magic
+pub const BLOCK_FOOTER_SIZE: usize = 8 + 8 + 8 + 4 + 2 + 2 + 4 + 4;
+pub const DEFAULT_BLOCK_SIZE: usize = 1 << 16;
+
+// How to compress a file into blocks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockCompression {
+    // zstd compression level
+    pub level: i32,
+    // bytes of data in each block, the last one may be shorter
+    pub block_size: usize,
+}
+
+impl Default for BlockCompression {
+    fn default() -> BlockCompression {
+        BlockCompression {
+            level: 3,
+            block_size: DEFAULT_BLOCK_SIZE,
+        }
+    }
+}
+
+// Frame offsets of a block-compressed file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockTable {
+    block_size: usize,
+    level: i32,
+    data_len: usize,
+    // offset of each frame, then the end of the last one
+    offsets: Vec<u64>,
+}
+
+impl BlockTable {
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    // Settings the file was written with, to write other files like it
+    pub fn compression(&self) -> BlockCompression {
+        BlockCompression {
+            level: self.level,
+            block_size: self.block_size,
+        }
+    }
+
+    // Length of the data once decompressed
+    pub fn data_len(&self) -> usize {
+        self.data_len
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    // Bytes of the file holding the frames of the given blocks
+    pub fn frame_range(&self, blocks: Range<usize>) -> Range<u64> {
+        self.offsets[blocks.start]..self.offsets[blocks.end]
+    }
+
+    // Blocks holding the given range of the data
+    pub fn blocks_of(&self, range: Range<usize>) -> Range<usize> {
+        if range.is_empty() {
+            return 0..0;
+        }
+        range.start / self.block_size..range.end.div_ceil(self.block_size)
+    }
+
+    pub fn memory_size(&self) -> usize {
+        std::mem::size_of::<BlockTable>() + self.offsets.len() * 8
+    }
+
+    // Offset of the table in the file, from the footer at the end of it
+    pub fn decode_footer(footer: &[u8]) -> Result<u64> {
+        if footer.len() != BLOCK_FOOTER_SIZE || &footer[BLOCK_FOOTER_SIZE - 4..] != BLOCK_MAGIC {
+            return Err(anyhow!("Not a block-compressed file"));
+        }
+        let version = u16::from_le_bytes([footer[28], footer[29]]);
+        if version != BLOCK_VERSION {
+            return Err(anyhow!("Unsupported block file version {}", version));
+        }
+        Ok(u64::from_le_bytes(footer[..8].try_into().unwrap()))
+    }
+
+    // Decode the end of a file, from the table offset given by its footer
+    pub fn decode(tail: &[u8]) -> Result<BlockTable> {
+        if tail.len() < BLOCK_FOOTER_SIZE {
+            return Err(anyhow!("Not a block-compressed file"));
+        }
+        let (table, footer) = tail.split_at(tail.len() - BLOCK_FOOTER_SIZE);
+        let table_offset = BlockTable::decode_footer(footer)?;
+        let read_u32 = |pos: usize| u32::from_le_bytes(footer[pos..pos + 4].try_into().unwrap());
+        let read_u64 = |pos: usize| u64::from_le_bytes(footer[pos..pos + 8].try_into().unwrap());
+        let data_len = read_u64(8) as usize;
+        let num_blocks = read_u64(16) as usize;
+        let block_size = read_u32(24) as usize;
+        let level = i16::from_le_bytes([footer[30], footer[31]]) as i32;
+
+        if table.len() != (num_blocks + 1) * 8 {
+            return Err(anyhow!(
+                "Block table has {} bytes, expected {}",
+                table.len(),
+                (num_blocks + 1) * 8
+            ));
+        }
+        let expected = read_u32(32);
+        let actual = crc32fast::hash(table);
+        if expected != actual {
+            return Err(anyhow!(
+                "Block table checksum is {:08x}, expected {:08x}",
+                actual,
+                expected
+            ));
+        }
+        let offsets = table
+            .chunks_exact(8)
+            .map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+            .collect::<Vec<u64>>();
+        if block_size == 0
+            || data_len.div_ceil(block_size) != num_blocks
+            || offsets[0] != 0
+            || offsets[num_blocks] != table_offset
+            || offsets.windows(2).any(|x| x[0] >= x[1])
+        {
+            return Err(anyhow!("Invalid block table"));
+        }
+        Ok(BlockTable {
+            block_size,
+            level,
+            data_len,
+            offsets,
+        })
+    }
+
+    // Read the table of a block-compressed file
+    pub async fn read(operator: &Operator, path: &str) -> Result<BlockTable> {
+        let len = operator.stat(path).await?.content_length();
+        if len < BLOCK_FOOTER_SIZE as u64 {
+            return Err(anyhow!("{}: not a block-compressed file", path));
+        }
+        let footer = operator
+            .read_with(path)
+            .range(len - BLOCK_FOOTER_SIZE as u64..len)
+            .await?;
+        let table_offset =
+            BlockTable::decode_footer(&footer).map_err(|e| anyhow!("{}: {}", path, e))?;
+        if table_offset > len - BLOCK_FOOTER_SIZE as u64 {
+            return Err(anyhow!("{}: invalid block table offset", path));
+        }
+        let tail = operator.read_with(path).range(table_offset..len).await?;
+        BlockTable::decode(&tail).map_err(|e| anyhow!("{}: {}", path, e))
+    }
+
+    // Decompress the frames of the given blocks, which start at the first of them
+    pub fn decompress_blocks(&self, blocks: Range<usize>, frames: &[u8]) -> Result<Vec<u8>> {
+        let frame_range = self.frame_range(blocks.clone());
+        if frames.len() as u64 != frame_range.end - frame_range.start {
+            return Err(anyhow!("Compressed blocks ended early"));
+        }
+        let mut decompressor = Decompressor::new()?;
+        let mut data = Vec::with_capacity(blocks.len() * self.block_size);
+        for block in blocks {
+            let frame = self.frame_range(block..block + 1);
+            let frame = &frames[(frame.start - frame_range.start) as usize
+                ..(frame.end - frame_range.start) as usize];
+            let block_len = self.block_size.min(self.data_len - block * self.block_size);
+            let decompressed = decompressor.decompress(frame, block_len)?;
+            if decompressed.len() != block_len {
+                return Err(anyhow!(
+                    "Block {} has {} bytes, expected {}",
+                    block,
+                    decompressed.len(),
+                    block_len
+                ));
+            }
+            data.extend_from_slice(&decompressed);
+        }
+        Ok(data)
+    }
+}
+
+// Compresses data pushed to it into frames of fixed-size blocks
+pub struct BlockEncoder {
+    compressor: Compressor<'static>,
+    level: i32,
+    block_size: usize,
+    block: Vec<u8>,
+    offsets: Vec<u64>,
+    data_len: u64,
+    compressed_len: u64,
+}
+
+impl BlockEncoder {
+    pub fn new(compression: BlockCompression) -> Result<BlockEncoder> {
+        if compression.block_size == 0 || compression.block_size > u32::MAX as usize {
+            return Err(anyhow!("Invalid block size {}", compression.block_size));
+        }
+        Ok(BlockEncoder {
+            compressor: Compressor::new(compression.level)?,
+            level: compression.level,
+            block_size: compression.block_size,
+            block: Vec::with_capacity(compression.block_size),
+            offsets: Vec::new(),
+            data_len: 0,
+            compressed_len: 0,
+        })
+    }
+
+    // Add data, appending the frames of any blocks it completes to out
+    pub fn push(&mut self, mut data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        while !data.is_empty() {
+            let len = (self.block_size - self.block.len()).min(data.len());
+            self.block.extend_from_slice(&data[..len]);
+            data = &data[len..];
+            if self.block.len() == self.block_size {
+                self.flush_block(out)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush_block(&mut self, out: &mut Vec<u8>) -> Result<()> {
+        let frame = self.compressor.compress(&self.block)?;
+        self.offsets.push(self.compressed_len);
+        self.compressed_len += frame.len() as u64;
+        self.data_len += self.block.len() as u64;
+        out.extend_from_slice(&frame);
+        self.block.clear();
+        Ok(())
+    }
+
+    // Append the last block, the table and the footer to out
+    pub fn finish(mut self, out: &mut Vec<u8>) -> Result<()> {
+        if !self.block.is_empty() {
+            self.flush_block(out)?;
+        }
+        self.offsets.push(self.compressed_len);
+        let table = self
+            .offsets
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect::<Vec<u8>>();
+        out.extend_from_slice(&table);
+        out.extend_from_slice(&self.compressed_len.to_le_bytes());
+        out.extend_from_slice(&self.data_len.to_le_bytes());
+        out.extend_from_slice(&((self.offsets.len() - 1) as u64).to_le_bytes());
+        out.extend_from_slice(&(self.block_size as u32).to_le_bytes());
+        out.extend_from_slice(&BLOCK_VERSION.to_le_bytes());
+        // levels past what fits all compress about as fast and as poorly
+        let level = self.level.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        out.extend_from_slice(&level.to_le_bytes());
+        out.extend_from_slice(&crc32fast::hash(&table).to_le_bytes());
+        out.extend_from_slice(BLOCK_MAGIC);
+        Ok(())
+    }
+}
+
+// Compress a whole buffer
+pub fn compress(data: &[u8], compression: BlockCompression) -> Result<Vec<u8>> {
+    let mut encoder = BlockEncoder::new(compression)?;
+    let mut out = Vec::new();
+    encoder.push(data, &mut out)?;
+    encoder.finish(&mut out)?;
+    Ok(out)
+}
+
+// Decompress a whole block-compressed file
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < BLOCK_FOOTER_SIZE {
+        return Err(anyhow!("Not a block-compressed file"));
+    }
+    let table_offset = BlockTable::decode_footer(&data[data.len() - BLOCK_FOOTER_SIZE..])?;
+    if table_offset > (data.len() - BLOCK_FOOTER_SIZE) as u64 {
+        return Err(anyhow!("Invalid block table offset"));
+    }
+    let table = BlockTable::decode(&data[table_offset as usize..])?;
+    table.decompress_blocks(0..table.num_blocks(), &data[..table_offset as usize])
+}
+
+// Read a range of the data of a block-compressed file, fetching only the frames holding it
+pub async fn read_range(
+    operator: &Operator,
+    path: &str,
+    table: &BlockTable,
+    range: Range<usize>,
+) -> Result<Vec<u8>> {
+    if range.end > table.data_len() {
+        return Err(anyhow!("{}: data ended early", path));
+    }
+    let blocks = table.blocks_of(range.clone());
+    if blocks.is_empty() {
+        return Ok(Vec::new());
+    }
+    let frames = operator
+        .read_with(path)
+        .range(table.frame_range(blocks.clone()))
+        .await?;
+    let mut data = table.decompress_blocks(blocks.clone(), &frames)?;
+    let skip = range.start - blocks.start * table.block_size();
+    data.truncate(skip + range.len());
+    data.drain(..skip);
+    Ok(data)
+}
+
+type FetchFuture = Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send>>;
+
+// Reader of the data of a block-compressed file, which fetches and decompresses
+// runs of frames of up to about fetch_size compressed bytes at a time.
+// Seeking moves to the block holding the new position, without reading the ones before it.
+pub struct BlockReader {
+    operator: Operator,
+    path: String,
+    table: Arc<BlockTable>,
+    fetch_size: usize,
+    // decompressed data fetched, and the position in it of the next byte
+    buf: Vec<u8>,
+    pos: usize,
+    // first block not fetched yet, and bytes of it to skip after a seek
+    next_block: usize,
+    skip: usize,
+    fetch: Option<FetchFuture>,
+    seek_pos: u64,
+}
+
+impl BlockReader {
+    pub fn new(
+        operator: &Operator,
+        path: &str,
+        table: BlockTable,
+        fetch_size: usize,
+    ) -> BlockReader {
+        BlockReader {
+            operator: operator.clone(),
+            path: path.to_string(),
+            table: Arc::new(table),
+            fetch_size,
+            buf: Vec::new(),
+            pos: 0,
+            next_block: 0,
+            skip: 0,
+            fetch: None,
+            seek_pos: 0,
+        }
+    }
+
+    pub fn table(&self) -> &BlockTable {
+        &self.table
+    }
+
+    // Start fetching the next run of frames
+    fn start_fetch(&mut self) -> FetchFuture {
+        let start = self.next_block;
+        let mut end = start + 1;
+        while end < self.table.num_blocks() {
+            let frames = self.table.frame_range(start..end + 1);
+            if frames.end - frames.start > self.fetch_size as u64 {
+                break;
+            }
+            end += 1;
+        }
+        let operator = self.operator.clone();
+        let path = self.path.clone();
+        let table = self.table.clone();
+        Box::pin(async move {
+            let frames = operator
+                .read_with(&path)
+                .range(table.frame_range(start..end))
+                .await?;
+            table
+                .decompress_blocks(start..end, &frames)
+                .map_err(|e| anyhow!("{}: {}", path, e))
+        })
+    }
+}
+
+impl AsyncRead for BlockReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.pos < this.buf.len() {
+                let len = out.remaining().min(this.buf.len() - this.pos);
+                out.put_slice(&this.buf[this.pos..this.pos + len]);
+                this.pos += len;
+                return Poll::Ready(Ok(()));
+            }
+            if this.next_block >= this.table.num_blocks() {
+                return Poll::Ready(Ok(()));
+            }
+            let fetch = match this.fetch.as_mut() {
+                Some(fetch) => fetch,
+                None => {
+                    let fetch = this.start_fetch();
+                    this.fetch.insert(fetch)
+                }
+            };
+            let data = match fetch.as_mut().poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(result) => {
+                    this.fetch = None;
+                    result.map_err(io::Error::other)?
+                }
+            };
+            // blocks are never empty, and only the last one is short
+            this.next_block += data.len().div_ceil(this.table.block_size());
+            this.buf = data;
+            this.pos = std::mem::take(&mut this.skip);
+        }
+    }
+}
+
+impl AsyncSeek for BlockReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let SeekFrom::Start(pos) = position else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Block-compressed files can only seek from the start",
+            ));
+        };
+        if pos > this.table.data_len() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek past the end of the data",
+            ));
+        }
+        let pos = pos as usize;
+        this.fetch = None;
+        this.buf.clear();
+        this.pos = 0;
+        this.next_block = pos / this.table.block_size();
+        this.skip = pos % this.table.block_size();
+        this.seek_pos = pos as u64;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.seek_pos))
+    }
+}
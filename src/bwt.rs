@@ -1,15 +1,49 @@
 use std::collections::BTreeSet;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use bit_vec::BitVec;
 use libdivsufsort_rs::divsufsort64;
 
+use crate::progress::{
+    Cancellation, Cancelled, MergeObserver, MergePhase, MergeStats, MergeStopped, NoopObserver,
+    PROGRESS_INTERVAL,
+};
+use crate::segment::RankBuilder;
+
+#[allow(clippy::upper_case_acronyms)]
 type BWT = Vec<u8>;
-// bwt, line index, character counts
-type BWTData = (BWT, Vec<usize>, [usize; 256]);
+// bwt, line index, character counts, and optionally the document id of each line.
+// Document ids are attached at build time and stay the same through merges and deletes,
+// unlike line ids.
+pub type BWTData = (BWT, Vec<usize>, [usize; 256], Option<DocIds>);
+// document id of each line, indexed by line id
+pub type DocIds = Vec<u64>;
 
 // Compute the BWT of a string, using the divsufsort crate.
 // Returns the BWT and the line index
 pub fn run_bwt(input: &Vec<u8>) -> BWTData {
+    run_bwt_cancellable(input, &Cancellation::new()).expect("Build without cancellation failed")
+}
+
+// Compute the BWT of a string like run_bwt, attaching a document id to each line in order.
+// Panics unless there is one document id per line.
+pub fn run_bwt_with_doc_ids(input: &Vec<u8>, doc_ids: DocIds) -> BWTData {
+    let num_lines = input.iter().filter(|&&x| x == b'\n').count();
+    assert!(
+        doc_ids.len() == num_lines,
+        "Number of document ids does not match number of lines"
+    );
+    let mut data = run_bwt(input);
+    data.3 = Some(doc_ids);
+    data
+}
+
+// Compute the BWT of a string, stopping early if cancelled.
+// The suffix sort itself can't be interrupted, so cancellation is checked around it.
+pub fn run_bwt_cancellable(input: &Vec<u8>, cancel: &Cancellation) -> Result<BWTData, Cancelled> {
+    cancel.check()?;
+
     // find newline indices
     let newlines = input
         .iter()
@@ -19,11 +53,16 @@ pub fn run_bwt(input: &Vec<u8>) -> BWTData {
         .collect::<Vec<usize>>();
 
     let sa = divsufsort64(input).unwrap();
+    cancel.check()?;
 
     let mut bwt = Vec::with_capacity(input.len());
     let mut line_index = Vec::with_capacity(input.len());
     let mut counts: [usize; 256] = [0; 256];
     for i in 0..sa.len() {
+        if i % PROGRESS_INTERVAL == 0 && i > 0 {
+            cancel.check()?;
+        }
+
         if sa[i] == 0 {
             bwt.push(input[input.len() - 1]);
         } else {
@@ -40,11 +79,17 @@ pub fn run_bwt(input: &Vec<u8>) -> BWTData {
         counts[bwt[i] as usize] += 1;
     }
 
-    (bwt, line_index, counts)
+    Ok((bwt, line_index, counts, None))
 }
 
 // Compute the interleave of two BWTs.
-fn compute_interleave(bwt0: &BWT, bwt1: &BWT, counts: &[usize; 256]) -> BitVec {
+fn compute_interleave(
+    bwt0: &BWT,
+    bwt1: &BWT,
+    counts: &[usize; 256],
+    stats: &mut MergeStats,
+    observer: &mut dyn MergeObserver,
+) -> Result<BitVec, MergeStopped> {
     // construct character starts array
     let mut starts: [usize; 256] = [0; 256];
     let mut sum = 0;
@@ -64,6 +109,17 @@ fn compute_interleave(bwt0: &BWT, bwt1: &BWT, counts: &[usize; 256]) -> BitVec {
         let mut offsets = starts;
         let mut new_interleave = BitVec::from_elem(interleave.len(), false);
         for i in 0..interleave.len() {
+            if i % PROGRESS_INTERVAL == 0
+                && i > 0
+                && !observer.on_progress(
+                    MergePhase::Interleave(stats.interleave_iterations),
+                    i,
+                    interleave.len(),
+                )
+            {
+                return Err(MergeStopped);
+            }
+
             if interleave[i] {
                 new_interleave.set(offsets[bwt1[ind[1]] as usize], true);
                 offsets[bwt1[ind[1]] as usize] += 1;
@@ -74,18 +130,240 @@ fn compute_interleave(bwt0: &BWT, bwt1: &BWT, counts: &[usize; 256]) -> BitVec {
             }
         }
 
+        stats.interleave_iterations += 1;
+        if !observer.on_iteration(stats) {
+            return Err(MergeStopped);
+        }
+
         if new_interleave == interleave {
             break;
         }
         interleave = new_interleave;
     }
-    interleave
+    Ok(interleave)
+}
+
+// Merges smaller than this are not worth splitting across threads
+const PARALLEL_MIN_LEN: usize = 1 << 16;
+
+// Process the interleave bits in words [word_start, word_end) for one pass.
+// ind are the BWT indices at the start of the range. The character offsets there
+// come from the chunk counts of both BWTs.
+fn interleave_range(
+    bwts: [&BWT; 2],
+    chunk_counts: [&[[usize; 256]]; 2],
+    starts: &[usize; 256],
+    interleave: &[u64],
+    new_interleave: &[AtomicU64],
+    (word_start, word_end): (usize, usize),
+    mut ind: [usize; 2],
+) {
+    let [bwt0, bwt1] = bwts;
+    let mut offsets = *starts;
+    for (bwt, (counts, &end)) in bwts.iter().zip(chunk_counts.iter().zip(ind.iter())) {
+        let chunk = end / COUNT_CHUNK;
+        for i in 0..256 {
+            offsets[i] += counts[chunk][i];
+        }
+        for &chr in bwt[chunk * COUNT_CHUNK..end].iter() {
+            offsets[chr as usize] += 1;
+        }
+    }
+
+    let len = bwt0.len() + bwt1.len();
+    let end = std::cmp::min(word_end * 64, len);
+    for i in word_start * 64..end {
+        if (interleave[i / 64] >> (i % 64)) & 1 == 1 {
+            let pos = offsets[bwt1[ind[1]] as usize];
+            new_interleave[pos / 64].fetch_or(1 << (pos % 64), Ordering::Relaxed);
+            offsets[bwt1[ind[1]] as usize] += 1;
+            ind[1] += 1;
+        } else {
+            offsets[bwt0[ind[0]] as usize] += 1;
+            ind[0] += 1;
+        }
+    }
+}
+
+// Characters between the character counts kept for computing offsets
+const COUNT_CHUNK: usize = 1 << 16;
+
+// Character counts of each prefix of the BWT that is a whole number of chunks,
+// counting the chunks on the given number of threads
+fn chunk_counts(bwt: &BWT, threads: usize) -> Vec<[usize; 256]> {
+    let chunks = bwt.chunks(COUNT_CHUNK).collect::<Vec<&[u8]>>();
+    let per_thread = std::cmp::max(chunks.len().div_ceil(threads), 1);
+    let counts = std::thread::scope(|scope| {
+        let handles = chunks
+            .chunks(per_thread)
+            .map(|chunks| {
+                scope.spawn(move || {
+                    chunks
+                        .iter()
+                        .map(|chunk| {
+                            let mut counts: [usize; 256] = [0; 256];
+                            for &chr in chunk.iter() {
+                                counts[chr as usize] += 1;
+                            }
+                            counts
+                        })
+                        .collect::<Vec<[usize; 256]>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect::<Vec<[usize; 256]>>()
+    });
+
+    let mut prefix = Vec::with_capacity(counts.len() + 1);
+    let mut sum: [usize; 256] = [0; 256];
+    prefix.push(sum);
+    for chunk in counts.iter() {
+        for i in 0..256 {
+            sum[i] += chunk[i];
+        }
+        prefix.push(sum);
+    }
+    prefix
+}
+
+// Compute the interleave of two BWTs, splitting each pass into ranges across threads.
+// The character counts of every chunk of the inputs are found once, so each range
+// gets its starting character offsets from its BWT indices, and the result is
+// identical to the sequential version.
+// The observer is only called between iterations.
+fn compute_interleave_parallel(
+    bwt0: &BWT,
+    bwt1: &BWT,
+    counts: &[usize; 256],
+    threads: usize,
+    stats: &mut MergeStats,
+    observer: &mut dyn MergeObserver,
+) -> Result<BitVec, MergeStopped> {
+    // construct character starts array
+    let mut starts: [usize; 256] = [0; 256];
+    let mut sum = 0;
+    for i in 0..256 {
+        starts[i] = sum;
+        sum += counts[i];
+    }
+    let counts0 = chunk_counts(bwt0, threads);
+    let counts1 = chunk_counts(bwt1, threads);
+
+    let len = bwt0.len() + bwt1.len();
+    let num_words = len.div_ceil(64);
+    let mut interleave = vec![0u64; num_words];
+    for i in bwt0.len()..len {
+        interleave[i / 64] |= 1 << (i % 64);
+    }
+
+    // ranges are whole words, so no two threads share a word of the old interleave
+    let words_per_range = std::cmp::max(num_words.div_ceil(threads), 1);
+    let ranges = (0..num_words)
+        .step_by(words_per_range)
+        .map(|start| (start, std::cmp::min(start + words_per_range, num_words)))
+        .collect::<Vec<(usize, usize)>>();
+
+    loop {
+        // BWT indices at the start of each range
+        let mut range_inds = Vec::with_capacity(ranges.len());
+        let mut ones = 0;
+        for &(word_start, word_end) in ranges.iter() {
+            range_inds.push([word_start * 64 - ones, ones]);
+            ones += interleave[word_start..word_end]
+                .iter()
+                .map(|x| x.count_ones() as usize)
+                .sum::<usize>();
+        }
+
+        let new_interleave = (0..num_words)
+            .map(|_| AtomicU64::new(0))
+            .collect::<Vec<AtomicU64>>();
+        std::thread::scope(|scope| {
+            for (&range, &ind) in ranges.iter().zip(range_inds.iter()) {
+                let (interleave, new_interleave) = (&interleave, &new_interleave);
+                let chunk_counts = [&counts0[..], &counts1[..]];
+                let starts = &starts;
+                scope.spawn(move || {
+                    interleave_range(
+                        [bwt0, bwt1],
+                        chunk_counts,
+                        starts,
+                        interleave,
+                        new_interleave,
+                        range,
+                        ind,
+                    )
+                });
+            }
+        });
+        let new_interleave = new_interleave
+            .into_iter()
+            .map(|x| x.into_inner())
+            .collect::<Vec<u64>>();
+
+        stats.interleave_iterations += 1;
+        if !observer.on_iteration(stats) {
+            return Err(MergeStopped);
+        }
+
+        if new_interleave == interleave {
+            break;
+        }
+        interleave = new_interleave;
+    }
+    Ok(BitVec::from_fn(len, |i| {
+        (interleave[i / 64] >> (i % 64)) & 1 == 1
+    }))
+}
+
+// Number of threads to use for a merge of the given length
+fn default_threads(len: usize) -> usize {
+    if len < PARALLEL_MIN_LEN {
+        return 1;
+    }
+    std::thread::available_parallelism().map_or(1, |x| x.get())
 }
 
 // Merge two BWTs using our algorithm.
+// Uses all available threads for the interleave computation.
+// Document ids are concatenated, bwt0's first. Either both BWTs have them or neither
+// does, which panics otherwise.
 pub fn bwt_merge(bwt0_d: &BWTData, bwt1_d: &BWTData) -> BWTData {
-    let (bwt0, line_ind0, counts0) = bwt0_d;
-    let (bwt1, line_ind1, counts1) = bwt1_d;
+    let threads = default_threads(bwt0_d.0.len() + bwt1_d.0.len());
+    bwt_merge_threads(bwt0_d, bwt1_d, threads)
+}
+
+// Merge two BWTs, computing the interleave with the given number of threads.
+// A single thread uses the sequential algorithm.
+pub fn bwt_merge_threads(bwt0_d: &BWTData, bwt1_d: &BWTData, threads: usize) -> BWTData {
+    merge_pair(bwt0_d, bwt1_d, threads, &mut NoopObserver)
+        .expect("NoopObserver never stops a merge")
+        .0
+}
+
+// Merge two BWTs, reporting progress to the observer.
+// Returns the merged BWT along with statistics about the merge.
+pub fn bwt_merge_observed(
+    bwt0_d: &BWTData,
+    bwt1_d: &BWTData,
+    observer: &mut dyn MergeObserver,
+) -> Result<(BWTData, MergeStats), MergeStopped> {
+    let threads = default_threads(bwt0_d.0.len() + bwt1_d.0.len());
+    merge_pair(bwt0_d, bwt1_d, threads, observer)
+}
+
+fn merge_pair(
+    bwt0_d: &BWTData,
+    bwt1_d: &BWTData,
+    threads: usize,
+    observer: &mut dyn MergeObserver,
+) -> Result<(BWTData, MergeStats), MergeStopped> {
+    let (bwt0, line_ind0, counts0, _) = bwt0_d;
+    let (bwt1, line_ind1, counts1, _) = bwt1_d;
+    let doc_ids = concat_doc_ids(&[bwt0_d, bwt1_d]);
 
     // construct character counts array
     let mut counts: [usize; 256] = [0; 256];
@@ -93,9 +371,23 @@ pub fn bwt_merge(bwt0_d: &BWTData, bwt1_d: &BWTData) -> BWTData {
         counts[i] = counts0[i] + counts1[i];
     }
 
-    let interleave = compute_interleave(bwt0, bwt1, &counts);
+    // two interleave bit vectors, plus the merged bwt and line index
+    let len = bwt0.len() + bwt1.len();
+    let mut stats = MergeStats {
+        peak_memory: 2 * len.div_ceil(8) + len * (1 + std::mem::size_of::<usize>()),
+        ..Default::default()
+    };
+
+    let start = std::time::Instant::now();
+    let interleave = if threads <= 1 {
+        compute_interleave(bwt0, bwt1, &counts, &mut stats, observer)?
+    } else {
+        compute_interleave_parallel(bwt0, bwt1, &counts, threads, &mut stats, observer)?
+    };
+    stats.interleave_time = start.elapsed();
 
     // construct bwt
+    let start = std::time::Instant::now();
     let mut bwt = Vec::with_capacity(interleave.len());
     let mut line_index = Vec::with_capacity(interleave.len());
     let mut ind0 = 0;
@@ -105,6 +397,13 @@ pub fn bwt_merge(bwt0_d: &BWTData, bwt1_d: &BWTData) -> BWTData {
     let num_newlines = counts0[b'\n' as usize];
 
     for i in 0..interleave.len() {
+        if i % PROGRESS_INTERVAL == 0
+            && i > 0
+            && !observer.on_progress(MergePhase::Output, i, interleave.len())
+        {
+            return Err(MergeStopped);
+        }
+
         if interleave[i] {
             bwt.push(bwt1[ind1]);
             line_index.push(line_ind1[ind1] + num_newlines);
@@ -115,7 +414,792 @@ pub fn bwt_merge(bwt0_d: &BWTData, bwt1_d: &BWTData) -> BWTData {
             ind0 += 1;
         }
     }
-    (bwt, line_index, counts)
+    stats.output_time = start.elapsed();
+    Ok(((bwt, line_index, counts, doc_ids), stats))
+}
+
+// Document ids of BWTs merged in the given order. Panics unless they either all have
+// them or none do, since the lines without them would leave gaps.
+fn concat_doc_ids(data: &[&BWTData]) -> Option<DocIds> {
+    if data.iter().all(|x| x.3.is_none()) {
+        return None;
+    }
+    let mut doc_ids = Vec::new();
+    for (_, _, counts, docs) in data.iter() {
+        let docs = docs
+            .as_ref()
+            .expect("Only some of the BWTs have document ids");
+        assert!(
+            docs.len() == counts[b'\n' as usize],
+            "Number of document ids does not match number of lines"
+        );
+        doc_ids.extend_from_slice(docs);
+    }
+    Some(doc_ids)
+}
+
+// Where the suffix before a row of a k-way merge or delete output is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PredRow {
+    // a row of this source placed in the source's own order
+    Source(usize),
+    // the tied row with this id
+    Tied(usize),
+    // none: the row starts the output, so the suffix before it is the output's last,
+    // which sorts first among those starting with a separator
+    Start,
+}
+
+// A row of an output placed on its own rather than in its source's order. Its suffix
+// up to the end of its piece also starts other suffixes of the source, so comparisons
+// with those run into the text after the piece, which is different in the output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TiedRow {
+    pub chr: u8,
+    pub line: usize,
+    pub pred: PredRow,
+}
+
+// Lines of a source that are contiguous in an output, with the rows of the suffix
+// at their start and of the separator ending them
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Piece {
+    pub source: usize,
+    pub lines: Range<usize>,
+    pub start_row: usize,
+    pub last_row: usize,
+    // whether the text after the piece in the output differs from the text after it
+    // in its source, so that its tied rows have to be placed on their own
+    pub relinked: bool,
+}
+
+// Tied rows at the end of a piece, from its last row back, as (row, BWT character,
+// output line id), and whether they reach back to the start of the piece
+pub type TiedChain = (Vec<(usize, u8, usize)>, bool);
+
+// Rows of a source that aren't placed like the rest, in increasing order: its tied
+// rows with their ids, and rows whose previous suffix is in another piece
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SourceLinks {
+    pub tied: Vec<(usize, usize)>,
+    pub links: Vec<(usize, PredRow)>,
+    // rows left out of the output, if any are
+    pub skipped: Option<BitVec>,
+}
+
+// Rows of the sources of an output that aren't placed like the rest
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Links {
+    pub sources: Vec<SourceLinks>,
+    pub tied_rows: Vec<TiedRow>,
+    // the output's last row, which sorts first among those starting with a separator
+    pub last: Option<PredRow>,
+}
+
+// Work out where the suffix before each row of an output is, given the pieces of
+// the output in order and the tied rows at the end of each
+pub fn link_pieces(num_sources: usize, pieces: &[Piece], chains: &[TiedChain]) -> Links {
+    let mut sources = vec![SourceLinks::default(); num_sources];
+    let mut tied_rows = Vec::new();
+    // the row before the start of the next piece
+    let mut last = PredRow::Start;
+    for (piece, (chain, reaches_start)) in pieces.iter().zip(chains.iter()) {
+        let first_id = tied_rows.len();
+        for (i, &(row, chr, line)) in chain.iter().enumerate() {
+            let pred = if i + 1 < chain.len() {
+                PredRow::Tied(first_id + i + 1)
+            } else if *reaches_start {
+                last
+            } else {
+                PredRow::Source(piece.source)
+            };
+            tied_rows.push(TiedRow { chr, line, pred });
+            sources[piece.source].tied.push((row, first_id + i));
+        }
+        if !reaches_start {
+            sources[piece.source].links.push((piece.start_row, last));
+        }
+        last = match chain.is_empty() {
+            true => PredRow::Source(piece.source),
+            false => PredRow::Tied(first_id),
+        };
+    }
+    for source in sources.iter_mut() {
+        source.tied.sort_unstable();
+        source.links.sort_unstable_by_key(|x| x.0);
+    }
+    Links {
+        sources,
+        tied_rows,
+        last: (!pieces.is_empty()).then_some(last),
+    }
+}
+
+// Walks the rows of a source that are placed in its own order, skipping its tied
+// rows and any rows left out of the output
+pub struct SourceRows<'a> {
+    links: &'a SourceLinks,
+    row: usize,
+    tied: usize,
+    link: usize,
+}
+
+impl SourceRows<'_> {
+    pub fn new(links: &SourceLinks) -> SourceRows<'_> {
+        SourceRows {
+            links,
+            row: 0,
+            tied: 0,
+            link: 0,
+        }
+    }
+
+    // Next row placed in source order, along with where the suffix before it is
+    // if that isn't the row of the source before it
+    pub fn next_row(&mut self) -> (usize, Option<PredRow>) {
+        loop {
+            if self
+                .links
+                .tied
+                .get(self.tied)
+                .is_some_and(|x| x.0 == self.row)
+            {
+                self.tied += 1;
+            } else if !self.links.skipped.as_ref().is_some_and(|x| x[self.row]) {
+                break;
+            }
+            self.row += 1;
+        }
+        let row = self.row;
+        self.row += 1;
+        match self.links.links.get(self.link) {
+            Some(&(link_row, pred)) if link_row == row => {
+                self.link += 1;
+                (row, Some(pred))
+            }
+            _ => (row, None),
+        }
+    }
+}
+
+// Occurrences of chr in a BWT of length len before pos, which can be len
+fn occurrences(
+    blocks: &[FMBlock],
+    len: usize,
+    counts: &[usize; 256],
+    pos: usize,
+    chr: u8,
+) -> usize {
+    match pos == len {
+        true => counts[chr as usize],
+        false => lf_map(blocks, pos, chr) - blocks[0].c_arr[chr as usize],
+    }
+}
+
+// Row of the suffix at the start of a BWT's text, if the text ends with a separator:
+// the only line start that is in the first line
+pub fn start_row(data: &BWTData) -> Option<usize> {
+    let (bwt, line_ind, _, _) = data;
+    (0..bwt.len()).find(|&i| bwt[i] == b'\n' && line_ind[i] == 0)
+}
+
+// Row of the suffix before the one at row i, in a BWT of text ending with a separator.
+// The LF-mapping gets this wrong for line starts: the suffix before the start of the
+// text is the lone separator at its end, which sorts first among those starting with
+// a separator, rather than the suffix of the separator at the end of the text followed
+// by the text again, which is where LF-mapping puts it.
+fn pred_row(blocks: &[FMBlock], bwt: &BWT, start_row: usize, i: usize) -> usize {
+    let chr = bwt[i];
+    let lf = lf_map(blocks, i, chr);
+    match chr {
+        b'\n' if i == start_row => blocks[0].c_arr[b'\n' as usize],
+        b'\n' => lf + 1 - usize::from(start_row < i),
+        _ => lf,
+    }
+}
+
+// Tied rows at the end of a piece of a BWT: walking back from its last row, the rows
+// whose suffix up to the end of the piece also starts another suffix.
+// line_of gives the output line id of a line of the BWT.
+fn tied_chain(
+    data: &BWTData,
+    blocks: &[FMBlock],
+    start_row: usize,
+    piece: &Piece,
+    line_of: impl Fn(usize) -> usize,
+) -> TiedChain {
+    let (bwt, _, counts, _) = data;
+    let c_arr = &blocks[0].c_arr;
+    let rank = |pos, chr| occurrences(blocks, bwt.len(), counts, pos, chr);
+
+    // rows of the suffixes starting with the end of the piece walked so far
+    let separator = c_arr[b'\n' as usize];
+    let (mut lo, mut hi) = (separator, separator + counts[b'\n' as usize]);
+    let mut row = piece.last_row;
+    let mut line = piece.lines.end - 1;
+    let mut rows = Vec::new();
+    while hi - lo > 1 {
+        let chr = bwt[row];
+        rows.push((row, chr, line_of(line)));
+        if chr == b'\n' {
+            if line == piece.lines.start {
+                return (rows, true);
+            }
+            line -= 1;
+            // the start of the text is followed by nothing in the BWT's own order
+            let rank = |pos| rank(pos, chr) - usize::from(start_row < pos);
+            (lo, hi) = (separator + 1 + rank(lo), separator + 1 + rank(hi));
+        } else {
+            let start = c_arr[chr as usize];
+            (lo, hi) = (start + rank(lo, chr), start + rank(hi, chr));
+        }
+        row = pred_row(blocks, bwt, start_row, row);
+    }
+    (rows, false)
+}
+
+// Rows between the rank checkpoints of place_tied_rows, small enough to count through
+// for each tied row in each pass
+const TIED_RANK_INTERVAL: usize = 1024;
+
+// A row of an output made of pieces of a single BWT: the kth of the BWT's rows kept in
+// their order, or a tied row
+#[derive(Clone, Copy)]
+enum OutputRow {
+    Kept(usize),
+    Tied(usize),
+}
+
+// Find where the tied rows of an output made of pieces of a single BWT of length len
+// go, giving the same interleave as compute_interleave_linked, as positions and tied
+// row ids in order. kept has the characters of the rows given by SourceRows in order,
+// and kept_links the links it gives, by their index in kept.
+// Those rows keep their order, so the interleave only changes where the tied rows are,
+// and each pass only moves those: the row whose suffix comes before a tied row's goes
+// where the tied row is, ranked among the rows with its character.
+// Each pass takes time in the number of tied rows rather than the length of the output.
+pub fn place_tied_rows(
+    kept: BWT,
+    kept_links: &[(usize, PredRow)],
+    len: usize,
+    links: &Links,
+    counts: &[usize; 256],
+    stats: &mut MergeStats,
+    observer: &mut dyn MergeObserver,
+) -> Result<Vec<(usize, usize)>, MergeStopped> {
+    let (runs, tied_order) = initial_runs(&[len], links);
+    let mut tied = Vec::with_capacity(tied_order.len());
+    let mut ids = tied_order.into_iter();
+    let mut pos = 0;
+    for &(label, len) in runs.iter() {
+        if label == 1 {
+            tied.push((pos, ids.next().expect("Fewer tied rows than positions")));
+        }
+        pos += len;
+    }
+    let Some(last) = links.last else {
+        return Ok(tied);
+    };
+
+    // construct character starts array
+    let mut starts: [usize; 256] = [0; 256];
+    let mut sum = 0;
+    for i in 0..256 {
+        starts[i] = sum;
+        sum += counts[i];
+    }
+
+    // the rows placing tied rows or the start of the output, with their characters
+    let mut placing = Vec::new();
+    for &(k, pred) in kept_links.iter() {
+        if let PredRow::Tied(_) | PredRow::Start = pred {
+            placing.push((OutputRow::Kept(k), kept[k], pred));
+        }
+    }
+    for (id, row) in links.tied_rows.iter().enumerate() {
+        if let PredRow::Tied(_) | PredRow::Start = row.pred {
+            placing.push((OutputRow::Tied(id), row.chr, row.pred));
+        }
+    }
+    // occurrences of each character in kept, from checkpoints every TIED_RANK_INTERVAL
+    let mut kept_counts = [0; 256];
+    for &chr in kept.iter() {
+        kept_counts[chr as usize] += 1;
+    }
+    let mut rank = RankBuilder::with_interval(&kept_counts, TIED_RANK_INTERVAL);
+    rank.extend(&kept);
+    let rank = rank.finish().expect("Counts are of the same characters");
+    let kept_rank = |k: usize, chr: u8| match rank.checkpoint_rank(k, chr) {
+        Some(checkpoint) => {
+            let block_start = k - k % TIED_RANK_INTERVAL;
+            checkpoint + kept[block_start..k].iter().filter(|&&x| x == chr).count()
+        }
+        None => 0,
+    };
+    // the start of the output places nothing
+    let start = placing
+        .iter()
+        .find(|x| x.2 == PredRow::Start)
+        .map(|x| (x.0, x.1));
+
+    loop {
+        let mut tied_pos = vec![0; tied.len()];
+        let mut tied_by_chr = vec![Vec::new(); 256];
+        for &(pos, id) in tied.iter() {
+            tied_pos[id] = pos;
+            tied_by_chr[links.tied_rows[id].chr as usize].push(pos);
+        }
+        // kept rows before each tied row
+        let kept_before = tied
+            .iter()
+            .enumerate()
+            .map(|(i, x)| x.0 - i)
+            .collect::<Vec<usize>>();
+        let position = |row| match row {
+            OutputRow::Kept(k) => k + kept_before.partition_point(|&x| x <= k),
+            OutputRow::Tied(id) => tied_pos[id],
+        };
+        // where the row before the one at pos goes, if its character is chr
+        let place = |pos: usize, chr: u8| {
+            let kept = pos - tied.partition_point(|x| x.0 < pos);
+            let rank =
+                kept_rank(kept, chr) + tied_by_chr[chr as usize].partition_point(|&x| x < pos);
+            // the last row goes first among those starting with a separator
+            let after_last = usize::from(chr == b'\n');
+            let after_start = start.is_some_and(|x| x.1 == chr && position(x.0) < pos);
+            starts[chr as usize] + after_last + rank - usize::from(after_start)
+        };
+
+        let mut new_tied = Vec::with_capacity(tied.len());
+        if let PredRow::Tied(id) = last {
+            new_tied.push((starts[b'\n' as usize], id));
+        }
+        for &(row, chr, pred) in placing.iter() {
+            if let PredRow::Tied(id) = pred {
+                new_tied.push((place(position(row), chr), id));
+            }
+        }
+        new_tied.sort_unstable();
+
+        stats.interleave_iterations += 1;
+        if !observer.on_iteration(stats) {
+            return Err(MergeStopped);
+        }
+        if new_tied == tied {
+            break;
+        }
+        tied = new_tied;
+    }
+
+    Ok(tied)
+}
+
+// Interleave of the rows of an output before the first pass: the rows of each source
+// in turn, in the source's own order. Each position holds the source of its row, or
+// the number of sources for a tied row.
+// Returns the interleave as runs of the same label, and the ids of the tied rows in order.
+pub fn initial_runs(lens: &[usize], links: &Links) -> (Vec<(u16, usize)>, Vec<usize>) {
+    let tied_label = links.sources.len() as u16;
+    let kept = |src: usize, rows: Range<usize>| match links.sources[src].skipped.as_ref() {
+        Some(skipped) => rows.filter(|&row| !skipped[row]).count(),
+        None => rows.len(),
+    };
+    let mut runs = Vec::new();
+    let mut tied_order = Vec::new();
+    for (src, (&len, source)) in lens.iter().zip(links.sources.iter()).enumerate() {
+        let mut start = 0;
+        for &(row, id) in source.tied.iter() {
+            runs.push((src as u16, kept(src, start..row)));
+            runs.push((tied_label, 1));
+            tied_order.push(id);
+            start = row + 1;
+        }
+        runs.push((src as u16, kept(src, start..len)));
+    }
+    runs.retain(|x| x.1 > 0);
+    (runs, tied_order)
+}
+
+// Compute the interleave of the rows of several BWTs that go into an output with the
+// given counts, as laid out by initial_linked_interleave.
+// The rows are sorted by the output's text, which ends at its last row, so that row
+// sorts first among those starting with a separator.
+fn compute_interleave_linked(
+    bwts: &[&BWT],
+    links: &Links,
+    counts: &[usize; 256],
+    stats: &mut MergeStats,
+    observer: &mut dyn MergeObserver,
+) -> Result<(Vec<u16>, Vec<usize>), MergeStopped> {
+    // construct character starts array
+    let mut starts: [usize; 256] = [0; 256];
+    let mut sum = 0;
+    for i in 0..256 {
+        starts[i] = sum;
+        sum += counts[i];
+    }
+
+    let lens = bwts.iter().map(|x| x.len()).collect::<Vec<usize>>();
+    let (runs, mut tied_order) = initial_runs(&lens, links);
+    let mut interleave = runs
+        .iter()
+        .flat_map(|&(label, len)| std::iter::repeat_n(label, len))
+        .collect::<Vec<u16>>();
+    let Some(last) = links.last else {
+        return Ok((interleave, tied_order));
+    };
+    let tied_label = bwts.len() as u16;
+
+    loop {
+        let mut rows = links
+            .sources
+            .iter()
+            .map(SourceRows::new)
+            .collect::<Vec<_>>();
+        let mut tied = tied_order.iter();
+
+        let mut offsets = starts;
+        let mut new_interleave = vec![0u16; interleave.len()];
+        let mut new_tied = Vec::with_capacity(tied_order.len());
+        let mut place = |pred: PredRow, chr: u8| {
+            let pos = offsets[chr as usize];
+            offsets[chr as usize] += 1;
+            match pred {
+                PredRow::Source(src) => new_interleave[pos] = src as u16,
+                PredRow::Tied(id) => {
+                    new_interleave[pos] = tied_label;
+                    new_tied.push((pos, id));
+                }
+                PredRow::Start => unreachable!("Only the start of the output has no row before it"),
+            }
+        };
+        place(last, b'\n');
+
+        for (i, &src) in interleave.iter().enumerate() {
+            if i % PROGRESS_INTERVAL == 0
+                && i > 0
+                && !observer.on_progress(
+                    MergePhase::Interleave(stats.interleave_iterations),
+                    i,
+                    interleave.len(),
+                )
+            {
+                return Err(MergeStopped);
+            }
+
+            let (chr, pred) = match src == tied_label {
+                true => {
+                    let row =
+                        links.tied_rows[*tied.next().expect("Fewer tied rows than positions")];
+                    (row.chr, row.pred)
+                }
+                false => {
+                    let src = src as usize;
+                    let (row, pred) = rows[src].next_row();
+                    (bwts[src][row], pred.unwrap_or(PredRow::Source(src)))
+                }
+            };
+            if pred != PredRow::Start {
+                place(pred, chr);
+            }
+        }
+        new_tied.sort_unstable();
+        let new_tied_order = new_tied.into_iter().map(|x| x.1).collect::<Vec<usize>>();
+
+        stats.interleave_iterations += 1;
+        if !observer.on_iteration(stats) {
+            return Err(MergeStopped);
+        }
+
+        if new_interleave == interleave && new_tied_order == tied_order {
+            break;
+        }
+        interleave = new_interleave;
+        tied_order = new_tied_order;
+    }
+    Ok((interleave, tied_order))
+}
+
+// Merge several BWTs at once, giving the same BWT and line index as run_bwt on their
+// texts concatenated in order.
+// The texts must end with a separator. Either all of the BWTs have document ids or
+// none do, which panics otherwise, like bwt_merge.
+pub fn bwt_merge_many(data: &[BWTData]) -> BWTData {
+    bwt_merge_many_observed(data, &mut NoopObserver)
+        .expect("NoopObserver never stops a merge")
+        .0
+}
+
+// Merge several BWTs at once, reporting progress to the observer.
+// Returns the merged BWT along with statistics about the merge.
+//
+// The rows of each BWT are placed in its own order, like in a pairwise merge, apart
+// from the tied rows at the end of its text, whose order among its rows depends on
+// the text of the BWTs after it. Those are found by backward search from the end of
+// the text and sorted on their own.
+pub fn bwt_merge_many_observed(
+    data: &[BWTData],
+    observer: &mut dyn MergeObserver,
+) -> Result<(BWTData, MergeStats), MergeStopped> {
+    // one label is kept for tied rows
+    assert!(
+        data.len() <= u16::MAX as usize,
+        "Too many BWTs to merge at once"
+    );
+    let doc_ids = concat_doc_ids(&data.iter().collect::<Vec<_>>());
+
+    // construct character counts array
+    let mut counts: [usize; 256] = [0; 256];
+    for (_, _, counts_i, _) in data.iter() {
+        for i in 0..256 {
+            counts[i] += counts_i[i];
+        }
+    }
+
+    // line ids of each BWT are offset by the number of lines before it
+    let mut line_offsets = Vec::with_capacity(data.len());
+    let mut num_newlines = 0;
+    for (_, _, counts_i, _) in data.iter() {
+        line_offsets.push(num_newlines);
+        num_newlines += counts_i[b'\n' as usize];
+    }
+
+    // each BWT is a piece of the output, and all but the last are followed by
+    // the next one rather than by the end of their text
+    let sources = (0..data.len())
+        .filter(|&src| !data[src].0.is_empty())
+        .collect::<Vec<usize>>();
+    let mut pieces = Vec::with_capacity(sources.len());
+    let mut chains = Vec::with_capacity(sources.len());
+    for (i, &src) in sources.iter().enumerate() {
+        let counts_i = &data[src].2;
+        let piece = Piece {
+            source: src,
+            lines: 0..counts_i[b'\n' as usize],
+            start_row: start_row(&data[src])
+                .expect("BWTs merged at once must be of text ending with a separator"),
+            last_row: counts_i[..b'\n' as usize].iter().sum(),
+            relinked: i + 1 < sources.len(),
+        };
+        chains.push(match piece.relinked {
+            true => {
+                let blocks = fm_index(&data[src]);
+                tied_chain(&data[src], &blocks, piece.start_row, &piece, |line| {
+                    line + line_offsets[src]
+                })
+            }
+            false => (Vec::new(), false),
+        });
+        pieces.push(piece);
+    }
+    let links = link_pieces(data.len(), &pieces, &chains);
+
+    // two interleaves and the tied rows, plus the merged bwt and line index
+    let len = counts.iter().sum::<usize>();
+    let mut stats = MergeStats {
+        peak_memory: 4 * len
+            + links.tied_rows.len()
+                * (std::mem::size_of::<TiedRow>() + 3 * std::mem::size_of::<usize>())
+            + len * (1 + std::mem::size_of::<usize>()),
+        ..Default::default()
+    };
+
+    let start = std::time::Instant::now();
+    let bwts = data.iter().map(|(bwt, _, _, _)| bwt).collect::<Vec<_>>();
+    let (interleave, tied_order) =
+        compute_interleave_linked(&bwts, &links, &counts, &mut stats, observer)?;
+    stats.interleave_time = start.elapsed();
+
+    // construct bwt
+    let start = std::time::Instant::now();
+    let mut bwt = Vec::with_capacity(interleave.len());
+    let mut line_index = Vec::with_capacity(interleave.len());
+    let mut rows = links
+        .sources
+        .iter()
+        .map(SourceRows::new)
+        .collect::<Vec<_>>();
+    let mut tied = tied_order.iter();
+    let tied_label = data.len() as u16;
+    for (i, &src) in interleave.iter().enumerate() {
+        if i % PROGRESS_INTERVAL == 0
+            && i > 0
+            && !observer.on_progress(MergePhase::Output, i, interleave.len())
+        {
+            return Err(MergeStopped);
+        }
+
+        if src == tied_label {
+            let row = links.tied_rows[*tied.next().expect("Fewer tied rows than positions")];
+            bwt.push(row.chr);
+            line_index.push(row.line);
+        } else {
+            let src = src as usize;
+            let (bwt_i, line_ind_i, _, _) = &data[src];
+            let (row, _) = rows[src].next_row();
+            bwt.push(bwt_i[row]);
+            line_index.push(line_ind_i[row] + line_offsets[src]);
+        }
+    }
+    stats.output_time = start.elapsed();
+    Ok(((bwt, line_index, counts, doc_ids), stats))
+}
+
+// Runs of lines left after deleting some, as pieces of the output with the rows at their
+// ends. line_start gives the row of the suffix at the start of a line, and pred_row the
+// row of the suffix before the one at a row. Every piece but one ending the text is
+// followed by deleted lines, so is relinked.
+pub fn remaining_pieces(
+    line_deleted: &[bool],
+    line_start: impl Fn(usize) -> usize,
+    pred_row: impl Fn(usize) -> usize,
+) -> Vec<Piece> {
+    let num_lines = line_deleted.len();
+    let mut pieces = Vec::new();
+    let mut line = 0;
+    while line < num_lines {
+        if line_deleted[line] {
+            line += 1;
+            continue;
+        }
+        let start = line;
+        while line < num_lines && !line_deleted[line] {
+            line += 1;
+        }
+        // the separator ending the piece is before the start of the next line,
+        // or of the text for the last line
+        pieces.push(Piece {
+            source: 0,
+            lines: start..line,
+            start_row: line_start(start),
+            last_row: pred_row(line_start(line % num_lines)),
+            relinked: line < num_lines,
+        });
+    }
+    pieces
+}
+
+// Remove lines from a BWT without rebuilding it, giving the same BWT as run_bwt on
+// the remaining text. The text must end with a separator, and the BWT must be one
+// run_bwt or bwt_merge_many would build: walking it back follows the text.
+// Remaining line ids are renumbered to stay contiguous, and document ids are kept.
+//
+// Each run of remaining lines is a piece of the output, placed like the inputs of
+// bwt_merge_many: its rows keep their order apart from the tied rows at its end,
+// whose order depended on the deleted lines after it.
+pub fn bwt_delete_lines(data: &BWTData, line_ids: &[usize]) -> BWTData {
+    let (bwt, line_ind, counts, doc_ids) = data;
+    let num_lines = counts[b'\n' as usize];
+
+    let mut line_deleted = vec![false; num_lines];
+    for &line_id in line_ids.iter() {
+        assert!(line_id < num_lines, "Line id {} out of range", line_id);
+        line_deleted[line_id] = true;
+    }
+    if !line_deleted.iter().any(|&x| x) {
+        return data.clone();
+    }
+
+    // number of deleted lines before each line
+    let mut deleted_before = Vec::with_capacity(num_lines);
+    let mut num_deleted = 0;
+    for &deleted in line_deleted.iter() {
+        deleted_before.push(num_deleted);
+        if deleted {
+            num_deleted += 1;
+        }
+    }
+
+    // a text not ending with a separator has rows past the last line
+    assert!(
+        line_ind.iter().all(|&x| x < num_lines),
+        "BWTs with lines deleted must be of text ending with a separator"
+    );
+
+    // rows of the deleted lines, the start of each line, and the counts of the rest
+    let mut skipped = BitVec::from_elem(bwt.len(), false);
+    let mut line_start = vec![0; num_lines];
+    let mut new_counts: [usize; 256] = [0; 256];
+    for i in 0..bwt.len() {
+        if line_deleted[line_ind[i]] {
+            skipped.set(i, true);
+        } else {
+            new_counts[bwt[i] as usize] += 1;
+        }
+        if bwt[i] == b'\n' {
+            line_start[line_ind[i]] = i;
+        }
+    }
+
+    let start_row = line_start[0];
+    let blocks = fm_index(data);
+    let pieces = remaining_pieces(
+        &line_deleted,
+        |line| line_start[line],
+        |row| pred_row(&blocks, bwt, start_row, row),
+    );
+    let chains = pieces
+        .iter()
+        .map(|piece| match piece.relinked {
+            true => tied_chain(data, &blocks, start_row, piece, |line| {
+                line - deleted_before[line]
+            }),
+            false => (Vec::new(), false),
+        })
+        .collect::<Vec<_>>();
+    let mut links = link_pieces(1, &pieces, &chains);
+    links.sources[0].skipped = Some(skipped);
+
+    // the rows other than the tied ones keep their order
+    let num_kept = new_counts.iter().sum::<usize>() - links.tied_rows.len();
+    let mut rows = SourceRows::new(&links.sources[0]);
+    let mut kept = Vec::with_capacity(num_kept);
+    let mut kept_links = Vec::new();
+    for k in 0..num_kept {
+        let (row, pred) = rows.next_row();
+        kept.push(bwt[row]);
+        if let Some(pred) = pred {
+            kept_links.push((k, pred));
+        }
+    }
+    let tied = place_tied_rows(
+        kept,
+        &kept_links,
+        bwt.len(),
+        &links,
+        &new_counts,
+        &mut MergeStats::default(),
+        &mut NoopObserver,
+    )
+    .expect("NoopObserver never stops a merge");
+
+    let len = new_counts.iter().sum();
+    let mut rows = SourceRows::new(&links.sources[0]);
+    let mut tied = tied.into_iter().peekable();
+    let mut new_bwt = Vec::with_capacity(len);
+    let mut new_line_ind = Vec::with_capacity(len);
+    for pos in 0..len {
+        match tied.next_if(|x| x.0 == pos) {
+            Some((_, id)) => {
+                new_bwt.push(links.tied_rows[id].chr);
+                new_line_ind.push(links.tied_rows[id].line);
+            }
+            None => {
+                let (row, _) = rows.next_row();
+                new_bwt.push(bwt[row]);
+                new_line_ind.push(line_ind[row] - deleted_before[line_ind[row]]);
+            }
+        }
+    }
+    let new_doc_ids = doc_ids.as_ref().map(|doc_ids| {
+        doc_ids
+            .iter()
+            .zip(line_deleted.iter())
+            .filter(|(_, &deleted)| !deleted)
+            .map(|(&doc_id, _)| doc_id)
+            .collect::<DocIds>()
+    });
+    (new_bwt, new_line_ind, new_counts, new_doc_ids)
 }
 
 const BLOCK_SIZE: usize = 1024;
@@ -127,8 +1211,8 @@ pub struct FMBlock {
 
 // Compute the FM-index of a BWT.
 pub fn fm_index(data: &BWTData) -> Vec<FMBlock> {
-    let (bwt, _, all_counts) = data;
-    let num_blocks = (bwt.len() + BLOCK_SIZE - 1) / BLOCK_SIZE;
+    let (bwt, _, all_counts, _) = data;
+    let num_blocks = bwt.len().div_ceil(BLOCK_SIZE);
     let mut blocks: Vec<FMBlock> = Vec::with_capacity(num_blocks);
 
     // calculate C array
@@ -196,13 +1280,10 @@ pub fn substring_search(blocks: &[FMBlock], pattern: &[u8], n: usize) -> Option<
     Some((start, end))
 }
 
-// Get all matching line indices from the BWT
-pub fn get_matching_lines(
-    bwt_data: &BWTData,
-    blocks: &[FMBlock],
-    pattern: &[u8],
-) -> BTreeSet<usize> {
-    let (bwt, line_ind, _) = bwt_data;
+// Get the ids of all matching lines from the BWT: their document ids if it has them,
+// otherwise their line ids
+pub fn get_matching_lines(bwt_data: &BWTData, blocks: &[FMBlock], pattern: &[u8]) -> BTreeSet<u64> {
+    let (bwt, line_ind, _, doc_ids) = bwt_data;
     let n = bwt.len();
     let res = substring_search(blocks, pattern, n);
     if res.is_none() {
@@ -210,9 +1291,12 @@ pub fn get_matching_lines(
     }
 
     let (start, end) = res.unwrap();
-    let mut lines: BTreeSet<usize> = BTreeSet::new();
-    for val in line_ind.iter().take(end).skip(start) {
-        lines.insert(*val);
+    let mut lines: BTreeSet<u64> = BTreeSet::new();
+    for &val in line_ind.iter().take(end).skip(start) {
+        lines.insert(match doc_ids {
+            Some(doc_ids) => doc_ids[val],
+            None => val as u64,
+        });
     }
     lines
 }
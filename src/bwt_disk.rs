@@ -1,125 +1,66 @@
-use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::collections::{BTreeSet, HashMap};
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
 
 use anyhow::{anyhow, Result};
 use bit_vec::BitVec;
-use opendal::{raw::oio::ReadExt, services::Fs, Error, Operator, Reader};
-use rand::seq::SliceRandom;
-
-use crate::bwt::run_bwt;
-
-// generate subsets of input file of certain sizes using naive algorithm
-// and calculate bwt and write to file
-const SIZES: &[usize] = &[
-    1024,
-    4096,
-    16384,
-    65536,
-    262144,
-    1048576,
-    2097152,
-    usize::MAX,
-];
-pub fn generate_test_files(input_file: &str, output_path: &str) {
-    let line_count = std::fs::read_to_string(input_file).unwrap().lines().count();
-    let mut rng = rand::thread_rng();
-
-    for xsize in SIZES {
-        // time
-        let start = std::time::Instant::now();
-
-        let mut size = *xsize;
-        if size == usize::MAX {
-            size = line_count / 2;
-        }
-
-        // generate random sample indices
-        let mut sample_indices: Vec<usize> = (0..line_count).collect();
-        sample_indices.shuffle(&mut rng);
-        sample_indices.truncate(size * 2);
-
-        // create two output files
-        for ind in 0..2 {
-            let mut indices = sample_indices[ind * size..(ind + 1) * size].to_vec();
-            indices.sort();
-
-            let mut strs: Vec<u8> = Vec::new();
-            let mut indices_i = 0;
-            for (i, line) in std::fs::read_to_string(input_file)
-                .unwrap()
-                .lines()
-                .enumerate()
-            {
-                if indices_i < indices.len() && i == indices[indices_i] {
-                    strs.extend_from_slice(line.as_bytes());
-                    strs.push(b'\n');
-                    indices_i += 1;
-                }
-            }
-
-            let bwt = run_bwt(&strs);
+use crc32fast::Hasher;
+use libdivsufsort_rs::divsufsort64;
+use opendal::{
+    services::{Fs, Memory},
+    Operator, Reader, Scheme, Writer,
+};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
 
-            let output_file = format!("{}/{}_{}.bwt", output_path, size, ind);
-            let output_index_file = format!("{}/{}_{}.index", output_path, size, ind);
-            let output_counts_file = format!("{}/{}_{}.counts", output_path, size, ind);
+use crate::block::{self, BlockCompression, BlockEncoder, BlockReader, BlockTable};
+use crate::bwt::{
+    bwt_delete_lines, initial_runs, link_pieces, place_tied_rows, remaining_pieces, run_bwt,
+    run_bwt_cancellable, BWTData, DocIds, Links, Piece, PredRow, SourceRows, TiedChain, TiedRow,
+};
+use crate::checkpoint::{MergeCheckpoint, OutputProgress};
+use crate::mapped::{local_path, map_file, MappedSegment};
+use crate::progress::{
+    Cancellation, MergeObserver, MergePhase, MergeStats, MergeStopped, NoopObserver,
+    PROGRESS_INTERVAL,
+};
+use crate::sample::sample_sizes;
+use crate::segment::{
+    decode_index_header, is_text_format, Checksums, IdDecoder, IdEncoding, IndexWriter,
+    MergeMismatch, RankBuilder, RankIndex, SegmentMeta, SegmentProblem, INDEX_HEADER_SIZE,
+    SEPARATOR,
+};
+use crate::spill::SpilledInterleave;
+use crate::stream::{ByteStream, ReadOptions};
 
-            std::fs::write(output_file, bwt.0).unwrap();
-            let index_str = bwt
-                .1
-                .iter()
-                .map(|x| x.to_string())
-                .collect::<Vec<String>>()
-                .join("\n")
-                + "\n";
-            std::fs::write(output_index_file, index_str).unwrap();
-            let counts_str = bwt
-                .2
-                .iter()
-                .map(|x| x.to_string())
-                .collect::<Vec<String>>()
-                .join("\n")
-                + "\n";
-            std::fs::write(output_counts_file, counts_str).unwrap();
-        }
-
-        // generate full sampled file
-        sample_indices.sort();
-
-        let mut strs: Vec<u8> = Vec::new();
-        let mut indices_i = 0;
-        for (i, line) in std::fs::read_to_string(input_file)
-            .unwrap()
-            .lines()
-            .enumerate()
-        {
-            if indices_i < sample_indices.len() && i == sample_indices[indices_i] {
-                strs.extend_from_slice(line.as_bytes());
-                strs.push(b'\n');
-                indices_i += 1;
-            }
-        }
+// Extensions of the files of a segment
+const SEGMENT_EXTS: &[&str] = &["bwt", "index", "counts", "rank", "docs"];
 
-        let output_file = format!("{}/{}_full.txt", output_path, size);
-        std::fs::write(output_file, strs).unwrap();
-        println!("generated test files for size {}", size);
+// Bytes buffered by output files before each write
+const WRITE_BUFFER_SIZE: usize = 1024 * 1024;
 
-        let duration = start.elapsed();
-        println!("time to generate for size {}: {:?}", size, duration);
+// Interleave before the first pass: all of the first BWT, then all of the second
+fn initial_interleave(bwt0_len: usize, bwt1_len: usize) -> BitVec {
+    let mut interleave = BitVec::from_elem(bwt0_len + bwt1_len, true);
+    for i in 0..bwt0_len {
+        interleave.set(i, false);
     }
+    interleave
 }
 
-// Size of buffer for reading files
-const BUFFER_SIZE: usize = 1024 * 1024;
-
-// Compute the interleave of two BWTs, using opendal Readers
+// Compute the interleave of two BWTs, streamed from disk, starting from the given one.
+// Saves the interleave after each pass if a checkpoint is given.
 async fn compute_interleave(
-    bwt0_reader: &mut Reader,
-    bwt1_reader: &mut Reader,
-    lens: (usize, usize),
+    bwt0: &mut ByteStream<SegmentReader>,
+    bwt1: &mut ByteStream<SegmentReader>,
+    mut interleave: BitVec,
     counts: &[usize; 256],
-) -> Result<BitVec, Error> {
-    let (bwt0_len, bwt1_len) = lens;
-
+    stats: &mut MergeStats,
+    observer: &mut dyn MergeObserver,
+    checkpoint: Option<&Checkpoint>,
+) -> Result<BitVec> {
     // construct character starts array
     let mut starts: [usize; 256] = [0; 256];
     let mut sum = 0;
@@ -128,68 +69,200 @@ async fn compute_interleave(
         sum += counts[i];
     }
 
-    let mut interleave = BitVec::from_elem(bwt0_len + bwt1_len, true);
-    for i in 0..bwt0_len {
-        interleave.set(i, false);
-    }
-
-    let mut interleave_iterations = 0;
-
     loop {
-        let mut ind: [usize; 2] = [0, 0];
-
-        // reset readers
-        bwt0_reader.seek(std::io::SeekFrom::Start(0)).await?;
-        bwt1_reader.seek(std::io::SeekFrom::Start(0)).await?;
-
-        let mut bwt0 = vec![0u8; BUFFER_SIZE];
-        let mut bwt1 = vec![0u8; BUFFER_SIZE];
-        bwt0_reader.read(&mut bwt0).await?;
-        bwt1_reader.read(&mut bwt1).await?;
+        bwt0.rewind().await?;
+        bwt1.rewind().await?;
 
         let mut offsets = starts;
         let mut new_interleave = BitVec::from_elem(interleave.len(), false);
         for i in 0..interleave.len() {
+            if i % PROGRESS_INTERVAL == 0
+                && i > 0
+                && !observer.on_progress(
+                    MergePhase::Interleave(stats.interleave_iterations),
+                    i,
+                    interleave.len(),
+                )
+            {
+                return Err(MergeStopped.into());
+            }
+
             if interleave[i] {
-                new_interleave.set(offsets[bwt1[ind[1]] as usize], true);
-                offsets[bwt1[ind[1]] as usize] += 1;
-                ind[1] += 1;
+                let chr = bwt1.next_byte().await? as usize;
+                new_interleave.set(offsets[chr], true);
+                offsets[chr] += 1;
+            } else {
+                let chr = bwt0.next_byte().await? as usize;
+                offsets[chr] += 1;
+            }
+        }
+
+        stats.bytes_read += bwt0.take_bytes_read() + bwt1.take_bytes_read();
+        stats.interleave_iterations += 1;
+        let converged = new_interleave == interleave;
+        if let (Some(checkpoint), false) = (checkpoint, converged) {
+            checkpoint
+                .save(stats.interleave_iterations, &new_interleave, None)
+                .await?;
+        }
+        if !observer.on_iteration(stats) {
+            return Err(MergeStopped.into());
+        }
+
+        if converged {
+            break;
+        }
+        interleave = new_interleave;
+    }
+
+    Ok(interleave)
+}
+
+// Byte of a stream at pos, which must not be before the stream's position
+async fn byte_at(stream: &mut ByteStream<SegmentReader>, pos: usize) -> Result<u8> {
+    stream.skip(pos as u64 - stream.position()).await?;
+    stream.next_byte().await
+}
+
+// Compute the interleave of BWTs streamed from disk like compute_interleave_many,
+// keeping it in files rather than in memory.
+// With links, the interleave is of the rows of an output made of pieces of the BWTs,
+// as in compute_interleave_many, and the ids of its tied rows are returned in order.
+async fn compute_interleave_spilled(
+    bwts: &mut [ByteStream<SegmentReader>],
+    interleave: &mut SpilledInterleave,
+    links: Option<(&Links, Vec<usize>)>,
+    separator: u8,
+    stats: &mut MergeStats,
+    observer: &mut dyn MergeObserver,
+) -> Result<Vec<usize>> {
+    let len = interleave.len();
+    let tied_label = bwts.len() as u16;
+    let (links, mut tied_order) = match links {
+        Some((links, tied_order)) => (Some(links), tied_order),
+        None => (None, Vec::new()),
+    };
+    let last = links.and_then(|x| x.last);
+    if len == 0 {
+        return Ok(tied_order);
+    }
+
+    // construct character starts array
+    let mut starts: [usize; 256] = [0; 256];
+    let mut sum = 0;
+    for (start, count) in starts.iter_mut().zip(interleave.counts().iter()) {
+        *start = sum;
+        sum += count;
+    }
 
-                if ind[1] == BUFFER_SIZE {
-                    bwt1_reader.read(&mut bwt1).await?;
-                    ind[1] = 0;
+    loop {
+        for bwt in bwts.iter_mut() {
+            bwt.rewind().await?;
+        }
+        let mut rows = links
+            .map(|x| x.sources.iter().map(SourceRows::new).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let mut tied = tied_order.iter();
+
+        let mut current = interleave.reader();
+        let mut next = interleave.writer();
+        // positions of the next interleave, to keep track of its tied rows
+        let mut offsets = starts;
+        let mut new_tied = Vec::with_capacity(tied_order.len());
+        let mut label = |pred: PredRow, chr: u8| {
+            let pos = offsets[chr as usize];
+            offsets[chr as usize] += 1;
+            match pred {
+                PredRow::Source(src) => src as u16,
+                PredRow::Tied(id) => {
+                    new_tied.push((pos, id));
+                    tied_label
                 }
-            } else {
-                offsets[bwt0[ind[0]] as usize] += 1;
-                ind[0] += 1;
+                PredRow::Start => unreachable!("Only the start of the output has no row before it"),
+            }
+        };
+        if let Some(last) = last {
+            next.push(separator, label(last, separator)).await?;
+        }
+        for i in 0..len {
+            if i % PROGRESS_INTERVAL == 0
+                && i > 0
+                && !observer.on_progress(
+                    MergePhase::Interleave(stats.interleave_iterations),
+                    i,
+                    len,
+                )
+            {
+                return Err(MergeStopped.into());
+            }
 
-                if ind[0] == BUFFER_SIZE {
-                    bwt0_reader.read(&mut bwt0).await?;
-                    ind[0] = 0;
+            let src = current.next().await?;
+            let (chr, pred) = match (links, src == tied_label) {
+                (Some(links), true) => {
+                    let id = *tied
+                        .next()
+                        .ok_or_else(|| anyhow!("Fewer tied rows than positions"))?;
+                    (links.tied_rows[id].chr, links.tied_rows[id].pred)
+                }
+                (Some(_), false) => {
+                    let src = src as usize;
+                    let (row, pred) = rows[src].next_row();
+                    let chr = byte_at(&mut bwts[src], row).await?;
+                    (chr, pred.unwrap_or(PredRow::Source(src)))
                 }
+                (None, _) => {
+                    let chr = bwts[src as usize].next_byte().await?;
+                    (chr, PredRow::Source(src as usize))
+                }
+            };
+            if pred != PredRow::Start {
+                next.push(chr, label(pred, chr)).await?;
             }
         }
+        new_tied.sort_unstable();
+        let new_tied_order = new_tied.into_iter().map(|x| x.1).collect::<Vec<usize>>();
 
-        interleave_iterations += 1;
+        let (changed, bytes_written, bytes_read) = next.close().await?;
+        stats.bytes_written += bytes_written;
+        stats.bytes_read += bytes_read + current.take_bytes_read();
+        for bwt in bwts.iter_mut() {
+            stats.bytes_read += bwt.take_bytes_read();
+        }
+        stats.interleave_iterations += 1;
+        interleave.advance().await?;
+        if !observer.on_iteration(stats) {
+            return Err(MergeStopped.into());
+        }
 
-        if new_interleave == interleave {
+        if !changed && new_tied_order == tied_order {
             break;
         }
-        interleave = new_interleave;
+        tied_order = new_tied_order;
     }
 
-    println!("interleave iterations: {}", interleave_iterations);
-    Ok(interleave)
+    Ok(tied_order)
 }
 
-// get operator and readers for filesystem
-fn get_operator() -> std::io::Result<Operator> {
+// Operator for the local filesystem, rooted at the given directory.
+// Relative roots are relative to the current directory.
+pub fn fs_operator(root: &str) -> Result<Operator> {
+    let root = std::env::current_dir()?.join(root);
     let mut builder = Fs::default();
-    let current_path = std::env::current_dir()?;
-    builder.root(current_path.to_str().expect("Current path not found"));
+    builder.root(root.to_str().ok_or_else(|| anyhow!("Invalid root path"))?);
     Ok(Operator::new(builder)?.finish())
 }
 
+// Operator for opendal's in-memory service, which keeps everything in the process
+pub fn memory_operator() -> Result<Operator> {
+    Ok(Operator::new(Memory::default())?.finish())
+}
+
+// Operator for any opendal service, from its scheme name and configuration,
+// e.g. "s3" with bucket, endpoint, region and credentials
+pub fn operator_from_config(scheme: &str, options: HashMap<String, String>) -> Result<Operator> {
+    Ok(Operator::via_map(Scheme::from_str(scheme)?, options)?)
+}
+
 async fn get_file_reader(
     path: &str,
     operator: &Operator,
@@ -197,224 +270,3472 @@ async fn get_file_reader(
     operator.clone().reader(path).await
 }
 
+async fn open_stream(path: &str, operator: &Operator) -> Result<ByteStream<Reader>> {
+    let reader = get_file_reader(path, operator).await?;
+    Ok(ByteStream::prefetching(reader, ReadOptions::default()))
+}
+
 async fn read_file_size(path: &str, operator: &Operator) -> Result<usize> {
     let len = operator.stat(path).await?.content_length() as usize;
     Ok(len)
 }
 
-// Read integers from a Reader
-// extra_num is the number formed by the last digits read
-async fn read_ints(reader: &mut Reader, extra_num: usize) -> Result<(Vec<usize>, usize)> {
-    let mut buf = vec![0u8; BUFFER_SIZE];
-    reader.read(&mut buf).await?;
+// Reader of a .bwt or .index file, decompressing it if it is block-compressed
+enum SegmentReader {
+    Plain(Reader),
+    Blocks(BlockReader),
+}
+
+impl AsyncRead for SegmentReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            SegmentReader::Plain(reader) => Pin::new(reader).poll_read(cx, buf),
+            SegmentReader::Blocks(reader) => Pin::new(reader).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncSeek for SegmentReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        match self.get_mut() {
+            SegmentReader::Plain(reader) => Pin::new(reader).start_seek(position),
+            SegmentReader::Blocks(reader) => Pin::new(reader).start_seek(position),
+        }
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        match self.get_mut() {
+            SegmentReader::Plain(reader) => Pin::new(reader).poll_complete(cx),
+            SegmentReader::Blocks(reader) => Pin::new(reader).poll_complete(cx),
+        }
+    }
+}
+
+// Stream the data of a .bwt or .index file of a segment, compressed or not.
+// Uncompressed files on the local filesystem are mapped into memory, and the
+// stream's chunks are slices of the map.
+// Returns the stream and the length of the data.
+async fn open_segment_file(
+    path: &str,
+    operator: &Operator,
+    compressed: bool,
+    options: ReadOptions,
+) -> Result<(ByteStream<SegmentReader>, usize)> {
+    if !compressed {
+        if let Some(map) = map_file(operator, path)? {
+            let len = map.len();
+            return Ok((ByteStream::mapped(map), len));
+        }
+    }
+    let (reader, len) = if compressed {
+        let table = BlockTable::read(operator, path).await?;
+        let len = table.data_len();
+        let reader = BlockReader::new(operator, path, table, options.buffer_size);
+        (SegmentReader::Blocks(reader), len)
+    } else {
+        let reader = get_file_reader(path, operator).await?;
+        let len = read_file_size(path, operator).await?;
+        (SegmentReader::Plain(reader), len)
+    };
+    Ok((ByteStream::prefetching(reader, options), len))
+}
 
+// Read integers from a stream, in the old text format with one decimal number per line.
+// extra_num is the number formed by the last digits read
+async fn read_text_ints(
+    stream: &mut ByteStream<Reader>,
+    extra_num: usize,
+) -> Result<(Vec<usize>, usize)> {
     let mut ints = Vec::new();
     let mut cur_num: usize = extra_num;
 
-    for chr in buf {
+    let chunk = stream.chunk().await?;
+    let len = chunk.len();
+    for &chr in chunk.iter() {
         if chr == b'\n' {
             ints.push(cur_num);
             cur_num = 0;
         } else if chr.is_ascii_digit() {
             cur_num = cur_num * 10 + (chr - b'0') as usize;
-        } else if chr == 0 {
-            break;
         } else {
             return Err(anyhow!("Invalid character in index file"));
         }
     }
+    stream.consume(len);
 
     Ok((ints, cur_num))
 }
 
-// Merge two BWTs using our algorithm.
-// Paths should be the paths to the extensionless files
-pub async fn bwt_merge_disk(bwt0_path: &str, bwt1_path: &str, output_path: &str) -> Result<()> {
-    // construct character counts array
-    let mut counts: [usize; 256] = [0; 256];
+// Error if a crash interrupted the commit of a segment, leaving some of its files
+// replaced and others not. Readers leave it to recover_output, or to the next write
+// of the segment, to finish the commit.
+async fn check_committed(operator: &Operator, bwt_path: &str) -> Result<()> {
+    let commit_path = format!("{}{}", bwt_path, COMMIT_SUFFIX);
+    if operator.is_exist(&commit_path).await? {
+        return Err(anyhow!(
+            "{}: segment has an unfinished commit, finish it with recover_output",
+            bwt_path
+        ));
+    }
+    Ok(())
+}
 
-    let operator = get_operator()?;
-    let mut counts0_reader =
-        get_file_reader(format!("{}.counts", bwt0_path).as_str(), &operator).await?;
-    let mut counts1_reader =
-        get_file_reader(format!("{}.counts", bwt1_path).as_str(), &operator).await?;
-    let mut buf: Vec<u8> = Vec::new();
-    counts0_reader.read_to_end(&mut buf).await?;
-    let counts0 = buf
-        .split(|&x| x == b'\n')
-        .filter(|x| !x.is_empty())
-        .map(|x| std::str::from_utf8(x).unwrap().parse().unwrap())
-        .collect::<Vec<usize>>();
-    buf.clear();
-    counts1_reader.read_to_end(&mut buf).await?;
-    let counts1 = buf
-        .split(|&x| x == b'\n')
-        .filter(|x| !x.is_empty())
-        .map(|x| std::str::from_utf8(x).unwrap().parse().unwrap())
-        .collect::<Vec<usize>>();
+// Read the metadata of a segment from its .counts file
+async fn read_meta(bwt_path: &str, operator: &Operator) -> Result<SegmentMeta> {
+    check_committed(operator, bwt_path).await?;
+    let data = operator
+        .read(format!("{}.counts", bwt_path).as_str())
+        .await?;
+    SegmentMeta::decode(&data).map_err(|e| anyhow!("{}: {}", bwt_path, e))
+}
 
-    assert!(
-        counts0.len() == 256 && counts1.len() == 256,
-        "Invalid counts file"
-    );
-    for i in 0..256 {
-        counts[i] = counts0[i] + counts1[i];
+// Stream of the line ids of an .index file
+struct IndexReader {
+    stream: ByteStream<SegmentReader>,
+    decoder: IdDecoder,
+    ids: Vec<usize>,
+    pos: usize,
+}
+
+impl IndexReader {
+    async fn open(
+        bwt_path: &str,
+        operator: &Operator,
+        compressed: bool,
+        options: ReadOptions,
+    ) -> Result<IndexReader> {
+        let index_path = format!("{}.index", bwt_path);
+        let (mut stream, _) =
+            open_segment_file(index_path.as_str(), operator, compressed, options).await?;
+        let mut header = [0u8; INDEX_HEADER_SIZE];
+        let len = stream.read_up_to(&mut header).await?;
+        let encoding =
+            decode_index_header(&header[..len]).map_err(|e| anyhow!("{}: {}", bwt_path, e))?;
+
+        Ok(IndexReader {
+            stream,
+            decoder: IdDecoder::new(encoding),
+            ids: Vec::new(),
+            pos: 0,
+        })
     }
-    let num_newlines = counts0[b'\n' as usize];
 
-    // get bwt file paths
-    let bwt0_file_path = format!("{}.bwt", bwt0_path);
-    let bwt1_file_path = format!("{}.bwt", bwt1_path);
+    // Next line id, or none at the end of the file
+    async fn next_id(&mut self) -> Result<Option<usize>> {
+        while self.pos == self.ids.len() {
+            self.ids.clear();
+            self.pos = 0;
+            let chunk = self.stream.chunk().await?;
+            if chunk.is_empty() {
+                if self.decoder.has_partial() {
+                    return Err(anyhow!("Line index ends in the middle of a line id"));
+                }
+                return Ok(None);
+            }
+            let len = chunk.len();
+            self.decoder.decode(chunk, &mut self.ids)?;
+            self.stream.consume(len);
+        }
+        self.pos += 1;
+        Ok(Some(self.ids[self.pos - 1]))
+    }
 
-    let mut bwt0_reader = get_file_reader(bwt0_file_path.as_str(), &operator).await?;
-    let mut bwt1_reader = get_file_reader(bwt1_file_path.as_str(), &operator).await?;
-    let bwt0_len = read_file_size(bwt0_file_path.as_str(), &operator).await?;
-    let bwt1_len = read_file_size(bwt1_file_path.as_str(), &operator).await?;
+    // Skip over line ids, which must all be present
+    async fn skip(&mut self, n: usize) -> Result<()> {
+        for _ in 0..n {
+            if self.next_id().await?.is_none() {
+                return Err(anyhow!("Line index ended while skipping ids"));
+            }
+        }
+        Ok(())
+    }
+}
 
-    let start = std::time::Instant::now();
-    let interleave = compute_interleave(
-        &mut bwt0_reader,
-        &mut bwt1_reader,
-        (bwt0_len, bwt1_len),
-        &counts,
-    )
-    .await?;
-    let duration = start.elapsed();
-    println!("interleave time: {:?}", duration);
+// Cheap check that a .bwt belongs with its .counts, before merging
+fn check_bwt_len(bwt_path: &str, meta: &SegmentMeta, bwt_len: usize) -> Result<()> {
+    let sum = meta.counts.iter().sum::<usize>();
+    if bwt_len != meta.bwt_len || sum != meta.bwt_len {
+        return Err(anyhow!(
+            "{}: .bwt has {} bytes and counts sum to {}, but the segment length is {}",
+            bwt_path,
+            bwt_len,
+            sum,
+            meta.bwt_len
+        ));
+    }
+    Ok(())
+}
 
-    // construct bwt
-    bwt0_reader.seek(std::io::SeekFrom::Start(0)).await?;
-    bwt1_reader.seek(std::io::SeekFrom::Start(0)).await?;
-    let mut bwt0 = vec![0u8; BUFFER_SIZE];
-    let mut bwt1 = vec![0u8; BUFFER_SIZE];
-    bwt0_reader.read(&mut bwt0).await?;
-    bwt1_reader.read(&mut bwt1).await?;
+fn index_too_short(bwt_path: &str) -> anyhow::Error {
+    anyhow!("{}: line index is shorter than the BWT", bwt_path)
+}
 
-    // read line index
-    let line_ind0_path = format!("{}.index", bwt0_path);
-    let line_ind1_path = format!("{}.index", bwt1_path);
-    let mut line_ind0_reader = get_file_reader(line_ind0_path.as_str(), &operator).await?;
-    let mut line_ind1_reader = get_file_reader(line_ind1_path.as_str(), &operator).await?;
-    let (mut line_ind0, mut extra_num0) = read_ints(&mut line_ind0_reader, 0).await?;
-    let (mut line_ind1, mut extra_num1) = read_ints(&mut line_ind1_reader, 0).await?;
-    let mut line_ind0_iter = line_ind0.iter();
-    let mut line_ind1_iter = line_ind1.iter();
+// Separator shared by all the segments
+fn common_separator(metas: &[SegmentMeta]) -> Result<u8> {
+    let separator = metas.first().map_or(SEPARATOR, |x| x.separator);
+    if metas.iter().any(|x| x.separator != separator) {
+        return Err(anyhow!("Segments have different separators"));
+    }
+    Ok(separator)
+}
 
-    let output_bwt_path = format!("{}.bwt", output_path);
-    let output_index_path = format!("{}.index", output_path);
-    let mut bwt_writer = BufWriter::new(File::create(output_bwt_path.as_str())?);
-    let mut index_writer = BufWriter::new(File::create(output_index_path.as_str())?);
-
-    let mut ind0 = 0;
-    let mut ind1 = 0;
-
-    for i in 0..interleave.len() {
-        if interleave[i] {
-            bwt_writer.write_all(&[bwt1[ind1]])?;
-
-            let line_ind_opt = line_ind1_iter.next();
-            let line_ind: usize;
-            if line_ind_opt.is_none() {
-                (line_ind1, extra_num1) = read_ints(&mut line_ind1_reader, extra_num1).await?;
-                line_ind1_iter = line_ind1.iter();
-                line_ind = *line_ind1_iter.next().expect("Line index is too short");
-            } else {
-                line_ind = *line_ind_opt.unwrap();
-            }
-            writeln!(index_writer, "{}", line_ind + num_newlines)?;
+// Write the document id of each line to the .docs file, as little-endian u64s
+pub async fn write_doc_ids(operator: &Operator, bwt_path: &str, doc_ids: &[u64]) -> Result<()> {
+    let data = doc_ids
+        .iter()
+        .flat_map(|x| x.to_le_bytes())
+        .collect::<Vec<u8>>();
+    let mut output = PartialOutput::new(operator, bwt_path).await?;
+    let result = output
+        .write(format!("{}.docs", bwt_path).as_str(), data)
+        .await;
+    output.finish(result).await
+}
 
-            ind1 += 1;
-            if ind1 == BUFFER_SIZE {
-                bwt1_reader.read(&mut bwt1).await?;
-                ind1 = 0;
-            }
-        } else {
-            bwt_writer.write_all(&[bwt0[ind0]])?;
-
-            let line_ind_opt = line_ind0_iter.next();
-            let line_ind: usize;
-            if line_ind_opt.is_none() {
-                (line_ind0, extra_num0) = read_ints(&mut line_ind0_reader, extra_num0).await?;
-                line_ind0_iter = line_ind0.iter();
-                line_ind = *line_ind0_iter.next().unwrap();
-            } else {
-                line_ind = *line_ind_opt.unwrap();
-            }
-            writeln!(index_writer, "{}", line_ind)?;
+// Read the document ids from the .docs file
+pub async fn read_doc_ids(operator: &Operator, bwt_path: &str) -> Result<DocIds> {
+    let buf = operator.read(format!("{}.docs", bwt_path).as_str()).await?;
+    if buf.len() % 8 != 0 {
+        return Err(anyhow!("Invalid document id file"));
+    }
+    Ok(buf
+        .chunks_exact(8)
+        .map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+        .collect())
+}
+
+// Buffered writer for an output file, keeping a checksum of its contents.
+// With an encoder, the data is block-compressed and the checksum is of the compressed file.
+struct OutputFile {
+    operator: Operator,
+    path: String,
+    writer: Writer,
+    buf: Vec<u8>,
+    hasher: Hasher,
+    len: u64,
+    encoder: Option<BlockEncoder>,
+}
+
+impl OutputFile {
+    async fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        match self.encoder.as_mut() {
+            Some(encoder) => encoder.push(data, &mut self.buf)?,
+            None => self.buf.extend_from_slice(data),
+        }
+        if self.buf.len() >= WRITE_BUFFER_SIZE {
+            self.flush_buf().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush_buf(&mut self) -> Result<()> {
+        if !self.buf.is_empty() {
+            self.hasher.update(&self.buf);
+            self.len += self.buf.len() as u64;
+            let buf = std::mem::replace(&mut self.buf, Vec::with_capacity(WRITE_BUFFER_SIZE));
+            self.writer.write(buf).await?;
+        }
+        Ok(())
+    }
+
+    // Make everything written so far durable, and continue by appending.
+    // Returns the length and checksum of the file at this point.
+    async fn sync(&mut self) -> Result<(u64, u32)> {
+        if self.encoder.is_some() {
+            return Err(anyhow!("Compressed output can't be resumed"));
+        }
+        self.flush_buf().await?;
+        self.writer.close().await?;
+        sync_local(&self.operator, &self.path)?;
+        self.writer = self.operator.writer_with(&self.path).append(true).await?;
+        Ok((self.len, self.hasher.clone().finalize()))
+    }
+
+    // Finish the file, returning its checksum
+    async fn close(mut self) -> Result<u32> {
+        if let Some(encoder) = self.encoder.take() {
+            encoder.finish(&mut self.buf)?;
+        }
+        self.flush_buf().await?;
+        self.writer.close().await?;
+        Ok(self.hasher.finalize())
+    }
+}
+
+// Line ids encoded into an output .index file
+struct IndexOutput {
+    ids: IndexWriter<Vec<u8>>,
+    file: OutputFile,
+}
+
+impl IndexOutput {
+    async fn write_id(&mut self, id: usize) -> Result<()> {
+        self.ids.write_id(id)?;
+        if self.ids.get_ref().len() >= WRITE_BUFFER_SIZE {
+            self.flush_ids().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush_ids(&mut self) -> Result<()> {
+        let data = std::mem::take(self.ids.get_mut());
+        self.file.write_all(&data).await
+    }
+
+    async fn sync(&mut self) -> Result<(u64, u32)> {
+        self.flush_ids().await?;
+        self.file.sync().await
+    }
+
+    async fn close(mut self) -> Result<u32> {
+        self.flush_ids().await?;
+        self.file.close().await
+    }
+}
+
+// Suffix of the temporary names outputs are written under until they are committed
+const TEMP_SUFFIX: &str = ".tmp";
+// Suffix of the commit marker of an output, which lists the files being renamed into place
+const COMMIT_SUFFIX: &str = ".commit";
+
+fn temp_path(path: &str) -> String {
+    format!("{}{}", path, TEMP_SUFFIX)
+}
+
+// Make a file, or the entries of a directory, durable on the local filesystem.
+// Other services have made writes durable once the writer is closed.
+fn sync_local(operator: &Operator, path: &str) -> Result<()> {
+    if let Some(local_path) = local_path(operator, path) {
+        std::fs::File::open(&local_path)
+            .and_then(|x| x.sync_all())
+            .map_err(|e| anyhow!("{}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+// Directory holding a file, as an operator path
+fn parent_dir(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+// Output files created so far by a merge or build, written under temporary names.
+// If the work succeeds, finish commits them: the files are synced, a commit marker
+// listing them is written, and they are renamed into place before the marker is removed.
+// A crash before the marker leaves the old files, and one after it is completed by
+// recover_output or the next write of the output. Until then readers refuse the output,
+// so they see the whole output or none of it.
+// Unless the work succeeds the temporary files are removed by finish, so errors and
+// stopped merges don't leave partial outputs behind. If the future is dropped instead,
+// they are removed in the background. Resumable merges keep them to continue from.
+// Scratch directories, such as interleaves kept on disk, are removed either way.
+struct PartialOutput {
+    operator: Operator,
+    output_path: String,
+    // final paths of the files
+    paths: Vec<String>,
+    scratch: Vec<String>,
+    done: bool,
+    resumable: bool,
+}
+
+impl PartialOutput {
+    // Start writing an output, first finishing any commit of it a crash interrupted
+    async fn new(operator: &Operator, output_path: &str) -> Result<PartialOutput> {
+        recover_output(operator, output_path).await?;
+        Ok(PartialOutput {
+            operator: operator.clone(),
+            output_path: output_path.to_string(),
+            paths: Vec::new(),
+            scratch: Vec::new(),
+            done: false,
+            resumable: false,
+        })
+    }
+
+    // Temporary name for a file of the output
+    fn add_path(&mut self, path: &str) -> String {
+        if !self.paths.iter().any(|x| x == path) {
+            self.paths.push(path.to_string());
+        }
+        temp_path(path)
+    }
+
+    // Directory of scratch files, removed once the work is finished
+    fn add_scratch(&mut self, dir: &str) {
+        self.scratch.push(format!("{}/", dir));
+    }
 
-            ind0 += 1;
-            if ind0 == BUFFER_SIZE {
-                bwt0_reader.read(&mut bwt0).await?;
-                ind0 = 0;
+    async fn create(&mut self, path: &str) -> Result<OutputFile> {
+        self.create_compressed(path, None).await
+    }
+
+    async fn create_compressed(
+        &mut self,
+        path: &str,
+        compression: Option<BlockCompression>,
+    ) -> Result<OutputFile> {
+        let path = self.add_path(path);
+        Ok(OutputFile {
+            operator: self.operator.clone(),
+            writer: self.operator.writer(&path).await?,
+            path,
+            buf: Vec::with_capacity(WRITE_BUFFER_SIZE),
+            hasher: Hasher::new(),
+            len: 0,
+            encoder: compression.map(BlockEncoder::new).transpose()?,
+        })
+    }
+
+    async fn create_index(
+        &mut self,
+        path: &str,
+        encoding: IdEncoding,
+        compression: Option<BlockCompression>,
+    ) -> Result<IndexOutput> {
+        Ok(IndexOutput {
+            ids: IndexWriter::new(Vec::with_capacity(WRITE_BUFFER_SIZE), encoding)?,
+            file: self.create_compressed(path, compression).await?,
+        })
+    }
+
+    // Append to a file left by an earlier run, with the given length and checksum
+    async fn append(&mut self, path: &str, len: u64, checksum: u32) -> Result<OutputFile> {
+        let path = self.add_path(path);
+        Ok(OutputFile {
+            operator: self.operator.clone(),
+            writer: self.operator.writer_with(&path).append(true).await?,
+            path,
+            buf: Vec::with_capacity(WRITE_BUFFER_SIZE),
+            hasher: Hasher::new_with_initial_len(checksum, len),
+            len,
+            encoder: None,
+        })
+    }
+
+    async fn append_index(
+        &mut self,
+        path: &str,
+        encoding: IdEncoding,
+        len: u64,
+        checksum: u32,
+    ) -> Result<IndexOutput> {
+        Ok(IndexOutput {
+            ids: IndexWriter::append(Vec::with_capacity(WRITE_BUFFER_SIZE), encoding),
+            file: self.append(path, len, checksum).await?,
+        })
+    }
+
+    async fn write(&mut self, path: &str, data: Vec<u8>) -> Result<()> {
+        let path = self.add_path(path);
+        self.operator.write(&path, data).await?;
+        Ok(())
+    }
+
+    // Commit the output if the work succeeded, otherwise remove it unless it can be resumed.
+    // Files are kept if the commit itself fails, since recover_output may still complete it.
+    async fn finish<T>(mut self, result: Result<T>) -> Result<T> {
+        self.done = true;
+        for dir in self.scratch.iter() {
+            let _ = self.operator.remove_all(dir).await;
+        }
+        match result {
+            Ok(value) => self.commit().await.map(|_| value),
+            Err(e) => {
+                if !self.resumable {
+                    for path in self.paths.iter() {
+                        let _ = self.operator.delete(&temp_path(path)).await;
+                    }
+                }
+                Err(e)
             }
         }
     }
 
-    // write counts
-    let output_counts_path = format!("{}.counts", output_path);
-    let counts_data = counts
-        .iter()
-        .map(|x| x.to_string())
-        .collect::<Vec<String>>()
-        .join("\n")
-        + "\n";
-    std::fs::write(output_counts_path, counts_data)?;
+    async fn commit(&self) -> Result<()> {
+        if self.paths.is_empty() {
+            return Ok(());
+        }
+        for path in self.paths.iter() {
+            sync_local(&self.operator, &temp_path(path))?;
+        }
+        let commit_path = format!("{}{}", self.output_path, COMMIT_SUFFIX);
+        self.operator
+            .write(&commit_path, self.paths.join("\n").into_bytes())
+            .await?;
+        sync_local(&self.operator, &commit_path)?;
+        sync_local(&self.operator, parent_dir(&commit_path))?;
+        rename_outputs(&self.operator, &self.paths).await?;
+        self.operator.delete(&commit_path).await?;
+        Ok(())
+    }
+}
 
+// Rename committed files into place, skipping any that already were
+async fn rename_outputs(operator: &Operator, paths: &[String]) -> Result<()> {
+    for path in paths.iter() {
+        let temp = temp_path(path);
+        if operator.is_exist(&temp).await? {
+            rename_file(operator, &temp, path).await?;
+        }
+    }
+    for path in paths.iter() {
+        sync_local(operator, parent_dir(path))?;
+    }
     Ok(())
 }
 
-pub async fn test_merge_disk(input_path: &str, output_path: &str, test_rebuild: bool) {
-    let mut test_sizes: Vec<usize> = SIZES[0..SIZES.len() - 1].to_vec();
-    test_sizes.push(3719388); // full size
+// Finish committing an output if a crash interrupted it, so that all of its files
+// are in place. Returns whether there was a commit to finish.
+// Writing the output again does this first. Readers don't, and refuse the output until
+// it is done.
+pub async fn recover_output(operator: &Operator, output_path: &str) -> Result<bool> {
+    let commit_path = format!("{}{}", output_path, COMMIT_SUFFIX);
+    if !operator.is_exist(&commit_path).await? {
+        return Ok(false);
+    }
+    let data = operator.read(&commit_path).await?;
+    let paths = String::from_utf8(data)
+        .map_err(|_| anyhow!("{}: damaged commit marker", output_path))?
+        .lines()
+        .map(|x| x.to_string())
+        .collect::<Vec<String>>();
+    rename_outputs(operator, &paths).await?;
+    operator.delete(&commit_path).await?;
+    Ok(true)
+}
 
-    let operator = get_operator().unwrap();
+impl Drop for PartialOutput {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let paths = match self.resumable {
+            true => Vec::new(),
+            false => std::mem::take(&mut self.paths),
+        };
+        let scratch = std::mem::take(&mut self.scratch);
+        if paths.is_empty() && scratch.is_empty() {
+            return;
+        }
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let operator = self.operator.clone();
+            handle.spawn(async move {
+                for path in paths.iter() {
+                    let _ = operator.delete(&temp_path(path)).await;
+                }
+                for dir in scratch.iter() {
+                    let _ = operator.remove_all(dir).await;
+                }
+            });
+        }
+    }
+}
 
-    for size in test_sizes.iter() {
-        let bwt0_path = format!("{}/{}_0", input_path, size);
-        let bwt1_path = format!("{}/{}_1", input_path, size);
-        let output_path_n = format!("{}/{}_merged", output_path, size);
+// Rename a file, copying and deleting it on services that can't rename
+async fn rename_file(operator: &Operator, from: &str, to: &str) -> Result<()> {
+    if operator.info().full_capability().rename {
+        operator.rename(from, to).await?;
+    } else {
+        let data = operator.read(from).await?;
+        operator.write(to, data).await?;
+        operator.delete(from).await?;
+    }
+    Ok(())
+}
 
-        // time merge
-        let merge_start = std::time::Instant::now();
-        bwt_merge_disk(&bwt0_path, &bwt1_path, &output_path_n)
-            .await
-            .unwrap();
-        let merge_duration = merge_start.elapsed();
-        println!("merge time for size {}: {:?}", size, merge_duration);
+// Checkpoint file of a resumable merge
+struct Checkpoint {
+    operator: Operator,
+    path: String,
+    // fingerprint of the inputs
+    inputs: u32,
+}
 
-        if test_rebuild {
-            // time full rebuild, including i/o times
-            let rebuild_start = std::time::Instant::now();
-            let path = format!("{}.bwt", output_path_n);
-            let mut reader = get_file_reader(path.as_str(), &operator).await.unwrap();
-            let mut full_text = Vec::new();
-            reader.read_to_end(&mut full_text).await.unwrap();
-            let full_bwt = run_bwt(&full_text);
+impl Checkpoint {
+    // Fingerprint the inputs by their .counts files, which hold their lengths and checksums
+    async fn new(operator: &Operator, path: &str, bwt_paths: &[&str]) -> Result<Checkpoint> {
+        let mut hasher = Hasher::new();
+        for bwt_path in bwt_paths.iter() {
+            check_committed(operator, bwt_path).await?;
+            hasher.update(
+                &operator
+                    .read(format!("{}.counts", bwt_path).as_str())
+                    .await?,
+            );
+        }
+        Ok(Checkpoint {
+            operator: operator.clone(),
+            path: path.to_string(),
+            inputs: hasher.finalize(),
+        })
+    }
 
-            let output_path = format!("{}/{}_merged_naive", output_path, size);
-            std::fs::write(format!("{}.bwt", output_path), full_bwt.0).unwrap();
-            std::fs::write(
-                format!("{}.index", output_path),
-                full_bwt
-                    .1
-                    .iter()
-                    .map(|x| x.to_string())
-                    .collect::<Vec<String>>()
-                    .join("\n"),
-            )
-            .unwrap();
-            std::fs::write(
-                format!("{}.counts", output_path),
-                full_bwt
-                    .2
-                    .iter()
-                    .map(|x| x.to_string())
-                    .collect::<Vec<String>>()
-                    .join("\n"),
-            )
-            .unwrap();
-            let rebuild_duration = rebuild_start.elapsed();
-            println!("rebuild time for size {}: {:?}", size, rebuild_duration);
+    // Checkpoint left by an earlier run on the same inputs, if any.
+    // Checkpoints of other inputs are ignored. Saves replace the file whole, so a
+    // damaged one wasn't left by a crash and is an error.
+    async fn load(&self) -> Result<Option<MergeCheckpoint>> {
+        if !self.operator.is_exist(&self.path).await? {
+            return Ok(None);
         }
+        let data = self.operator.read(&self.path).await?;
+        let checkpoint = MergeCheckpoint::decode(&data)
+            .map_err(|e| anyhow!("{}: {}, remove it to start the merge over", self.path, e))?;
+        Ok(Some(checkpoint).filter(|x| x.inputs == self.inputs))
+    }
+
+    async fn save(
+        &self,
+        iteration: usize,
+        interleave: &BitVec,
+        output: Option<OutputProgress>,
+    ) -> Result<()> {
+        let checkpoint = MergeCheckpoint {
+            inputs: self.inputs,
+            iteration,
+            interleave: interleave.clone(),
+            output,
+        };
+        // written aside and renamed over the last one, so a crash leaves either whole
+        let temp = temp_path(&self.path);
+        self.operator.write(&temp, checkpoint.encode()).await?;
+        sync_local(&self.operator, &temp)?;
+        rename_file(&self.operator, &temp, &self.path).await?;
+        sync_local(&self.operator, parent_dir(&self.path))?;
+        Ok(())
+    }
+}
+
+// Whether the output files are exactly as a checkpoint recorded them, so they can be appended to
+async fn can_resume_output(
+    operator: &Operator,
+    output_path: &str,
+    progress: &OutputProgress,
+) -> Result<bool> {
+    if !operator.info().full_capability().write_can_append {
+        return Ok(false);
+    }
+    for (ext, len) in [("bwt", progress.bwt_len), ("index", progress.index_len)] {
+        let path = temp_path(&format!("{}.{}", output_path, ext));
+        if !operator.is_exist(&path).await? || operator.stat(&path).await?.content_length() != len {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// Concatenate the document ids of the inputs into the output, in input order.
+// Either all inputs or none of them should have a .docs file.
+async fn merge_doc_ids(
+    bwt_paths: &[&str],
+    num_lines: &[usize],
+    output_path: &str,
+    operator: &Operator,
+    output: &mut PartialOutput,
+) -> Result<()> {
+    let mut has_docs = Vec::with_capacity(bwt_paths.len());
+    for bwt_path in bwt_paths.iter() {
+        has_docs.push(
+            operator
+                .is_exist(format!("{}.docs", bwt_path).as_str())
+                .await?,
+        );
+    }
+    if !has_docs.iter().any(|&x| x) {
+        return Ok(());
+    }
+    if !has_docs.iter().all(|&x| x) {
+        return Err(anyhow!("Only some of the inputs have document ids"));
+    }
+
+    let mut docs_writer = output
+        .create(format!("{}.docs", output_path).as_str())
+        .await?;
+    for (bwt_path, lines) in bwt_paths.iter().zip(num_lines.iter()) {
+        let docs_path = format!("{}.docs", bwt_path);
+        if read_file_size(docs_path.as_str(), operator).await? != lines * 8 {
+            return Err(anyhow!(
+                "Document ids of {} do not match its number of lines",
+                bwt_path
+            ));
+        }
+
+        let mut docs_stream = open_stream(docs_path.as_str(), operator).await?;
+        loop {
+            let chunk = docs_stream.chunk().await?;
+            if chunk.is_empty() {
+                break;
+            }
+            let len = chunk.len();
+            docs_writer.write_all(chunk).await?;
+            docs_stream.consume(len);
+        }
+    }
+    docs_writer.close().await?;
+    Ok(())
+}
+
+// Estimate of the memory used by a disk merge: the interleave, which is kept in memory
+// along with the next one during each pass, and the read buffers of the .bwt and .index
+// of each input
+fn disk_merge_memory(
+    len: usize,
+    interleave_bits: usize,
+    num_inputs: usize,
+    options: ReadOptions,
+) -> usize {
+    2 * (len * interleave_bits).div_ceil(8) + num_inputs * 2 * options.memory_size()
+}
+
+// Options for disk merges
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MergeOptions {
+    // buffers for reading each input
+    pub read: ReadOptions,
+    // bytes the merge may use, by the estimate in MergeStats::peak_memory. Over it,
+    // the interleave is kept in files next to the output instead of in memory.
+    pub memory_budget: Option<usize>,
+    // how the output's .bwt and .index are written
+    pub compression: OutputCompression,
+}
+
+// Compression of the output of a merge or delete
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputCompression {
+    // block-compressed with the block size and level of the first compressed input,
+    // or uncompressed if no input is
+    #[default]
+    LikeInputs,
+    Uncompressed,
+    Blocks(BlockCompression),
+}
+
+// Block compression of an output, None if it is written uncompressed
+async fn output_compression(
+    operator: &Operator,
+    bwt_paths: &[&str],
+    metas: &[SegmentMeta],
+    compression: OutputCompression,
+) -> Result<Option<BlockCompression>> {
+    match compression {
+        OutputCompression::LikeInputs => {
+            match bwt_paths
+                .iter()
+                .zip(metas)
+                .find(|(_, meta)| meta.compressed)
+            {
+                Some((bwt_path, _)) => {
+                    let path = format!("{}.bwt", bwt_path);
+                    Ok(Some(BlockTable::read(operator, &path).await?.compression()))
+                }
+                None => Ok(None),
+            }
+        }
+        OutputCompression::Uncompressed => Ok(None),
+        OutputCompression::Blocks(compression) => Ok(Some(compression)),
+    }
+}
+
+// Memory of the tied rows of a merge, which are kept in memory wherever its interleave is,
+// along with their order and the order found by each pass
+fn tied_rows_memory(num_tied: usize) -> usize {
+    num_tied * (std::mem::size_of::<TiedRow>() + 3 * std::mem::size_of::<usize>())
+}
+
+// Decide where the interleave of a merge is kept, returning it if it goes on disk,
+// along with the estimated memory of the merge.
+// The interleave starts from the given runs of labels, and has num_tied tied rows.
+// Errors if the merge can't keep to the budget even with the interleave on disk.
+#[allow(clippy::too_many_arguments)]
+fn plan_interleave(
+    operator: &Operator,
+    output_path: &str,
+    num_inputs: usize,
+    initial: &[(u16, usize)],
+    num_tied: usize,
+    counts: &[usize; 256],
+    interleave_bits: usize,
+    options: MergeOptions,
+    output: &mut PartialOutput,
+) -> Result<(Option<SpilledInterleave>, usize)> {
+    let len = initial.iter().map(|x| x.1).sum();
+    let memory = disk_merge_memory(len, interleave_bits, num_inputs, options.read)
+        + tied_rows_memory(num_tied);
+    let budget = match options.memory_budget {
+        Some(budget) if memory > budget => budget,
+        _ => return Ok((None, memory)),
+    };
+    let memory = disk_merge_memory(len, 0, num_inputs, options.read)
+        + tied_rows_memory(num_tied)
+        + SpilledInterleave::memory_size(counts, options.read);
+    if memory > budget {
+        return Err(anyhow!(
+            "Merge needs {} bytes even with its interleave on disk, over the budget of {}",
+            memory,
+            budget
+        ));
+    }
+    let dir = format!("{}.interleave", output_path);
+    output.add_scratch(&dir);
+    let interleave = SpilledInterleave::new(operator, &dir, initial, counts, options.read);
+    Ok((Some(interleave), memory))
+}
+
+// Total size of the files written for a merge output, before they are committed
+async fn output_size(output_path: &str, operator: &Operator) -> Result<u64> {
+    let mut size = 0;
+    for ext in SEGMENT_EXTS.iter() {
+        let path = temp_path(&format!("{}.{}", output_path, ext));
+        if operator.is_exist(path.as_str()).await? {
+            size += read_file_size(path.as_str(), operator).await? as u64;
+        }
+    }
+    Ok(size)
+}
+
+// Merge two BWTs using our algorithm.
+// Document ids in .docs files are carried through unchanged, if the inputs have them.
+// Paths should be the paths to the extensionless files
+pub async fn bwt_merge_disk(
+    operator: &Operator,
+    bwt0_path: &str,
+    bwt1_path: &str,
+    output_path: &str,
+) -> Result<MergeStats> {
+    bwt_merge_disk_observed(
+        operator,
+        bwt0_path,
+        bwt1_path,
+        output_path,
+        &mut NoopObserver,
+    )
+    .await
+}
+
+// Merge two BWTs on disk, reporting progress to the observer.
+// Returns statistics about the merge. If the merge fails or is stopped, for example
+// by a Cancellation observer, the partially written output is removed.
+pub async fn bwt_merge_disk_observed(
+    operator: &Operator,
+    bwt0_path: &str,
+    bwt1_path: &str,
+    output_path: &str,
+    observer: &mut dyn MergeObserver,
+) -> Result<MergeStats> {
+    bwt_merge_disk_with_options(
+        operator,
+        bwt0_path,
+        bwt1_path,
+        output_path,
+        observer,
+        MergeOptions::default(),
+    )
+    .await
+}
+
+// Merge two BWTs on disk like bwt_merge_disk_observed, reading the inputs with the
+// given buffers. More buffers let the next parts of both inputs be read while
+// the current ones are processed, which helps most on slow or remote storage.
+// With a memory budget the merge would go over, the interleave is kept on disk
+// in {output_path}.interleave, and each pass streams it alongside the inputs.
+pub async fn bwt_merge_disk_with_options(
+    operator: &Operator,
+    bwt0_path: &str,
+    bwt1_path: &str,
+    output_path: &str,
+    observer: &mut dyn MergeObserver,
+    options: MergeOptions,
+) -> Result<MergeStats> {
+    let mut output = PartialOutput::new(operator, output_path).await?;
+    let result = merge_two_disk(
+        operator,
+        [bwt0_path, bwt1_path],
+        output_path,
+        observer,
+        None,
+        options,
+        &mut output,
+    )
+    .await;
+    output.finish(result).await
+}
+
+// Merge two BWTs on disk like bwt_merge_disk_observed, saving progress to a checkpoint file:
+// the interleave after each pass and, on services that can append, how far the output has
+// been written. Calling it again with the same arguments after an error or crash continues
+// from the checkpoint and gives the same output, so partial output is kept on errors.
+// The checkpoint is removed once the merge succeeds.
+pub async fn bwt_merge_disk_resumable(
+    operator: &Operator,
+    bwt0_path: &str,
+    bwt1_path: &str,
+    output_path: &str,
+    checkpoint_path: &str,
+    observer: &mut dyn MergeObserver,
+) -> Result<MergeStats> {
+    bwt_merge_disk_resumable_with_options(
+        operator,
+        bwt0_path,
+        bwt1_path,
+        output_path,
+        checkpoint_path,
+        observer,
+        MergeOptions::default(),
+    )
+    .await
+}
+
+// Resumable merge with options for reading the inputs and writing the output.
+// Compressed output can't be appended to when resuming, since its block table is
+// written last, so errors if the options ask for it, including by default when an
+// input is compressed. The interleave is held in memory for the checkpoints, so
+// a memory budget isn't supported either.
+pub async fn bwt_merge_disk_resumable_with_options(
+    operator: &Operator,
+    bwt0_path: &str,
+    bwt1_path: &str,
+    output_path: &str,
+    checkpoint_path: &str,
+    observer: &mut dyn MergeObserver,
+    options: MergeOptions,
+) -> Result<MergeStats> {
+    if options.memory_budget.is_some() {
+        return Err(anyhow!("Resumable merges can't keep to a memory budget"));
+    }
+    let checkpoint = Checkpoint::new(operator, checkpoint_path, &[bwt0_path, bwt1_path]).await?;
+    let mut output = PartialOutput::new(operator, output_path).await?;
+    output.resumable = true;
+    let result = merge_two_disk(
+        operator,
+        [bwt0_path, bwt1_path],
+        output_path,
+        observer,
+        Some(&checkpoint),
+        options,
+        &mut output,
+    )
+    .await;
+    let stats = output.finish(result).await?;
+    operator.delete(checkpoint_path).await?;
+    Ok(stats)
+}
+
+async fn merge_two_disk(
+    operator: &Operator,
+    [bwt0_path, bwt1_path]: [&str; 2],
+    output_path: &str,
+    observer: &mut dyn MergeObserver,
+    checkpoint: Option<&Checkpoint>,
+    merge_options: MergeOptions,
+    output: &mut PartialOutput,
+) -> Result<MergeStats> {
+    let mut stats = MergeStats::default();
+    let options = merge_options.read;
+
+    // construct character counts array
+    let mut counts: [usize; 256] = [0; 256];
+
+    let operator = operator.clone();
+    let meta0 = read_meta(bwt0_path, &operator).await?;
+    let meta1 = read_meta(bwt1_path, &operator).await?;
+    let separator = common_separator(&[meta0.clone(), meta1.clone()])?;
+    for (i, count) in counts.iter_mut().enumerate() {
+        *count = meta0.counts[i] + meta1.counts[i];
+    }
+    let num_newlines = meta0.num_lines;
+    let compression = output_compression(
+        &operator,
+        &[bwt0_path, bwt1_path],
+        &[meta0.clone(), meta1.clone()],
+        merge_options.compression,
+    )
+    .await?;
+    if checkpoint.is_some() && compression.is_some() {
+        return Err(anyhow!(
+            "Resumable merges can't write compressed output, use OutputCompression::Uncompressed"
+        ));
+    }
+
+    merge_doc_ids(
+        &[bwt0_path, bwt1_path],
+        &[num_newlines, meta1.num_lines],
+        output_path,
+        &operator,
+        output,
+    )
+    .await?;
+
+    // get bwt file paths
+    let bwt0_file_path = format!("{}.bwt", bwt0_path);
+    let bwt1_file_path = format!("{}.bwt", bwt1_path);
+
+    let (mut bwt0, bwt0_len) = open_segment_file(
+        bwt0_file_path.as_str(),
+        &operator,
+        meta0.compressed,
+        options,
+    )
+    .await?;
+    let (mut bwt1, bwt1_len) = open_segment_file(
+        bwt1_file_path.as_str(),
+        &operator,
+        meta1.compressed,
+        options,
+    )
+    .await?;
+    check_bwt_len(bwt0_path, &meta0, bwt0_len)?;
+    check_bwt_len(bwt1_path, &meta1, bwt1_len)?;
+    let len = bwt0_len + bwt1_len;
+    // resumable merges have no budget, since checkpoints hold the interleave itself
+    let (mut spilled, peak_memory) = plan_interleave(
+        &operator,
+        output_path,
+        2,
+        &[(0, bwt0_len), (1, bwt1_len)],
+        0,
+        &counts,
+        1,
+        merge_options,
+        output,
+    )?;
+    stats.peak_memory = peak_memory;
+
+    let start = std::time::Instant::now();
+    let mut resumed_output = None;
+    let mut interleave = BitVec::new();
+    match spilled.as_mut() {
+        Some(spilled) => {
+            let mut bwts = [bwt0, bwt1];
+            compute_interleave_spilled(&mut bwts, spilled, None, separator, &mut stats, observer)
+                .await?;
+            [bwt0, bwt1] = bwts;
+        }
+        None => {
+            interleave = initial_interleave(bwt0_len, bwt1_len);
+            let saved = match checkpoint {
+                Some(checkpoint) => checkpoint.load().await?,
+                None => None,
+            };
+            if let Some(saved) = saved.filter(|x| x.interleave.len() == len) {
+                stats.interleave_iterations = saved.iteration;
+                interleave = saved.interleave;
+                resumed_output = saved.output;
+            }
+            if resumed_output.is_none() {
+                interleave = compute_interleave(
+                    &mut bwt0, &mut bwt1, interleave, &counts, &mut stats, observer, checkpoint,
+                )
+                .await?;
+            }
+        }
+    }
+    stats.interleave_time = start.elapsed();
+
+    // construct bwt
+    let start = std::time::Instant::now();
+    bwt0.rewind().await?;
+    bwt1.rewind().await?;
+
+    // read line index
+    let line_ind0_path = format!("{}.index", bwt0_path);
+    let line_ind1_path = format!("{}.index", bwt1_path);
+    stats.bytes_read += read_file_size(line_ind0_path.as_str(), &operator).await? as u64;
+    stats.bytes_read += read_file_size(line_ind1_path.as_str(), &operator).await? as u64;
+    let mut line_ind0 = IndexReader::open(bwt0_path, &operator, meta0.compressed, options).await?;
+    let mut line_ind1 = IndexReader::open(bwt1_path, &operator, meta1.compressed, options).await?;
+
+    let output_bwt_path = format!("{}.bwt", output_path);
+    let output_index_path = format!("{}.index", output_path);
+    let encoding = IdEncoding::fixed_for(meta0.num_lines + meta1.num_lines);
+    let mut start_pos = 0;
+    let mut rank = RankBuilder::new(&counts);
+    let (mut bwt_writer, mut index_writer);
+    match resumed_output {
+        Some(progress) if can_resume_output(&operator, output_path, &progress).await? => {
+            start_pos = progress.position;
+            // rank checkpoints of the output written so far
+            let mut written = open_stream(&temp_path(&output_bwt_path), &operator).await?;
+            let mut remaining = progress.bwt_len as usize;
+            while remaining > 0 {
+                let chunk = written.chunk().await?;
+                if chunk.is_empty() {
+                    return Err(anyhow!("{}: output ended early", output_bwt_path));
+                }
+                let len = chunk.len().min(remaining);
+                rank.extend(&chunk[..len]);
+                written.consume(len);
+                remaining -= len;
+            }
+            let ones = interleave.iter().take(start_pos).filter(|&x| x).count();
+            bwt0.skip((start_pos - ones) as u64).await?;
+            bwt1.skip(ones as u64).await?;
+            line_ind0.skip(start_pos - ones).await?;
+            line_ind1.skip(ones).await?;
+            bwt_writer = output
+                .append(&output_bwt_path, progress.bwt_len, progress.bwt_crc)
+                .await?;
+            index_writer = output
+                .append_index(
+                    &output_index_path,
+                    encoding,
+                    progress.index_len,
+                    progress.index_crc,
+                )
+                .await?;
+        }
+        _ => {
+            bwt_writer = output
+                .create_compressed(output_bwt_path.as_str(), compression)
+                .await?;
+            index_writer = output
+                .create_index(output_index_path.as_str(), encoding, compression)
+                .await?;
+        }
+    }
+    // output is only checkpointed where it can be appended to when resuming
+    let output_checkpoint =
+        checkpoint.filter(|_| operator.info().full_capability().write_can_append);
+    if let Some(checkpoint) = checkpoint {
+        if start_pos == 0 {
+            let progress = match output_checkpoint {
+                Some(_) => output_progress(0, &mut bwt_writer, &mut index_writer).await?,
+                None => OutputProgress::default(),
+            };
+            checkpoint
+                .save(stats.interleave_iterations, &interleave, Some(progress))
+                .await?;
+        }
+    }
+
+    let mut spilled_reader = spilled.as_ref().map(|x| x.reader());
+    for i in start_pos..len {
+        if i % PROGRESS_INTERVAL == 0 && i > start_pos {
+            if let Some(checkpoint) = output_checkpoint {
+                let progress = output_progress(i, &mut bwt_writer, &mut index_writer).await?;
+                checkpoint
+                    .save(stats.interleave_iterations, &interleave, Some(progress))
+                    .await?;
+            }
+            if !observer.on_progress(MergePhase::Output, i, len) {
+                return Err(MergeStopped.into());
+            }
+        }
+
+        let from1 = match spilled_reader.as_mut() {
+            Some(reader) => reader.next().await? == 1,
+            None => interleave[i],
+        };
+        if from1 {
+            let chr = bwt1.next_byte().await?;
+            bwt_writer.write_all(&[chr]).await?;
+            rank.push(chr);
+
+            let line_ind = line_ind1
+                .next_id()
+                .await?
+                .ok_or_else(|| index_too_short(bwt1_path))?;
+            index_writer.write_id(line_ind + num_newlines).await?;
+        } else {
+            let chr = bwt0.next_byte().await?;
+            bwt_writer.write_all(&[chr]).await?;
+            rank.push(chr);
+
+            let line_ind = line_ind0
+                .next_id()
+                .await?
+                .ok_or_else(|| index_too_short(bwt0_path))?;
+            index_writer.write_id(line_ind).await?;
+        }
+    }
+    stats.bytes_read += bwt0.take_bytes_read() + bwt1.take_bytes_read();
+    if let Some(reader) = spilled_reader.as_mut() {
+        stats.bytes_read += reader.take_bytes_read();
+    }
+
+    // write counts
+    let output_counts_path = format!("{}.counts", output_path);
+    let checksums = Checksums {
+        bwt: bwt_writer.close().await?,
+        index: index_writer.close().await?,
+    };
+    let meta = SegmentMeta {
+        compressed: compression.is_some(),
+        ..SegmentMeta::new(separator, counts, checksums)
+    };
+    output
+        .write(output_counts_path.as_str(), meta.encode())
+        .await?;
+    output
+        .write(
+            format!("{}.rank", output_path).as_str(),
+            rank.finish()?.encode(),
+        )
+        .await?;
+    stats.output_time = start.elapsed();
+    stats.bytes_written += output_size(output_path, &operator).await?;
+
+    Ok(stats)
+}
+
+// Make the output written so far durable, and record how far it got
+async fn output_progress(
+    position: usize,
+    bwt_writer: &mut OutputFile,
+    index_writer: &mut IndexOutput,
+) -> Result<OutputProgress> {
+    let (bwt_len, bwt_crc) = bwt_writer.sync().await?;
+    let (index_len, index_crc) = index_writer.sync().await?;
+    Ok(OutputProgress {
+        position,
+        bwt_len,
+        bwt_crc,
+        index_len,
+        index_crc,
+    })
+}
+
+// Row of the suffix at the start of a segment's text, found by streaming its .bwt
+// and .index, along with the rank checkpoints of its .bwt if asked for.
+// The segment's text must end with its separator.
+async fn scan_start_row(
+    operator: &Operator,
+    bwt_path: &str,
+    meta: &SegmentMeta,
+    with_rank: bool,
+    options: ReadOptions,
+) -> Result<(usize, Option<RankIndex>)> {
+    let bwt_file_path = format!("{}.bwt", bwt_path);
+    let (mut bwt, _) =
+        open_segment_file(bwt_file_path.as_str(), operator, meta.compressed, options).await?;
+    let mut line_ind = IndexReader::open(bwt_path, operator, meta.compressed, options).await?;
+    let mut rank = with_rank.then(|| RankBuilder::new(&meta.counts));
+    let mut start_row = None;
+    for i in 0..meta.bwt_len {
+        let chr = bwt.next_byte().await?;
+        let line = line_ind
+            .next_id()
+            .await?
+            .ok_or_else(|| index_too_short(bwt_path))?;
+        if chr == meta.separator && line == 0 {
+            start_row = Some(i);
+        }
+        if let Some(rank) = rank.as_mut() {
+            rank.push(chr);
+        }
+    }
+    let start_row = start_row.ok_or_else(|| {
+        anyhow!(
+            "{}: segments merged at once must be of text ending with a separator",
+            bwt_path
+        )
+    })?;
+    Ok((start_row, rank.map(|x| x.finish()).transpose()?))
+}
+
+// Tied rows at the end of a piece of a segment, like tied_chain for a BWT in memory.
+// line_of gives the output line id of a line of the segment.
+async fn disk_tied_chain(
+    index: &DiskFMIndex,
+    start_row: usize,
+    piece: &Piece,
+    line_of: impl Fn(usize) -> usize,
+) -> Result<TiedChain> {
+    let separator = index.meta.separator;
+    let start = |chr| index.rank.start(chr);
+
+    // rows of the suffixes starting with the end of the piece walked so far
+    let (mut lo, mut hi) = (
+        start(separator),
+        start(separator) + index.meta.counts[separator as usize],
+    );
+    let mut row = piece.last_row;
+    let mut line = piece.lines.end - 1;
+    let mut rows = Vec::new();
+    while hi - lo > 1 {
+        let chr = index.byte_at(row).await?;
+        rows.push((row, chr, line_of(line)));
+        let lf = start(chr) + index.rank(row, chr).await?;
+        if chr == separator {
+            if line == piece.lines.start {
+                return Ok((rows, true));
+            }
+            line -= 1;
+            // the start of the text is followed by nothing in the segment's own order
+            let after_start = |pos| usize::from(start_row < pos);
+            lo = start(chr) + 1 + index.rank(lo, chr).await? - after_start(lo);
+            hi = start(chr) + 1 + index.rank(hi, chr).await? - after_start(hi);
+            row = match row == start_row {
+                true => start(chr),
+                false => lf + 1 - after_start(row),
+            };
+        } else {
+            lo = start(chr) + index.rank(lo, chr).await?;
+            hi = start(chr) + index.rank(hi, chr).await?;
+            row = lf;
+        }
+    }
+    Ok((rows, false))
+}
+
+// Links of the rows of a merge of several segments, as in bwt_merge_many_observed.
+// Every non-empty segment is a piece of the output, followed by the next one.
+async fn link_segments(
+    operator: &Operator,
+    bwt_paths: &[&str],
+    metas: &[SegmentMeta],
+    line_offsets: &[usize],
+    options: ReadOptions,
+) -> Result<Links> {
+    let sources = (0..metas.len())
+        .filter(|&src| metas[src].bwt_len > 0)
+        .collect::<Vec<usize>>();
+    let mut pieces = Vec::with_capacity(sources.len());
+    let mut chains = Vec::with_capacity(sources.len());
+    for (i, &src) in sources.iter().enumerate() {
+        let meta = &metas[src];
+        let relinked = i + 1 < sources.len();
+        let (start_row, rank) =
+            scan_start_row(operator, bwt_paths[src], meta, relinked, options).await?;
+        let piece = Piece {
+            source: src,
+            lines: 0..meta.num_lines,
+            start_row,
+            last_row: meta.counts[..meta.separator as usize].iter().sum(),
+            relinked,
+        };
+        chains.push(match rank {
+            Some(rank) => {
+                let index =
+                    DiskFMIndex::with_rank(operator, bwt_paths[src], meta.clone(), rank).await?;
+                disk_tied_chain(&index, start_row, &piece, |line| line + line_offsets[src]).await?
+            }
+            None => (Vec::new(), false),
+        });
+        pieces.push(piece);
+    }
+    Ok(link_pieces(metas.len(), &pieces, &chains))
+}
+
+// Compute the interleave of the rows of an output made of pieces of BWTs streamed
+// from disk, like compute_interleave_linked for BWTs in memory.
+// Each position holds the index of the BWT it comes from, or the number of BWTs for
+// a tied row, and the ids of the tied rows are returned in order.
+async fn compute_interleave_many(
+    bwts: &mut [ByteStream<SegmentReader>],
+    lens: &[usize],
+    links: &Links,
+    counts: &[usize; 256],
+    separator: u8,
+    stats: &mut MergeStats,
+    observer: &mut dyn MergeObserver,
+) -> Result<(Vec<u16>, Vec<usize>)> {
+    // construct character starts array
+    let mut starts: [usize; 256] = [0; 256];
+    let mut sum = 0;
+    for i in 0..256 {
+        starts[i] = sum;
+        sum += counts[i];
+    }
+
+    let (runs, mut tied_order) = initial_runs(lens, links);
+    let mut interleave = runs
+        .iter()
+        .flat_map(|&(label, len)| std::iter::repeat_n(label, len))
+        .collect::<Vec<u16>>();
+    let Some(last) = links.last else {
+        return Ok((interleave, tied_order));
+    };
+    let tied_label = bwts.len() as u16;
+
+    loop {
+        for bwt in bwts.iter_mut() {
+            bwt.rewind().await?;
+        }
+        let mut rows = links
+            .sources
+            .iter()
+            .map(SourceRows::new)
+            .collect::<Vec<_>>();
+        let mut tied = tied_order.iter();
+
+        let mut offsets = starts;
+        let mut new_interleave = vec![0u16; interleave.len()];
+        let mut new_tied = Vec::with_capacity(tied_order.len());
+        let mut place = |pred: PredRow, chr: u8| {
+            let pos = offsets[chr as usize];
+            offsets[chr as usize] += 1;
+            match pred {
+                PredRow::Source(src) => new_interleave[pos] = src as u16,
+                PredRow::Tied(id) => {
+                    new_interleave[pos] = tied_label;
+                    new_tied.push((pos, id));
+                }
+                PredRow::Start => unreachable!("Only the start of the output has no row before it"),
+            }
+        };
+        place(last, separator);
+
+        for (i, &src) in interleave.iter().enumerate() {
+            if i % PROGRESS_INTERVAL == 0
+                && i > 0
+                && !observer.on_progress(
+                    MergePhase::Interleave(stats.interleave_iterations),
+                    i,
+                    interleave.len(),
+                )
+            {
+                return Err(MergeStopped.into());
+            }
+
+            let (chr, pred) = match src == tied_label {
+                true => {
+                    let id = *tied
+                        .next()
+                        .ok_or_else(|| anyhow!("Fewer tied rows than positions"))?;
+                    (links.tied_rows[id].chr, links.tied_rows[id].pred)
+                }
+                false => {
+                    let src = src as usize;
+                    let (row, pred) = rows[src].next_row();
+                    let chr = byte_at(&mut bwts[src], row).await?;
+                    (chr, pred.unwrap_or(PredRow::Source(src)))
+                }
+            };
+            if pred != PredRow::Start {
+                place(pred, chr);
+            }
+        }
+        new_tied.sort_unstable();
+        let new_tied_order = new_tied.into_iter().map(|x| x.1).collect::<Vec<usize>>();
+
+        for bwt in bwts.iter_mut() {
+            stats.bytes_read += bwt.take_bytes_read();
+        }
+        stats.interleave_iterations += 1;
+        if !observer.on_iteration(stats) {
+            return Err(MergeStopped.into());
+        }
+
+        if new_interleave == interleave && new_tied_order == tied_order {
+            break;
+        }
+        interleave = new_interleave;
+        tied_order = new_tied_order;
+    }
+
+    Ok((interleave, tied_order))
+}
+
+// Merge several BWTs at once, in a single interleave computation.
+// Like bwt_merge_many, the output is the same as building the inputs' texts
+// concatenated in order, which must each end with the separator.
+// Line ids of each input are offset by the number of lines in the inputs before it,
+// and document ids are concatenated in input order.
+// Paths should be the paths to the extensionless files
+pub async fn bwt_merge_disk_many(
+    operator: &Operator,
+    bwt_paths: &[&str],
+    output_path: &str,
+) -> Result<MergeStats> {
+    bwt_merge_disk_many_observed(operator, bwt_paths, output_path, &mut NoopObserver).await
+}
+
+// Merge several BWTs on disk, reporting progress to the observer.
+// Returns statistics about the merge, and removes partial output like bwt_merge_disk_observed.
+pub async fn bwt_merge_disk_many_observed(
+    operator: &Operator,
+    bwt_paths: &[&str],
+    output_path: &str,
+    observer: &mut dyn MergeObserver,
+) -> Result<MergeStats> {
+    bwt_merge_disk_many_with_options(
+        operator,
+        bwt_paths,
+        output_path,
+        observer,
+        MergeOptions::default(),
+    )
+    .await
+}
+
+// Merge several BWTs on disk like bwt_merge_disk_many_observed, reading each input
+// with the given buffers and keeping to the memory budget, as bwt_merge_disk_with_options
+pub async fn bwt_merge_disk_many_with_options(
+    operator: &Operator,
+    bwt_paths: &[&str],
+    output_path: &str,
+    observer: &mut dyn MergeObserver,
+    options: MergeOptions,
+) -> Result<MergeStats> {
+    // one label is kept for tied rows
+    if bwt_paths.len() > u16::MAX as usize {
+        return Err(anyhow!("Too many BWTs to merge at once"));
+    }
+
+    let mut output = PartialOutput::new(operator, output_path).await?;
+    let result = merge_many_disk(
+        operator,
+        bwt_paths,
+        output_path,
+        observer,
+        options,
+        &mut output,
+    )
+    .await;
+    output.finish(result).await
+}
+
+async fn merge_many_disk(
+    operator: &Operator,
+    bwt_paths: &[&str],
+    output_path: &str,
+    observer: &mut dyn MergeObserver,
+    merge_options: MergeOptions,
+    output: &mut PartialOutput,
+) -> Result<MergeStats> {
+    let mut stats = MergeStats::default();
+    let options = merge_options.read;
+
+    // construct character counts array and line offsets
+    let mut counts: [usize; 256] = [0; 256];
+    let mut line_offsets = Vec::with_capacity(bwt_paths.len());
+    let mut num_lines = Vec::with_capacity(bwt_paths.len());
+    let mut num_newlines = 0;
+
+    let operator = operator.clone();
+    let mut metas = Vec::with_capacity(bwt_paths.len());
+    for bwt_path in bwt_paths.iter() {
+        let meta = read_meta(bwt_path, &operator).await?;
+        for (count, meta_count) in counts.iter_mut().zip(meta.counts.iter()) {
+            *count += meta_count;
+        }
+        line_offsets.push(num_newlines);
+        num_lines.push(meta.num_lines);
+        num_newlines += meta.num_lines;
+        metas.push(meta);
+    }
+    let separator = common_separator(&metas)?;
+    let compression =
+        output_compression(&operator, bwt_paths, &metas, merge_options.compression).await?;
+
+    merge_doc_ids(bwt_paths, &num_lines, output_path, &operator, output).await?;
+
+    let mut bwts = Vec::with_capacity(bwt_paths.len());
+    let mut bwt_lens = Vec::with_capacity(bwt_paths.len());
+    for (bwt_path, meta) in bwt_paths.iter().zip(metas.iter()) {
+        let bwt_file_path = format!("{}.bwt", bwt_path);
+        let (bwt, bwt_len) =
+            open_segment_file(bwt_file_path.as_str(), &operator, meta.compressed, options).await?;
+        bwts.push(bwt);
+        check_bwt_len(bwt_path, meta, bwt_len)?;
+        bwt_lens.push(bwt_len);
+    }
+
+    let links = link_segments(&operator, bwt_paths, &metas, &line_offsets, options).await?;
+    let (initial, tied_order) = initial_runs(&bwt_lens, &links);
+
+    // source ids take 16 bits per position
+    let (mut spilled, peak_memory) = plan_interleave(
+        &operator,
+        output_path,
+        bwt_paths.len(),
+        &initial,
+        links.tied_rows.len(),
+        &counts,
+        16,
+        merge_options,
+        output,
+    )?;
+    stats.peak_memory = peak_memory;
+
+    let start = std::time::Instant::now();
+    let (interleave, tied_order) = match spilled.as_mut() {
+        Some(spilled) => {
+            let tied_order = compute_interleave_spilled(
+                &mut bwts,
+                spilled,
+                Some((&links, tied_order)),
+                separator,
+                &mut stats,
+                observer,
+            )
+            .await?;
+            (Vec::new(), tied_order)
+        }
+        None => {
+            compute_interleave_many(
+                &mut bwts, &bwt_lens, &links, &counts, separator, &mut stats, observer,
+            )
+            .await?
+        }
+    };
+    stats.interleave_time = start.elapsed();
+
+    let start = std::time::Instant::now();
+    let output_rows = LinkedOutput {
+        bwt_paths,
+        metas: &metas,
+        links: &links,
+        interleave: &interleave,
+        spilled: spilled.as_ref(),
+        tied_order: &tied_order,
+        counts: &counts,
+        separator,
+        compression,
+    };
+    write_linked_output(
+        &operator,
+        &output_rows,
+        &mut bwts,
+        |src, line| line + line_offsets[src],
+        output_path,
+        output,
+        &mut stats,
+        observer,
+        options,
+    )
+    .await?;
+    stats.output_time = start.elapsed();
+    stats.bytes_written += output_size(output_path, &operator).await?;
+
+    Ok(stats)
+}
+
+// Rows of an output made of pieces of segments, as found by compute_interleave_many
+// or compute_interleave_spilled
+struct LinkedOutput<'a> {
+    bwt_paths: &'a [&'a str],
+    metas: &'a [SegmentMeta],
+    links: &'a Links,
+    // the interleave if it is in memory, or empty if it was spilled
+    interleave: &'a [u16],
+    spilled: Option<&'a SpilledInterleave>,
+    tied_order: &'a [usize],
+    counts: &'a [usize; 256],
+    separator: u8,
+    compression: Option<BlockCompression>,
+}
+
+// Write the .bwt, .index, .counts and .rank of an output from its rows.
+// line_of gives the output line id of a line id of a segment.
+#[allow(clippy::too_many_arguments)]
+async fn write_linked_output(
+    operator: &Operator,
+    rows: &LinkedOutput<'_>,
+    bwts: &mut [ByteStream<SegmentReader>],
+    line_of: impl Fn(usize, usize) -> usize,
+    output_path: &str,
+    output: &mut PartialOutput,
+    stats: &mut MergeStats,
+    observer: &mut dyn MergeObserver,
+    options: ReadOptions,
+) -> Result<()> {
+    let LinkedOutput {
+        bwt_paths,
+        metas,
+        links,
+        interleave,
+        spilled,
+        tied_order,
+        counts,
+        separator,
+        compression,
+    } = *rows;
+    for bwt in bwts.iter_mut() {
+        bwt.rewind().await?;
+    }
+
+    // read line index
+    let mut line_inds = Vec::with_capacity(bwt_paths.len());
+    for (bwt_path, meta) in bwt_paths.iter().zip(metas.iter()) {
+        let line_ind_path = format!("{}.index", bwt_path);
+        stats.bytes_read += read_file_size(line_ind_path.as_str(), operator).await? as u64;
+        line_inds.push(IndexReader::open(bwt_path, operator, meta.compressed, options).await?);
+    }
+
+    let output_bwt_path = format!("{}.bwt", output_path);
+    let output_index_path = format!("{}.index", output_path);
+    let mut bwt_writer = output
+        .create_compressed(output_bwt_path.as_str(), compression)
+        .await?;
+    let mut index_writer = output
+        .create_index(
+            output_index_path.as_str(),
+            IdEncoding::fixed_for(counts[separator as usize]),
+            compression,
+        )
+        .await?;
+
+    let mut rank = RankBuilder::new(counts);
+    let len = interleave.len() + spilled.map_or(0, |x| x.len());
+    let mut sources = interleave.iter();
+    let mut spilled_reader = spilled.map(|x| x.reader());
+    let mut rows = links
+        .sources
+        .iter()
+        .map(SourceRows::new)
+        .collect::<Vec<_>>();
+    // rows of each input read so far
+    let mut next_rows = vec![0; bwt_paths.len()];
+    let mut tied = tied_order.iter();
+    let tied_label = bwt_paths.len() as u16;
+    for i in 0..len {
+        if i % PROGRESS_INTERVAL == 0 && i > 0 && !observer.on_progress(MergePhase::Output, i, len)
+        {
+            return Err(MergeStopped.into());
+        }
+
+        let src = match spilled_reader.as_mut() {
+            Some(reader) => reader.next().await?,
+            None => *sources
+                .next()
+                .ok_or_else(|| anyhow!("Interleave ended early"))?,
+        };
+        let (chr, line_ind) = match src == tied_label {
+            true => {
+                let id = *tied
+                    .next()
+                    .ok_or_else(|| anyhow!("Fewer tied rows than positions"))?;
+                (links.tied_rows[id].chr, links.tied_rows[id].line)
+            }
+            false => {
+                let src = src as usize;
+                let (row, _) = rows[src].next_row();
+                let chr = byte_at(&mut bwts[src], row).await?;
+                line_inds[src].skip(row - next_rows[src]).await?;
+                next_rows[src] = row + 1;
+                let line_ind = line_inds[src]
+                    .next_id()
+                    .await?
+                    .ok_or_else(|| index_too_short(bwt_paths[src]))?;
+                (chr, line_of(src, line_ind))
+            }
+        };
+        bwt_writer.write_all(&[chr]).await?;
+        rank.push(chr);
+        index_writer.write_id(line_ind).await?;
+    }
+    for bwt in bwts.iter_mut() {
+        stats.bytes_read += bwt.take_bytes_read();
+    }
+    if let Some(reader) = spilled_reader.as_mut() {
+        stats.bytes_read += reader.take_bytes_read();
+    }
+
+    // write counts
+    let output_counts_path = format!("{}.counts", output_path);
+    let checksums = Checksums {
+        bwt: bwt_writer.close().await?,
+        index: index_writer.close().await?,
+    };
+    let meta = SegmentMeta {
+        compressed: compression.is_some(),
+        ..SegmentMeta::new(separator, *counts, checksums)
+    };
+    output
+        .write(output_counts_path.as_str(), meta.encode())
+        .await?;
+    output
+        .write(
+            format!("{}.rank", output_path).as_str(),
+            rank.finish()?.encode(),
+        )
+        .await?;
+    Ok(())
+}
+
+// Read a whole BWT, line index, counts and any document ids into memory
+async fn read_bwt_data(bwt_path: &str, operator: &Operator) -> Result<BWTData> {
+    let meta = read_meta(bwt_path, operator).await?;
+    if meta.separator != SEPARATOR {
+        return Err(anyhow!(
+            "{}: in-memory BWTs only support newline separators",
+            bwt_path
+        ));
+    }
+    let mut bwt = operator.read(format!("{}.bwt", bwt_path).as_str()).await?;
+    if meta.compressed {
+        bwt = block::decompress(&bwt).map_err(|e| anyhow!("{}: {}", bwt_path, e))?;
+    }
+    check_bwt_len(bwt_path, &meta, bwt.len())?;
+
+    let mut line_ind_reader =
+        IndexReader::open(bwt_path, operator, meta.compressed, ReadOptions::default()).await?;
+    let mut line_ind = Vec::with_capacity(bwt.len());
+    while line_ind.len() < bwt.len() {
+        let id = line_ind_reader
+            .next_id()
+            .await?
+            .ok_or_else(|| index_too_short(bwt_path))?;
+        line_ind.push(id);
+    }
+
+    let doc_ids = if operator
+        .is_exist(format!("{}.docs", bwt_path).as_str())
+        .await?
+    {
+        let doc_ids = read_doc_ids(operator, bwt_path).await?;
+        if doc_ids.len() != meta.num_lines {
+            return Err(anyhow!(
+                "Document ids of {} do not match its number of lines",
+                bwt_path
+            ));
+        }
+        Some(doc_ids)
+    } else {
+        None
+    };
+
+    Ok((bwt, line_ind, meta.counts, doc_ids))
+}
+
+// Write a BWT, line index and counts to the three files, its rank checkpoints,
+// and its document ids if it has them.
+// The .bwt and .index are block-compressed if a compression is given.
+async fn write_bwt_data(
+    data: &BWTData,
+    output_path: &str,
+    encoding: IdEncoding,
+    compression: Option<BlockCompression>,
+    output: &mut PartialOutput,
+) -> Result<()> {
+    let bwt = match compression {
+        Some(compression) => block::compress(&data.0, compression)?,
+        None => data.0.clone(),
+    };
+    let bwt_checksum = crc32fast::hash(&bwt);
+    output
+        .write(format!("{}.bwt", output_path).as_str(), bwt)
+        .await?;
+
+    let mut index_writer = output
+        .create_index(
+            format!("{}.index", output_path).as_str(),
+            encoding,
+            compression,
+        )
+        .await?;
+    for &line_ind in data.1.iter() {
+        index_writer.write_id(line_ind).await?;
+    }
+
+    let checksums = Checksums {
+        bwt: bwt_checksum,
+        index: index_writer.close().await?,
+    };
+    let meta = SegmentMeta {
+        compressed: compression.is_some(),
+        ..SegmentMeta::new(SEPARATOR, data.2, checksums)
+    };
+    output
+        .write(format!("{}.counts", output_path).as_str(), meta.encode())
+        .await?;
+
+    let mut rank = RankBuilder::new(&data.2);
+    rank.extend(&data.0);
+    output
+        .write(
+            format!("{}.rank", output_path).as_str(),
+            rank.finish()?.encode(),
+        )
+        .await?;
+
+    if let Some(doc_ids) = data.3.as_ref() {
+        let docs = doc_ids
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect::<Vec<u8>>();
+        output
+            .write(format!("{}.docs", output_path).as_str(), docs)
+            .await?;
+    }
+    Ok(())
+}
+
+// Write an in-memory BWT as a segment, with line ids in the given encoding
+pub async fn write_segment(
+    operator: &Operator,
+    data: &BWTData,
+    output_path: &str,
+    encoding: IdEncoding,
+) -> Result<()> {
+    let mut output = PartialOutput::new(operator, output_path).await?;
+    let result = write_bwt_data(data, output_path, encoding, None, &mut output).await;
+    output.finish(result).await
+}
+
+// Write an in-memory BWT as a segment like write_segment, with the .bwt and .index
+// block-compressed so they can still be streamed and queried without decompressing them whole
+pub async fn write_segment_compressed(
+    operator: &Operator,
+    data: &BWTData,
+    output_path: &str,
+    encoding: IdEncoding,
+    compression: BlockCompression,
+) -> Result<()> {
+    let mut output = PartialOutput::new(operator, output_path).await?;
+    let result = write_bwt_data(data, output_path, encoding, Some(compression), &mut output).await;
+    output.finish(result).await
+}
+
+// Save an in-memory BWT as a segment, with line ids in the narrowest fixed width
+// that holds them, so load_segment gives back exactly the same data
+pub async fn save_segment(operator: &Operator, data: &BWTData, prefix: &str) -> Result<()> {
+    let max_id = data.1.iter().copied().max().unwrap_or(0);
+    write_segment(operator, data, prefix, IdEncoding::fixed_for(max_id)).await
+}
+
+// Load a whole segment into memory, compressed or not
+pub async fn load_segment(operator: &Operator, prefix: &str) -> Result<BWTData> {
+    read_bwt_data(prefix, operator).await
+}
+
+// Runtime for the blocking versions of async functions.
+// Their callers must not already be running in an async runtime.
+fn blocking_runtime() -> Result<tokio::runtime::Runtime> {
+    Ok(tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?)
+}
+
+// save_segment for callers that aren't async
+pub fn save_segment_blocking(operator: &Operator, data: &BWTData, prefix: &str) -> Result<()> {
+    blocking_runtime()?.block_on(save_segment(operator, data, prefix))
+}
+
+// load_segment for callers that aren't async
+pub fn load_segment_blocking(operator: &Operator, prefix: &str) -> Result<BWTData> {
+    blocking_runtime()?.block_on(load_segment(operator, prefix))
+}
+
+// Copy a segment with its .bwt and .index block-compressed, or decompressed if no
+// compression is given. The .rank and .docs files are copied unchanged.
+pub async fn compress_segment(
+    operator: &Operator,
+    bwt_path: &str,
+    output_path: &str,
+    compression: Option<BlockCompression>,
+) -> Result<()> {
+    let mut output = PartialOutput::new(operator, output_path).await?;
+    let result = copy_segment(operator, bwt_path, output_path, compression, &mut output).await;
+    output.finish(result).await
+}
+
+async fn copy_segment(
+    operator: &Operator,
+    bwt_path: &str,
+    output_path: &str,
+    compression: Option<BlockCompression>,
+    output: &mut PartialOutput,
+) -> Result<()> {
+    let meta = read_meta(bwt_path, operator).await?;
+    let mut checksums = Vec::with_capacity(2);
+    for ext in ["bwt", "index"] {
+        let path = format!("{}.{}", bwt_path, ext);
+        let (mut stream, len) = open_segment_file(
+            path.as_str(),
+            operator,
+            meta.compressed,
+            ReadOptions::default(),
+        )
+        .await?;
+        if ext == "bwt" {
+            check_bwt_len(bwt_path, &meta, len)?;
+        }
+        let mut writer = output
+            .create_compressed(format!("{}.{}", output_path, ext).as_str(), compression)
+            .await?;
+        loop {
+            let chunk = stream.chunk().await?;
+            if chunk.is_empty() {
+                break;
+            }
+            let len = chunk.len();
+            writer.write_all(chunk).await?;
+            stream.consume(len);
+        }
+        checksums.push(writer.close().await?);
+    }
+
+    let checksums = Checksums {
+        bwt: checksums[0],
+        index: checksums[1],
+    };
+    let new_meta = SegmentMeta {
+        compressed: compression.is_some(),
+        ..SegmentMeta::new(meta.separator, meta.counts, checksums)
+    };
+    output
+        .write(
+            format!("{}.counts", output_path).as_str(),
+            new_meta.encode(),
+        )
+        .await?;
+    for ext in ["rank", "docs"] {
+        let path = format!("{}.{}", bwt_path, ext);
+        if operator.is_exist(path.as_str()).await? {
+            output
+                .write(
+                    format!("{}.{}", output_path, ext).as_str(),
+                    operator.read(path.as_str()).await?,
+                )
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+// Convert a segment from the old text format, with one decimal number per line
+// in .index and .counts, to the binary format. The .bwt file is unchanged.
+// Returns false if the segment is already binary.
+pub async fn migrate_text_segment(operator: &Operator, bwt_path: &str) -> Result<bool> {
+    recover_output(operator, bwt_path).await?;
+    let counts_path = format!("{}.counts", bwt_path);
+    let counts_data = operator.read(counts_path.as_str()).await?;
+    if !is_text_format(&counts_data) {
+        SegmentMeta::decode(&counts_data).map_err(|e| anyhow!("{}: {}", bwt_path, e))?;
+        return Ok(false);
+    }
+
+    let counts_vec = counts_data
+        .split(|&x| x == b'\n')
+        .filter(|x| !x.is_empty())
+        .map(|x| std::str::from_utf8(x).unwrap().parse())
+        .collect::<Result<Vec<usize>, _>>()?;
+    if counts_vec.len() != 256 {
+        return Err(anyhow!("Invalid counts file"));
+    }
+    let mut counts = [0; 256];
+    counts.copy_from_slice(&counts_vec);
+
+    let mut output = PartialOutput::new(operator, bwt_path).await?;
+    let result = migrate_index_and_counts(operator, bwt_path, counts, &mut output).await;
+    output.finish(result).await?;
+    Ok(true)
+}
+
+async fn migrate_index_and_counts(
+    operator: &Operator,
+    bwt_path: &str,
+    counts: [usize; 256],
+    output: &mut PartialOutput,
+) -> Result<()> {
+    let bwt_len = counts.iter().sum::<usize>();
+    let counts_path = format!("{}.counts", bwt_path);
+
+    // migrations from before outputs were committed together may have been interrupted
+    // after converting the index, leaving it binary. Anything else is an error rather than
+    // something to convert.
+    let index_path = format!("{}.index", bwt_path);
+    let mut header = [0u8; INDEX_HEADER_SIZE];
+    let header_len = open_stream(index_path.as_str(), operator)
+        .await?
+        .read_up_to(&mut header)
+        .await?;
+    let header = &header[..header_len];
+    let index_checksum;
+    if header.is_empty() || is_text_format(header) {
+        let mut stream = open_stream(index_path.as_str(), operator).await?;
+        let mut index_writer = output
+            .create_index(
+                index_path.as_str(),
+                IdEncoding::fixed_for(counts[SEPARATOR as usize]),
+                None,
+            )
+            .await?;
+        let mut num_ids = 0;
+        let mut extra_num = 0;
+        loop {
+            let (ints, new_extra_num) = read_text_ints(&mut stream, extra_num).await?;
+            if ints.is_empty() {
+                break;
+            }
+            num_ids += ints.len();
+            for line_ind in ints {
+                index_writer.write_id(line_ind).await?;
+            }
+            extra_num = new_extra_num;
+        }
+        // the last id may be missing its trailing newline
+        if num_ids + 1 == bwt_len {
+            index_writer.write_id(extra_num).await?;
+            num_ids += 1;
+        }
+        if num_ids != bwt_len {
+            return Err(anyhow!(
+                "Line index has {} ids, expected {}",
+                num_ids,
+                bwt_len
+            ));
+        }
+        index_checksum = index_writer.close().await?;
+    } else {
+        decode_index_header(header).map_err(|e| anyhow!("{}: {}", bwt_path, e))?;
+        index_checksum = file_checksum(index_path.as_str(), operator).await?;
+    }
+
+    let checksums = Checksums {
+        bwt: file_checksum(format!("{}.bwt", bwt_path).as_str(), operator).await?,
+        index: index_checksum,
+    };
+    let meta = SegmentMeta::new(SEPARATOR, counts, checksums);
+    output.write(counts_path.as_str(), meta.encode()).await?;
+    Ok(())
+}
+
+// CRC32 of a whole file
+async fn file_checksum(path: &str, operator: &Operator) -> Result<u32> {
+    let mut stream = open_stream(path, operator).await?;
+    let mut hasher = Hasher::new();
+    loop {
+        let chunk = stream.chunk().await?;
+        if chunk.is_empty() {
+            break;
+        }
+        hasher.update(chunk);
+        let len = chunk.len();
+        stream.consume(len);
+    }
+    Ok(hasher.finalize())
+}
+
+// What verify_segment found in the data of a .bwt
+struct BwtScan {
+    len: usize,
+    counts: [usize; 256],
+    // of the data, which is the file unless it is compressed
+    checksum: u32,
+}
+
+async fn scan_bwt(
+    path: &str,
+    operator: &Operator,
+    compressed: bool,
+    mut rank: Option<&mut RankBuilder>,
+) -> Result<BwtScan> {
+    let (mut stream, _) =
+        open_segment_file(path, operator, compressed, ReadOptions::default()).await?;
+    let mut hasher = Hasher::new();
+    let mut scan = BwtScan {
+        len: 0,
+        counts: [0; 256],
+        checksum: 0,
+    };
+    loop {
+        let chunk = stream.chunk().await?;
+        if chunk.is_empty() {
+            break;
+        }
+        hasher.update(chunk);
+        for &chr in chunk.iter() {
+            scan.counts[chr as usize] += 1;
+        }
+        if let Some(rank) = rank.as_mut() {
+            rank.extend(chunk);
+        }
+        let len = chunk.len();
+        stream.consume(len);
+        scan.len += len;
+    }
+    scan.checksum = hasher.finalize();
+    Ok(scan)
+}
+
+// What verify_segment found in the data of an .index
+struct IndexScan {
+    num_ids: usize,
+    truncated: bool,
+    out_of_range: Option<SegmentProblem>,
+    // of the data, which is the file unless it is compressed
+    checksum: u32,
+}
+
+async fn scan_index(path: &str, operator: &Operator, meta: &SegmentMeta) -> Result<IndexScan> {
+    let (mut stream, _) =
+        open_segment_file(path, operator, meta.compressed, ReadOptions::default()).await?;
+    let mut hasher = Hasher::new();
+    let mut header = [0u8; INDEX_HEADER_SIZE];
+    let header_len = stream.read_up_to(&mut header).await?;
+    hasher.update(&header[..header_len]);
+    let encoding = decode_index_header(&header[..header_len])?;
+    let mut decoder = IdDecoder::new(encoding);
+    let mut ids = Vec::new();
+    let mut num_ids = 0;
+    let mut out_of_range = None;
+    loop {
+        let chunk = stream.chunk().await?;
+        if chunk.is_empty() {
+            break;
+        }
+        hasher.update(chunk);
+        ids.clear();
+        decoder.decode(chunk, &mut ids)?;
+        let len = chunk.len();
+        stream.consume(len);
+
+        // the last line has the id num_lines when the text doesn't end in a separator
+        if out_of_range.is_none() {
+            if let Some(pos) = ids.iter().position(|&x| x > meta.num_lines) {
+                out_of_range = Some(SegmentProblem::LineIdOutOfRange {
+                    position: num_ids + pos,
+                    line_id: ids[pos],
+                    num_lines: meta.num_lines,
+                });
+            }
+        }
+        num_ids += ids.len();
+    }
+    Ok(IndexScan {
+        num_ids,
+        truncated: decoder.has_partial(),
+        out_of_range,
+        checksum: hasher.finalize(),
+    })
+}
+
+// Check that the files of a segment are intact and belong together: checksums,
+// lengths, character counts, and that line ids are in range.
+// Returns the problems found, or an error if the segment can't be read at all.
+pub async fn verify_segment(operator: &Operator, bwt_path: &str) -> Result<Vec<SegmentProblem>> {
+    let operator = operator.clone();
+    let meta = read_meta(bwt_path, &operator).await?;
+    let mut problems = Vec::new();
+
+    let sum = meta.counts.iter().sum::<usize>();
+    if sum != meta.bwt_len {
+        problems.push(SegmentProblem::CountsSum {
+            sum,
+            bwt_len: meta.bwt_len,
+        });
+    }
+    let separators = meta.counts[meta.separator as usize];
+    if separators != meta.num_lines {
+        problems.push(SegmentProblem::NumLines {
+            num_lines: meta.num_lines,
+            separators,
+        });
+    }
+
+    // rank checkpoints are rebuilt with the interval of the .rank file, if there is one
+    let rank_path = format!("{}.rank", bwt_path);
+    let mut saved_rank = None;
+    let mut rank = None;
+    if operator.is_exist(rank_path.as_str()).await? {
+        match RankIndex::decode(&operator.read(rank_path.as_str()).await?) {
+            Ok(saved) => {
+                rank = Some(RankBuilder::with_interval(&meta.counts, saved.interval()));
+                saved_rank = Some(saved);
+            }
+            Err(_) => problems.push(SegmentProblem::RankMismatch),
+        }
+    }
+
+    // bwt length, character counts and checksum.
+    // Checksums of compressed files are of the files themselves, not of their data.
+    let bwt_file_path = format!("{}.bwt", bwt_path);
+    let mut bwt_checksum = None;
+    if meta.compressed {
+        bwt_checksum = Some(file_checksum(bwt_file_path.as_str(), &operator).await?);
+    }
+    match scan_bwt(&bwt_file_path, &operator, meta.compressed, rank.as_mut()).await {
+        Ok(scan) => {
+            bwt_checksum.get_or_insert(scan.checksum);
+            if scan.len != meta.bwt_len {
+                problems.push(SegmentProblem::BwtLength {
+                    expected: meta.bwt_len,
+                    actual: scan.len,
+                });
+            }
+            for (chr, (&actual, &expected)) in
+                scan.counts.iter().zip(meta.counts.iter()).enumerate()
+            {
+                if actual != expected {
+                    problems.push(SegmentProblem::CharCount {
+                        chr: chr as u8,
+                        expected,
+                        actual,
+                    });
+                }
+            }
+        }
+        Err(e) if meta.compressed => {
+            problems.push(SegmentProblem::Undecodable {
+                file: ".bwt",
+                error: e.to_string(),
+            });
+            rank = None;
+        }
+        Err(e) => return Err(e),
+    }
+
+    // line ids and index checksum
+    let index_file_path = format!("{}.index", bwt_path);
+    let mut index_checksum = None;
+    if meta.compressed {
+        index_checksum = Some(file_checksum(index_file_path.as_str(), &operator).await?);
+    }
+    match scan_index(&index_file_path, &operator, &meta).await {
+        Ok(scan) => {
+            index_checksum.get_or_insert(scan.checksum);
+            if scan.truncated {
+                problems.push(SegmentProblem::TruncatedLineId);
+            }
+            if scan.num_ids != meta.bwt_len {
+                problems.push(SegmentProblem::IndexLength {
+                    expected: meta.bwt_len,
+                    actual: scan.num_ids,
+                });
+            }
+            problems.extend(scan.out_of_range);
+        }
+        Err(e) if meta.compressed => problems.push(SegmentProblem::Undecodable {
+            file: ".index",
+            error: e.to_string(),
+        }),
+        Err(e) => return Err(anyhow!("{}: {}", bwt_path, e)),
+    }
+
+    if let Some(checksums) = meta.checksums {
+        if let Some(actual) = bwt_checksum.filter(|&x| x != checksums.bwt) {
+            problems.push(SegmentProblem::BwtChecksum {
+                expected: checksums.bwt,
+                actual,
+            });
+        }
+        if let Some(actual) = index_checksum.filter(|&x| x != checksums.index) {
+            problems.push(SegmentProblem::IndexChecksum {
+                expected: checksums.index,
+                actual,
+            });
+        }
+    }
+
+    if let (Some(rank), Some(saved)) = (rank, saved_rank) {
+        if rank.finish().ok().as_ref() != Some(&saved) {
+            problems.push(SegmentProblem::RankMismatch);
+        }
+    }
+
+    let docs_path = format!("{}.docs", bwt_path);
+    if operator.is_exist(docs_path.as_str()).await? {
+        let docs_len = read_file_size(docs_path.as_str(), &operator).await?;
+        if docs_len != meta.num_lines * 8 {
+            problems.push(SegmentProblem::DocIdsLength {
+                expected: meta.num_lines,
+                actual: docs_len / 8,
+            });
+        }
+    }
+
+    Ok(problems)
+}
+
+// Write the .rank file of an existing segment, such as one written before
+// rank checkpoints were added, by streaming its .bwt
+pub async fn write_rank_index(operator: &Operator, bwt_path: &str) -> Result<()> {
+    let mut output = PartialOutput::new(operator, bwt_path).await?;
+    let meta = read_meta(bwt_path, operator).await?;
+    let (mut stream, _) = open_segment_file(
+        format!("{}.bwt", bwt_path).as_str(),
+        operator,
+        meta.compressed,
+        ReadOptions::default(),
+    )
+    .await?;
+    let mut rank = RankBuilder::new(&meta.counts);
+    loop {
+        let chunk = stream.chunk().await?;
+        if chunk.is_empty() {
+            break;
+        }
+        rank.extend(chunk);
+        let len = chunk.len();
+        stream.consume(len);
+    }
+    let rank = rank.finish().map_err(|e| anyhow!("{}: {}", bwt_path, e))?;
+    let result = output
+        .write(format!("{}.rank", bwt_path).as_str(), rank.encode())
+        .await;
+    output.finish(result).await
+}
+
+// Read part of the data of a .bwt or .index file.
+// For compressed files, given their block table, only the blocks holding it are read.
+async fn read_segment_range(
+    operator: &Operator,
+    path: &str,
+    blocks: Option<&BlockTable>,
+    range: Range<usize>,
+) -> Result<Vec<u8>> {
+    match blocks {
+        Some(table) => block::read_range(operator, path, table, range).await,
+        None if range.is_empty() => Ok(Vec::new()),
+        None => Ok(operator
+            .read_with(path)
+            .range(range.start as u64..range.end as u64)
+            .await?),
+    }
+}
+
+// FM index of a segment that stays on disk. Only the rank checkpoints and the
+// metadata are kept in memory; each rank reads at most one checkpoint interval
+// of the .bwt, and matching lines are read from the .index as needed.
+// For compressed segments the block tables are kept too, so that only the blocks
+// holding those ranges are read.
+pub struct DiskFMIndex {
+    operator: Operator,
+    bwt_path: String,
+    meta: SegmentMeta,
+    rank: RankIndex,
+    encoding: IdEncoding,
+    bwt_blocks: Option<BlockTable>,
+    index_blocks: Option<BlockTable>,
+    mapped: Option<MappedSegment>,
+}
+
+impl DiskFMIndex {
+    pub async fn open(operator: &Operator, bwt_path: &str) -> Result<DiskFMIndex> {
+        let meta = read_meta(bwt_path, operator).await?;
+        let rank_path = format!("{}.rank", bwt_path);
+        if !operator.is_exist(rank_path.as_str()).await? {
+            return Err(anyhow!(
+                "{}: segment has no .rank file, write one with write_rank_index",
+                bwt_path
+            ));
+        }
+        let rank = RankIndex::decode(&operator.read(rank_path.as_str()).await?)
+            .map_err(|e| anyhow!("{}: {}", bwt_path, e))?;
+        if rank.bwt_len() != meta.bwt_len
+            || (0..=255u8)
+                .any(|chr| rank.start(chr) != meta.counts[..chr as usize].iter().sum::<usize>())
+        {
+            return Err(anyhow!("{}: .rank does not match the counts", bwt_path));
+        }
+        DiskFMIndex::with_rank(operator, bwt_path, meta, rank).await
+    }
+
+    // FM index of a segment with rank checkpoints from elsewhere than its .rank file
+    async fn with_rank(
+        operator: &Operator,
+        bwt_path: &str,
+        meta: SegmentMeta,
+        rank: RankIndex,
+    ) -> Result<DiskFMIndex> {
+        // local segments are mapped, and need no more reading to open
+        if let Some(mapped) = MappedSegment::open(operator, bwt_path, &meta)? {
+            return Ok(DiskFMIndex {
+                operator: operator.clone(),
+                bwt_path: bwt_path.to_string(),
+                meta,
+                rank,
+                encoding: mapped.encoding(),
+                bwt_blocks: None,
+                index_blocks: None,
+                mapped: Some(mapped),
+            });
+        }
+
+        let index_path = format!("{}.index", bwt_path);
+        let (mut bwt_blocks, mut index_blocks) = (None, None);
+        if meta.compressed {
+            let bwt_table =
+                BlockTable::read(operator, format!("{}.bwt", bwt_path).as_str()).await?;
+            check_bwt_len(bwt_path, &meta, bwt_table.data_len())?;
+            bwt_blocks = Some(bwt_table);
+            index_blocks = Some(BlockTable::read(operator, index_path.as_str()).await?);
+        }
+        let header = match index_blocks.as_ref() {
+            Some(table) => {
+                let len = INDEX_HEADER_SIZE.min(table.data_len());
+                block::read_range(operator, index_path.as_str(), table, 0..len).await?
+            }
+            None if meta.bwt_len > 0 => {
+                operator
+                    .read_with(index_path.as_str())
+                    .range(0..INDEX_HEADER_SIZE as u64)
+                    .await?
+            }
+            None => operator.read(index_path.as_str()).await?,
+        };
+        let encoding = decode_index_header(&header).map_err(|e| anyhow!("{}: {}", bwt_path, e))?;
+
+        Ok(DiskFMIndex {
+            operator: operator.clone(),
+            bwt_path: bwt_path.to_string(),
+            meta,
+            rank,
+            encoding,
+            bwt_blocks,
+            index_blocks,
+            mapped: None,
+        })
+    }
+
+    pub fn meta(&self) -> &SegmentMeta {
+        &self.meta
+    }
+
+    // Whether the segment is mapped into memory rather than read through the operator
+    pub fn is_mapped(&self) -> bool {
+        self.mapped.is_some()
+    }
+
+    // Bytes of memory held between queries
+    pub fn memory_size(&self) -> usize {
+        std::mem::size_of::<DiskFMIndex>() - std::mem::size_of::<RankIndex>()
+            + self.bwt_path.len()
+            + self.rank.memory_size()
+            + [&self.bwt_blocks, &self.index_blocks]
+                .iter()
+                .flat_map(|x| x.as_ref())
+                .map(|x| x.memory_size() - std::mem::size_of::<BlockTable>())
+                .sum::<usize>()
+    }
+
+    // Occurrences of chr in the BWT before pos
+    async fn rank(&self, pos: usize, chr: u8) -> Result<usize> {
+        let Some(mut rank) = self.rank.checkpoint_rank(pos, chr) else {
+            return Ok(0);
+        };
+        let block_start = pos - pos % self.rank.interval();
+        if let Some(mapped) = self.mapped.as_ref() {
+            rank += mapped.bwt()[block_start..pos]
+                .iter()
+                .filter(|&&x| x == chr)
+                .count();
+            return Ok(rank);
+        }
+        if block_start < pos {
+            let block = read_segment_range(
+                &self.operator,
+                format!("{}.bwt", self.bwt_path).as_str(),
+                self.bwt_blocks.as_ref(),
+                block_start..pos,
+            )
+            .await?;
+            if block.len() != pos - block_start {
+                return Err(anyhow!("{}: .bwt ended early", self.bwt_path));
+            }
+            rank += block.iter().filter(|&&x| x == chr).count();
+        }
+        Ok(rank)
+    }
+
+    // Character of the BWT at pos
+    async fn byte_at(&self, pos: usize) -> Result<u8> {
+        if let Some(mapped) = self.mapped.as_ref() {
+            return Ok(mapped.bwt()[pos]);
+        }
+        let data = read_segment_range(
+            &self.operator,
+            format!("{}.bwt", self.bwt_path).as_str(),
+            self.bwt_blocks.as_ref(),
+            pos..pos + 1,
+        )
+        .await?;
+        data.first()
+            .copied()
+            .ok_or_else(|| anyhow!("{}: .bwt ended early", self.bwt_path))
+    }
+
+    // Search for a pattern, like substring_search on an in-memory FM index.
+    // Returns (start, end) indices of the pattern in the BWT, end is exclusive
+    pub async fn substring_search(&self, pattern: &[u8]) -> Result<Option<(usize, usize)>> {
+        let mut start = 0;
+        let mut end = self.meta.bwt_len;
+        for &chr in pattern.iter().rev() {
+            start = self.rank.start(chr) + self.rank(start, chr).await?;
+            end = self.rank.start(chr) + self.rank(end, chr).await?;
+            if start > end {
+                return Ok(None);
+            }
+        }
+        Ok(Some((start, end)))
+    }
+
+    // Line ids at BWT positions start..end
+    async fn line_ids(&self, start: usize, end: usize) -> Result<Vec<usize>> {
+        let mut ids = Vec::with_capacity(end - start);
+        if start == end {
+            return Ok(ids);
+        }
+        if let Some(mapped) = self.mapped.as_ref() {
+            return mapped
+                .line_ids(start, end)
+                .map_err(|e| anyhow!("{}: {}", self.bwt_path, e));
+        }
+        match self.encoding {
+            IdEncoding::Fixed(width) => {
+                let width = width as usize;
+                let data = read_segment_range(
+                    &self.operator,
+                    format!("{}.index", self.bwt_path).as_str(),
+                    self.index_blocks.as_ref(),
+                    INDEX_HEADER_SIZE + start * width..INDEX_HEADER_SIZE + end * width,
+                )
+                .await?;
+                if data.len() != (end - start) * width {
+                    return Err(index_too_short(&self.bwt_path));
+                }
+                IdDecoder::new(self.encoding).decode(&data, &mut ids)?;
+            }
+            // varints can't be indexed into, so earlier ids are streamed past
+            IdEncoding::Varint => {
+                let mut reader = IndexReader::open(
+                    &self.bwt_path,
+                    &self.operator,
+                    self.meta.compressed,
+                    ReadOptions::default(),
+                )
+                .await?;
+                reader.skip(start).await?;
+                for _ in start..end {
+                    let id = reader
+                        .next_id()
+                        .await?
+                        .ok_or_else(|| index_too_short(&self.bwt_path))?;
+                    ids.push(id);
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    // Get all matching line indices, like get_matching_lines
+    pub async fn matching_lines(&self, pattern: &[u8]) -> Result<BTreeSet<usize>> {
+        match self.substring_search(pattern).await? {
+            Some((start, end)) => Ok(self.line_ids(start, end).await?.into_iter().collect()),
+            None => Ok(BTreeSet::new()),
+        }
+    }
+
+    // Get the document ids of all matching lines, like get_matching_lines on a BWT with them,
+    // reading only the needed parts of the .docs file
+    pub async fn matching_docs(&self, pattern: &[u8]) -> Result<BTreeSet<u64>> {
+        let lines = self
+            .matching_lines(pattern)
+            .await?
+            .into_iter()
+            .collect::<Vec<usize>>();
+        let docs_path = format!("{}.docs", self.bwt_path);
+        let mut docs = BTreeSet::new();
+        // read runs of consecutive lines together
+        let mut i = 0;
+        while i < lines.len() {
+            let mut j = i + 1;
+            while j < lines.len() && lines[j] == lines[j - 1] + 1 {
+                j += 1;
+            }
+            let data = self
+                .operator
+                .read_with(docs_path.as_str())
+                .range((lines[i] * 8) as u64..(lines[j - 1] * 8 + 8) as u64)
+                .await?;
+            if data.len() != (j - i) * 8 {
+                return Err(anyhow!("Invalid document id file"));
+            }
+            docs.extend(
+                data.chunks_exact(8)
+                    .map(|x| u64::from_le_bytes(x.try_into().unwrap())),
+            );
+            i = j;
+        }
+        Ok(docs)
+    }
+}
+
+// Options for building segments
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BuildOptions {
+    // block compression of the .bwt and .index, None to write them uncompressed
+    pub compression: Option<BlockCompression>,
+}
+
+impl Default for BuildOptions {
+    fn default() -> BuildOptions {
+        BuildOptions {
+            compression: Some(BlockCompression::default()),
+        }
+    }
+}
+
+// Build the BWT of a text file on disk, writing it to output_path block-compressed.
+// Stops if cancelled, without leaving partial output behind.
+pub async fn bwt_build_disk(
+    operator: &Operator,
+    input_path: &str,
+    output_path: &str,
+    cancel: &Cancellation,
+) -> Result<()> {
+    bwt_build_disk_with_options(
+        operator,
+        input_path,
+        output_path,
+        cancel,
+        BuildOptions::default(),
+    )
+    .await
+}
+
+pub async fn bwt_build_disk_with_options(
+    operator: &Operator,
+    input_path: &str,
+    output_path: &str,
+    cancel: &Cancellation,
+    options: BuildOptions,
+) -> Result<()> {
+    let input = operator.read(input_path).await?;
+    cancel.check()?;
+
+    let build_cancel = cancel.clone();
+    let data =
+        tokio::task::spawn_blocking(move || run_bwt_cancellable(&input, &build_cancel)).await??;
+
+    let mut output = PartialOutput::new(operator, output_path).await?;
+    let encoding = IdEncoding::fixed_for(data.2[SEPARATOR as usize]);
+    let mut result = write_bwt_data(
+        &data,
+        output_path,
+        encoding,
+        options.compression,
+        &mut output,
+    )
+    .await;
+    if result.is_ok() {
+        result = cancel.check().map_err(|e| e.into());
+    }
+    output.finish(result).await
+}
+
+// Remove lines from a segment, writing the result to output_path.
+// Remaining line ids are renumbered to stay contiguous, and document ids are kept.
+// Unlike bwt_delete_lines_disk this doesn't stream: the whole segment is loaded and
+// the lines are deleted in memory by bwt_delete_lines.
+pub async fn bwt_delete_lines_in_memory(
+    operator: &Operator,
+    bwt_path: &str,
+    line_ids: &[usize],
+    output_path: &str,
+) -> Result<()> {
+    let mut output = PartialOutput::new(operator, output_path).await?;
+    let result =
+        delete_lines_in_memory(operator, bwt_path, line_ids, output_path, &mut output).await;
+    output.finish(result).await
+}
+
+async fn delete_lines_in_memory(
+    operator: &Operator,
+    bwt_path: &str,
+    line_ids: &[usize],
+    output_path: &str,
+    output: &mut PartialOutput,
+) -> Result<()> {
+    let data = read_bwt_data(bwt_path, operator).await?;
+    let num_lines = data.2[SEPARATOR as usize];
+    // the output is compressed like the input
+    let meta = read_meta(bwt_path, operator).await?;
+    let compression = output_compression(
+        operator,
+        &[bwt_path],
+        &[meta],
+        OutputCompression::LikeInputs,
+    )
+    .await?;
+    if let Some(line_id) = line_ids.iter().find(|&&x| x >= num_lines) {
+        return Err(anyhow!("Line id {} out of range", line_id));
+    }
+
+    // document ids of the remaining lines are kept along with them
+    let new_data = bwt_delete_lines(&data, line_ids);
+    let encoding = IdEncoding::fixed_for(new_data.2[SEPARATOR as usize]);
+    write_bwt_data(&new_data, output_path, encoding, compression, output).await
+}
+
+// Remove lines from a segment, writing the result to output_path, without loading it
+// into memory. The output is the same as bwt_delete_lines gives: the segment built
+// from its remaining lines, which must be what bwt_build_disk or bwt_merge_disk_many
+// would write for its text, ending with the separator.
+// Remaining line ids are renumbered to stay contiguous, and document ids are kept.
+pub async fn bwt_delete_lines_disk(
+    operator: &Operator,
+    bwt_path: &str,
+    line_ids: &[usize],
+    output_path: &str,
+) -> Result<MergeStats> {
+    bwt_delete_lines_disk_with_options(
+        operator,
+        bwt_path,
+        line_ids,
+        output_path,
+        &mut NoopObserver,
+        MergeOptions::default(),
+    )
+    .await
+}
+
+// Remove lines from a segment like bwt_delete_lines_disk, reporting progress to the
+// observer and reading and keeping to the memory budget as bwt_merge_disk_with_options.
+// Returns statistics like a merge, and removes partial output on failure.
+pub async fn bwt_delete_lines_disk_with_options(
+    operator: &Operator,
+    bwt_path: &str,
+    line_ids: &[usize],
+    output_path: &str,
+    observer: &mut dyn MergeObserver,
+    options: MergeOptions,
+) -> Result<MergeStats> {
+    let mut output = PartialOutput::new(operator, output_path).await?;
+    let result = delete_lines_disk(
+        operator,
+        bwt_path,
+        line_ids,
+        output_path,
+        observer,
+        options,
+        &mut output,
+    )
+    .await;
+    output.finish(result).await
+}
+
+// Rows of a segment found by scan_deleted_lines
+struct DeletedScan {
+    // rows of the deleted lines
+    skipped: BitVec,
+    // start row of each line at the edge of a run of remaining lines, and of line 0
+    line_rows: HashMap<usize, usize>,
+    // separators before each of those rows
+    separators_before: HashMap<usize, usize>,
+    rank: RankIndex,
+    // character counts of the remaining lines
+    counts: [usize; 256],
+}
+
+// Stream a segment's .bwt and .index to find the rows of the deleted lines and the
+// start rows of the lines where the remaining ones are cut
+async fn scan_deleted_lines(
+    operator: &Operator,
+    bwt_path: &str,
+    meta: &SegmentMeta,
+    line_deleted: &[bool],
+    options: ReadOptions,
+) -> Result<DeletedScan> {
+    let bwt_file_path = format!("{}.bwt", bwt_path);
+    let (mut bwt, _) =
+        open_segment_file(bwt_file_path.as_str(), operator, meta.compressed, options).await?;
+    let mut line_ind = IndexReader::open(bwt_path, operator, meta.compressed, options).await?;
+    let num_lines = line_deleted.len();
+    let needed = |line: usize| line == 0 || line_deleted[line] != line_deleted[line - 1];
+
+    let mut skipped = BitVec::from_elem(meta.bwt_len, false);
+    let mut line_rows = HashMap::new();
+    let mut separators_before = HashMap::new();
+    let mut rank = RankBuilder::new(&meta.counts);
+    let mut counts = [0; 256];
+    let mut separators = 0;
+    for i in 0..meta.bwt_len {
+        let chr = bwt.next_byte().await?;
+        let line = line_ind
+            .next_id()
+            .await?
+            .ok_or_else(|| index_too_short(bwt_path))?;
+        // a text not ending with a separator has rows past the last line
+        if line >= num_lines {
+            return Err(anyhow!(
+                "{}: lines can only be deleted from text ending with a separator",
+                bwt_path
+            ));
+        }
+        if line_deleted[line] {
+            skipped.set(i, true);
+        } else {
+            counts[chr as usize] += 1;
+        }
+        if chr == meta.separator {
+            if needed(line) {
+                line_rows.insert(line, i);
+                separators_before.insert(i, separators);
+            }
+            separators += 1;
+        }
+        rank.push(chr);
+    }
+    if let Some(line) = (0..num_lines).find(|&x| needed(x) && !line_rows.contains_key(&x)) {
+        return Err(anyhow!("{}: no row starts line {}", bwt_path, line));
+    }
+    Ok(DeletedScan {
+        skipped,
+        line_rows,
+        separators_before,
+        rank: rank.finish()?,
+        counts,
+    })
+}
+
+// Copy the document ids of the remaining lines of a segment, if it has any
+async fn delete_doc_ids(
+    operator: &Operator,
+    bwt_path: &str,
+    line_deleted: &[bool],
+    output_path: &str,
+    output: &mut PartialOutput,
+) -> Result<()> {
+    let docs_path = format!("{}.docs", bwt_path);
+    if !operator.is_exist(docs_path.as_str()).await? {
+        return Ok(());
+    }
+    if read_file_size(docs_path.as_str(), operator).await? != line_deleted.len() * 8 {
+        return Err(anyhow!(
+            "Document ids of {} do not match its number of lines",
+            bwt_path
+        ));
+    }
+
+    let mut docs_stream = open_stream(docs_path.as_str(), operator).await?;
+    let mut docs_writer = output
+        .create(format!("{}.docs", output_path).as_str())
+        .await?;
+    let mut doc_id = [0u8; 8];
+    for &deleted in line_deleted.iter() {
+        for byte in doc_id.iter_mut() {
+            *byte = docs_stream.next_byte().await?;
+        }
+        if !deleted {
+            docs_writer.write_all(&doc_id).await?;
+        }
+    }
+    docs_writer.close().await?;
+    Ok(())
+}
+
+async fn delete_lines_disk(
+    operator: &Operator,
+    bwt_path: &str,
+    line_ids: &[usize],
+    output_path: &str,
+    observer: &mut dyn MergeObserver,
+    merge_options: MergeOptions,
+    output: &mut PartialOutput,
+) -> Result<MergeStats> {
+    let mut stats = MergeStats::default();
+    let options = merge_options.read;
+    let meta = read_meta(bwt_path, operator).await?;
+    let separator = meta.separator;
+    let num_lines = meta.num_lines;
+
+    let mut line_deleted = vec![false; num_lines];
+    for &line_id in line_ids.iter() {
+        if line_id >= num_lines {
+            return Err(anyhow!("Line id {} out of range", line_id));
+        }
+        line_deleted[line_id] = true;
+    }
+    let mut deleted = line_ids.to_vec();
+    deleted.sort_unstable();
+    deleted.dedup();
+    let deleted_before = |line: usize| deleted.partition_point(|&x| x < line);
+
+    let compression = output_compression(
+        operator,
+        &[bwt_path],
+        std::slice::from_ref(&meta),
+        merge_options.compression,
+    )
+    .await?;
+    delete_doc_ids(operator, bwt_path, &line_deleted, output_path, output).await?;
+
+    let scan = scan_deleted_lines(operator, bwt_path, &meta, &line_deleted, options).await?;
+    for ext in ["bwt", "index"] {
+        let path = format!("{}.{}", bwt_path, ext);
+        stats.bytes_read += read_file_size(path.as_str(), operator).await? as u64;
+    }
+
+    // each run of remaining lines is a piece of the output, as in bwt_delete_lines
+    let separator_start: usize = meta.counts[..separator as usize].iter().sum();
+    let start_row = scan.line_rows.get(&0).copied().unwrap_or(0);
+    let pieces = remaining_pieces(
+        &line_deleted,
+        |line| scan.line_rows[&line],
+        |row| match row == start_row {
+            true => separator_start,
+            false => {
+                separator_start + scan.separators_before[&row] + 1 - usize::from(start_row < row)
+            }
+        },
+    );
+    // the tied rows of all but the last piece are walked back with the segment's ranks
+    let index = match pieces.iter().any(|x| x.relinked) {
+        true => Some(DiskFMIndex::with_rank(operator, bwt_path, meta.clone(), scan.rank).await?),
+        false => None,
+    };
+    let mut chains = Vec::with_capacity(pieces.len());
+    for piece in pieces.iter() {
+        chains.push(match (piece.relinked, index.as_ref()) {
+            (true, Some(index)) => {
+                disk_tied_chain(index, start_row, piece, |line| line - deleted_before(line)).await?
+            }
+            _ => (Vec::new(), false),
+        });
+    }
+    let mut links = link_pieces(1, &pieces, &chains);
+    links.sources[0].skipped = Some(scan.skipped);
+
+    let bwt_file_path = format!("{}.bwt", bwt_path);
+    let (bwt, bwt_len) =
+        open_segment_file(bwt_file_path.as_str(), operator, meta.compressed, options).await?;
+    check_bwt_len(bwt_path, &meta, bwt_len)?;
+    let mut bwts = [bwt];
+    let (initial, tied_order) = initial_runs(&[bwt_len], &links);
+
+    // the rows skipped and the lines deleted are kept besides the interleave
+    let (mut spilled, peak_memory) = plan_interleave(
+        operator,
+        output_path,
+        1,
+        &initial,
+        links.tied_rows.len(),
+        &scan.counts,
+        16,
+        merge_options,
+        output,
+    )?;
+    stats.peak_memory = peak_memory + bwt_len.div_ceil(8) + num_lines;
+
+    let start = std::time::Instant::now();
+    let (interleave, tied_order) = match spilled.as_mut() {
+        Some(spilled) => {
+            let tied_order = compute_interleave_spilled(
+                &mut bwts,
+                spilled,
+                Some((&links, tied_order)),
+                separator,
+                &mut stats,
+                observer,
+            )
+            .await?;
+            (Vec::new(), tied_order)
+        }
+        None => {
+            // the rows other than the tied ones keep their order, so only the tied
+            // rows are placed by each pass, with the rest read once
+            let num_kept = scan.counts.iter().sum::<usize>() - links.tied_rows.len();
+            let mut rows = SourceRows::new(&links.sources[0]);
+            let mut kept = Vec::with_capacity(num_kept);
+            let mut kept_links = Vec::new();
+            for k in 0..num_kept {
+                if k % PROGRESS_INTERVAL == 0
+                    && k > 0
+                    && !observer.on_progress(MergePhase::Interleave(0), k, num_kept)
+                {
+                    return Err(MergeStopped.into());
+                }
+                let (row, pred) = rows.next_row();
+                kept.push(byte_at(&mut bwts[0], row).await?);
+                if let Some(pred) = pred {
+                    kept_links.push((k, pred));
+                }
+            }
+            stats.bytes_read += bwts[0].take_bytes_read();
+            let tied = place_tied_rows(
+                kept,
+                &kept_links,
+                bwt_len,
+                &links,
+                &scan.counts,
+                &mut stats,
+                observer,
+            )?;
+            let mut interleave = vec![0; num_kept + tied.len()];
+            for &(pos, _) in tied.iter() {
+                interleave[pos] = 1;
+            }
+            (interleave, tied.into_iter().map(|x| x.1).collect())
+        }
+    };
+    stats.interleave_time = start.elapsed();
+
+    let start = std::time::Instant::now();
+    let output_rows = LinkedOutput {
+        bwt_paths: &[bwt_path],
+        metas: std::slice::from_ref(&meta),
+        links: &links,
+        interleave: &interleave,
+        spilled: spilled.as_ref(),
+        tied_order: &tied_order,
+        counts: &scan.counts,
+        separator,
+        compression,
+    };
+    write_linked_output(
+        operator,
+        &output_rows,
+        &mut bwts,
+        |_, line| line - deleted_before(line),
+        output_path,
+        output,
+        &mut stats,
+        observer,
+        options,
+    )
+    .await?;
+    stats.output_time = start.elapsed();
+    stats.bytes_written += output_size(output_path, operator).await?;
+
+    Ok(stats)
+}
+
+// Append lines to a segment in place. The lines are built into a BWT in memory and
+// merged after the segment's own, so they get the line ids following the existing ones,
+// and the merged files replace the segment's together. Lines must not contain the separator.
+pub async fn append_lines(
+    operator: &Operator,
+    index_prefix: &str,
+    lines: &[Vec<u8>],
+) -> Result<MergeStats> {
+    append_batch(operator, index_prefix, lines, None).await
+}
+
+// Append lines like append_lines, with a document id for each line.
+// The segment must have document ids too.
+pub async fn append_lines_with_docs(
+    operator: &Operator,
+    index_prefix: &str,
+    lines: &[Vec<u8>],
+    doc_ids: &[u64],
+) -> Result<MergeStats> {
+    append_batch(operator, index_prefix, lines, Some(doc_ids)).await
+}
+
+async fn append_batch(
+    operator: &Operator,
+    index_prefix: &str,
+    lines: &[Vec<u8>],
+    doc_ids: Option<&[u64]>,
+) -> Result<MergeStats> {
+    if let Some(doc_ids) = doc_ids {
+        if doc_ids.len() != lines.len() {
+            return Err(anyhow!(
+                "{} lines but {} document ids",
+                lines.len(),
+                doc_ids.len()
+            ));
+        }
+    }
+    if let Some(i) = lines.iter().position(|x| x.contains(&SEPARATOR)) {
+        return Err(anyhow!("Line {} of the batch contains a separator", i));
+    }
+    if lines.is_empty() {
+        return Ok(MergeStats::default());
+    }
+
+    let mut text = Vec::with_capacity(lines.iter().map(|x| x.len() + 1).sum());
+    for line in lines.iter() {
+        text.extend_from_slice(line);
+        text.push(SEPARATOR);
+    }
+    let mut data = tokio::task::spawn_blocking(move || run_bwt(&text)).await?;
+    data.3 = doc_ids.map(|x| x.to_vec());
+
+    // the batch is written next to the segment, and the merge into the segment's own
+    // prefix replaces its files only once it has been committed
+    let batch_path = format!("{}.append-batch", index_prefix);
+    let result = async {
+        save_segment(operator, &data, &batch_path).await?;
+        bwt_merge_disk(operator, index_prefix, &batch_path, index_prefix).await
+    }
+    .await;
+    for ext in SEGMENT_EXTS.iter() {
+        let _ = operator
+            .delete(format!("{}.{}", batch_path, ext).as_str())
+            .await;
+    }
+    result
+}
+
+// Whether the suffixes of text at a and b match up to and including the end of their lines.
+// The text must end with a separator.
+fn same_line_suffix(text: &[u8], a: usize, b: usize) -> bool {
+    for (&x, &y) in text[a..].iter().zip(text[b..].iter()) {
+        if x != y {
+            return false;
+        }
+        if x == SEPARATOR {
+            return true;
+        }
+    }
+    false
+}
+
+// How verify_merge_with_options compares a merged segment with a rebuild
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VerifyMode {
+    // every position must hold the rebuild's BWT byte and line id
+    #[default]
+    Exact,
+    // rotations that are equal up to the end of their lines may be in any order
+    // among themselves. Each input of a pairwise merge is sorted on its own, so such
+    // rotations can be in a different order than in a rebuild, which sorts on into
+    // the following lines.
+    AllowLineEndReorders,
+}
+
+// Result of comparing a merged segment with a rebuild
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub bwt_len: usize,
+    // first mismatch not allowed by the mode
+    pub mismatch: Option<MergeMismatch>,
+    // runs of positions holding the rebuild's rotations in another order, allowed by
+    // VerifyMode::AllowLineEndReorders
+    pub reordered: Vec<Range<usize>>,
+}
+
+// Compare a merged segment with a rebuild from the text it indexes, position by position,
+// reading the merged .bwt and .index as streams.
+// Returns the BWT length and the first position that differs, if any.
+pub async fn verify_merge(
+    operator: &Operator,
+    text_path: &str,
+    merged_path: &str,
+) -> Result<(usize, Option<MergeMismatch>)> {
+    let report =
+        verify_merge_with_options(operator, text_path, merged_path, VerifyMode::Exact).await?;
+    Ok((report.bwt_len, report.mismatch))
+}
+
+// Compare a merged segment with a rebuild like verify_merge, in the given mode.
+// Runs of rotations in another order than the rebuild's are reported apart from the
+// mismatch when the mode allows them.
+pub async fn verify_merge_with_options(
+    operator: &Operator,
+    text_path: &str,
+    merged_path: &str,
+    mode: VerifyMode,
+) -> Result<VerifyReport> {
+    let text = operator.read(text_path).await?;
+    let n = text.len();
+    if text.last().is_some_and(|&x| x != SEPARATOR) {
+        return Err(anyhow!("{}: text must end with a separator", text_path));
+    }
+
+    let mut reordered = Vec::new();
+    let report = |mismatch| VerifyReport {
+        bwt_len: n,
+        mismatch,
+        reordered: Vec::new(),
+    };
+
+    let meta = read_meta(merged_path, operator).await?;
+    if meta.bwt_len != n {
+        let mismatch = MergeMismatch::BwtLength {
+            merged: meta.bwt_len,
+            rebuilt: n,
+        };
+        return Ok(report(Some(mismatch)));
+    }
+    let mut counts = [0usize; 256];
+    for &chr in text.iter() {
+        counts[chr as usize] += 1;
+    }
+    if let Some(chr) = (0..=255u8).find(|&x| meta.counts[x as usize] != counts[x as usize]) {
+        let mismatch = MergeMismatch::CharCount {
+            chr,
+            merged: meta.counts[chr as usize],
+            rebuilt: counts[chr as usize],
+        };
+        return Ok(report(Some(mismatch)));
+    }
+
+    let (text, sa) = tokio::task::spawn_blocking(move || {
+        let sa = match text.is_empty() {
+            true => Some(Vec::new()),
+            false => divsufsort64(&text).map(|sa| sa.into_iter().map(|x| x as usize).collect()),
+        };
+        (text, sa)
+    })
+    .await?;
+    let sa: Vec<usize> = sa.ok_or_else(|| anyhow!("{}: suffix sort failed", text_path))?;
+    let newlines = text
+        .iter()
+        .enumerate()
+        .filter(|(_, &x)| x == SEPARATOR)
+        .map(|(i, _)| i)
+        .collect::<Vec<usize>>();
+    // BWT byte and line id of the rotation at a suffix, as run_bwt computes them
+    let rebuilt_at = |suffix: usize| {
+        let chr = text[(suffix + n - 1) % n];
+        let line_id = match newlines.binary_search(&suffix) {
+            Ok(i) | Err(i) => i,
+        };
+        (chr, line_id)
+    };
+
+    let bwt_file_path = format!("{}.bwt", merged_path);
+    let (mut bwt, bwt_len) = open_segment_file(
+        bwt_file_path.as_str(),
+        operator,
+        meta.compressed,
+        ReadOptions::default(),
+    )
+    .await?;
+    check_bwt_len(merged_path, &meta, bwt_len)?;
+    let mut index = IndexReader::open(
+        merged_path,
+        operator,
+        meta.compressed,
+        ReadOptions::default(),
+    )
+    .await?;
+
+    let mut start = 0;
+    let mut merged = Vec::new();
+    let mut rebuilt = Vec::new();
+    while start < n {
+        let mut end = start + 1;
+        if mode == VerifyMode::AllowLineEndReorders {
+            while end < n && same_line_suffix(&text, sa[start], sa[end]) {
+                end += 1;
+            }
+        }
+        merged.clear();
+        rebuilt.clear();
+        for &suffix in sa[start..end].iter() {
+            let Some(line_id) = index.next_id().await? else {
+                let mismatch = MergeMismatch::IndexLength {
+                    merged: start + merged.len(),
+                    rebuilt: n,
+                };
+                return Ok(VerifyReport {
+                    reordered,
+                    ..report(Some(mismatch))
+                });
+            };
+            merged.push((bwt.next_byte().await?, line_id));
+            rebuilt.push(rebuilt_at(suffix));
+        }
+        if merged != rebuilt {
+            let first = (0..merged.len())
+                .find(|&i| merged[i] != rebuilt[i])
+                .unwrap();
+            let mismatch = MergeMismatch::Position {
+                position: start + first,
+                merged: merged[first],
+                rebuilt: rebuilt[first],
+            };
+            merged.sort();
+            rebuilt.sort();
+            if merged != rebuilt {
+                return Ok(VerifyReport {
+                    reordered,
+                    ..report(Some(mismatch))
+                });
+            }
+            reordered.push(start..end);
+        }
+        start = end;
+    }
+    if index.next_id().await?.is_some() {
+        let mut merged = n + 1;
+        while index.next_id().await?.is_some() {
+            merged += 1;
+        }
+        let mismatch = MergeMismatch::IndexLength { merged, rebuilt: n };
+        return Ok(VerifyReport {
+            reordered,
+            ..report(Some(mismatch))
+        });
+    }
+    Ok(VerifyReport {
+        reordered,
+        ..report(None)
+    })
+}
+
+// Merge the two parts of each sample pairwise, timing the merges, and check each one
+// against a rebuild in the given mode if one is given
+pub async fn test_merge_disk(
+    operator: &Operator,
+    input_path: &str,
+    output_path: &str,
+    test_rebuild: bool,
+    verify: Option<VerifyMode>,
+    options: MergeOptions,
+) {
+    // sizes written by the sampler
+    let test_sizes = sample_sizes(operator, input_path).await.unwrap();
+    let mut failed = Vec::new();
+
+    for size in test_sizes.iter() {
+        let bwt0_path = format!("{}/{}_0", input_path, size);
+        let bwt1_path = format!("{}/{}_1", input_path, size);
+        let output_path_n = format!("{}/{}_merged", output_path, size);
+
+        // time merge
+        let merge_start = std::time::Instant::now();
+        let stats = bwt_merge_disk_with_options(
+            operator,
+            &bwt0_path,
+            &bwt1_path,
+            &output_path_n,
+            &mut NoopObserver,
+            options,
+        )
+        .await
+        .unwrap();
+        let merge_duration = merge_start.elapsed();
+        println!("interleave iterations: {}", stats.interleave_iterations);
+        println!("peak memory estimate: {} bytes", stats.peak_memory);
+        println!("interleave time: {:?}", stats.interleave_time);
+        println!("merge time for size {}: {:?}", size, merge_duration);
+
+        if test_rebuild {
+            // time full rebuild, including i/o times
+            let rebuild_start = std::time::Instant::now();
+            let path = format!("{}/{}_full.txt", input_path, size);
+            let full_text = operator.read(path.as_str()).await.unwrap();
+            let full_bwt = run_bwt(&full_text);
+
+            let output_path = format!("{}/{}_merged_naive", output_path, size);
+            save_segment(operator, &full_bwt, &output_path)
+                .await
+                .unwrap();
+            let rebuild_duration = rebuild_start.elapsed();
+            println!("rebuild time for size {}: {:?}", size, rebuild_duration);
+        }
+
+        if let Some(mode) = verify {
+            let text_path = format!("{}/{}_full.txt", input_path, size);
+            let report = verify_merge_with_options(operator, &text_path, &output_path_n, mode)
+                .await
+                .unwrap();
+            if !report.reordered.is_empty() {
+                println!(
+                    "size {}: {} runs of rotations equal up to the end of their lines reordered, \
+                     {} positions",
+                    size,
+                    report.reordered.len(),
+                    report.reordered.iter().map(|x| x.len()).sum::<usize>()
+                );
+            }
+            match report.mismatch {
+                None => println!("size {}: pass, {} positions match", size, report.bwt_len),
+                Some(mismatch) => {
+                    println!("size {}: FAIL, {}", size, mismatch);
+                    failed.push(*size);
+                }
+            }
+        }
+    }
+
+    if verify.is_some() {
+        println!(
+            "{} of {} sizes passed, failed: {:?}",
+            test_sizes.len() - failed.len(),
+            test_sizes.len(),
+            failed
+        );
     }
 }
@@ -0,0 +1,109 @@
+// Checkpoint of a resumable disk merge.
+// It holds the interleave after the last completed pass and, once the interleave has
+// converged, how far the output has been written. Layout, all integers little-endian:
+// magic, version, phase (0 interleave, 1 output), reserved byte, fingerprint of the inputs,
+// iteration, output position, .bwt length and CRC32, .index length and CRC32,
+// interleave length in bits, the interleave bits, and a CRC32 of the bytes before it.
+
+use anyhow::{anyhow, Result};
+use bit_vec::BitVec;
+
+pub const CHECKPOINT_VERSION: u16 = 1;
+pub const CHECKPOINT_MAGIC: &[u8; 4] = b"BWTK";
+
+const HEADER_SIZE: usize = 4 + 2 + 1 + 1 + 4 + 8 + 8 + 8 + 4 + 8 + 4 + 8;
+
+// How far the output of a merge has been written, with the length and running
+// CRC32 of each output file at that point
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OutputProgress {
+    pub position: usize,
+    pub bwt_len: u64,
+    pub bwt_crc: u32,
+    pub index_len: u64,
+    pub index_crc: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MergeCheckpoint {
+    // identifies the inputs, so a checkpoint isn't resumed against different ones
+    pub inputs: u32,
+    // number of interleave passes completed
+    pub iteration: usize,
+    pub interleave: BitVec,
+    // set once the interleave has converged
+    pub output: Option<OutputProgress>,
+}
+
+impl MergeCheckpoint {
+    pub fn encode(&self) -> Vec<u8> {
+        let output = self.output.unwrap_or_default();
+        let mut data = Vec::with_capacity(HEADER_SIZE + self.interleave.len().div_ceil(8) + 4);
+        data.extend_from_slice(CHECKPOINT_MAGIC);
+        data.extend_from_slice(&CHECKPOINT_VERSION.to_le_bytes());
+        data.push(self.output.is_some() as u8);
+        data.push(0);
+        data.extend_from_slice(&self.inputs.to_le_bytes());
+        data.extend_from_slice(&(self.iteration as u64).to_le_bytes());
+        data.extend_from_slice(&(output.position as u64).to_le_bytes());
+        data.extend_from_slice(&output.bwt_len.to_le_bytes());
+        data.extend_from_slice(&output.bwt_crc.to_le_bytes());
+        data.extend_from_slice(&output.index_len.to_le_bytes());
+        data.extend_from_slice(&output.index_crc.to_le_bytes());
+        data.extend_from_slice(&(self.interleave.len() as u64).to_le_bytes());
+        data.extend_from_slice(&self.interleave.to_bytes());
+        data.extend_from_slice(&crc32fast::hash(&data).to_le_bytes());
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<MergeCheckpoint> {
+        if data.len() < HEADER_SIZE + 4 || &data[..4] != CHECKPOINT_MAGIC {
+            return Err(anyhow!("Not a merge checkpoint"));
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version != CHECKPOINT_VERSION {
+            return Err(anyhow!("Unsupported checkpoint version {}", version));
+        }
+        let expected = u32::from_le_bytes(data[data.len() - 4..].try_into().unwrap());
+        let actual = crc32fast::hash(&data[..data.len() - 4]);
+        if expected != actual {
+            return Err(anyhow!(
+                "Checkpoint checksum is {:08x}, expected {:08x}",
+                actual,
+                expected
+            ));
+        }
+
+        let read_u32 = |pos: usize| u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+        let read_u64 = |pos: usize| u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap());
+        let len = read_u64(HEADER_SIZE - 8) as usize;
+        let bits = &data[HEADER_SIZE..data.len() - 4];
+        if bits.len() != len.div_ceil(8) {
+            return Err(anyhow!(
+                "Checkpoint has {} interleave bytes, expected {}",
+                bits.len(),
+                len.div_ceil(8)
+            ));
+        }
+        let mut interleave = BitVec::from_bytes(bits);
+        interleave.truncate(len);
+
+        let output = match data[6] {
+            0 => None,
+            1 => Some(OutputProgress {
+                position: read_u64(20) as usize,
+                bwt_len: read_u64(28),
+                bwt_crc: read_u32(36),
+                index_len: read_u64(40),
+                index_crc: read_u32(48),
+            }),
+            phase => return Err(anyhow!("Invalid checkpoint phase {}", phase)),
+        };
+        Ok(MergeCheckpoint {
+            inputs: read_u32(8),
+            iteration: read_u64(12) as usize,
+            interleave,
+            output,
+        })
+    }
+}
@@ -1,3 +1,14 @@
+pub mod block;
 pub mod bwt;
 pub mod bwt_disk;
+pub mod checkpoint;
+pub mod louds;
+pub mod lsm;
+pub mod mapped;
+pub mod progress;
+pub mod sample;
+pub mod search;
+pub mod segment;
+pub mod spill;
+pub mod stream;
 pub mod trie;
@@ -0,0 +1,211 @@
+// Succinct static binary tries, for the read-only tries we ship.
+// The shape is stored in level order (LOUDS): two bits per node, whether it has a left
+// and a right child. Nodes are numbered in level order from the root at 0, so the child
+// at bit p is node rank1(p) + 1, as every set bit before it is an earlier child.
+// The data of every node is kept in one postings array in the same order, with the
+// number of items of each node in unary (that many zeros, then a one), so a node's
+// data ends at select1(node) - node.
+
+use serde::{Deserialize, Serialize};
+
+use crate::trie::BinaryTrieNode;
+
+// Bits covered by each entry of the rank directory
+const RANK_BLOCK_BITS: usize = 512;
+const WORDS_PER_BLOCK: usize = RANK_BLOCK_BITS / 64;
+
+// Bits as they're serialized; the rank directory is rebuilt when they're read
+#[derive(Serialize, Deserialize, Clone)]
+struct RawBits {
+    words: Vec<u64>,
+    len: usize,
+}
+
+// Bit vector with rank and select over its set bits
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(from = "RawBits", into = "RawBits")]
+pub struct RankSelect {
+    words: Vec<u64>,
+    len: usize,
+    // set bits before each block
+    ranks: Vec<u64>,
+}
+
+impl From<RawBits> for RankSelect {
+    fn from(raw: RawBits) -> RankSelect {
+        let mut ranks = Vec::with_capacity(raw.words.len() / WORDS_PER_BLOCK + 1);
+        let mut ones = 0;
+        for block in raw.words.chunks(WORDS_PER_BLOCK) {
+            ranks.push(ones);
+            ones += block.iter().map(|x| x.count_ones() as u64).sum::<u64>();
+        }
+        ranks.push(ones);
+        RankSelect {
+            words: raw.words,
+            len: raw.len,
+            ranks,
+        }
+    }
+}
+
+impl From<RankSelect> for RawBits {
+    fn from(bits: RankSelect) -> RawBits {
+        RawBits {
+            words: bits.words,
+            len: bits.len,
+        }
+    }
+}
+
+impl RankSelect {
+    pub fn new(bits: impl IntoIterator<Item = bool>) -> RankSelect {
+        let mut words = Vec::new();
+        let mut len = 0;
+        for bit in bits {
+            if len % 64 == 0 {
+                words.push(0);
+            }
+            if bit {
+                words[len / 64] |= 1 << (len % 64);
+            }
+            len += 1;
+        }
+        RankSelect::from(RawBits { words, len })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, i: usize) -> bool {
+        i < self.len && (self.words[i / 64] >> (i % 64)) & 1 == 1
+    }
+
+    // Number of set bits
+    pub fn count_ones(&self) -> usize {
+        *self.ranks.last().unwrap() as usize
+    }
+
+    // Set bits before position i
+    pub fn rank1(&self, i: usize) -> usize {
+        let i = i.min(self.len);
+        let block = i / RANK_BLOCK_BITS;
+        let word = i / 64;
+        let mut rank = self.ranks[block] as usize;
+        for &x in self.words[block * WORDS_PER_BLOCK..word].iter() {
+            rank += x.count_ones() as usize;
+        }
+        if !i.is_multiple_of(64) {
+            rank += (self.words[word] & ((1 << (i % 64)) - 1)).count_ones() as usize;
+        }
+        rank
+    }
+
+    // Position of the set bit with k set bits before it, if there is one
+    pub fn select1(&self, k: usize) -> Option<usize> {
+        if k >= self.count_ones() {
+            return None;
+        }
+        // last block with at most k set bits before it
+        let block = self.ranks.partition_point(|&x| x as usize <= k) - 1;
+        let mut remaining = k - self.ranks[block] as usize;
+        for (i, &word) in self.words[block * WORDS_PER_BLOCK..].iter().enumerate() {
+            let ones = word.count_ones() as usize;
+            if remaining < ones {
+                let mut word = word;
+                for _ in 0..remaining {
+                    // clear the lowest set bit
+                    word &= word - 1;
+                }
+                let position = (block * WORDS_PER_BLOCK + i) * 64;
+                return Some(position + word.trailing_zeros() as usize);
+            }
+            remaining -= ones;
+        }
+        None
+    }
+
+    // Bytes used by the bits and the rank directory
+    pub fn memory_size(&self) -> usize {
+        (self.words.len() + self.ranks.len()) * 8
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LoudsTrie<T> {
+    // whether each node has a left and a right child, in level order
+    shape: RankSelect,
+    // number of items of each node, in unary
+    data_ends: RankSelect,
+    postings: Vec<T>,
+}
+
+impl<T: Clone> LoudsTrie<T> {
+    pub fn num_nodes(&self) -> usize {
+        self.shape.len() / 2
+    }
+
+    fn data(&self, node: usize) -> &[T] {
+        let end = |node: usize| self.data_ends.select1(node).unwrap() - node;
+        let start = match node {
+            0 => 0,
+            _ => end(node - 1),
+        };
+        &self.postings[start..end(node)]
+    }
+
+    // Query the trie for matching indices, like BinaryTrieNode::query:
+    // if the string does not exist it may return a few results that don't match
+    pub fn query(&self, query: &[u8]) -> Vec<T> {
+        let mut node = 0;
+        let mut results = Vec::new();
+        for i in 0..query.len() * 8 {
+            let bit = (query[i / 8] >> (7 - (i % 8))) & 1;
+            let position = 2 * node + bit as usize;
+            if !self.shape.get(position) {
+                break;
+            }
+            results.extend_from_slice(self.data(node));
+            node = self.shape.rank1(position) + 1;
+        }
+
+        results.extend_from_slice(self.data(node));
+        results
+    }
+
+    // Serialized bytes of the shape and data bounds, without the postings.
+    // The rank directories are rebuilt when reading, so they aren't included.
+    pub fn structure_size(&self) -> usize {
+        bincode::serialized_size(&(&self.shape, &self.data_ends)).unwrap() as usize
+    }
+}
+
+impl<T: Clone> From<&BinaryTrieNode<T>> for LoudsTrie<T> {
+    fn from(trie: &BinaryTrieNode<T>) -> LoudsTrie<T> {
+        let mut shape = Vec::new();
+        let mut data_ends = Vec::new();
+        let mut postings = Vec::new();
+        // nodes in level order, each queued once its parent is visited
+        let mut queue = std::collections::VecDeque::from([trie]);
+        while let Some(node) = queue.pop_front() {
+            for child in [&node.left, &node.right] {
+                shape.push(child.is_some());
+                if let Some(child) = child {
+                    queue.push_back(child);
+                }
+            }
+            postings.extend_from_slice(&node.data);
+            data_ends.extend(std::iter::repeat_n(false, node.data.len()));
+            data_ends.push(true);
+        }
+        LoudsTrie {
+            shape: RankSelect::new(shape),
+            data_ends: RankSelect::new(data_ends),
+            postings,
+        }
+    }
+}
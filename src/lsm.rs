@@ -0,0 +1,456 @@
+// Directory of BWT segments, ingested in batches and compacted by merging.
+// New batches become small segments, appended in order, so the lines of the
+// directory keep their order across compactions: a compaction merges a run of
+// adjacent segments, which concatenates their lines.
+//
+// Segments are named seg-<id> in the directory, block-compressed. The live segments are listed in
+// a manifest, written as a new manifest-<generation> file on every change, so
+// that committing a merge or a batch is a single atomic write on any service.
+// Files that the newest manifest doesn't refer to are removed on open, along with
+// interleaves left on disk by merges that didn't finish.
+// A directory has a single writer.
+//
+// Manifest layout, all integers little-endian: magic, version, flags, reserved byte,
+// generation, next segment id, number of segments, then the id, BWT length and
+// number of lines of each segment in order, and a CRC32 of the bytes before it.
+
+use anyhow::{anyhow, Result};
+use opendal::{ErrorKind, Operator};
+
+use crate::block::BlockCompression;
+use crate::bwt::run_bwt_cancellable;
+use crate::bwt_disk::{bwt_merge_disk, bwt_merge_disk_many, write_segment_compressed};
+use crate::progress::{Cancellation, MergeStats};
+use crate::search::SegmentSearcher;
+use crate::segment::{IdEncoding, SEPARATOR};
+
+pub const MANIFEST_VERSION: u16 = 1;
+pub const MANIFEST_MAGIC: &[u8; 4] = b"BWTD";
+
+// magic, version, flags, reserved byte, generation, next id, number of segments
+const MANIFEST_HEADER_SIZE: usize = 4 + 2 + 1 + 1 + 8 + 8 + 8;
+const MANIFEST_ENTRY_SIZE: usize = 8 + 8 + 8;
+
+// Manifest flag set when every segment has document ids
+const HAS_DOCS: u8 = 1;
+
+const MANIFEST_PREFIX: &str = "manifest-";
+const SEGMENT_PREFIX: &str = "seg-";
+// Suffix of the directories that merges spill their interleave to
+const INTERLEAVE_SUFFIX: &str = ".interleave";
+
+// Files written for each segment
+const SEGMENT_EXTS: &[&str] = &["bwt", "index", "counts", "rank", "docs"];
+
+// A live segment of a directory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SegmentInfo {
+    pub id: u64,
+    pub bwt_len: usize,
+    pub num_lines: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    pub generation: u64,
+    pub next_id: u64,
+    // none until the first segment is added
+    pub has_docs: Option<bool>,
+    pub segments: Vec<SegmentInfo>,
+}
+
+impl Manifest {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(
+            MANIFEST_HEADER_SIZE + self.segments.len() * MANIFEST_ENTRY_SIZE + 4,
+        );
+        data.extend_from_slice(MANIFEST_MAGIC);
+        data.extend_from_slice(&MANIFEST_VERSION.to_le_bytes());
+        data.push(match self.has_docs {
+            Some(true) => HAS_DOCS,
+            _ => 0,
+        });
+        data.push(0);
+        data.extend_from_slice(&self.generation.to_le_bytes());
+        data.extend_from_slice(&self.next_id.to_le_bytes());
+        data.extend_from_slice(&(self.segments.len() as u64).to_le_bytes());
+        for segment in self.segments.iter() {
+            data.extend_from_slice(&segment.id.to_le_bytes());
+            data.extend_from_slice(&(segment.bwt_len as u64).to_le_bytes());
+            data.extend_from_slice(&(segment.num_lines as u64).to_le_bytes());
+        }
+        data.extend_from_slice(&crc32fast::hash(&data).to_le_bytes());
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Manifest> {
+        if data.len() < MANIFEST_HEADER_SIZE + 4 || &data[..4] != MANIFEST_MAGIC {
+            return Err(anyhow!("Not a segment directory manifest"));
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version != MANIFEST_VERSION {
+            return Err(anyhow!("Unsupported manifest version {}", version));
+        }
+        let expected = u32::from_le_bytes(data[data.len() - 4..].try_into().unwrap());
+        let actual = crc32fast::hash(&data[..data.len() - 4]);
+        if expected != actual {
+            return Err(anyhow!(
+                "Manifest checksum is {:08x}, expected {:08x}",
+                actual,
+                expected
+            ));
+        }
+
+        let read_u64 = |pos: usize| u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap());
+        let num_segments = read_u64(24) as usize;
+        let size = MANIFEST_HEADER_SIZE + num_segments * MANIFEST_ENTRY_SIZE + 4;
+        if data.len() != size {
+            return Err(anyhow!(
+                "Manifest has {} bytes, expected {}",
+                data.len(),
+                size
+            ));
+        }
+        let segments = (0..num_segments)
+            .map(|i| {
+                let pos = MANIFEST_HEADER_SIZE + i * MANIFEST_ENTRY_SIZE;
+                SegmentInfo {
+                    id: read_u64(pos),
+                    bwt_len: read_u64(pos + 8) as usize,
+                    num_lines: read_u64(pos + 16) as usize,
+                }
+            })
+            .collect::<Vec<SegmentInfo>>();
+        Ok(Manifest {
+            generation: read_u64(8),
+            next_id: read_u64(16),
+            has_docs: (!segments.is_empty()).then_some(data[6] & HAS_DOCS != 0),
+            segments,
+        })
+    }
+}
+
+// Size-tiered compaction: segments up to base_len bytes are in tier 0, and each
+// tier holds segments up to fan_in times larger than the one below. Once fan_in
+// adjacent segments are in the same tier, they are merged into one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompactionPolicy {
+    pub fan_in: usize,
+    pub base_len: usize,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> CompactionPolicy {
+        CompactionPolicy {
+            fan_in: 4,
+            base_len: 1 << 20,
+        }
+    }
+}
+
+impl CompactionPolicy {
+    pub fn tier(&self, bwt_len: usize) -> usize {
+        let mut tier = 0;
+        let mut limit = self.base_len;
+        while bwt_len > limit {
+            limit = limit.saturating_mul(self.fan_in);
+            tier += 1;
+        }
+        tier
+    }
+
+    // The run of segments to merge next, if any: the leftmost of the runs of
+    // fan_in adjacent segments in the lowest tier that has one
+    pub fn pick(&self, segments: &[SegmentInfo]) -> Option<std::ops::Range<usize>> {
+        if self.fan_in < 2 {
+            return None;
+        }
+        let tiers = segments
+            .iter()
+            .map(|x| self.tier(x.bwt_len))
+            .collect::<Vec<usize>>();
+        let mut best: Option<std::ops::Range<usize>> = None;
+        for start in 0..tiers.len().saturating_sub(self.fan_in - 1) {
+            let end = start + self.fan_in;
+            if tiers[start..end].iter().all(|&x| x == tiers[start])
+                && best.as_ref().is_none_or(|x| tiers[start] < tiers[x.start])
+            {
+                best = Some(start..end);
+            }
+        }
+        best
+    }
+}
+
+pub struct SegmentDir {
+    operator: Operator,
+    path: String,
+    policy: CompactionPolicy,
+    manifest: Manifest,
+}
+
+impl SegmentDir {
+    // Open the directory at path, which starts empty if it has no manifest,
+    // and remove files left behind by unfinished or replaced segments
+    pub async fn open(
+        operator: &Operator,
+        path: &str,
+        policy: CompactionPolicy,
+    ) -> Result<SegmentDir> {
+        if policy.fan_in < 2 {
+            return Err(anyhow!("Compaction fan-in must be at least 2"));
+        }
+        let path = path.trim_end_matches('/').to_string();
+        let names = list_names(operator, &path).await?;
+
+        // the newest manifest that is intact
+        let mut generations = names
+            .iter()
+            .filter_map(|x| x.strip_prefix(MANIFEST_PREFIX)?.parse::<u64>().ok())
+            .collect::<Vec<u64>>();
+        generations.sort_unstable_by(|a, b| b.cmp(a));
+        let mut manifest = None;
+        for generation in generations.iter() {
+            let data = operator
+                .read(manifest_path(&path, *generation).as_str())
+                .await?;
+            if let Ok(saved) = Manifest::decode(&data) {
+                manifest = Some(saved);
+                break;
+            }
+        }
+        let manifest = manifest.unwrap_or(Manifest {
+            generation: 0,
+            next_id: 0,
+            has_docs: None,
+            segments: Vec::new(),
+        });
+
+        let dir = SegmentDir {
+            operator: operator.clone(),
+            path,
+            policy,
+            manifest,
+        };
+        for name in names.iter() {
+            // interleaves kept on disk by merges are only used while the merge runs
+            if name.ends_with('/') {
+                if name.ends_with(&format!("{}/", INTERLEAVE_SUFFIX)) {
+                    dir.operator
+                        .remove_all(format!("{}/{}", dir.path, name).as_str())
+                        .await?;
+                }
+                continue;
+            }
+            let stale = match name.strip_prefix(MANIFEST_PREFIX) {
+                Some(generation) => generation
+                    .parse::<u64>()
+                    .is_ok_and(|x| x != dir.manifest.generation),
+                None => segment_id(name).is_some_and(|id| !dir.is_live(id)),
+            };
+            if stale {
+                dir.operator
+                    .delete(format!("{}/{}", dir.path, name).as_str())
+                    .await?;
+            }
+        }
+        Ok(dir)
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    pub fn segments(&self) -> &[SegmentInfo] {
+        &self.manifest.segments
+    }
+
+    // Path of a segment, without extension, as taken by the disk merge functions
+    pub fn segment_path(&self, id: u64) -> String {
+        format!("{}/{}{:08}", self.path, SEGMENT_PREFIX, id)
+    }
+
+    // Line id in the whole directory of the first line of each segment
+    pub fn line_offsets(&self) -> Vec<usize> {
+        let mut offset = 0;
+        self.manifest
+            .segments
+            .iter()
+            .map(|x| {
+                offset += x.num_lines;
+                offset - x.num_lines
+            })
+            .collect()
+    }
+
+    // Searcher over the live segments, with line ids for the whole directory
+    pub async fn searcher(&self) -> Result<SegmentSearcher> {
+        let paths = self
+            .manifest
+            .segments
+            .iter()
+            .map(|x| self.segment_path(x.id))
+            .collect::<Vec<String>>();
+        let paths = paths.iter().map(|x| x.as_str()).collect::<Vec<&str>>();
+        SegmentSearcher::open(&self.operator, &paths).await
+    }
+
+    fn is_live(&self, id: u64) -> bool {
+        self.manifest.segments.iter().any(|x| x.id == id)
+    }
+
+    // Write the manifest for the next generation, which commits the change
+    async fn commit(&mut self, mut manifest: Manifest) -> Result<()> {
+        manifest.generation = self.manifest.generation + 1;
+        self.operator
+            .write(
+                manifest_path(&self.path, manifest.generation).as_str(),
+                manifest.encode(),
+            )
+            .await?;
+        let old_generation = self.manifest.generation;
+        self.manifest = manifest;
+        // an old manifest left behind is removed on the next open
+        if old_generation > 0 {
+            let _ = self
+                .operator
+                .delete(manifest_path(&self.path, old_generation).as_str())
+                .await;
+        }
+        Ok(())
+    }
+
+    // Add a batch of lines, each ending with a newline, as a new segment.
+    // Either every batch of a directory has document ids, one per line, or none do.
+    pub async fn add_batch(
+        &mut self,
+        text: Vec<u8>,
+        doc_ids: Option<&[u64]>,
+    ) -> Result<SegmentInfo> {
+        if text.last() != Some(&SEPARATOR) {
+            return Err(anyhow!("A batch must be non-empty and end with a newline"));
+        }
+        let has_docs = doc_ids.is_some();
+        if self.manifest.has_docs.is_some_and(|x| x != has_docs) {
+            return Err(anyhow!(
+                "Segments in a directory must all have document ids, or none"
+            ));
+        }
+
+        let mut data =
+            tokio::task::spawn_blocking(move || run_bwt_cancellable(&text, &Cancellation::new()))
+                .await??;
+        let info = SegmentInfo {
+            id: self.manifest.next_id,
+            bwt_len: data.0.len(),
+            num_lines: data.2[SEPARATOR as usize],
+        };
+        if let Some(doc_ids) = doc_ids {
+            if doc_ids.len() != info.num_lines {
+                return Err(anyhow!(
+                    "Batch has {} lines but {} document ids",
+                    info.num_lines,
+                    doc_ids.len()
+                ));
+            }
+        }
+        data.3 = doc_ids.map(|x| x.to_vec());
+
+        let segment_path = self.segment_path(info.id);
+        // merges keep the block settings of their inputs, so these carry through compaction
+        write_segment_compressed(
+            &self.operator,
+            &data,
+            &segment_path,
+            IdEncoding::fixed_for(info.num_lines),
+            BlockCompression::default(),
+        )
+        .await?;
+
+        let mut manifest = self.manifest.clone();
+        manifest.next_id += 1;
+        manifest.has_docs = Some(has_docs);
+        manifest.segments.push(info);
+        self.commit(manifest).await?;
+        Ok(info)
+    }
+
+    // Merge the next run of segments chosen by the compaction policy, if any
+    pub async fn compact_once(&mut self) -> Result<Option<MergeStats>> {
+        let Some(range) = self.policy.pick(&self.manifest.segments) else {
+            return Ok(None);
+        };
+        let inputs = self.manifest.segments[range.clone()].to_vec();
+        let input_paths = inputs
+            .iter()
+            .map(|x| self.segment_path(x.id))
+            .collect::<Vec<String>>();
+        let input_paths = input_paths
+            .iter()
+            .map(|x| x.as_str())
+            .collect::<Vec<&str>>();
+
+        let info = SegmentInfo {
+            id: self.manifest.next_id,
+            bwt_len: inputs.iter().map(|x| x.bwt_len).sum(),
+            num_lines: inputs.iter().map(|x| x.num_lines).sum(),
+        };
+        let output_path = self.segment_path(info.id);
+        let stats = match input_paths[..] {
+            [bwt0_path, bwt1_path] => {
+                bwt_merge_disk(&self.operator, bwt0_path, bwt1_path, &output_path).await?
+            }
+            _ => bwt_merge_disk_many(&self.operator, &input_paths, &output_path).await?,
+        };
+
+        let mut manifest = self.manifest.clone();
+        manifest.next_id += 1;
+        manifest.segments.splice(range, [info]);
+        self.commit(manifest).await?;
+
+        // the inputs are no longer live, so if this fails they are removed on the next open
+        for input_path in input_paths.iter() {
+            for ext in SEGMENT_EXTS.iter() {
+                let _ = self
+                    .operator
+                    .delete(format!("{}.{}", input_path, ext).as_str())
+                    .await;
+            }
+        }
+        Ok(Some(stats))
+    }
+
+    // Merge segments until the compaction policy has nothing left to merge
+    pub async fn compact(&mut self) -> Result<Vec<MergeStats>> {
+        let mut all_stats = Vec::new();
+        while let Some(stats) = self.compact_once().await? {
+            all_stats.push(stats);
+        }
+        Ok(all_stats)
+    }
+}
+
+fn manifest_path(dir: &str, generation: u64) -> String {
+    format!("{}/{}{:020}", dir, MANIFEST_PREFIX, generation)
+}
+
+// Id of the segment a file belongs to, from a name like seg-00000001.bwt
+fn segment_id(name: &str) -> Option<u64> {
+    let (stem, _) = name.strip_prefix(SEGMENT_PREFIX)?.split_once('.')?;
+    stem.parse().ok()
+}
+
+// Names of the files and subdirectories in a directory, which may not exist yet.
+// Subdirectories end with a slash.
+async fn list_names(operator: &Operator, dir: &str) -> Result<Vec<String>> {
+    let dir = format!("{}/", dir);
+    match operator.list(dir.as_str()).await {
+        Ok(entries) => Ok(entries
+            .iter()
+            .filter(|x| x.path() != dir)
+            .map(|x| x.name().to_string())
+            .collect()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -1,15 +1,36 @@
 use bwt_merge::bwt::{bwt_merge, fm_index, get_matching_lines, run_bwt};
 use clap::Parser;
+use std::collections::HashMap;
 use std::io::{self, BufRead, Read, Write};
 use std::path::PathBuf;
 use std::time::Instant;
 
 #[derive(Parser)]
 struct Cli {
-    /// Mode to generate several BWT files
+    /// Mode to generate several BWT files, sampling the input file into data/tests
     #[arg(short, long)]
     generate: bool,
 
+    /// Sample the input file at several sizes into a directory, writing the
+    /// segments of two parts of each sample and the full sampled text
+    #[arg(long, value_name = "DIR")]
+    sample: Option<String>,
+
+    /// Numbers of lines in each part of a sample, comma-separated
+    /// Default: sizes from 1024 lines up to half the input
+    #[arg(long, value_delimiter = ',')]
+    sizes: Vec<usize>,
+
+    /// Fraction of each sample in the first part
+    /// Default: 0.5
+    #[arg(long, default_value_t = 0.5)]
+    split: f64,
+
+    /// Seed for sampling, so samples can be reproduced
+    /// Default: 0
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
     /// Mode to test BWT merge on disk
     #[arg(short, long)]
     test_disk: bool,
@@ -18,6 +39,17 @@ struct Cli {
     #[arg(short, long)]
     rebuild: bool,
 
+    /// Whether to check each disk merge against a rebuild from the sampled text,
+    /// position by position
+    #[arg(long)]
+    verify_rebuild: bool,
+
+    /// With --verify-rebuild, let rotations that are equal up to the end of their
+    /// lines be in any order, as a pairwise merge leaves them, and report such
+    /// reorders separately
+    #[arg(long)]
+    allow_line_end_reorders: bool,
+
     /// Input file
     #[arg(short, long, value_name = "FILE")]
     input_file: Option<PathBuf>,
@@ -41,23 +73,225 @@ struct Cli {
 
     #[arg(long, value_name = "FILE")]
     trie_file: Option<PathBuf>,
+
+    /// Migrate a segment from the text format to the binary format
+    #[arg(long, value_name = "PREFIX")]
+    migrate: Option<String>,
+
+    /// Check the checksums and consistency of a segment
+    #[arg(long, value_name = "PREFIX")]
+    verify: Option<String>,
+
+    /// Copy a segment with its .bwt and .index block-compressed with zstd
+    #[arg(long, value_names = ["PREFIX", "OUTPUT"], num_args = 2)]
+    compress: Option<Vec<String>>,
+
+    /// Append the lines of the input file to a segment
+    #[arg(long, value_name = "PREFIX")]
+    append: Option<String>,
+
+    /// Add the input file as a batch to a segment directory, then compact it
+    #[arg(long, value_name = "DIR")]
+    ingest: Option<String>,
+
+    /// Number of segments merged at once when compacting
+    /// Default: 4
+    #[arg(long, default_value_t = 4)]
+    fan_in: usize,
+
+    /// Size of each read buffer for disk merges, in bytes
+    /// Default: 1 MiB
+    #[arg(long, default_value_t = 1 << 20)]
+    buffer_size: usize,
+
+    /// Number of read buffers per input for disk merges; with more than one,
+    /// the next buffers are read while the current one is processed
+    /// Default: 2
+    #[arg(long, default_value_t = 2)]
+    buffer_count: usize,
+
+    /// Memory budget for disk merges, in bytes; merges that would go over it
+    /// keep their interleave on disk
+    #[arg(long, value_name = "BYTES")]
+    memory_budget: Option<usize>,
+
+    /// Storage service for segments, e.g. fs, memory or s3
+    /// Default: the local filesystem, rooted at the current directory
+    #[arg(long, value_name = "SCHEME")]
+    storage: Option<String>,
+
+    /// Configuration option for the storage service, e.g. bucket=segments
+    #[arg(long, value_name = "KEY=VALUE")]
+    storage_option: Vec<String>,
 }
 
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
 
-    if cli.generate {
-        let input_file = cli.input_file.unwrap();
-        let output_path = "./data/tests";
-        bwt_merge::bwt_disk::generate_test_files(input_file.to_str().unwrap(), output_path);
+    let operator = match cli.storage {
+        Some(scheme) => {
+            let mut options = HashMap::new();
+            for option in cli.storage_option.iter() {
+                let (key, value) = option
+                    .split_once('=')
+                    .expect("Storage options must be KEY=VALUE");
+                options.insert(key.to_string(), value.to_string());
+            }
+            bwt_merge::bwt_disk::operator_from_config(&scheme, options).unwrap()
+        }
+        None => bwt_merge::bwt_disk::fs_operator(".").unwrap(),
+    };
+
+    let sample_dir = match cli.generate {
+        true => Some("data/tests".to_string()),
+        false => cli.sample,
+    };
+    if let Some(output_dir) = sample_dir {
+        let input_file = cli.input_file.expect("Sampling needs an input file");
+        let mut config = bwt_merge::sample::SampleConfig {
+            split: cli.split,
+            seed: cli.seed,
+            ..Default::default()
+        };
+        if !cli.sizes.is_empty() {
+            config.sizes = cli.sizes;
+        }
+        let start = Instant::now();
+        let samples = bwt_merge::sample::sample_dataset(
+            &operator,
+            input_file.to_str().unwrap(),
+            &output_dir,
+            &config,
+        )
+        .await
+        .unwrap();
+        for sample in samples.iter() {
+            println!(
+                "sampled size {}: {} + {} lines",
+                sample.size, sample.num_lines[0], sample.num_lines[1]
+            );
+        }
+        println!("time to sample: {:?}", start.elapsed());
+        return;
+    }
+
+    if let Some(segment_path) = cli.migrate {
+        if bwt_merge::bwt_disk::migrate_text_segment(&operator, &segment_path)
+            .await
+            .unwrap()
+        {
+            println!("migrated {}", segment_path);
+        } else {
+            println!("{} is already in the binary format", segment_path);
+        }
+        return;
+    }
+
+    if let Some(segment_path) = cli.verify {
+        let problems = bwt_merge::bwt_disk::verify_segment(&operator, &segment_path)
+            .await
+            .unwrap();
+        if problems.is_empty() {
+            println!("{} is ok", segment_path);
+            return;
+        }
+        for problem in problems.iter() {
+            println!("{}: {}", segment_path, problem);
+        }
+        std::process::exit(1);
+    }
+
+    if let Some(paths) = cli.compress {
+        bwt_merge::bwt_disk::compress_segment(
+            &operator,
+            &paths[0],
+            &paths[1],
+            Some(Default::default()),
+        )
+        .await
+        .unwrap();
+        for ext in ["bwt", "index"] {
+            let mut sizes = Vec::new();
+            for path in paths.iter() {
+                let path = format!("{}.{}", path, ext);
+                sizes.push(operator.stat(&path).await.unwrap().content_length());
+            }
+            println!("{} size: {} -> {}", ext, sizes[0], sizes[1]);
+        }
+        return;
+    }
+
+    if let Some(segment_path) = cli.append {
+        let input_file = cli.input_file.expect("Appending needs an input file");
+        let text = std::fs::read(input_file).unwrap();
+        let lines = text
+            .split(|&x| x == b'\n')
+            .map(|x| x.to_vec())
+            .collect::<Vec<Vec<u8>>>();
+        // a trailing newline doesn't start another line
+        let num_lines = lines.len() - text.ends_with(b"\n") as usize;
+        let start = Instant::now();
+        bwt_merge::bwt_disk::append_lines(&operator, &segment_path, &lines[..num_lines])
+            .await
+            .unwrap();
+        println!(
+            "appended {} lines to {} in {:?}",
+            num_lines,
+            segment_path,
+            start.elapsed()
+        );
+        return;
+    }
+
+    if let Some(dir_path) = cli.ingest {
+        let input_file = cli.input_file.expect("Ingesting needs an input file");
+        let text = std::fs::read(input_file).unwrap();
+        let policy = bwt_merge::lsm::CompactionPolicy {
+            fan_in: cli.fan_in,
+            ..Default::default()
+        };
+        let mut dir = bwt_merge::lsm::SegmentDir::open(&operator, &dir_path, policy)
+            .await
+            .unwrap();
+        let segment = dir.add_batch(text, None).await.unwrap();
+        println!(
+            "added segment {} with {} lines",
+            segment.id, segment.num_lines
+        );
+        let merges = dir.compact().await.unwrap();
+        println!(
+            "ran {} merges, {} segments remain",
+            merges.len(),
+            dir.segments().len()
+        );
         return;
     }
 
     if cli.test_disk {
-        let input_path = "./data/tests";
-        let output_path = "./data/test_out_new";
-        bwt_merge::bwt_disk::test_merge_disk(input_path, output_path, cli.rebuild).await;
+        let input_path = "data/tests";
+        let output_path = "data/test_out_new";
+        let options = bwt_merge::bwt_disk::MergeOptions {
+            read: bwt_merge::stream::ReadOptions {
+                buffer_size: cli.buffer_size,
+                buffer_count: cli.buffer_count,
+            },
+            memory_budget: cli.memory_budget,
+            ..Default::default()
+        };
+        bwt_merge::bwt_disk::test_merge_disk(
+            &operator,
+            input_path,
+            output_path,
+            cli.rebuild,
+            cli.verify_rebuild
+                .then_some(match cli.allow_line_end_reorders {
+                    true => bwt_merge::bwt_disk::VerifyMode::AllowLineEndReorders,
+                    false => bwt_merge::bwt_disk::VerifyMode::Exact,
+                }),
+            options,
+        )
+        .await;
         return;
     }
 
@@ -97,9 +331,8 @@ async fn main() {
             println!("trie build time: {:?}", trie_duration);
         }
 
-        if cli.query.is_some() {
+        if let Some(query_str) = cli.query {
             // try a query
-            let query_str = cli.query.unwrap();
             let query = query_str.as_bytes().to_vec();
             let res = trie.query(&query);
             println!("trie res: {:?}", res);
@@ -107,7 +340,9 @@ async fn main() {
 
         if cli.compare_zstd {
             // serialize trie
+            let start_time = Instant::now();
             let serialized = bincode::serialize(&trie).unwrap();
+            println!("trie serialize time: {:?}", start_time.elapsed());
 
             // compress
             println!("original trie size: {}", serialized.len());
@@ -115,8 +350,23 @@ async fn main() {
             encoder.write_all(&serialized).unwrap();
             let compressed = encoder.finish().unwrap();
             println!("compressed size: {}", compressed.len());
+            let louds = bwt_merge::louds::LoudsTrie::from(&trie);
+            let louds_size = bincode::serialize(&louds).unwrap().len();
+            println!(
+                "louds trie size: {} ({} nodes, {} serialized bytes without postings)",
+                louds_size,
+                louds.num_nodes(),
+                louds.structure_size()
+            );
             std::fs::write("data/trie.zstd", compressed).unwrap();
 
+            // same trie as an arena
+            let arena = bwt_merge::trie::ArenaTrie::from(&trie);
+            let start_time = Instant::now();
+            let arena_serialized = bincode::serialize(&arena).unwrap();
+            println!("arena trie serialize time: {:?}", start_time.elapsed());
+            println!("arena trie size: {}", arena_serialized.len());
+
             // find compressed size of bwt
             let mut bwt_manual = input_lines.join(&b'\n');
             bwt_manual.push(b'\n');
@@ -0,0 +1,120 @@
+// Segments on the local filesystem, mapped into memory. The .bwt and .index are
+// used as slices, so merges and queries skip opendal's async reads for every chunk;
+// merges stream them with ByteStream::mapped, whose chunks are slices of the map.
+// Only uncompressed segments of an fs operator can be mapped; everything else
+// falls back to reading through the operator.
+
+use std::fs::File;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use memmap2::Mmap;
+use opendal::{Operator, Scheme};
+
+use crate::segment::{decode_index_header, IdDecoder, IdEncoding, SegmentMeta, INDEX_HEADER_SIZE};
+
+// Path of a file on the local filesystem, if the operator is an fs operator
+pub fn local_path(operator: &Operator, path: &str) -> Option<PathBuf> {
+    let info = operator.info();
+    if info.scheme() != Scheme::Fs {
+        return None;
+    }
+    Some(PathBuf::from(info.root()).join(path.trim_start_matches('/')))
+}
+
+// Map a whole file of an fs operator into memory, or None for other operators
+pub fn map_file(operator: &Operator, path: &str) -> Result<Option<Mmap>> {
+    let Some(local_path) = local_path(operator, path) else {
+        return Ok(None);
+    };
+    let file = File::open(&local_path).map_err(|e| anyhow!("{}: {}", path, e))?;
+    // outputs are written under temporary names and renamed over the old files once
+    // committed (see PartialOutput in bwt_disk.rs), so a segment file is replaced
+    // rather than modified, and the mapping keeps the contents it was opened with
+    let map = unsafe { Mmap::map(&file) }.map_err(|e| anyhow!("{}: {}", path, e))?;
+    Ok(Some(map))
+}
+
+pub struct MappedSegment {
+    bwt: Mmap,
+    index: Mmap,
+    encoding: IdEncoding,
+}
+
+impl MappedSegment {
+    // Map the .bwt and .index of a segment described by meta.
+    // Returns None if the operator isn't an fs operator or the segment is compressed.
+    pub fn open(
+        operator: &Operator,
+        bwt_path: &str,
+        meta: &SegmentMeta,
+    ) -> Result<Option<MappedSegment>> {
+        if meta.compressed {
+            return Ok(None);
+        }
+        let Some(bwt) = map_file(operator, format!("{}.bwt", bwt_path).as_str())? else {
+            return Ok(None);
+        };
+        let index = map_file(operator, format!("{}.index", bwt_path).as_str())?
+            .ok_or_else(|| anyhow!("{}: .index can't be mapped", bwt_path))?;
+        if bwt.len() != meta.bwt_len {
+            return Err(anyhow!(
+                "{}: .bwt has {} bytes, the counts say {}",
+                bwt_path,
+                bwt.len(),
+                meta.bwt_len
+            ));
+        }
+        let header = &index[..INDEX_HEADER_SIZE.min(index.len())];
+        let encoding = decode_index_header(header).map_err(|e| anyhow!("{}: {}", bwt_path, e))?;
+        Ok(Some(MappedSegment {
+            bwt,
+            index,
+            encoding,
+        }))
+    }
+
+    pub fn bwt(&self) -> &[u8] {
+        &self.bwt
+    }
+
+    // Encoded line ids, after the header
+    pub fn index(&self) -> &[u8] {
+        &self.index[INDEX_HEADER_SIZE..]
+    }
+
+    pub fn encoding(&self) -> IdEncoding {
+        self.encoding
+    }
+
+    // Line ids at BWT positions start..end. Fixed-width ids are read directly,
+    // varints by decoding from the start of the index.
+    pub fn line_ids(&self, start: usize, end: usize) -> Result<Vec<usize>> {
+        let mut ids = Vec::with_capacity(end - start);
+        match self.encoding {
+            IdEncoding::Fixed(width) => {
+                let width = width as usize;
+                let data = self
+                    .index()
+                    .get(start * width..end * width)
+                    .ok_or_else(|| anyhow!("Line index is too short"))?;
+                IdDecoder::new(self.encoding).decode(data, &mut ids)?;
+            }
+            IdEncoding::Varint => {
+                let mut decoder = IdDecoder::new(self.encoding);
+                let mut all = Vec::with_capacity(end);
+                for chunk in self.index().chunks(1 << 16) {
+                    if all.len() >= end {
+                        break;
+                    }
+                    decoder.decode(chunk, &mut all)?;
+                }
+                if all.len() < end {
+                    return Err(anyhow!("Line index is too short"));
+                }
+                ids.extend_from_slice(&all[start..end]);
+            }
+        }
+        Ok(ids)
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use tokio_util::sync::CancellationToken;
+
+// Positions processed between progress callbacks
+pub const PROGRESS_INTERVAL: usize = 1 << 20;
+
+// Phase of a merge, reported to observers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergePhase {
+    // computing the interleave, with the current iteration number
+    Interleave(usize),
+    // writing the merged bwt and line index
+    Output,
+}
+
+// Statistics collected during a merge
+#[derive(Clone, Debug, Default)]
+pub struct MergeStats {
+    pub interleave_iterations: usize,
+    // bytes read from and written to storage, zero for in-memory merges
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub interleave_time: Duration,
+    pub output_time: Duration,
+    // estimate of the largest amount of memory used by the merge itself, in bytes
+    pub peak_memory: usize,
+}
+
+// Receives progress updates during a merge.
+// Returning false from a callback stops the merge with MergeStopped.
+pub trait MergeObserver: Send {
+    // Called every PROGRESS_INTERVAL positions within a pass. The end of an interleave
+    // pass is reported by on_iteration instead.
+    fn on_progress(&mut self, _phase: MergePhase, _done: usize, _total: usize) -> bool {
+        true
+    }
+
+    // Called after each interleave iteration
+    fn on_iteration(&mut self, _stats: &MergeStats) -> bool {
+        true
+    }
+}
+
+impl<T: MergeObserver + ?Sized> MergeObserver for &mut T {
+    fn on_progress(&mut self, phase: MergePhase, done: usize, total: usize) -> bool {
+        (**self).on_progress(phase, done, total)
+    }
+
+    fn on_iteration(&mut self, stats: &MergeStats) -> bool {
+        (**self).on_iteration(stats)
+    }
+}
+
+// Observer that ignores all updates
+pub struct NoopObserver;
+
+impl MergeObserver for NoopObserver {}
+
+// Cancellation token and deadline for long-running merges and builds.
+// Merges take it as an observer, or wrapped around another observer with observe.
+#[derive(Clone, Debug, Default)]
+pub struct Cancellation {
+    token: Option<CancellationToken>,
+    deadline: Option<Instant>,
+}
+
+impl Cancellation {
+    pub fn new() -> Cancellation {
+        Cancellation::default()
+    }
+
+    pub fn with_token(mut self, token: CancellationToken) -> Cancellation {
+        self.token = Some(token);
+        self
+    }
+
+    pub fn with_deadline(mut self, deadline: Instant) -> Cancellation {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.as_ref().is_some_and(|x| x.is_cancelled())
+            || self.deadline.is_some_and(|x| Instant::now() >= x)
+    }
+
+    // Ok if the work should continue
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            return Err(Cancelled);
+        }
+        Ok(())
+    }
+
+    // Wrap an observer, so the merge stops when either of them says so
+    pub fn observe<O: MergeObserver>(self, inner: O) -> CancellableObserver<O> {
+        CancellableObserver {
+            cancel: self,
+            inner,
+        }
+    }
+}
+
+impl MergeObserver for Cancellation {
+    fn on_progress(&mut self, _phase: MergePhase, _done: usize, _total: usize) -> bool {
+        !self.is_cancelled()
+    }
+
+    fn on_iteration(&mut self, _stats: &MergeStats) -> bool {
+        !self.is_cancelled()
+    }
+}
+
+// Observer that also stops when its Cancellation is cancelled
+pub struct CancellableObserver<O: MergeObserver> {
+    cancel: Cancellation,
+    inner: O,
+}
+
+impl<O: MergeObserver> MergeObserver for CancellableObserver<O> {
+    fn on_progress(&mut self, phase: MergePhase, done: usize, total: usize) -> bool {
+        !self.cancel.is_cancelled() && self.inner.on_progress(phase, done, total)
+    }
+
+    fn on_iteration(&mut self, stats: &MergeStats) -> bool {
+        !self.cancel.is_cancelled() && self.inner.on_iteration(stats)
+    }
+}
+
+// Error returned when an observer stops a merge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeStopped;
+
+impl fmt::Display for MergeStopped {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "merge stopped by observer")
+    }
+}
+
+impl std::error::Error for MergeStopped {}
+
+// Error returned when a build is cancelled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
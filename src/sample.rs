@@ -0,0 +1,198 @@
+// Sample datasets for testing merges from a large text file, one line per entry.
+// A size is the number of lines in each part, so twice as many lines are sampled.
+// For each size, a uniform sample of lines is taken in a single streaming pass over
+// the input with reservoir sampling, then split at random into two parts by the split
+// ratio, each keeping the order of the input. Written to the output directory:
+// {size}_0 and {size}_1, the segments of the two parts, and {size}_full.txt, the text
+// of the first part then the second, which is what merging the two segments indexes.
+// The same seed always gives the same samples.
+
+use anyhow::{anyhow, Result};
+use opendal::{ErrorKind, Operator};
+use rand::seq::SliceRandom;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::bwt::run_bwt;
+use crate::bwt_disk::save_segment;
+use crate::segment::SEPARATOR;
+use crate::stream::{ByteStream, ReadOptions};
+
+// Sizes sampled by default; usize::MAX splits the whole input in two
+pub const DEFAULT_SIZES: &[usize] = &[
+    1024,
+    4096,
+    16384,
+    65536,
+    262144,
+    1048576,
+    2097152,
+    usize::MAX,
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleConfig {
+    // numbers of lines in each part, each capped at half the lines in the input
+    pub sizes: Vec<usize>,
+    // fraction of the sampled lines in the first part
+    pub split: f64,
+    pub seed: u64,
+}
+
+impl Default for SampleConfig {
+    fn default() -> SampleConfig {
+        SampleConfig {
+            sizes: DEFAULT_SIZES.to_vec(),
+            split: 0.5,
+            seed: 0,
+        }
+    }
+}
+
+// Files written for one size
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SampleInfo {
+    // lines in each part at an even split, half the lines sampled, which names the files
+    pub size: usize,
+    // lines in each part
+    pub num_lines: [usize; 2],
+}
+
+// Uniform sample of up to size lines of the input, in input order.
+// Lines are kept without their separators.
+async fn sample_lines(
+    operator: &Operator,
+    input_path: &str,
+    size: usize,
+    rng: &mut StdRng,
+) -> Result<Vec<Vec<u8>>> {
+    let reader = operator.reader(input_path).await?;
+    let mut stream = ByteStream::prefetching(reader, ReadOptions::default());
+    // line number and contents of each line kept so far
+    let mut reservoir: Vec<(usize, Vec<u8>)> = Vec::new();
+    let mut line = Vec::new();
+    let mut num_lines = 0;
+    let mut offer = |num_lines: usize, line: &mut Vec<u8>| {
+        if reservoir.len() < size {
+            reservoir.push((num_lines, std::mem::take(line)));
+        } else {
+            let slot = rng.gen_range(0..=num_lines);
+            if slot < size {
+                reservoir[slot] = (num_lines, std::mem::take(line));
+            }
+        }
+        line.clear();
+    };
+    loop {
+        let chunk = stream.chunk().await?;
+        if chunk.is_empty() {
+            break;
+        }
+        let len = chunk.len();
+        for part in chunk.split_inclusive(|&x| x == SEPARATOR) {
+            match part.split_last() {
+                Some((&SEPARATOR, text)) => {
+                    line.extend_from_slice(text);
+                    offer(num_lines, &mut line);
+                    num_lines += 1;
+                }
+                _ => line.extend_from_slice(part),
+            }
+        }
+        stream.consume(len);
+    }
+    // the last line may have no separator
+    if !line.is_empty() {
+        offer(num_lines, &mut line);
+    }
+
+    reservoir.sort_by_key(|(i, _)| *i);
+    Ok(reservoir.into_iter().map(|(_, line)| line).collect())
+}
+
+// Build and write the segment of one part of a sample, returning its text
+async fn write_part(operator: &Operator, lines: Vec<&[u8]>, segment_path: &str) -> Result<Vec<u8>> {
+    let mut text = Vec::new();
+    for line in lines.iter() {
+        text.extend_from_slice(line);
+        text.push(SEPARATOR);
+    }
+    let input = text.clone();
+    let data = tokio::task::spawn_blocking(move || run_bwt(&input)).await?;
+    save_segment(operator, &data, segment_path).await?;
+    Ok(text)
+}
+
+// Sample the input file at each size of the config, writing the files to output_dir
+pub async fn sample_dataset(
+    operator: &Operator,
+    input_path: &str,
+    output_dir: &str,
+    config: &SampleConfig,
+) -> Result<Vec<SampleInfo>> {
+    if !(0.0..=1.0).contains(&config.split) {
+        return Err(anyhow!(
+            "Split ratio {} is not between 0 and 1",
+            config.split
+        ));
+    }
+
+    let mut samples = Vec::with_capacity(config.sizes.len());
+    for &size in config.sizes.iter() {
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        let mut lines =
+            sample_lines(operator, input_path, size.saturating_mul(2), &mut rng).await?;
+        // a short input is split in two whole halves
+        let size = size.min(lines.len() / 2);
+        if lines.len() > 2 * size {
+            lines.remove(rng.gen_range(0..lines.len()));
+        }
+        let n = lines.len();
+        let n0 = ((n as f64 * config.split).round() as usize).min(n);
+        if n0 == 0 || n0 == n {
+            return Err(anyhow!(
+                "Splitting {} lines at {} leaves a part empty",
+                n,
+                config.split
+            ));
+        }
+
+        // which part each line goes to, at random
+        let mut order = (0..n).collect::<Vec<usize>>();
+        order.shuffle(&mut rng);
+        let mut in_first = vec![false; n];
+        for &i in order[..n0].iter() {
+            in_first[i] = true;
+        }
+        let mut parts: [Vec<&[u8]>; 2] = [Vec::with_capacity(n0), Vec::with_capacity(n - n0)];
+        for (line, first) in lines.iter().zip(in_first) {
+            parts[if first { 0 } else { 1 }].push(line);
+        }
+
+        let num_lines = [parts[0].len(), parts[1].len()];
+        let mut full = Vec::new();
+        for (i, part) in parts.into_iter().enumerate() {
+            let segment_path = format!("{}/{}_{}", output_dir, size, i);
+            full.extend(write_part(operator, part, &segment_path).await?);
+        }
+        operator
+            .write(format!("{}/{}_full.txt", output_dir, size).as_str(), full)
+            .await?;
+        samples.push(SampleInfo { size, num_lines });
+    }
+    Ok(samples)
+}
+
+// Sizes of the samples in a directory, from the names of their full text files
+pub async fn sample_sizes(operator: &Operator, dir: &str) -> Result<Vec<usize>> {
+    let entries = match operator.list(format!("{}/", dir).as_str()).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut sizes = entries
+        .iter()
+        .filter_map(|x| x.name().strip_suffix("_full.txt")?.parse().ok())
+        .collect::<Vec<usize>>();
+    sizes.sort();
+    Ok(sizes)
+}
@@ -0,0 +1,94 @@
+// Search across several segments on disk, such as the live segments of a directory
+// before compaction has merged them. Each segment is searched with its own FM index,
+// and its line ids are offset by the number of lines in the segments before it, so
+// results use global line ids as if the segments had been merged in order.
+
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use opendal::Operator;
+
+use crate::bwt_disk::DiskFMIndex;
+
+pub struct SegmentSearcher {
+    segments: Vec<DiskFMIndex>,
+    // global line id of the first line of each segment, then the total number of lines
+    line_offsets: Vec<usize>,
+}
+
+impl SegmentSearcher {
+    // Open the segments at the given paths, in line order.
+    // Each needs a .rank file, see write_rank_index.
+    pub async fn open(operator: &Operator, bwt_paths: &[&str]) -> Result<SegmentSearcher> {
+        let mut segments = Vec::with_capacity(bwt_paths.len());
+        let mut line_offsets = Vec::with_capacity(bwt_paths.len() + 1);
+        let mut num_lines = 0;
+        for bwt_path in bwt_paths.iter() {
+            let segment = DiskFMIndex::open(operator, bwt_path).await?;
+            line_offsets.push(num_lines);
+            num_lines += segment.meta().num_lines;
+            segments.push(segment);
+        }
+        line_offsets.push(num_lines);
+        Ok(SegmentSearcher {
+            segments,
+            line_offsets,
+        })
+    }
+
+    pub fn num_segments(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn num_lines(&self) -> usize {
+        self.line_offsets[self.segments.len()]
+    }
+
+    // Segment holding a global line id, and the line id within that segment
+    pub fn locate_line(&self, line: usize) -> Option<(usize, usize)> {
+        if line >= self.num_lines() {
+            return None;
+        }
+        let segment = self.line_offsets.partition_point(|&x| x <= line) - 1;
+        Some((segment, line - self.line_offsets[segment]))
+    }
+
+    // Range of the pattern in the BWT of each segment, as substring_search
+    pub async fn substring_search(&self, pattern: &[u8]) -> Result<Vec<Option<(usize, usize)>>> {
+        let mut ranges = Vec::with_capacity(self.segments.len());
+        for segment in self.segments.iter() {
+            ranges.push(segment.substring_search(pattern).await?);
+        }
+        Ok(ranges)
+    }
+
+    // Number of occurrences of the pattern in all segments
+    pub async fn count(&self, pattern: &[u8]) -> Result<usize> {
+        Ok(self
+            .substring_search(pattern)
+            .await?
+            .iter()
+            .flatten()
+            .map(|(start, end)| end - start)
+            .sum())
+    }
+
+    // Global ids of all matching lines, in order
+    pub async fn matching_lines(&self, pattern: &[u8]) -> Result<BTreeSet<usize>> {
+        let mut lines = BTreeSet::new();
+        for (segment, offset) in self.segments.iter().zip(self.line_offsets.iter()) {
+            let segment_lines = segment.matching_lines(pattern).await?;
+            lines.extend(segment_lines.into_iter().map(|x| x + offset));
+        }
+        Ok(lines)
+    }
+
+    // Document ids of all matching lines, from the .docs file of each segment
+    pub async fn matching_docs(&self, pattern: &[u8]) -> Result<BTreeSet<u64>> {
+        let mut docs = BTreeSet::new();
+        for segment in self.segments.iter() {
+            docs.extend(segment.matching_docs(pattern).await?);
+        }
+        Ok(docs)
+    }
+}
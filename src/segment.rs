@@ -0,0 +1,753 @@
+// Binary on-disk format of a BWT segment.
+// A segment is three files sharing a path prefix:
+// .bwt holds the raw BWT bytes,
+// .index holds a header and then the line id of each BWT position,
+// .counts holds the segment metadata: separator, lengths, character counts and,
+// since version 2, CRC32 checksums of the other two files and of itself, and a flag
+// for segments whose .bwt and .index are block-compressed, see block.rs; their
+// checksums are of the compressed files.
+// An optional .rank sidecar holds rank checkpoints for querying the segment on disk.
+// All integers are little-endian.
+
+use std::fmt;
+use std::io::{self, Write};
+
+use anyhow::{anyhow, Result};
+
+pub const SEGMENT_VERSION: u16 = 2;
+pub const COUNTS_MAGIC: &[u8; 4] = b"BWTC";
+pub const INDEX_MAGIC: &[u8; 4] = b"BWTI";
+pub const RANK_MAGIC: &[u8; 4] = b"BWTR";
+pub const RANK_VERSION: u16 = 1;
+
+// Byte separating lines in the text
+pub const SEPARATOR: u8 = b'\n';
+
+// magic, version, separator, flags (reserved before version 2), bwt length,
+// number of lines, counts
+pub const META_V1_SIZE: usize = 4 + 2 + 1 + 1 + 8 + 8 + 256 * 8;
+// then checksums of the .bwt, the .index, and the bytes before it
+pub const META_SIZE: usize = META_V1_SIZE + 4 + 4 + 4;
+// magic, version, encoding, width
+pub const INDEX_HEADER_SIZE: usize = 4 + 2 + 1 + 1;
+// magic, version, alphabet size, interval, bwt length, number of checkpoints
+const RANK_HEADER_SIZE: usize = 4 + 2 + 2 + 4 + 8 + 8;
+
+// Positions between rank checkpoints in the .rank file
+pub const RANK_INTERVAL: usize = 1 << 14;
+
+// Flag for segments whose .bwt and .index are block-compressed
+const FLAG_COMPRESSED: u8 = 1;
+
+// How line ids are stored in the .index file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdEncoding {
+    // little-endian integers of the given number of bytes, from 1 to 8
+    Fixed(u8),
+    // LEB128 variable-length integers
+    Varint,
+}
+
+impl IdEncoding {
+    // Smallest fixed width that can store line ids up to max_id.
+    // A text without a trailing separator has a last line with the id of the
+    // number of separators, so that count is a safe bound for any segment.
+    pub fn fixed_for(max_id: usize) -> IdEncoding {
+        let bits = 64 - (max_id as u64).leading_zeros();
+        IdEncoding::Fixed(bits.div_ceil(8).max(1) as u8)
+    }
+
+    fn to_bytes(self) -> [u8; 2] {
+        match self {
+            IdEncoding::Fixed(width) => [0, width],
+            IdEncoding::Varint => [1, 0],
+        }
+    }
+
+    fn from_bytes(bytes: [u8; 2]) -> Result<IdEncoding> {
+        match bytes {
+            [0, width @ 1..=8] => Ok(IdEncoding::Fixed(width)),
+            [1, 0] => Ok(IdEncoding::Varint),
+            _ => Err(anyhow!("Invalid line id encoding {:?}", bytes)),
+        }
+    }
+}
+
+// CRC32 checksums of the whole .bwt and .index files
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checksums {
+    pub bwt: u32,
+    pub index: u32,
+}
+
+// Contents of the .counts file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SegmentMeta {
+    pub version: u16,
+    pub separator: u8,
+    pub bwt_len: usize,
+    pub num_lines: usize,
+    pub counts: [usize; 256],
+    // missing in version 1 segments
+    pub checksums: Option<Checksums>,
+    // whether the .bwt and .index are block-compressed, only in version 2 segments
+    pub compressed: bool,
+}
+
+impl SegmentMeta {
+    pub fn new(separator: u8, counts: [usize; 256], checksums: Checksums) -> SegmentMeta {
+        SegmentMeta {
+            version: SEGMENT_VERSION,
+            separator,
+            bwt_len: counts.iter().sum(),
+            num_lines: counts[separator as usize],
+            counts,
+            checksums: Some(checksums),
+            compressed: false,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(META_SIZE);
+        data.extend_from_slice(COUNTS_MAGIC);
+        data.extend_from_slice(&self.version.to_le_bytes());
+        data.push(self.separator);
+        data.push(if self.compressed { FLAG_COMPRESSED } else { 0 });
+        data.extend_from_slice(&(self.bwt_len as u64).to_le_bytes());
+        data.extend_from_slice(&(self.num_lines as u64).to_le_bytes());
+        for count in self.counts.iter() {
+            data.extend_from_slice(&(*count as u64).to_le_bytes());
+        }
+        if self.version >= 2 {
+            let checksums = self.checksums.expect("Version 2 segments have checksums");
+            data.extend_from_slice(&checksums.bwt.to_le_bytes());
+            data.extend_from_slice(&checksums.index.to_le_bytes());
+            data.extend_from_slice(&crc32fast::hash(&data).to_le_bytes());
+        }
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<SegmentMeta> {
+        if is_text_format(data) {
+            return Err(anyhow!(
+                "Counts are in the old text format, migrate the segment first"
+            ));
+        }
+        if data.len() < 6 || &data[..4] != COUNTS_MAGIC {
+            return Err(anyhow!("Not a segment counts file"));
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version > SEGMENT_VERSION {
+            return Err(anyhow!("Unsupported segment version {}", version));
+        }
+        let size = if version >= 2 {
+            META_SIZE
+        } else {
+            META_V1_SIZE
+        };
+        if data.len() != size {
+            return Err(anyhow!(
+                "Counts file has {} bytes, expected {}",
+                data.len(),
+                size
+            ));
+        }
+
+        let read_u32 = |pos: usize| u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+        let read_u64 = |pos: usize| u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap());
+        let mut checksums = None;
+        if version >= 2 {
+            let expected = read_u32(META_SIZE - 4);
+            let actual = crc32fast::hash(&data[..META_SIZE - 4]);
+            if expected != actual {
+                return Err(anyhow!(
+                    "Counts checksum is {:08x}, expected {:08x}",
+                    actual,
+                    expected
+                ));
+            }
+            checksums = Some(Checksums {
+                bwt: read_u32(META_V1_SIZE),
+                index: read_u32(META_V1_SIZE + 4),
+            });
+        }
+
+        let flags = if version >= 2 { data[7] } else { 0 };
+        if flags & !FLAG_COMPRESSED != 0 {
+            return Err(anyhow!("Unknown segment flags {:#04x}", flags));
+        }
+
+        let mut counts = [0; 256];
+        for (i, count) in counts.iter_mut().enumerate() {
+            *count = read_u64(24 + i * 8) as usize;
+        }
+        Ok(SegmentMeta {
+            version,
+            separator: data[6],
+            bwt_len: read_u64(8) as usize,
+            num_lines: read_u64(16) as usize,
+            counts,
+            checksums,
+            compressed: flags & FLAG_COMPRESSED != 0,
+        })
+    }
+}
+
+// Whether a counts or index file is in the old format, one decimal number per line
+pub fn is_text_format(data: &[u8]) -> bool {
+    !data.is_empty() && data.iter().all(|x| x.is_ascii_digit() || *x == b'\n')
+}
+
+pub fn encode_index_header(encoding: IdEncoding) -> [u8; INDEX_HEADER_SIZE] {
+    let mut header = [0; INDEX_HEADER_SIZE];
+    header[..4].copy_from_slice(INDEX_MAGIC);
+    header[4..6].copy_from_slice(&SEGMENT_VERSION.to_le_bytes());
+    header[6..].copy_from_slice(&encoding.to_bytes());
+    header
+}
+
+pub fn decode_index_header(header: &[u8]) -> Result<IdEncoding> {
+    if is_text_format(header) {
+        return Err(anyhow!(
+            "Index is in the old text format, migrate the segment first"
+        ));
+    }
+    if header.len() < INDEX_HEADER_SIZE || &header[..4] != INDEX_MAGIC {
+        return Err(anyhow!("Not a segment index file"));
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version > SEGMENT_VERSION {
+        return Err(anyhow!("Unsupported segment version {}", version));
+    }
+    IdEncoding::from_bytes([header[6], header[7]])
+}
+
+// Writes the index header, then line ids in the given encoding
+pub struct IndexWriter<W: Write> {
+    writer: W,
+    encoding: IdEncoding,
+}
+
+impl<W: Write> IndexWriter<W> {
+    pub fn new(mut writer: W, encoding: IdEncoding) -> io::Result<IndexWriter<W>> {
+        writer.write_all(&encode_index_header(encoding))?;
+        Ok(IndexWriter { writer, encoding })
+    }
+
+    // Continue an index whose header has already been written
+    pub fn append(writer: W, encoding: IdEncoding) -> IndexWriter<W> {
+        IndexWriter { writer, encoding }
+    }
+
+    pub fn write_id(&mut self, id: usize) -> io::Result<()> {
+        let id = id as u64;
+        match self.encoding {
+            IdEncoding::Fixed(width) => {
+                let width = width as usize;
+                if width < 8 && id >> (width * 8) != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Line id {} does not fit in {} bytes", id, width),
+                    ));
+                }
+                self.writer.write_all(&id.to_le_bytes()[..width])
+            }
+            IdEncoding::Varint => {
+                let mut buf = [0u8; 10];
+                let mut len = 0;
+                let mut id = id;
+                loop {
+                    let byte = (id & 0x7f) as u8;
+                    id >>= 7;
+                    if id == 0 {
+                        buf[len] = byte;
+                        len += 1;
+                        break;
+                    }
+                    buf[len] = byte | 0x80;
+                    len += 1;
+                }
+                self.writer.write_all(&buf[..len])
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+// Decodes line ids from the body of an index file, fed in chunks of any size.
+// An id split across two chunks is carried over to the next call.
+pub struct IdDecoder {
+    encoding: IdEncoding,
+    partial: Vec<u8>,
+    value: u64,
+    shift: u32,
+}
+
+impl IdDecoder {
+    pub fn new(encoding: IdEncoding) -> IdDecoder {
+        IdDecoder {
+            encoding,
+            partial: Vec::new(),
+            value: 0,
+            shift: 0,
+        }
+    }
+
+    pub fn decode(&mut self, data: &[u8], ids: &mut Vec<usize>) -> Result<()> {
+        match self.encoding {
+            IdEncoding::Fixed(width) => {
+                let width = width as usize;
+                let mut data = data;
+                if !self.partial.is_empty() {
+                    let needed = (width - self.partial.len()).min(data.len());
+                    self.partial.extend_from_slice(&data[..needed]);
+                    data = &data[needed..];
+                    if self.partial.len() < width {
+                        return Ok(());
+                    }
+                    ids.push(fixed_id(&self.partial));
+                    self.partial.clear();
+                }
+
+                let mut chunks = data.chunks_exact(width);
+                ids.extend(chunks.by_ref().map(fixed_id));
+                self.partial.extend_from_slice(chunks.remainder());
+            }
+            IdEncoding::Varint => {
+                for &byte in data.iter() {
+                    if self.shift >= 64 {
+                        return Err(anyhow!("Line id varint is too long"));
+                    }
+                    self.value |= ((byte & 0x7f) as u64) << self.shift;
+                    if byte & 0x80 == 0 {
+                        ids.push(self.value as usize);
+                        self.value = 0;
+                        self.shift = 0;
+                    } else {
+                        self.shift += 7;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Whether an id was cut off at the end of the data so far
+    pub fn has_partial(&self) -> bool {
+        !self.partial.is_empty() || self.shift > 0
+    }
+}
+
+fn fixed_id(bytes: &[u8]) -> usize {
+    let mut buf = [0u8; 8];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    u64::from_le_bytes(buf) as usize
+}
+
+// Rank checkpoints of a BWT: the number of occurrences of each character before every
+// interval-th position, so that ranks can be computed by reading at most one interval
+// of the BWT. Only characters that occur in the BWT are stored.
+// The .rank file holds the header, the alphabet, the total count of each of its
+// characters, the checkpoints, and a CRC32 of the bytes before it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RankIndex {
+    interval: usize,
+    bwt_len: usize,
+    alphabet: Vec<u8>,
+    // position of each character in the alphabet, or u16::MAX if it doesn't occur
+    slots: [u16; 256],
+    // number of characters smaller than each character, as in the FM index C array
+    starts: [usize; 256],
+    // checkpoint k holds ranks[k * alphabet.len()..(k + 1) * alphabet.len()]
+    ranks: Vec<u64>,
+}
+
+impl RankIndex {
+    fn new(interval: usize, counts: &[usize; 256]) -> RankIndex {
+        let alphabet = (0..=255u8)
+            .filter(|&chr| counts[chr as usize] > 0)
+            .collect::<Vec<u8>>();
+        let mut slots = [u16::MAX; 256];
+        for (slot, &chr) in alphabet.iter().enumerate() {
+            slots[chr as usize] = slot as u16;
+        }
+        let mut starts = [0; 256];
+        let mut sum = 0;
+        for (start, count) in starts.iter_mut().zip(counts.iter()) {
+            *start = sum;
+            sum += count;
+        }
+        RankIndex {
+            interval,
+            bwt_len: sum,
+            alphabet,
+            slots,
+            starts,
+            ranks: Vec::new(),
+        }
+    }
+
+    pub fn interval(&self) -> usize {
+        self.interval
+    }
+
+    pub fn bwt_len(&self) -> usize {
+        self.bwt_len
+    }
+
+    // Number of BWT characters smaller than chr
+    pub fn start(&self, chr: u8) -> usize {
+        self.starts[chr as usize]
+    }
+
+    // Occurrences of chr before the checkpoint at or before pos,
+    // or none if chr doesn't occur in the BWT
+    pub fn checkpoint_rank(&self, pos: usize, chr: u8) -> Option<usize> {
+        let slot = self.slots[chr as usize];
+        if slot == u16::MAX {
+            return None;
+        }
+        let checkpoint = pos / self.interval;
+        Some(self.ranks[checkpoint * self.alphabet.len() + slot as usize] as usize)
+    }
+
+    // Bytes of memory used by the checkpoints
+    pub fn memory_size(&self) -> usize {
+        std::mem::size_of::<RankIndex>() + self.alphabet.len() + self.ranks.len() * 8
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let num_checkpoints = self.bwt_len / self.interval + 1;
+        let mut data = Vec::with_capacity(
+            RANK_HEADER_SIZE + self.alphabet.len() * 9 + self.ranks.len() * 8 + 4,
+        );
+        data.extend_from_slice(RANK_MAGIC);
+        data.extend_from_slice(&RANK_VERSION.to_le_bytes());
+        data.extend_from_slice(&(self.alphabet.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(self.interval as u32).to_le_bytes());
+        data.extend_from_slice(&(self.bwt_len as u64).to_le_bytes());
+        data.extend_from_slice(&(num_checkpoints as u64).to_le_bytes());
+        data.extend_from_slice(&self.alphabet);
+        let mut counts = [0; 256];
+        for (chr, count) in counts.iter_mut().enumerate() {
+            let next = self.starts.get(chr + 1).copied().unwrap_or(self.bwt_len);
+            *count = next - self.starts[chr];
+        }
+        for &chr in self.alphabet.iter() {
+            data.extend_from_slice(&(counts[chr as usize] as u64).to_le_bytes());
+        }
+        for rank in self.ranks.iter() {
+            data.extend_from_slice(&rank.to_le_bytes());
+        }
+        data.extend_from_slice(&crc32fast::hash(&data).to_le_bytes());
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<RankIndex> {
+        if data.len() < RANK_HEADER_SIZE + 4 || &data[..4] != RANK_MAGIC {
+            return Err(anyhow!("Not a segment rank file"));
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version != RANK_VERSION {
+            return Err(anyhow!("Unsupported rank file version {}", version));
+        }
+        let expected = u32::from_le_bytes(data[data.len() - 4..].try_into().unwrap());
+        let actual = crc32fast::hash(&data[..data.len() - 4]);
+        if expected != actual {
+            return Err(anyhow!(
+                "Rank file checksum is {:08x}, expected {:08x}",
+                actual,
+                expected
+            ));
+        }
+
+        let read_u64 = |pos: usize| u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap());
+        let alphabet_len = u16::from_le_bytes([data[6], data[7]]) as usize;
+        let interval = u32::from_le_bytes(data[8..12].try_into().unwrap()) as usize;
+        let bwt_len = read_u64(12) as usize;
+        let num_checkpoints = read_u64(20) as usize;
+        let size = RANK_HEADER_SIZE + alphabet_len * 9 + num_checkpoints * alphabet_len * 8 + 4;
+        if interval == 0 || alphabet_len > 256 || data.len() != size {
+            return Err(anyhow!(
+                "Rank file has {} bytes, expected {}",
+                data.len(),
+                size
+            ));
+        }
+        if num_checkpoints != bwt_len / interval + 1 {
+            return Err(anyhow!(
+                "Rank file has {} checkpoints, expected {}",
+                num_checkpoints,
+                bwt_len / interval + 1
+            ));
+        }
+
+        let alphabet = &data[RANK_HEADER_SIZE..RANK_HEADER_SIZE + alphabet_len];
+        let mut counts = [0; 256];
+        let counts_start = RANK_HEADER_SIZE + alphabet_len;
+        for (i, &chr) in alphabet.iter().enumerate() {
+            counts[chr as usize] = read_u64(counts_start + i * 8) as usize;
+        }
+        let mut index = RankIndex::new(interval, &counts);
+        if index.alphabet != alphabet || index.bwt_len != bwt_len {
+            return Err(anyhow!("Rank file alphabet does not match its counts"));
+        }
+        let ranks_start = counts_start + alphabet_len * 8;
+        index.ranks = (0..num_checkpoints * alphabet_len)
+            .map(|i| read_u64(ranks_start + i * 8))
+            .collect();
+        Ok(index)
+    }
+}
+
+// Builds the rank checkpoints of a BWT from its characters, given in order
+pub struct RankBuilder {
+    index: RankIndex,
+    counts: [u64; 256],
+    pos: usize,
+}
+
+impl RankBuilder {
+    // counts are the character counts of the whole BWT
+    pub fn new(counts: &[usize; 256]) -> RankBuilder {
+        RankBuilder::with_interval(counts, RANK_INTERVAL)
+    }
+
+    pub fn with_interval(counts: &[usize; 256], interval: usize) -> RankBuilder {
+        assert!(interval > 0 && interval <= u32::MAX as usize);
+        RankBuilder {
+            index: RankIndex::new(interval, counts),
+            counts: [0; 256],
+            pos: 0,
+        }
+    }
+
+    fn save_checkpoint(&mut self) {
+        for &chr in self.index.alphabet.iter() {
+            self.index.ranks.push(self.counts[chr as usize]);
+        }
+    }
+
+    pub fn push(&mut self, chr: u8) {
+        if self.pos.is_multiple_of(self.index.interval) {
+            self.save_checkpoint();
+        }
+        self.counts[chr as usize] += 1;
+        self.pos += 1;
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        for &chr in data.iter() {
+            self.push(chr);
+        }
+    }
+
+    // Finish the checkpoints, checking that the characters matched the counts
+    pub fn finish(mut self) -> Result<RankIndex> {
+        if self.pos.is_multiple_of(self.index.interval) {
+            self.save_checkpoint();
+        }
+        let mismatch = (0..256).any(|chr| {
+            let next = self.index.starts.get(chr + 1).copied();
+            let count = next.unwrap_or(self.index.bwt_len) - self.index.starts[chr];
+            self.counts[chr] != count as u64
+        });
+        if self.pos != self.index.bwt_len || mismatch {
+            return Err(anyhow!("BWT characters do not match its counts"));
+        }
+        Ok(self.index)
+    }
+}
+
+// Inconsistency found when verifying a segment
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SegmentProblem {
+    BwtChecksum {
+        expected: u32,
+        actual: u32,
+    },
+    IndexChecksum {
+        expected: u32,
+        actual: u32,
+    },
+    // the lengths in the counts don't add up
+    CountsSum {
+        sum: usize,
+        bwt_len: usize,
+    },
+    NumLines {
+        num_lines: usize,
+        separators: usize,
+    },
+    BwtLength {
+        expected: usize,
+        actual: usize,
+    },
+    CharCount {
+        chr: u8,
+        expected: usize,
+        actual: usize,
+    },
+    IndexLength {
+        expected: usize,
+        actual: usize,
+    },
+    TruncatedLineId,
+    LineIdOutOfRange {
+        position: usize,
+        line_id: usize,
+        num_lines: usize,
+    },
+    DocIdsLength {
+        expected: usize,
+        actual: usize,
+    },
+    // the .rank sidecar is damaged or doesn't match the .bwt
+    RankMismatch,
+    // a block-compressed .bwt or .index can't be decompressed
+    Undecodable {
+        file: &'static str,
+        error: String,
+    },
+}
+
+impl fmt::Display for SegmentProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SegmentProblem::BwtChecksum { expected, actual } => write!(
+                f,
+                ".bwt checksum is {:08x}, expected {:08x}",
+                actual, expected
+            ),
+            SegmentProblem::IndexChecksum { expected, actual } => write!(
+                f,
+                ".index checksum is {:08x}, expected {:08x}",
+                actual, expected
+            ),
+            SegmentProblem::CountsSum { sum, bwt_len } => write!(
+                f,
+                "character counts sum to {}, but the BWT length is {}",
+                sum, bwt_len
+            ),
+            SegmentProblem::NumLines {
+                num_lines,
+                separators,
+            } => write!(
+                f,
+                "segment has {} lines, but {} separators",
+                num_lines, separators
+            ),
+            SegmentProblem::BwtLength { expected, actual } => {
+                write!(f, ".bwt has {} bytes, expected {}", actual, expected)
+            }
+            SegmentProblem::CharCount {
+                chr,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "byte {:#04x} occurs {} times in the .bwt, expected {}",
+                chr, actual, expected
+            ),
+            SegmentProblem::IndexLength { expected, actual } => {
+                write!(f, ".index has {} line ids, expected {}", actual, expected)
+            }
+            SegmentProblem::TruncatedLineId => {
+                write!(f, ".index ends in the middle of a line id")
+            }
+            SegmentProblem::LineIdOutOfRange {
+                position,
+                line_id,
+                num_lines,
+            } => write!(
+                f,
+                "line id {} at position {} is out of range for {} lines",
+                line_id, position, num_lines
+            ),
+            SegmentProblem::DocIdsLength { expected, actual } => {
+                write!(f, ".docs has {} ids, expected {}", actual, expected)
+            }
+            SegmentProblem::RankMismatch => {
+                write!(f, ".rank does not match the .bwt, rebuild it")
+            }
+            SegmentProblem::Undecodable { file, error } => {
+                write!(f, "{} can't be decompressed: {}", file, error)
+            }
+        }
+    }
+}
+
+// First difference found when comparing a merged segment with a rebuild from its text
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MergeMismatch {
+    BwtLength {
+        merged: usize,
+        rebuilt: usize,
+    },
+    CharCount {
+        chr: u8,
+        merged: usize,
+        rebuilt: usize,
+    },
+    // the merged .index ended after this many line ids
+    IndexLength {
+        merged: usize,
+        rebuilt: usize,
+    },
+    // BWT byte and line id at a position
+    Position {
+        position: usize,
+        merged: (u8, usize),
+        rebuilt: (u8, usize),
+    },
+}
+
+impl fmt::Display for MergeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MergeMismatch::BwtLength { merged, rebuilt } => write!(
+                f,
+                "merged BWT has {} bytes, the rebuild {}",
+                merged, rebuilt
+            ),
+            MergeMismatch::CharCount {
+                chr,
+                merged,
+                rebuilt,
+            } => write!(
+                f,
+                "byte {:#04x} occurs {} times in the merged BWT, {} in the rebuild",
+                chr, merged, rebuilt
+            ),
+            MergeMismatch::IndexLength { merged, rebuilt } => write!(
+                f,
+                "merged .index has {} line ids, the rebuild {}",
+                merged, rebuilt
+            ),
+            MergeMismatch::Position {
+                position,
+                merged,
+                rebuilt,
+            } => write!(
+                f,
+                "position {} has byte {:#04x} of line {} in the merged BWT, byte {:#04x} of line {} in the rebuild",
+                position, merged.0, merged.1, rebuilt.0, rebuilt.1
+            ),
+        }
+    }
+}
@@ -0,0 +1,347 @@
+// Interleaves kept in files, for disk merges whose interleave doesn't fit in their
+// memory budget. Each pass streams the current interleave alongside the BWT inputs
+// and writes the next one. The entries of the next interleave for each byte value
+// are written in order, so they go to a file per byte value, and reading the files
+// in byte order gives the next interleave.
+// Entries are the label of each position, usually the input it comes from: a bit for
+// two labels, packed from the lowest bit of each byte, or a little-endian u16 for more.
+// The interleave before the first pass, runs of labels such as all of each input in
+// turn, isn't written at all.
+// Each bucket is compared with the current interleave at the same positions while it is
+// written, which after the first pass is the current file for the same byte value, so
+// a pass knows whether the interleave changed without reading both again.
+
+use anyhow::{anyhow, Result};
+use opendal::{Operator, Reader, Writer};
+
+use crate::stream::{ByteStream, ReadOptions};
+
+// Largest buffer of each bucket's file and of its comparison with the current interleave.
+// There can be 256 buckets, so it is much smaller than a stream's usual buffers.
+const BUCKET_BUFFER_SIZE: usize = 64 << 10;
+
+fn bucket_buffer_size(options: ReadOptions) -> usize {
+    options.buffer_size.clamp(1, BUCKET_BUFFER_SIZE)
+}
+
+pub struct SpilledInterleave {
+    operator: Operator,
+    dir: String,
+    // runs of the same label in the initial interleave
+    initial: Vec<(u16, usize)>,
+    counts: [usize; 256],
+    // passes written so far; 0 is the initial interleave
+    generation: usize,
+    options: ReadOptions,
+}
+
+impl SpilledInterleave {
+    // Interleave starting from the given runs of labels, for inputs with the given
+    // combined character counts, with its files in dir
+    pub fn new(
+        operator: &Operator,
+        dir: &str,
+        initial: &[(u16, usize)],
+        counts: &[usize; 256],
+        options: ReadOptions,
+    ) -> SpilledInterleave {
+        SpilledInterleave {
+            operator: operator.clone(),
+            dir: dir.to_string(),
+            initial: initial.to_vec(),
+            counts: *counts,
+            generation: 0,
+            options,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.initial.iter().map(|x| x.1).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Character counts of the inputs, which are the lengths of the buckets
+    pub fn counts(&self) -> &[usize; 256] {
+        &self.counts
+    }
+
+    // Whether entries take a u16. Passes move labels around, so the initial interleave
+    // has all of them.
+    fn wide(&self) -> bool {
+        self.initial.iter().any(|x| x.0 > 1)
+    }
+
+    fn generation_dir(&self, generation: usize) -> String {
+        format!("{}/{}", self.dir, generation)
+    }
+
+    fn reader_for(&self, generation: usize) -> InterleaveReader {
+        let (dir, runs, labels) = match generation {
+            0 => (
+                None,
+                self.initial.iter().map(|x| x.1).collect(),
+                self.initial.iter().map(|x| x.0).collect(),
+            ),
+            _ => (
+                Some(self.generation_dir(generation)),
+                self.counts.to_vec(),
+                Vec::new(),
+            ),
+        };
+        InterleaveReader {
+            operator: self.operator.clone(),
+            dir,
+            wide: self.wide(),
+            runs,
+            labels,
+            next_run: 0,
+            remaining: 0,
+            stream: None,
+            bits: 0,
+            bits_left: 0,
+            options: self.options,
+            bytes_read: 0,
+        }
+    }
+
+    // Reader of the current interleave, from the start
+    pub fn reader(&self) -> InterleaveReader {
+        self.reader_for(self.generation)
+    }
+
+    // Reader of the current interleave at the positions of the next one's bucket for chr
+    fn bucket_reader(&self, chr: usize) -> InterleaveReader {
+        let start: usize = self.counts[..chr].iter().sum();
+        let end = start + self.counts[chr];
+        let mut reader = self.reader();
+        reader.options = ReadOptions {
+            buffer_size: bucket_buffer_size(self.options),
+            buffer_count: 1,
+        };
+        if self.generation == 0 {
+            // the parts of the initial runs inside the bucket
+            let mut offset = 0;
+            for run in reader.runs.iter_mut() {
+                let run_start = offset;
+                offset += *run;
+                *run = offset.min(end).saturating_sub(run_start.max(start));
+            }
+        } else {
+            for (i, run) in reader.runs.iter_mut().enumerate() {
+                if i != chr {
+                    *run = 0;
+                }
+            }
+        }
+        reader
+    }
+
+    // Writer of the next interleave. Each bucket's file and comparison are opened by its
+    // first entry and closed by its last, so only buckets that are partly written hold them.
+    pub fn writer(&self) -> InterleaveWriter {
+        let dir = self.generation_dir(self.generation + 1);
+        let buckets = self
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(chr, &count)| BucketWriter {
+                path: format!("{}/{}", dir, chr),
+                remaining: count,
+                writer: None,
+                buf: Vec::new(),
+                bits: 0,
+                num_bits: 0,
+                current: self.bucket_reader(chr),
+            })
+            .collect();
+        InterleaveWriter {
+            operator: self.operator.clone(),
+            buckets,
+            wide: self.wide(),
+            buffer_size: bucket_buffer_size(self.options),
+            changed: false,
+            bytes_written: 0,
+            bytes_read: 0,
+        }
+    }
+
+    // Move on to the interleave written by the last writer, removing the current one
+    pub async fn advance(&mut self) -> Result<()> {
+        if self.generation > 0 {
+            let dir = format!("{}/", self.generation_dir(self.generation));
+            self.operator.remove_all(&dir).await?;
+        }
+        self.generation += 1;
+        Ok(())
+    }
+
+    // Estimate of the memory used by an interleave on disk with the given character counts:
+    // the buffer of the interleave read during a pass, and of each bucket's file and its
+    // comparison, which are all open at worst
+    pub fn memory_size(counts: &[usize; 256], options: ReadOptions) -> usize {
+        let buckets = counts.iter().filter(|&&x| x > 0).count();
+        options.memory_size() + buckets * 2 * bucket_buffer_size(options)
+    }
+}
+
+// Entries of an interleave, read in order
+pub struct InterleaveReader {
+    operator: Operator,
+    // directory of the files, or None for the initial interleave
+    dir: Option<String>,
+    wide: bool,
+    // entries in each run: each initial run's, or each byte value's
+    runs: Vec<usize>,
+    // label of each initial run
+    labels: Vec<u16>,
+    next_run: usize,
+    remaining: usize,
+    stream: Option<ByteStream<Reader>>,
+    // bits of the current byte not yet read
+    bits: u8,
+    bits_left: u32,
+    options: ReadOptions,
+    bytes_read: u64,
+}
+
+impl InterleaveReader {
+    // Label of the next position, which must be in the interleave
+    pub async fn next(&mut self) -> Result<u16> {
+        while self.remaining == 0 {
+            if self.next_run == self.runs.len() {
+                return Err(anyhow!("Interleave ended early"));
+            }
+            self.remaining = self.runs[self.next_run];
+            self.next_run += 1;
+            if let Some(mut stream) = self.stream.take() {
+                self.bytes_read += stream.take_bytes_read();
+            }
+            self.bits_left = 0;
+            if let (Some(dir), true) = (self.dir.as_ref(), self.remaining > 0) {
+                let reader = self
+                    .operator
+                    .reader(&format!("{}/{}", dir, self.next_run - 1))
+                    .await?;
+                self.stream = Some(ByteStream::prefetching(reader, self.options));
+            }
+        }
+        self.remaining -= 1;
+
+        let Some(stream) = self.stream.as_mut() else {
+            return Ok(self.labels[self.next_run - 1]);
+        };
+        if self.wide {
+            let low = stream.next_byte().await?;
+            let high = stream.next_byte().await?;
+            return Ok(u16::from_le_bytes([low, high]));
+        }
+        if self.bits_left == 0 {
+            self.bits = stream.next_byte().await?;
+            self.bits_left = 8;
+        }
+        let bit = self.bits & 1;
+        self.bits >>= 1;
+        self.bits_left -= 1;
+        Ok(bit as u16)
+    }
+
+    // Bytes read from storage since the last call
+    pub fn take_bytes_read(&mut self) -> u64 {
+        if let Some(stream) = self.stream.as_mut() {
+            self.bytes_read += stream.take_bytes_read();
+        }
+        std::mem::take(&mut self.bytes_read)
+    }
+}
+
+struct BucketWriter {
+    path: String,
+    // entries not pushed yet
+    remaining: usize,
+    // the bucket's file, while it is being written
+    writer: Option<Writer>,
+    buf: Vec<u8>,
+    bits: u8,
+    num_bits: u32,
+    // the current interleave at the bucket's positions
+    current: InterleaveReader,
+}
+
+// Entries of the next interleave, pushed in order for each byte value
+pub struct InterleaveWriter {
+    operator: Operator,
+    buckets: Vec<BucketWriter>,
+    wide: bool,
+    buffer_size: usize,
+    // whether an entry differs from the current interleave
+    changed: bool,
+    bytes_written: u64,
+    bytes_read: u64,
+}
+
+impl InterleaveWriter {
+    // Add the label of the next position for the byte value chr
+    pub async fn push(&mut self, chr: u8, src: u16) -> Result<()> {
+        let bucket = &mut self.buckets[chr as usize];
+        if bucket.remaining == 0 {
+            return Err(anyhow!(
+                "More entries for byte {} than its character count",
+                chr
+            ));
+        }
+        let writer = match bucket.writer.as_mut() {
+            Some(writer) => writer,
+            None => {
+                bucket.buf = Vec::with_capacity(self.buffer_size);
+                bucket
+                    .writer
+                    .insert(self.operator.writer(&bucket.path).await?)
+            }
+        };
+        bucket.remaining -= 1;
+        if !self.changed && bucket.current.next().await? != src {
+            self.changed = true;
+        }
+        if self.wide {
+            bucket.buf.extend_from_slice(&src.to_le_bytes());
+        } else {
+            bucket.bits |= (src as u8 & 1) << bucket.num_bits;
+            bucket.num_bits += 1;
+            if bucket.num_bits == 8 || bucket.remaining == 0 {
+                bucket.buf.push(bucket.bits);
+                bucket.bits = 0;
+                bucket.num_bits = 0;
+            }
+        }
+        if bucket.buf.len() >= self.buffer_size || bucket.remaining == 0 {
+            self.bytes_written += bucket.buf.len() as u64;
+            let buf = std::mem::replace(&mut bucket.buf, Vec::with_capacity(self.buffer_size));
+            writer.write(buf).await?;
+        }
+        if bucket.remaining == 0 {
+            // the bucket is done, so let go of its file, comparison and buffer
+            if let Some(mut writer) = bucket.writer.take() {
+                writer.close().await?;
+            }
+            self.bytes_read += bucket.current.take_bytes_read();
+            bucket.current.stream = None;
+            bucket.buf = Vec::new();
+        }
+        Ok(())
+    }
+
+    // Check that every bucket was filled. Returns whether the next interleave differs from
+    // the current one, and the bytes written and read comparing them.
+    pub async fn close(self) -> Result<(bool, u64, u64)> {
+        if let Some(chr) = self.buckets.iter().position(|x| x.remaining > 0) {
+            return Err(anyhow!(
+                "Fewer entries for byte {} than its character count",
+                chr
+            ));
+        }
+        Ok((self.changed, self.bytes_written, self.bytes_read))
+    }
+}
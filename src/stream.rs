@@ -0,0 +1,302 @@
+use std::io::{self, SeekFrom};
+
+use anyhow::{anyhow, Result};
+use memmap2::Mmap;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+// Buffers for reading a stream. With more than one, a background task reads the
+// next buffers while the current one is processed, so reading overlaps with work.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReadOptions {
+    pub buffer_size: usize,
+    // buffers in the ring, including the one being processed; 1 reads in the foreground
+    pub buffer_count: usize,
+}
+
+impl Default for ReadOptions {
+    fn default() -> ReadOptions {
+        ReadOptions {
+            buffer_size: 1 << 20,
+            buffer_count: 2,
+        }
+    }
+}
+
+impl ReadOptions {
+    // Bytes of buffers held by a stream
+    pub fn memory_size(&self) -> usize {
+        self.buffer_size.max(1) * self.buffer_count.max(1)
+    }
+}
+
+// Background task reading ahead into a ring of buffers.
+// Filled buffers are sent to the stream, which sends each back once it is done with it.
+struct Prefetch<R> {
+    task: JoinHandle<R>,
+    filled: mpsc::Receiver<io::Result<Vec<u8>>>,
+    free: mpsc::Sender<Vec<u8>>,
+}
+
+impl<R: AsyncRead + Unpin + Send + 'static> Prefetch<R> {
+    fn spawn(reader: R, options: ReadOptions) -> Prefetch<R> {
+        let count = options.buffer_count.max(2);
+        let (filled_tx, filled) = mpsc::channel(count);
+        let (free, free_rx) = mpsc::channel(count);
+        // the stream holds one buffer, the rest start out free
+        for _ in 1..count {
+            free.try_send(Vec::new()).unwrap();
+        }
+        let task = tokio::spawn(prefetch(
+            reader,
+            options.buffer_size.max(1),
+            filled_tx,
+            free_rx,
+        ));
+        Prefetch { task, filled, free }
+    }
+
+    // Stop reading ahead, and get the reader back
+    async fn stop(self) -> Result<R> {
+        // the task stops at its next send or wait for a free buffer
+        drop(self.filled);
+        drop(self.free);
+        Ok(self.task.await?)
+    }
+}
+
+// Fill free buffers from the reader until the data ends or the stream goes away
+async fn prefetch<R: AsyncRead + Unpin>(
+    mut reader: R,
+    buffer_size: usize,
+    filled: mpsc::Sender<io::Result<Vec<u8>>>,
+    mut free: mpsc::Receiver<Vec<u8>>,
+) -> R {
+    while let Some(mut buf) = free.recv().await {
+        buf.resize(buffer_size, 0);
+        let mut len = 0;
+        let mut result = Ok(());
+        while len < buf.len() {
+            match reader.read(&mut buf[len..]).await {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        buf.truncate(len);
+        let done = result.is_err() || len == 0;
+        if filled.send(result.map(|_| buf)).await.is_err() || done {
+            break;
+        }
+    }
+    reader
+}
+
+// Buffered stream of bytes from an async reader, such as an opendal Reader.
+// A read may return fewer bytes than asked for, especially from remote services,
+// so the buffer is refilled whenever it runs out, and only a read of zero bytes
+// is taken as the end of the data.
+// A stream of a file mapped into memory has no reader, and the whole map is its buffer.
+pub struct ByteStream<R> {
+    // taken by the prefetch task while it runs
+    reader: Option<R>,
+    prefetch: Option<Prefetch<R>>,
+    map: Option<Mmap>,
+    options: ReadOptions,
+    buf: Vec<u8>,
+    pos: usize,
+    len: usize,
+    // offset in the data of the start of the buffer
+    start: u64,
+    eof: bool,
+    bytes_read: u64,
+}
+
+impl<R: AsyncRead + Unpin> ByteStream<R> {
+    pub fn new(reader: R, buffer_size: usize) -> ByteStream<R> {
+        ByteStream {
+            reader: Some(reader),
+            prefetch: None,
+            map: None,
+            options: ReadOptions {
+                buffer_size,
+                buffer_count: 1,
+            },
+            buf: vec![0u8; buffer_size.max(1)],
+            pos: 0,
+            len: 0,
+            start: 0,
+            eof: false,
+            bytes_read: 0,
+        }
+    }
+
+    // Stream of a mapped file, whose chunks are slices of the map
+    pub fn mapped(map: Mmap) -> ByteStream<R> {
+        ByteStream {
+            reader: None,
+            prefetch: None,
+            map: Some(map),
+            options: ReadOptions {
+                buffer_size: 0,
+                buffer_count: 1,
+            },
+            buf: Vec::new(),
+            pos: 0,
+            len: 0,
+            start: 0,
+            eof: false,
+            bytes_read: 0,
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        match self.map.as_ref() {
+            Some(map) => map,
+            None => &self.buf,
+        }
+    }
+
+    // Refill the buffer if it has all been consumed.
+    // Returns false at the end of the data.
+    async fn fill(&mut self) -> Result<bool> {
+        if self.pos < self.len {
+            return Ok(true);
+        }
+        if self.eof {
+            return Ok(false);
+        }
+        self.start += self.len as u64;
+        self.pos = 0;
+        match (
+            self.map.as_ref(),
+            self.prefetch.as_mut(),
+            self.reader.as_mut(),
+        ) {
+            // the map is a single buffer
+            (Some(map), _, _) => self.len = if self.start == 0 { map.len() } else { 0 },
+            (None, Some(prefetch), _) => {
+                self.len = 0;
+                if let Some(buf) = prefetch.filled.recv().await {
+                    let used = std::mem::replace(&mut self.buf, buf?);
+                    // the ring has room for every buffer, and the task may have finished
+                    let _ = prefetch.free.try_send(used);
+                    self.len = self.buf.len();
+                }
+            }
+            (None, None, Some(reader)) => self.len = reader.read(&mut self.buf).await?,
+            (None, None, None) => return Err(anyhow!("Stream lost its reader")),
+        }
+        self.bytes_read += self.len as u64;
+        self.eof = self.len == 0;
+        Ok(!self.eof)
+    }
+
+    // Next byte of the data, which must not have ended
+    pub async fn next_byte(&mut self) -> Result<u8> {
+        if self.pos == self.len && !self.fill().await? {
+            return Err(anyhow!("Data ended early, after {} bytes", self.start));
+        }
+        let byte = self.data()[self.pos];
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    // Bytes buffered but not yet consumed, refilling the buffer if needed.
+    // Empty only at the end of the data.
+    pub async fn chunk(&mut self) -> Result<&[u8]> {
+        self.fill().await?;
+        Ok(&self.data()[self.pos..self.len])
+    }
+
+    // Mark bytes returned by chunk as consumed
+    pub fn consume(&mut self, n: usize) {
+        assert!(
+            n <= self.len - self.pos,
+            "Consumed more bytes than buffered"
+        );
+        self.pos += n;
+    }
+
+    // Skip over bytes, which must all be present
+    pub async fn skip(&mut self, mut n: u64) -> Result<()> {
+        while n > 0 {
+            let available = self.chunk().await?.len();
+            if available == 0 {
+                return Err(anyhow!("Data ended early, after {} bytes", self.start));
+            }
+            let len = available.min(n as usize);
+            self.consume(len);
+            n -= len as u64;
+        }
+        Ok(())
+    }
+
+    // Fill out with as many bytes as the data has left, returning how many were read
+    pub async fn read_up_to(&mut self, out: &mut [u8]) -> Result<usize> {
+        let mut len = 0;
+        while len < out.len() {
+            let chunk = self.chunk().await?;
+            if chunk.is_empty() {
+                break;
+            }
+            let n = chunk.len().min(out.len() - len);
+            out[len..len + n].copy_from_slice(&chunk[..n]);
+            self.consume(n);
+            len += n;
+        }
+        Ok(len)
+    }
+
+    // Offset in the data of the next byte
+    pub fn position(&self) -> u64 {
+        self.start + self.pos as u64
+    }
+
+    // Bytes read from the underlying reader since the last call
+    pub fn take_bytes_read(&mut self) -> u64 {
+        std::mem::take(&mut self.bytes_read)
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send + 'static> ByteStream<R> {
+    // Stream that reads ahead into a ring of buffers in the background, if the options
+    // have more than one buffer
+    pub fn prefetching(reader: R, options: ReadOptions) -> ByteStream<R> {
+        let mut stream = ByteStream::new(reader, options.buffer_size);
+        if options.buffer_count > 1 {
+            stream.options = options;
+            stream.buf = Vec::new();
+            stream.prefetch = Some(Prefetch::spawn(stream.reader.take().unwrap(), options));
+        }
+        stream
+    }
+}
+
+impl<R: AsyncRead + AsyncSeek + Unpin + Send + 'static> ByteStream<R> {
+    // Go back to the start of the data
+    pub async fn rewind(&mut self) -> Result<()> {
+        if self.map.is_none() {
+            let mut reader = match (self.prefetch.take(), self.reader.take()) {
+                (Some(prefetch), _) => prefetch.stop().await?,
+                (None, Some(reader)) => reader,
+                (None, None) => return Err(anyhow!("Stream lost its reader")),
+            };
+            reader.seek(SeekFrom::Start(0)).await?;
+            if self.options.buffer_count > 1 {
+                self.prefetch = Some(Prefetch::spawn(reader, self.options));
+            } else {
+                self.reader = Some(reader);
+            }
+        }
+        self.pos = 0;
+        self.len = 0;
+        self.start = 0;
+        self.eof = false;
+        Ok(())
+    }
+}
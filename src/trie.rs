@@ -5,6 +5,35 @@ use std::{
 
 use serde::{Deserialize, Serialize};
 
+// Depth of the node each string's data goes in: one bit past where it differs from both
+// of its neighbours, plus extra bits, but no deeper than the string.
+// Strings should be sorted.
+fn node_depths(strs: &[Vec<u8>], extra_bits: usize) -> Vec<usize> {
+    // calculate LCPs
+    let mut lcp = vec![0; strs.len() + 1];
+    // lcp[i] contains lcp of strs[i-1] and strs[i]
+    // lcp[0] := lcp[n] := 0
+    for i in 0..strs.len().saturating_sub(1) {
+        let mut j = 0;
+        while j < strs[i].len() * 8
+            && j < strs[i + 1].len() * 8
+            && get_bit(&strs[i], j) == get_bit(&strs[i + 1], j)
+        {
+            j += 1;
+        }
+        lcp[i + 1] = j;
+    }
+
+    (0..strs.len())
+        .map(|i| min(max(lcp[i], lcp[i + 1]) + 1 + extra_bits, strs[i].len() * 8))
+        .collect()
+}
+
+// Bit i of a string, big endian
+fn get_bit(s: &[u8], i: usize) -> bool {
+    (s[i / 8] >> (7 - (i % 8))) & 1 == 1
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct BinaryTrieNode<T: Clone> {
     pub left: Option<Box<BinaryTrieNode<T>>>,
@@ -34,35 +63,14 @@ impl<T: Clone> BinaryTrieNode<T> {
         str_data: &[Vec<T>],
         extra_bits: usize,
     ) -> BinaryTrieNode<T> {
-        // big endian
-        let get_bit = |stri: usize, i: usize| -> bool {
-            let chr = i / 8;
-            let bit = 7 - (i % 8);
-            (strs[stri][chr] >> bit) & 1 == 1
-        };
-
-        // calculate LCPs
-        let mut lcp = vec![0; strs.len() + 1];
-        // lcp[i] contains lcp of strs[i-1] and strs[i]
-        // lcp[0] := lcp[n] := 0
-        for i in 0..strs.len() - 1 {
-            let mut j = 0;
-            while j < strs[i].len() * 8
-                && j < strs[i + 1].len() * 8
-                && get_bit(i, j) == get_bit(i + 1, j)
-            {
-                j += 1;
-            }
-            lcp[i + 1] = j;
-        }
+        let node_depths = node_depths(strs, extra_bits);
 
         // build trie
         let mut root = BinaryTrieNode::new();
         for i in 0..strs.len() {
-            let node_depth = min(max(lcp[i], lcp[i + 1]) + 1 + extra_bits, strs[i].len() * 8);
             let mut node = &mut root;
-            for j in 0..node_depth {
-                if !get_bit(i, j) {
+            for j in 0..node_depths[i] {
+                if !get_bit(&strs[i], j) {
                     if node.left.is_none() {
                         node.left = Some(Box::new(BinaryTrieNode::new()));
                     }
@@ -105,16 +113,10 @@ impl<T: Clone> BinaryTrieNode<T> {
     // but only a few, so you can check manually
     // Collects all results seen on the way, in order to support merging
     pub fn query(&self, query: &[u8]) -> Vec<T> {
-        let get_bit = |i: usize| -> bool {
-            let chr = i / 8;
-            let bit = 7 - (i % 8);
-            (query[chr] >> bit) & 1 == 1
-        };
-
         let mut node = self;
         let mut results = Vec::new();
         for i in 0..query.len() * 8 {
-            if !get_bit(i) {
+            if !get_bit(query, i) {
                 if node.left.is_none() {
                     break;
                 }
@@ -151,29 +153,209 @@ pub fn merge_tries<T: Clone>(t1: &BinaryTrieNode<T>, t2: &BinaryTrieNode<T>) ->
     output.data.extend(t1.data.clone());
     output.data.extend(t2.data.clone());
 
-    if t1.left.is_none() {
-        output.left = t2.left.clone();
-    } else if t2.left.is_none() {
-        output.left = t1.left.clone();
-    } else {
-        output.left = Some(Box::new(merge_tries(
-            t1.left.as_ref().unwrap(),
-            t2.left.as_ref().unwrap(),
-        )));
+    output.left = match (&t1.left, &t2.left) {
+        (None, _) => t2.left.clone(),
+        (_, None) => t1.left.clone(),
+        (Some(l1), Some(l2)) => Some(Box::new(merge_tries(l1, l2))),
+    };
+
+    output.right = match (&t1.right, &t2.right) {
+        (None, _) => t2.right.clone(),
+        (_, None) => t1.right.clone(),
+        (Some(r1), Some(r2)) => Some(Box::new(merge_tries(r1, r2))),
+    };
+
+    output
+}
+
+// Marks a missing child in ArenaTrie; the root is never a child, so its index is free
+const NO_CHILD: u32 = 0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+struct ArenaNode {
+    // left and right children
+    children: [u32; 2],
+    // end of the node's data in the postings; it starts where the previous node's ends
+    data_end: u32,
+}
+
+/// Binary trie with its nodes in one contiguous array, in preorder, with u32 child
+/// indices, and the data of every node in one shared postings array.
+/// Built, queried and extended like BinaryTrieNode, with the same results,
+/// but far smaller in memory and much faster to serialize.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ArenaTrie<T> {
+    nodes: Vec<ArenaNode>,
+    postings: Vec<T>,
+}
+
+// Nodes as they're added, with each item of data recorded along with its node
+struct ArenaBuilder<T> {
+    children: Vec<[u32; 2]>,
+    data: Vec<(u32, T)>,
+}
+
+impl<T> ArenaBuilder<T> {
+    fn new() -> ArenaBuilder<T> {
+        ArenaBuilder {
+            children: vec![[NO_CHILD; 2]],
+            data: Vec::new(),
+        }
     }
 
-    if t1.right.is_none() {
-        output.right = t2.right.clone();
-    } else if t2.right.is_none() {
-        output.right = t1.right.clone();
-    } else {
-        output.right = Some(Box::new(merge_tries(
-            t1.right.as_ref().unwrap(),
-            t2.right.as_ref().unwrap(),
-        )));
+    // Child of a node on the given side, added if it doesn't exist yet
+    fn child(&mut self, node: u32, right: bool) -> u32 {
+        let child = self.children[node as usize][right as usize];
+        if child != NO_CHILD {
+            return child;
+        }
+        let child = u32::try_from(self.children.len()).expect("Too many trie nodes");
+        self.children.push([NO_CHILD; 2]);
+        self.children[node as usize][right as usize] = child;
+        child
     }
 
-    output
+    // Renumber the nodes in preorder and group the data by node, keeping the order
+    // it was added in within each node
+    fn finish(mut self) -> ArenaTrie<T> {
+        let mut order = Vec::with_capacity(self.children.len());
+        let mut stack = vec![0u32];
+        while let Some(node) = stack.pop() {
+            order.push(node);
+            for &child in self.children[node as usize].iter().rev() {
+                if child != NO_CHILD {
+                    stack.push(child);
+                }
+            }
+        }
+        let mut new_index = vec![NO_CHILD; self.children.len()];
+        for (i, &node) in order.iter().enumerate() {
+            new_index[node as usize] = i as u32;
+        }
+
+        self.data.sort_by_key(|(node, _)| new_index[*node as usize]);
+        let mut data_sizes = vec![0usize; order.len()];
+        for (node, _) in self.data.iter() {
+            data_sizes[new_index[*node as usize] as usize] += 1;
+        }
+        let mut data_end = 0;
+        let nodes = order
+            .iter()
+            .zip(data_sizes)
+            .map(|(&node, size)| {
+                data_end += size;
+                ArenaNode {
+                    children: self.children[node as usize].map(|x| match x {
+                        NO_CHILD => NO_CHILD,
+                        _ => new_index[x as usize],
+                    }),
+                    data_end: u32::try_from(data_end).expect("Too much trie data"),
+                }
+            })
+            .collect();
+        ArenaTrie {
+            nodes,
+            postings: self.data.into_iter().map(|(_, x)| x).collect(),
+        }
+    }
+}
+
+impl<T: Clone> ArenaTrie<T> {
+    /// Builds a trie from a list of strings and their corresponding indices,
+    /// like BinaryTrieNode::build.
+    /// String list should be sorted.
+    pub fn build(strs: &[Vec<u8>], str_data: &[Vec<T>]) -> ArenaTrie<T> {
+        ArenaTrie::build_extra(strs, str_data, 8)
+    }
+
+    /// Build, specifying extra bits
+    pub fn build_extra(strs: &[Vec<u8>], str_data: &[Vec<T>], extra_bits: usize) -> ArenaTrie<T> {
+        let mut builder = ArenaBuilder::new();
+        for ((s, data), depth) in strs.iter().zip(str_data).zip(node_depths(strs, extra_bits)) {
+            let mut node = 0;
+            for j in 0..depth {
+                node = builder.child(node, get_bit(s, j));
+            }
+            builder.data.extend(data.iter().map(|x| (node, x.clone())));
+        }
+        builder.finish()
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn data(&self, node: u32) -> &[T] {
+        let start = match node {
+            0 => 0,
+            _ => self.nodes[node as usize - 1].data_end as usize,
+        };
+        &self.postings[start..self.nodes[node as usize].data_end as usize]
+    }
+
+    /// Merge another trie into this one, like BinaryTrieNode::extend.
+    /// Data of nodes in both tries comes from this trie first.
+    pub fn extend(&mut self, other: ArenaTrie<T>) {
+        let mut builder = ArenaBuilder::new();
+        // a node of the merged trie, and the nodes of each trie it comes from
+        let mut stack = vec![(0, Some(0), Some(0))];
+        while let Some((node, a, b)) = stack.pop() {
+            for (trie, source) in [(&*self, a), (&other, b)] {
+                if let Some(source) = source {
+                    let data = trie.data(source).iter().map(|x| (node, x.clone()));
+                    builder.data.extend(data);
+                }
+            }
+            for side in [true, false] {
+                let child = |trie: &ArenaTrie<T>, source: Option<u32>| {
+                    source
+                        .map(|x| trie.nodes[x as usize].children[side as usize])
+                        .filter(|&x| x != NO_CHILD)
+                };
+                let (a, b) = (child(self, a), child(&other, b));
+                if a.is_some() || b.is_some() {
+                    stack.push((builder.child(node, side), a, b));
+                }
+            }
+        }
+        *self = builder.finish();
+    }
+
+    /// Query the trie for matching indices, like BinaryTrieNode::query:
+    /// if the string does not exist it may return a few results that don't match.
+    pub fn query(&self, query: &[u8]) -> Vec<T> {
+        let mut node = 0;
+        let mut results = Vec::new();
+        for i in 0..query.len() * 8 {
+            let child = self.nodes[node as usize].children[get_bit(query, i) as usize];
+            if child == NO_CHILD {
+                break;
+            }
+            results.extend_from_slice(self.data(node));
+            node = child;
+        }
+
+        results.extend_from_slice(self.data(node));
+        results
+    }
+}
+
+impl<T: Clone> From<&BinaryTrieNode<T>> for ArenaTrie<T> {
+    fn from(trie: &BinaryTrieNode<T>) -> ArenaTrie<T> {
+        let mut builder = ArenaBuilder::new();
+        let mut stack = vec![(0, trie)];
+        while let Some((node, source)) = stack.pop() {
+            builder
+                .data
+                .extend(source.data.iter().map(|x| (node, x.clone())));
+            for (side, child) in [(true, &source.right), (false, &source.left)] {
+                if let Some(child) = child {
+                    stack.push((builder.child(node, side), child));
+                }
+            }
+        }
+        builder.finish()
+    }
 }
 
 pub fn hex_to_u8(hex: &str) -> Result<Vec<u8>, ParseIntError> {
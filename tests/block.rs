@@ -0,0 +1,125 @@
+use std::io::SeekFrom;
+
+use bwt_merge::block::{
+    compress, decompress, read_range, BlockCompression, BlockReader, BlockTable, BLOCK_FOOTER_SIZE,
+};
+use bwt_merge::bwt_disk::memory_operator;
+use bwt_merge::stream::ByteStream;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+const COMPRESSION: BlockCompression = BlockCompression {
+    level: 3,
+    block_size: 1000,
+};
+
+fn random_data(rng: &mut StdRng, len: usize) -> Vec<u8> {
+    (0..len).map(|_| b"abc\n"[rng.gen_range(0..4)]).collect()
+}
+
+fn table_of(data: &[u8]) -> BlockTable {
+    let footer = &data[data.len() - BLOCK_FOOTER_SIZE..];
+    let table_offset = BlockTable::decode_footer(footer).unwrap();
+    BlockTable::decode(&data[table_offset as usize..]).unwrap()
+}
+
+#[test]
+fn block_round_trip() {
+    let mut rng = StdRng::seed_from_u64(40);
+    for len in [0, 1, 999, 1000, 1001, 5500] {
+        let data = random_data(&mut rng, len);
+        let compressed = compress(&data, COMPRESSION).unwrap();
+        assert_eq!(decompress(&compressed).unwrap(), data);
+
+        let table = table_of(&compressed);
+        assert_eq!(table.data_len(), len);
+        assert_eq!(table.block_size(), 1000);
+        assert_eq!(table.compression(), COMPRESSION);
+        assert_eq!(table.num_blocks(), len.div_ceil(1000));
+        assert_eq!(table.blocks_of(999..1001), 0..2);
+        assert_eq!(table.blocks_of(1000..1000), 0..0);
+    }
+
+    let data = random_data(&mut rng, 5500);
+    let compressed = compress(&data, COMPRESSION).unwrap();
+    assert!(compressed.len() < data.len() / 2);
+
+    // the level is recorded in the footer, with files from before it reading as 0
+    let fast = BlockCompression {
+        level: -5,
+        ..COMPRESSION
+    };
+    assert_eq!(
+        table_of(&compress(&data, fast).unwrap()).compression(),
+        fast
+    );
+    let mut unrecorded = compressed.clone();
+    let len = unrecorded.len();
+    unrecorded[len - 10..len - 8].copy_from_slice(&[0, 0]);
+    assert_eq!(table_of(&unrecorded).compression().level, 0);
+    assert_eq!(decompress(&unrecorded).unwrap(), data);
+
+    // damaged tables and truncated files are rejected
+    let table_offset = table_of(&compressed).frame_range(0..6).end as usize;
+    let mut damaged = compressed.clone();
+    damaged[table_offset + 8] ^= 1;
+    assert!(decompress(&damaged)
+        .unwrap_err()
+        .to_string()
+        .contains("checksum"));
+    assert!(decompress(&compressed[..compressed.len() - 1]).is_err());
+    assert!(decompress(&compressed[1..]).is_err());
+    assert!(decompress(&data).is_err());
+
+    let invalid = BlockCompression {
+        block_size: 0,
+        ..COMPRESSION
+    };
+    assert!(compress(&data, invalid).is_err());
+}
+
+#[tokio::test]
+async fn block_reader_seeks() {
+    let mut rng = StdRng::seed_from_u64(41);
+    let operator = memory_operator().unwrap();
+    let data = random_data(&mut rng, 10500);
+    operator
+        .write("data.z", compress(&data, COMPRESSION).unwrap())
+        .await
+        .unwrap();
+    let table = BlockTable::read(&operator, "data.z").await.unwrap();
+    assert_eq!(table.num_blocks(), 11);
+
+    // fetches of a few frames at a time, read through a small buffer
+    let reader = BlockReader::new(&operator, "data.z", table.clone(), 2000);
+    let mut stream = ByteStream::new(reader, 300);
+    let mut read = vec![0; data.len() + 1];
+    assert_eq!(stream.read_up_to(&mut read).await.unwrap(), data.len());
+    assert_eq!(&read[..data.len()], &data[..]);
+    stream.rewind().await.unwrap();
+    assert_eq!(stream.next_byte().await.unwrap(), data[0]);
+
+    let mut reader = BlockReader::new(&operator, "data.z", table.clone(), 1);
+    for pos in [0, 999, 1000, 5432, 10499, 10500] {
+        reader.seek(SeekFrom::Start(pos as u64)).await.unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, &data[pos..]);
+    }
+    assert!(reader.seek(SeekFrom::Start(10501)).await.is_err());
+    assert!(reader.seek(SeekFrom::Current(0)).await.is_err());
+
+    for _ in 0..50 {
+        let start = rng.gen_range(0..=data.len());
+        let end = rng.gen_range(start..=data.len());
+        assert_eq!(
+            read_range(&operator, "data.z", &table, start..end)
+                .await
+                .unwrap(),
+            &data[start..end]
+        );
+    }
+    assert!(read_range(&operator, "data.z", &table, 10000..10501)
+        .await
+        .is_err());
+}
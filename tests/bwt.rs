@@ -0,0 +1,423 @@
+use std::collections::BTreeSet;
+
+use bwt_merge::bwt::{
+    bwt_delete_lines, bwt_merge, bwt_merge_many, bwt_merge_many_observed, bwt_merge_observed,
+    bwt_merge_threads, fm_index, get_matching_lines, run_bwt, run_bwt_cancellable,
+    run_bwt_with_doc_ids,
+};
+use bwt_merge::progress::{Cancellation, Cancelled, MergeObserver, MergeStats, MergeStopped};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio_util::sync::CancellationToken;
+
+const ALPHABET: &[u8] = b"abc";
+
+// Creates n random lines of up to max_len characters, each terminated by a newline
+fn random_text(rng: &mut StdRng, n: usize, max_len: usize) -> Vec<u8> {
+    let mut text = Vec::new();
+    for _ in 0..n {
+        let len = rng.gen_range(0..=max_len);
+        for _ in 0..len {
+            text.push(ALPHABET[rng.gen_range(0..ALPHABET.len())]);
+        }
+        text.push(b'\n');
+    }
+    text
+}
+
+fn all_patterns(max_len: usize) -> Vec<Vec<u8>> {
+    let mut patterns = vec![Vec::new()];
+    let mut last = vec![Vec::new()];
+    for _ in 0..max_len {
+        let mut next = Vec::new();
+        for pattern in last.iter() {
+            for &chr in ALPHABET.iter() {
+                let mut new_pattern = pattern.clone();
+                new_pattern.push(chr);
+                next.push(new_pattern);
+            }
+        }
+        patterns.extend(next.clone());
+        last = next;
+    }
+    patterns
+}
+
+// lines of the text containing the pattern
+fn naive_matching_lines(text: &[u8], pattern: &[u8]) -> BTreeSet<u64> {
+    let num_lines = text.iter().filter(|&&x| x == b'\n').count();
+    text.split(|&x| x == b'\n')
+        .take(num_lines)
+        .enumerate()
+        .filter(|(_, line)| pattern.is_empty() || line.windows(pattern.len()).any(|w| w == pattern))
+        .map(|(i, _)| i as u64)
+        .collect()
+}
+
+#[test]
+fn merge_many_matches_rebuild() {
+    let mut rng = StdRng::seed_from_u64(26);
+    for round in 0..200 {
+        let num_inputs = rng.gen_range(1..6);
+        // short lines over few characters, so inputs share long suffixes
+        let max_len = match round % 2 {
+            0 => 6,
+            _ => 2,
+        };
+        let mut texts = Vec::new();
+        for _ in 0..num_inputs {
+            let num_lines = rng.gen_range(0..8);
+            texts.push(random_text(&mut rng, num_lines, max_len));
+        }
+        // the same text several times over
+        if round % 5 == 0 {
+            texts = vec![texts[0].clone(); num_inputs];
+        }
+        let inputs = texts.iter().map(run_bwt).collect::<Vec<_>>();
+
+        let merged = bwt_merge_many(&inputs);
+        assert_eq!(merged, run_bwt(&texts.concat()));
+    }
+}
+
+#[test]
+fn merge_many_observed() {
+    let mut rng = StdRng::seed_from_u64(27);
+    let inputs = (0..3)
+        .map(|_| run_bwt(&random_text(&mut rng, 50, 10)))
+        .collect::<Vec<_>>();
+
+    let mut observer = StopAfter {
+        max_iterations: usize::MAX,
+        iterations_seen: Vec::new(),
+    };
+    let (merged, stats) = bwt_merge_many_observed(&inputs, &mut observer).unwrap();
+    assert_eq!(merged, bwt_merge_many(&inputs));
+    assert!(stats.interleave_iterations > 1);
+    assert_eq!(
+        observer.iterations_seen,
+        (1..=stats.interleave_iterations).collect::<Vec<usize>>()
+    );
+    assert!(stats.peak_memory >= merged.0.len());
+
+    let mut observer = StopAfter {
+        max_iterations: 1,
+        iterations_seen: Vec::new(),
+    };
+    assert_eq!(
+        bwt_merge_many_observed(&inputs, &mut observer).unwrap_err(),
+        MergeStopped
+    );
+
+    let token = CancellationToken::new();
+    token.cancel();
+    let cancel = Cancellation::new().with_token(token);
+    assert_eq!(
+        bwt_merge_many_observed(&inputs, &mut cancel.clone()).unwrap_err(),
+        MergeStopped
+    );
+}
+
+#[test]
+fn merge_many_query() {
+    let mut rng = StdRng::seed_from_u64(126);
+    let patterns = all_patterns(3);
+    for _ in 0..20 {
+        let num_inputs = rng.gen_range(2..5);
+        let mut texts = Vec::new();
+        for _ in 0..num_inputs {
+            let num_lines = rng.gen_range(1..10);
+            texts.push(random_text(&mut rng, num_lines, 8));
+        }
+
+        let inputs = texts.iter().map(run_bwt).collect::<Vec<_>>();
+        let merged = bwt_merge_many(&inputs);
+        let concat = texts.concat();
+        let rebuilt = run_bwt(&concat);
+        assert_eq!(merged, rebuilt);
+
+        let merged_index = fm_index(&merged);
+        let rebuilt_index = fm_index(&rebuilt);
+        for pattern in patterns.iter() {
+            let expected = naive_matching_lines(&concat, pattern);
+            assert_eq!(
+                get_matching_lines(&merged, &merged_index, pattern),
+                expected
+            );
+            assert_eq!(
+                get_matching_lines(&rebuilt, &rebuilt_index, pattern),
+                expected
+            );
+        }
+    }
+}
+
+#[test]
+fn merge_many_single() {
+    let data = run_bwt(&b"abc\nbca\n".to_vec());
+    let merged = bwt_merge_many(std::slice::from_ref(&data));
+    assert_eq!(merged, data);
+}
+
+#[test]
+fn merge_threads_deterministic() {
+    let mut rng = StdRng::seed_from_u64(27);
+    // the largest inputs span several of the chunks the parallel passes count characters in
+    for num_lines in [1, 10, 100, 2000, 30000] {
+        let data0 = run_bwt(&random_text(&mut rng, num_lines, 12));
+        let data1 = run_bwt(&random_text(&mut rng, num_lines, 12));
+
+        let sequential = bwt_merge_threads(&data0, &data1, 1);
+        for threads in [2, 3, 4, 7, 16] {
+            let parallel = bwt_merge_threads(&data0, &data1, threads);
+            assert_eq!(
+                parallel, sequential,
+                "{} lines, {} threads",
+                num_lines, threads
+            );
+        }
+        assert_eq!(bwt_merge(&data0, &data1), sequential);
+    }
+}
+
+#[test]
+fn delete_whole_input() {
+    let mut rng = StdRng::seed_from_u64(28);
+    for _ in 0..20 {
+        let texts = (0..3)
+            .map(|_| {
+                let num_lines = rng.gen_range(1..8);
+                random_text(&mut rng, num_lines, 6)
+            })
+            .collect::<Vec<_>>();
+        let inputs = texts.iter().map(run_bwt).collect::<Vec<_>>();
+        let merged = bwt_merge_many(&inputs);
+
+        // deleting every line of the middle input is the same as never merging it
+        let start = inputs[0].2[b'\n' as usize];
+        let end = start + inputs[1].2[b'\n' as usize];
+        let line_ids = (start..end).collect::<Vec<usize>>();
+        let deleted = bwt_delete_lines(&merged, &line_ids);
+
+        let expected = run_bwt(&[texts[0].clone(), texts[2].clone()].concat());
+        assert_eq!(deleted, expected);
+    }
+}
+
+#[test]
+fn delete_matches_rebuild() {
+    let mut rng = StdRng::seed_from_u64(228);
+    for round in 0..200 {
+        // short lines over few characters, so lines share long suffixes
+        let max_len = match round % 2 {
+            0 => 8,
+            _ => 2,
+        };
+        let num_lines = rng.gen_range(1..30);
+        let mut text = random_text(&mut rng, num_lines, max_len);
+        if round % 5 == 0 {
+            text = text.repeat(3);
+        }
+        let lines = text.split_inclusive(|&x| x == b'\n').collect::<Vec<_>>();
+        let line_ids = (0..lines.len())
+            .filter(|_| rng.gen_bool(0.3))
+            .collect::<Vec<usize>>();
+
+        let remaining_text = lines
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !line_ids.contains(i))
+            .flat_map(|(_, line)| line.to_vec())
+            .collect::<Vec<u8>>();
+        let deleted = bwt_delete_lines(&run_bwt(&text), &line_ids);
+        assert_eq!(deleted, run_bwt(&remaining_text));
+    }
+}
+
+#[test]
+fn delete_large_input() {
+    let mut rng = StdRng::seed_from_u64(328);
+    let text = random_text(&mut rng, 20000, 12);
+    let data = run_bwt(&text);
+    let line_ids = (0..20000)
+        .filter(|_| rng.gen_bool(0.01))
+        .collect::<Vec<usize>>();
+    let remaining_text = text
+        .split_inclusive(|&x| x == b'\n')
+        .enumerate()
+        .filter(|(i, _)| line_ids.binary_search(i).is_err())
+        .flat_map(|(_, line)| line.to_vec())
+        .collect::<Vec<u8>>();
+    assert_eq!(bwt_delete_lines(&data, &line_ids), run_bwt(&remaining_text));
+}
+
+#[test]
+fn delete_and_query() {
+    let mut rng = StdRng::seed_from_u64(128);
+    let patterns = all_patterns(3);
+    for _ in 0..20 {
+        let num_lines = rng.gen_range(1..30);
+        let text = random_text(&mut rng, num_lines, 8);
+        let data = run_bwt(&text);
+
+        let line_ids = (0..num_lines)
+            .filter(|_| rng.gen_bool(0.3))
+            .collect::<Vec<usize>>();
+        let deleted = bwt_delete_lines(&data, &line_ids);
+
+        let remaining_text = text
+            .split_inclusive(|&x| x == b'\n')
+            .enumerate()
+            .filter(|(i, _)| !line_ids.contains(i))
+            .flat_map(|(_, line)| line.to_vec())
+            .collect::<Vec<u8>>();
+        assert_eq!(deleted, run_bwt(&remaining_text));
+
+        // the result should still merge correctly with other BWTs
+        let other_text = random_text(&mut rng, 5, 8);
+        let merged = bwt_merge(&deleted, &run_bwt(&other_text));
+        let merged_text = [remaining_text.clone(), other_text].concat();
+
+        let deleted_index = fm_index(&deleted);
+        let merged_index = fm_index(&merged);
+        for pattern in patterns.iter() {
+            assert_eq!(
+                get_matching_lines(&deleted, &deleted_index, pattern),
+                naive_matching_lines(&remaining_text, pattern)
+            );
+            assert_eq!(
+                get_matching_lines(&merged, &merged_index, pattern),
+                naive_matching_lines(&merged_text, pattern)
+            );
+        }
+    }
+}
+
+#[test]
+#[should_panic(expected = "Number of document ids does not match number of lines")]
+fn doc_ids_per_line() {
+    run_bwt_with_doc_ids(&b"ab\nc\n".to_vec(), vec![1, 2, 3]);
+}
+
+#[test]
+#[should_panic(expected = "Only some of the BWTs have document ids")]
+fn doc_ids_on_some_inputs() {
+    let with_docs = run_bwt_with_doc_ids(&b"ab\nc\n".to_vec(), vec![1, 2]);
+    bwt_merge_many(&[with_docs, run_bwt(&b"ca\n".to_vec())]);
+}
+
+#[test]
+fn doc_ids_independent_of_merge_order() {
+    let mut rng = StdRng::seed_from_u64(29);
+    let patterns = all_patterns(2);
+    for _ in 0..20 {
+        let text0 = random_text(&mut rng, 6, 6);
+        let text1 = random_text(&mut rng, 4, 6);
+        let doc_ids0 = (0..6).map(|x| 1000 + x).collect::<Vec<u64>>();
+        let doc_ids1 = (0..4).map(|x| 5000 + x * 7).collect::<Vec<u64>>();
+        let data0 = run_bwt_with_doc_ids(&text0, doc_ids0.clone());
+        let data1 = run_bwt_with_doc_ids(&text1, doc_ids1.clone());
+
+        let merged01 = bwt_merge(&data0, &data1);
+        let merged10 = bwt_merge(&data1, &data0);
+        assert_eq!(merged01.3, Some([&doc_ids0[..], &doc_ids1[..]].concat()));
+        let merged_many = bwt_merge_many(&[data0.clone(), data1.clone()]);
+        assert_eq!(merged_many.3, merged01.3);
+        // deleting lines drops their document ids and keeps the rest
+        assert_eq!(
+            bwt_delete_lines(&merged_many, &[0, 7]).3,
+            Some([&doc_ids0[1..], &doc_ids1[..1], &doc_ids1[2..]].concat())
+        );
+        let index01 = fm_index(&merged01);
+        let index10 = fm_index(&merged10);
+
+        for pattern in patterns.iter() {
+            let expected = naive_matching_lines(&text0, pattern)
+                .into_iter()
+                .map(|x| doc_ids0[x as usize])
+                .chain(
+                    naive_matching_lines(&text1, pattern)
+                        .into_iter()
+                        .map(|x| doc_ids1[x as usize]),
+                )
+                .collect::<BTreeSet<u64>>();
+            assert_eq!(get_matching_lines(&merged01, &index01, pattern), expected);
+            assert_eq!(get_matching_lines(&merged10, &index10, pattern), expected);
+        }
+    }
+}
+
+// Observer that stops the merge after a number of iterations
+struct StopAfter {
+    max_iterations: usize,
+    iterations_seen: Vec<usize>,
+}
+
+impl MergeObserver for StopAfter {
+    fn on_iteration(&mut self, stats: &MergeStats) -> bool {
+        self.iterations_seen.push(stats.interleave_iterations);
+        stats.interleave_iterations < self.max_iterations
+    }
+}
+
+#[test]
+fn merge_observed() {
+    let mut rng = StdRng::seed_from_u64(30);
+    let data0 = run_bwt(&random_text(&mut rng, 50, 10));
+    let data1 = run_bwt(&random_text(&mut rng, 50, 10));
+
+    let mut observer = StopAfter {
+        max_iterations: usize::MAX,
+        iterations_seen: Vec::new(),
+    };
+    let (merged, stats) = bwt_merge_observed(&data0, &data1, &mut observer).unwrap();
+    assert_eq!(merged, bwt_merge(&data0, &data1));
+    assert!(stats.interleave_iterations > 1);
+    assert_eq!(
+        observer.iterations_seen,
+        (1..=stats.interleave_iterations).collect::<Vec<usize>>()
+    );
+    assert!(stats.peak_memory >= merged.0.len());
+
+    let mut observer = StopAfter {
+        max_iterations: 1,
+        iterations_seen: Vec::new(),
+    };
+    assert_eq!(
+        bwt_merge_observed(&data0, &data1, &mut observer).unwrap_err(),
+        MergeStopped
+    );
+    assert_eq!(observer.iterations_seen, vec![1]);
+}
+
+#[test]
+fn cancel_build_and_merge() {
+    let mut rng = StdRng::seed_from_u64(31);
+    let text0 = random_text(&mut rng, 50, 10);
+    let data0 = run_bwt(&text0);
+    let data1 = run_bwt(&random_text(&mut rng, 50, 10));
+
+    let token = CancellationToken::new();
+    let cancel = Cancellation::new().with_token(token.clone());
+    assert_eq!(run_bwt_cancellable(&text0, &cancel), Ok(data0.clone()));
+    assert!(bwt_merge_observed(&data0, &data1, &mut cancel.clone()).is_ok());
+
+    token.cancel();
+    assert_eq!(run_bwt_cancellable(&text0, &cancel), Err(Cancelled));
+    assert_eq!(
+        bwt_merge_observed(&data0, &data1, &mut cancel.clone()).unwrap_err(),
+        MergeStopped
+    );
+
+    // a passed deadline cancels too, also when wrapping another observer
+    let cancel = Cancellation::new().with_deadline(std::time::Instant::now());
+    assert_eq!(run_bwt_cancellable(&text0, &cancel), Err(Cancelled));
+    let mut observer = StopAfter {
+        max_iterations: usize::MAX,
+        iterations_seen: Vec::new(),
+    };
+    assert_eq!(
+        bwt_merge_observed(&data0, &data1, &mut cancel.observe(&mut observer)).unwrap_err(),
+        MergeStopped
+    );
+    assert!(observer.iterations_seen.is_empty());
+}
@@ -0,0 +1,1927 @@
+use std::collections::{BTreeSet, HashMap};
+
+use bwt_merge::block::{BlockCompression, BlockTable};
+use bwt_merge::bwt::{
+    bwt_delete_lines, bwt_merge, bwt_merge_many, fm_index, get_matching_lines, run_bwt,
+    run_bwt_with_doc_ids, BWTData,
+};
+use bwt_merge::bwt_disk::{
+    append_lines, append_lines_with_docs, bwt_build_disk, bwt_delete_lines_disk,
+    bwt_delete_lines_disk_with_options, bwt_delete_lines_in_memory, bwt_merge_disk,
+    bwt_merge_disk_many, bwt_merge_disk_many_observed, bwt_merge_disk_many_with_options,
+    bwt_merge_disk_observed, bwt_merge_disk_resumable, bwt_merge_disk_resumable_with_options,
+    bwt_merge_disk_with_options, compress_segment, fs_operator, load_segment,
+    load_segment_blocking, memory_operator, migrate_text_segment, operator_from_config,
+    read_doc_ids, recover_output, save_segment, save_segment_blocking, verify_merge,
+    verify_segment, write_doc_ids, write_rank_index, write_segment, write_segment_compressed,
+    DiskFMIndex, MergeOptions, OutputCompression,
+};
+use bwt_merge::checkpoint::MergeCheckpoint;
+use bwt_merge::mapped::{local_path, MappedSegment};
+use bwt_merge::progress::{
+    Cancellation, Cancelled, MergeObserver, MergePhase, MergeStats, MergeStopped, NoopObserver,
+    PROGRESS_INTERVAL,
+};
+use bwt_merge::segment::{
+    decode_index_header, IdDecoder, IdEncoding, SegmentMeta, SegmentProblem, INDEX_HEADER_SIZE,
+    RANK_INTERVAL,
+};
+use bwt_merge::stream::ReadOptions;
+use opendal::Operator;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio_util::sync::CancellationToken;
+
+// relative to the crate root, since the tests use an fs operator rooted at the current directory
+const TEST_DIR: &str = "target/test_data/bwt_disk";
+
+fn random_text(rng: &mut StdRng, n: usize, max_len: usize) -> Vec<u8> {
+    let mut text = Vec::new();
+    for _ in 0..n {
+        let len = rng.gen_range(0..=max_len);
+        for _ in 0..len {
+            text.push(b"abc"[rng.gen_range(0..3)]);
+        }
+        text.push(b'\n');
+    }
+    text
+}
+
+fn operator() -> Operator {
+    fs_operator(".").unwrap()
+}
+
+fn ints_to_str(ints: &[usize]) -> String {
+    ints.iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<String>>()
+        .join("\n")
+        + "\n"
+}
+
+// Read the raw files of an uncompressed segment, without its document ids
+fn read_triplet(path: &str) -> BWTData {
+    let bwt = std::fs::read(format!("{}.bwt", path)).unwrap();
+    let index = std::fs::read(format!("{}.index", path)).unwrap();
+    let mut decoder = IdDecoder::new(decode_index_header(&index).unwrap());
+    let mut line_ids = Vec::new();
+    decoder
+        .decode(&index[INDEX_HEADER_SIZE..], &mut line_ids)
+        .unwrap();
+    let meta = SegmentMeta::decode(&std::fs::read(format!("{}.counts", path)).unwrap()).unwrap();
+    (bwt, line_ids, meta.counts, None)
+}
+
+async fn block_compression(path: &str) -> BlockCompression {
+    BlockTable::read(&operator(), &format!("{}.bwt", path))
+        .await
+        .unwrap()
+        .compression()
+}
+
+// Line ids from a DiskFMIndex, to compare with get_matching_lines
+fn line_ids(lines: BTreeSet<usize>) -> BTreeSet<u64> {
+    lines.into_iter().map(|x| x as u64).collect()
+}
+
+async fn write_triplet(path: &str, data: &BWTData) {
+    let num_lines = data.2[b'\n' as usize];
+    write_segment(&operator(), data, path, IdEncoding::fixed_for(num_lines))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn merge_disk_many() {
+    let dir = format!("{}/merge_many", TEST_DIR);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut rng = StdRng::seed_from_u64(26);
+    let mut texts = Vec::new();
+    let mut inputs = Vec::new();
+    let mut paths = Vec::new();
+    for i in 0..4 {
+        let num_lines = rng.gen_range(1..50);
+        let text = random_text(&mut rng, num_lines, 10);
+        let data = run_bwt(&text);
+        let path = format!("{}/input_{}", dir, i);
+        write_triplet(&path, &data).await;
+        texts.push(text);
+        inputs.push(data);
+        paths.push(path);
+    }
+
+    let output_path = format!("{}/merged", dir);
+    let path_strs = paths.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+    bwt_merge_disk_many(&operator(), &path_strs, &output_path)
+        .await
+        .unwrap();
+
+    let expected = bwt_merge_many(&inputs);
+    assert_eq!(read_triplet(&output_path), expected);
+    // the same as building the concatenated inputs
+    let rebuilt = run_bwt(&texts.concat());
+    assert_eq!(read_triplet(&output_path), rebuilt);
+
+    let text_path = format!("{}/concat.txt", dir);
+    std::fs::write(&text_path, texts.concat()).unwrap();
+    let (bwt_len, mismatch) = verify_merge(&operator(), &text_path, &output_path)
+        .await
+        .unwrap();
+    assert_eq!(bwt_len, expected.0.len());
+    assert!(mismatch.is_none(), "{:?}", mismatch);
+
+    // the same short lines in all but the first input, so most of their rows are tied,
+    // with the interleave in memory and on disk
+    let text = random_text(&mut rng, 300, 2);
+    let data = run_bwt(&text);
+    for (i, path) in paths.iter().enumerate().skip(1) {
+        write_triplet(path, &data).await;
+        texts[i] = text.clone();
+    }
+    let rebuilt = run_bwt(&texts.concat());
+    let read = ReadOptions {
+        buffer_size: 64,
+        buffer_count: 2,
+    };
+    let options = MergeOptions {
+        read,
+        ..Default::default()
+    };
+    let unbudgeted = bwt_merge_disk_many_with_options(
+        &operator(),
+        &path_strs,
+        &output_path,
+        &mut NoopObserver,
+        options,
+    )
+    .await
+    .unwrap();
+    assert_eq!(read_triplet(&output_path), rebuilt);
+    let stats = bwt_merge_disk_many_with_options(
+        &operator(),
+        &path_strs,
+        &output_path,
+        &mut NoopObserver,
+        MergeOptions {
+            memory_budget: Some(unbudgeted.peak_memory - 1),
+            ..options
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(read_triplet(&output_path), rebuilt);
+    assert!(stats.bytes_written > unbudgeted.bytes_written);
+}
+
+#[tokio::test]
+async fn delete_lines_in_memory() {
+    let dir = format!("{}/delete_lines", TEST_DIR);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut rng = StdRng::seed_from_u64(28);
+    let data = run_bwt(&random_text(&mut rng, 40, 10));
+    let input_path = format!("{}/input", dir);
+    write_triplet(&input_path, &data).await;
+
+    let line_ids = [0, 3, 4, 17, 39];
+    let output_path = format!("{}/deleted", dir);
+    bwt_delete_lines_in_memory(&operator(), &input_path, &line_ids, &output_path)
+        .await
+        .unwrap();
+
+    let expected = bwt_delete_lines(&data, &line_ids);
+    assert_eq!(read_triplet(&output_path), expected);
+
+    assert!(
+        bwt_delete_lines_in_memory(&operator(), &input_path, &[40], &output_path)
+            .await
+            .is_err()
+    );
+}
+
+// Lines of text other than those deleted, and their document ids
+fn remaining_lines(text: &[u8], doc_ids: &[u64], line_ids: &[usize]) -> (Vec<u8>, Vec<u64>) {
+    let kept = |i: &usize| !line_ids.contains(i);
+    let text = text
+        .split_inclusive(|&x| x == b'\n')
+        .enumerate()
+        .filter(|(i, _)| kept(i))
+        .flat_map(|(_, line)| line.to_vec())
+        .collect();
+    let doc_ids = (0..doc_ids.len())
+        .filter(kept)
+        .map(|i| doc_ids[i])
+        .collect();
+    (text, doc_ids)
+}
+
+#[tokio::test]
+async fn delete_lines_disk() {
+    let dir = format!("{}/delete_lines_disk", TEST_DIR);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut rng = StdRng::seed_from_u64(128);
+    for round in 0..20 {
+        let num_lines = rng.gen_range(1..60);
+        let mut text = random_text(&mut rng, num_lines, 3 + round % 2 * 10);
+        if round % 4 == 0 {
+            text = text.repeat(2);
+        }
+        let num_lines = text.iter().filter(|&&x| x == b'\n').count();
+        let doc_ids = (0..num_lines as u64).map(|x| x * 3 + 1).collect::<Vec<_>>();
+        let input_path = format!("{}/input", dir);
+        write_triplet(&input_path, &run_bwt(&text)).await;
+        write_doc_ids(&operator(), &input_path, &doc_ids)
+            .await
+            .unwrap();
+
+        let line_ids = (0..num_lines)
+            .filter(|_| rng.gen_bool(0.3))
+            .collect::<Vec<usize>>();
+        let (remaining_text, remaining_docs) = remaining_lines(&text, &doc_ids, &line_ids);
+        let expected = run_bwt(&remaining_text);
+
+        let output_path = format!("{}/deleted", dir);
+        let unbudgeted = bwt_delete_lines_disk(&operator(), &input_path, &line_ids, &output_path)
+            .await
+            .unwrap();
+        assert_eq!(read_triplet(&output_path), expected);
+        assert_eq!(
+            read_doc_ids(&operator(), &output_path).await.unwrap(),
+            remaining_docs
+        );
+
+        // the same with the interleave on disk
+        if unbudgeted.interleave_iterations > 0 {
+            bwt_delete_lines_disk_with_options(
+                &operator(),
+                &input_path,
+                &line_ids,
+                &output_path,
+                &mut NoopObserver,
+                MergeOptions {
+                    memory_budget: Some(unbudgeted.peak_memory - 1),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            assert_eq!(read_triplet(&output_path), expected);
+        }
+    }
+
+    let input_path = format!("{}/input", dir);
+    let num_lines = read_triplet(&input_path).2[b'\n' as usize];
+    let output_path = format!("{}/out_of_range", dir);
+    assert!(
+        bwt_delete_lines_disk(&operator(), &input_path, &[num_lines], &output_path)
+            .await
+            .is_err()
+    );
+    assert!(!std::path::Path::new(&format!("{}.bwt", output_path)).exists());
+}
+
+#[tokio::test]
+async fn delete_lines_disk_large() {
+    let dir = format!("{}/delete_lines_disk_large", TEST_DIR);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut rng = StdRng::seed_from_u64(228);
+    let text = random_text(&mut rng, 20000, 12);
+    let input_path = format!("{}/input", dir);
+    write_triplet(&input_path, &run_bwt(&text)).await;
+    let line_ids = (0..20000)
+        .filter(|_| rng.gen_bool(0.01))
+        .collect::<Vec<usize>>();
+
+    let output_path = format!("{}/deleted", dir);
+    bwt_delete_lines_disk(&operator(), &input_path, &line_ids, &output_path)
+        .await
+        .unwrap();
+    let (remaining_text, _) = remaining_lines(&text, &[], &line_ids);
+    assert_eq!(read_triplet(&output_path), run_bwt(&remaining_text));
+}
+
+#[tokio::test]
+async fn doc_ids_disk() {
+    let dir = format!("{}/doc_ids", TEST_DIR);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut rng = StdRng::seed_from_u64(29);
+    let paths = [format!("{}/input_0", dir), format!("{}/input_1", dir)];
+    let doc_ids = [vec![42, 7, 99], vec![1 << 40, 3]];
+    for (path, doc_ids) in paths.iter().zip(doc_ids.iter()) {
+        let data = run_bwt(&random_text(&mut rng, doc_ids.len(), 10));
+        write_triplet(path, &data).await;
+        write_doc_ids(&operator(), path, doc_ids).await.unwrap();
+    }
+
+    let output_path = format!("{}/merged", dir);
+    bwt_merge_disk(&operator(), &paths[0], &paths[1], &output_path)
+        .await
+        .unwrap();
+    assert_eq!(
+        read_doc_ids(&operator(), &output_path).await.unwrap(),
+        vec![42, 7, 99, 1 << 40, 3]
+    );
+
+    // deleting lines needs the exact BWT of the k-way merge
+    bwt_merge_disk_many(&operator(), &[&paths[0], &paths[1]], &output_path)
+        .await
+        .unwrap();
+    let deleted_path = format!("{}/deleted", dir);
+    bwt_delete_lines_in_memory(&operator(), &output_path, &[1, 3], &deleted_path)
+        .await
+        .unwrap();
+    assert_eq!(
+        read_doc_ids(&operator(), &deleted_path).await.unwrap(),
+        vec![42, 99, 3]
+    );
+
+    // inputs must either all have document ids or none
+    std::fs::remove_file(format!("{}.docs", paths[1])).unwrap();
+    assert!(
+        bwt_merge_disk(&operator(), &paths[0], &paths[1], &output_path)
+            .await
+            .is_err()
+    );
+}
+
+struct StopAfterFirst;
+
+impl MergeObserver for StopAfterFirst {
+    fn on_iteration(&mut self, _stats: &MergeStats) -> bool {
+        false
+    }
+}
+
+#[tokio::test]
+async fn merge_disk_stats() {
+    let dir = format!("{}/stats", TEST_DIR);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut rng = StdRng::seed_from_u64(30);
+    let paths = [format!("{}/input_0", dir), format!("{}/input_1", dir)];
+    let mut input_size = 0;
+    for path in paths.iter() {
+        let data = run_bwt(&random_text(&mut rng, 30, 10));
+        write_triplet(path, &data).await;
+        input_size += data.0.len() as u64;
+    }
+
+    let output_path = format!("{}/merged", dir);
+    let stats = bwt_merge_disk(&operator(), &paths[0], &paths[1], &output_path)
+        .await
+        .unwrap();
+    assert!(stats.interleave_iterations > 1);
+    // every pass reads both bwts, plus one more pass for the output
+    assert!(stats.bytes_read >= input_size * (stats.interleave_iterations as u64 + 1));
+    let output_size = ["bwt", "index", "counts", "rank"]
+        .iter()
+        .map(|ext| {
+            std::fs::metadata(format!("{}.{}", output_path, ext))
+                .unwrap()
+                .len()
+        })
+        .sum::<u64>();
+    assert_eq!(stats.bytes_written, output_size);
+
+    let err = bwt_merge_disk_observed(
+        &operator(),
+        &paths[0],
+        &paths[1],
+        &output_path,
+        &mut StopAfterFirst,
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(err.downcast::<MergeStopped>().unwrap(), MergeStopped);
+}
+
+#[tokio::test]
+async fn merge_read_options() {
+    let dir = format!("{}/read_options", TEST_DIR);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut rng = StdRng::seed_from_u64(41);
+    let mut inputs = Vec::new();
+    let mut paths = Vec::new();
+    for i in 0..3 {
+        let data = run_bwt(&random_text(&mut rng, 500, 10));
+        let path = format!("{}/input_{}", dir, i);
+        write_triplet(&path, &data).await;
+        inputs.push(data);
+        paths.push(path);
+    }
+    let path_strs = paths.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+
+    let output_path = format!("{}/merged", dir);
+    let mut peak_memory = Vec::new();
+    for (buffer_size, buffer_count) in [(7, 1), (7, 4), (1000, 2), (1 << 20, 3)] {
+        let options = MergeOptions {
+            read: ReadOptions {
+                buffer_size,
+                buffer_count,
+            },
+            ..Default::default()
+        };
+        let stats = bwt_merge_disk_with_options(
+            &operator(),
+            &paths[0],
+            &paths[1],
+            &output_path,
+            &mut NoopObserver,
+            options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            read_triplet(&output_path),
+            bwt_merge(&inputs[0], &inputs[1])
+        );
+        peak_memory.push(stats.peak_memory);
+
+        bwt_merge_disk_many_with_options(
+            &operator(),
+            &path_strs,
+            &output_path,
+            &mut NoopObserver,
+            options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(read_triplet(&output_path), bwt_merge_many(&inputs));
+    }
+    // the estimate counts every buffer
+    assert!(peak_memory.windows(2).all(|x| x[0] < x[1]));
+}
+
+#[tokio::test]
+async fn merge_memory_budget() {
+    let dir = format!("{}/memory_budget", TEST_DIR);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut rng = StdRng::seed_from_u64(48);
+    let mut inputs = Vec::new();
+    let mut paths = Vec::new();
+    for i in 0..3 {
+        let data = run_bwt(&random_text(&mut rng, 1000, 10));
+        let path = format!("{}/input_{}", dir, i);
+        write_triplet(&path, &data).await;
+        inputs.push(data);
+        paths.push(path);
+    }
+    let path_strs = paths.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+    let output_path = format!("{}/merged", dir);
+    let interleave_dir = format!("{}.interleave", output_path);
+    let read = ReadOptions {
+        buffer_size: 64,
+        buffer_count: 2,
+    };
+
+    let unbudgeted = bwt_merge_disk_with_options(
+        &operator(),
+        &paths[0],
+        &paths[1],
+        &output_path,
+        &mut NoopObserver,
+        MergeOptions {
+            read,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    let budget = unbudgeted.peak_memory / 2;
+    let options = MergeOptions {
+        read,
+        memory_budget: Some(budget),
+        ..Default::default()
+    };
+    let stats = bwt_merge_disk_with_options(
+        &operator(),
+        &paths[0],
+        &paths[1],
+        &output_path,
+        &mut NoopObserver,
+        options,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        read_triplet(&output_path),
+        bwt_merge(&inputs[0], &inputs[1])
+    );
+    assert!(stats.peak_memory <= budget);
+    assert_eq!(
+        stats.interleave_iterations,
+        unbudgeted.interleave_iterations
+    );
+    // each pass also reads and writes the interleave
+    assert!(stats.bytes_read > unbudgeted.bytes_read);
+    assert!(stats.bytes_written > unbudgeted.bytes_written);
+    assert!(!std::path::Path::new(&interleave_dir).exists());
+
+    // a merge of several inputs keeps its tied rows in memory either way
+    let unbudgeted = bwt_merge_disk_many_with_options(
+        &operator(),
+        &path_strs,
+        &output_path,
+        &mut NoopObserver,
+        MergeOptions {
+            read,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    let budget = unbudgeted.peak_memory / 2;
+    let stats = bwt_merge_disk_many_with_options(
+        &operator(),
+        &path_strs,
+        &output_path,
+        &mut NoopObserver,
+        MergeOptions {
+            memory_budget: Some(budget),
+            ..options
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(read_triplet(&output_path), bwt_merge_many(&inputs));
+    assert!(stats.peak_memory <= budget);
+    assert!(stats.bytes_written > unbudgeted.bytes_written);
+    assert!(!std::path::Path::new(&interleave_dir).exists());
+
+    // the interleave is removed when the merge stops too
+    let err = bwt_merge_disk_with_options(
+        &operator(),
+        &paths[0],
+        &paths[1],
+        &format!("{}/stopped", dir),
+        &mut StopAfterFirst,
+        options,
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(err.downcast::<MergeStopped>().unwrap(), MergeStopped);
+    assert!(!std::path::Path::new(&format!("{}/stopped.interleave", dir)).exists());
+
+    // a budget too small even for the read buffers
+    let options = MergeOptions {
+        read,
+        memory_budget: Some(100),
+        ..Default::default()
+    };
+    let small_path = format!("{}/small", dir);
+    assert!(bwt_merge_disk_with_options(
+        &operator(),
+        &paths[0],
+        &paths[1],
+        &small_path,
+        &mut NoopObserver,
+        options,
+    )
+    .await
+    .is_err());
+    assert!(!output_exists(&small_path));
+}
+
+fn output_exists(path: &str) -> bool {
+    ["bwt", "index", "counts", "rank", "docs"]
+        .iter()
+        .flat_map(|ext| [format!("{}.{}", path, ext), format!("{}.{}.tmp", path, ext)])
+        .any(|path| std::path::Path::new(&path).exists())
+}
+
+// Cancels the token once the output is being written
+struct CancelOnOutput(CancellationToken);
+
+impl MergeObserver for CancelOnOutput {
+    fn on_progress(&mut self, phase: MergePhase, _done: usize, _total: usize) -> bool {
+        if phase == MergePhase::Output {
+            self.0.cancel();
+        }
+        true
+    }
+}
+
+#[tokio::test]
+async fn cancel_disk() {
+    let dir = format!("{}/cancel", TEST_DIR);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut rng = StdRng::seed_from_u64(31);
+    let input_path = format!("{}/input.txt", dir);
+    let text = random_text(&mut rng, 200_000, 10);
+    std::fs::write(&input_path, &text).unwrap();
+
+    let built_path = format!("{}/built", dir);
+    bwt_build_disk(&operator(), &input_path, &built_path, &Cancellation::new())
+        .await
+        .unwrap();
+    // built segments are block-compressed by default
+    assert_eq!(
+        load_segment(&operator(), &built_path).await.unwrap().0,
+        run_bwt(&text).0
+    );
+
+    let token = CancellationToken::new();
+    token.cancel();
+    let cancel = Cancellation::new().with_token(token);
+    let cancelled_path = format!("{}/cancelled", dir);
+    let err = bwt_build_disk(&operator(), &input_path, &cancelled_path, &cancel)
+        .await
+        .unwrap_err();
+    assert_eq!(err.downcast::<Cancelled>().unwrap(), Cancelled);
+    assert!(!output_exists(&cancelled_path));
+
+    // cancelled halfway through writing the output, with document ids already written
+    write_doc_ids(&operator(), &built_path, &vec![7; 200_000])
+        .await
+        .unwrap();
+    let token = CancellationToken::new();
+    let mut observer = Cancellation::new()
+        .with_token(token.clone())
+        .observe(CancelOnOutput(token));
+    let err = bwt_merge_disk_observed(
+        &operator(),
+        &built_path,
+        &built_path,
+        &cancelled_path,
+        &mut observer,
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(err.downcast::<MergeStopped>().unwrap(), MergeStopped);
+    assert!(!output_exists(&cancelled_path));
+
+    let mut cancel = Cancellation::new().with_deadline(std::time::Instant::now());
+    let err = bwt_merge_disk_many_observed(
+        &operator(),
+        &[&built_path, &built_path],
+        &cancelled_path,
+        &mut cancel,
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(err.downcast::<MergeStopped>().unwrap(), MergeStopped);
+    assert!(!output_exists(&cancelled_path));
+
+    // inputs are left alone
+    assert!(std::path::Path::new(&format!("{}.docs", built_path)).exists());
+}
+
+#[tokio::test]
+async fn migrate_text() {
+    let dir = format!("{}/migrate", TEST_DIR);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut rng = StdRng::seed_from_u64(32);
+    let mut inputs = Vec::new();
+    let mut paths = Vec::new();
+    for i in 0..2 {
+        let data = run_bwt(&random_text(&mut rng, 300, 10));
+        let path = format!("{}/input_{}", dir, i);
+        std::fs::write(format!("{}.bwt", path), &data.0).unwrap();
+        std::fs::write(format!("{}.index", path), ints_to_str(&data.1)).unwrap();
+        std::fs::write(format!("{}.counts", path), ints_to_str(&data.2)).unwrap();
+        inputs.push(data);
+        paths.push(path);
+    }
+
+    // text segments can't be merged until they are migrated
+    let output_path = format!("{}/merged", dir);
+    let err = bwt_merge_disk(&operator(), &paths[0], &paths[1], &output_path)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("migrate"));
+
+    for (path, data) in paths.iter().zip(inputs.iter()) {
+        let text_size = std::fs::metadata(format!("{}.index", path)).unwrap().len();
+        assert!(migrate_text_segment(&operator(), path).await.unwrap());
+        assert!(!migrate_text_segment(&operator(), path).await.unwrap());
+
+        // 300 lines fit in 2 bytes per id
+        let index = std::fs::read(format!("{}.index", path)).unwrap();
+        assert!((index.len() as u64) < text_size);
+        let meta = SegmentMeta::decode(&std::fs::read(format!("{}.counts", path)).unwrap());
+        assert_eq!(meta.unwrap().num_lines, 300);
+        assert_eq!(index.len(), INDEX_HEADER_SIZE + 2 * data.1.len());
+    }
+
+    // an index that is neither text nor binary, or that is missing, isn't converted
+    let damaged_path = format!("{}/damaged", dir);
+    let counts = ints_to_str(&inputs[0].2);
+    std::fs::write(format!("{}.bwt", damaged_path), &inputs[0].0).unwrap();
+    std::fs::write(format!("{}.counts", damaged_path), &counts).unwrap();
+    std::fs::write(format!("{}.index", damaged_path), b"12\nxyz\n").unwrap();
+    let err = migrate_text_segment(&operator(), &damaged_path)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("Not a segment index file"));
+    std::fs::remove_file(format!("{}.index", damaged_path)).unwrap();
+    assert!(migrate_text_segment(&operator(), &damaged_path)
+        .await
+        .is_err());
+    assert_eq!(
+        std::fs::read(format!("{}.counts", damaged_path)).unwrap(),
+        counts.as_bytes()
+    );
+
+    bwt_merge_disk(&operator(), &paths[0], &paths[1], &output_path)
+        .await
+        .unwrap();
+    let merged = bwt_merge(&inputs[0], &inputs[1]);
+    assert_eq!(
+        std::fs::read(format!("{}.bwt", output_path)).unwrap(),
+        merged.0
+    );
+}
+
+#[tokio::test]
+async fn varint_segments() {
+    let dir = format!("{}/varint", TEST_DIR);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut rng = StdRng::seed_from_u64(32);
+    let mut inputs = Vec::new();
+    let mut paths = Vec::new();
+    for i in 0..2 {
+        let data = run_bwt(&random_text(&mut rng, 500, 10));
+        let path = format!("{}/input_{}", dir, i);
+        write_segment(&operator(), &data, &path, IdEncoding::Varint)
+            .await
+            .unwrap();
+        inputs.push(data);
+        paths.push(path);
+    }
+
+    // the output uses fixed-width ids, and deleting reads them back
+    let output_path = format!("{}/merged", dir);
+    bwt_merge_disk(&operator(), &paths[0], &paths[1], &output_path)
+        .await
+        .unwrap();
+    let deleted_path = format!("{}/deleted", dir);
+    bwt_delete_lines_in_memory(&operator(), &output_path, &[], &deleted_path)
+        .await
+        .unwrap();
+
+    let merged = bwt_merge(&inputs[0], &inputs[1]);
+    assert_eq!(
+        std::fs::read(format!("{}.bwt", deleted_path)).unwrap(),
+        merged.0
+    );
+    let index = std::fs::read(format!("{}.index", deleted_path)).unwrap();
+    assert_eq!(index.len(), INDEX_HEADER_SIZE + 2 * merged.1.len());
+}
+
+#[tokio::test]
+async fn verify_segments() {
+    let dir = format!("{}/verify", TEST_DIR);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut rng = StdRng::seed_from_u64(33);
+    let paths = [format!("{}/input_0", dir), format!("{}/input_1", dir)];
+    for path in paths.iter() {
+        let data = run_bwt(&random_text(&mut rng, 100, 10));
+        write_triplet(path, &data).await;
+        write_doc_ids(&operator(), path, &vec![1; 100])
+            .await
+            .unwrap();
+        assert_eq!(verify_segment(&operator(), path).await.unwrap(), vec![]);
+    }
+    let output_path = format!("{}/merged", dir);
+    bwt_merge_disk(&operator(), &paths[0], &paths[1], &output_path)
+        .await
+        .unwrap();
+    assert_eq!(
+        verify_segment(&operator(), &output_path).await.unwrap(),
+        vec![]
+    );
+
+    // a .bwt from a different segment
+    let mixed_path = format!("{}/mixed", dir);
+    std::fs::copy(format!("{}.bwt", paths[1]), format!("{}.bwt", mixed_path)).unwrap();
+    for ext in ["index", "counts", "docs"] {
+        std::fs::copy(
+            format!("{}.{}", paths[0], ext),
+            format!("{}.{}", mixed_path, ext),
+        )
+        .unwrap();
+    }
+    let problems = verify_segment(&operator(), &mixed_path).await.unwrap();
+    assert!(problems
+        .iter()
+        .any(|x| matches!(x, SegmentProblem::BwtChecksum { .. })));
+    assert!(problems
+        .iter()
+        .any(|x| matches!(x, SegmentProblem::CharCount { .. })));
+    let err = bwt_merge_disk(&operator(), &paths[0], &mixed_path, &output_path)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("mixed"));
+
+    // the merged index, with ids out of range for a single input
+    std::fs::copy(format!("{}.bwt", paths[0]), format!("{}.bwt", mixed_path)).unwrap();
+    let mut index = std::fs::read(format!("{}.index", output_path)).unwrap();
+    std::fs::write(format!("{}.index", mixed_path), &index).unwrap();
+    let problems = verify_segment(&operator(), &mixed_path).await.unwrap();
+    assert!(problems.contains(&SegmentProblem::IndexLength {
+        expected: std::fs::metadata(format!("{}.bwt", paths[0]))
+            .unwrap()
+            .len() as usize,
+        actual: index.len() - INDEX_HEADER_SIZE,
+    }));
+    assert!(problems
+        .iter()
+        .any(|x| matches!(x, SegmentProblem::LineIdOutOfRange { num_lines: 100, .. })));
+
+    // a .rank from a different segment
+    std::fs::copy(format!("{}.rank", paths[1]), format!("{}.rank", mixed_path)).unwrap();
+    let problems = verify_segment(&operator(), &mixed_path).await.unwrap();
+    assert!(problems.contains(&SegmentProblem::RankMismatch));
+
+    // a single flipped byte
+    let last = index.len() - 1;
+    index[last] ^= 1;
+    std::fs::write(format!("{}.index", output_path), &index).unwrap();
+    let problems = verify_segment(&operator(), &output_path).await.unwrap();
+    assert_eq!(problems.len(), 1);
+    assert!(matches!(problems[0], SegmentProblem::IndexChecksum { .. }));
+}
+
+#[tokio::test]
+async fn unterminated_last_line() {
+    let dir = format!("{}/unterminated", TEST_DIR);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // 256 lines and one without a separator, whose id 256 doesn't fit in a byte
+    let mut rng = StdRng::seed_from_u64(35);
+    let mut text = random_text(&mut rng, 256, 10);
+    text.extend_from_slice(b"abc");
+    let data = run_bwt(&text);
+    assert_eq!(data.1.iter().max(), Some(&256));
+
+    let input_path = format!("{}/input.txt", dir);
+    std::fs::write(&input_path, &text).unwrap();
+    let built_path = format!("{}/built", dir);
+    bwt_build_disk(&operator(), &input_path, &built_path, &Cancellation::new())
+        .await
+        .unwrap();
+    let written_path = format!("{}/written", dir);
+    write_triplet(&written_path, &data).await;
+    for path in [&built_path, &written_path] {
+        assert_eq!(load_segment(&operator(), path).await.unwrap(), data);
+        assert_eq!(verify_segment(&operator(), path).await.unwrap(), vec![]);
+    }
+
+    let other = run_bwt(&random_text(&mut rng, 10, 10));
+    let other_path = format!("{}/other", dir);
+    write_triplet(&other_path, &other).await;
+    let merged_path = format!("{}/merged", dir);
+    bwt_merge_disk(&operator(), &other_path, &built_path, &merged_path)
+        .await
+        .unwrap();
+    assert_eq!(
+        load_segment(&operator(), &merged_path).await.unwrap(),
+        bwt_merge(&other, &data)
+    );
+    assert_eq!(
+        verify_segment(&operator(), &merged_path).await.unwrap(),
+        vec![]
+    );
+}
+
+// Merge, delete, verify and migrate segments through an operator, which may be any service
+async fn run_on_operator(operator: &Operator, dir: &str) {
+    let mut rng = StdRng::seed_from_u64(34);
+    let mut inputs = Vec::new();
+    let mut paths = Vec::new();
+    for i in 0..3 {
+        let data = run_bwt(&random_text(&mut rng, 100, 10));
+        let path = format!("{}/input_{}", dir, i);
+        let encoding = IdEncoding::fixed_for(data.2[b'\n' as usize]);
+        write_segment(operator, &data, &path, encoding)
+            .await
+            .unwrap();
+        write_doc_ids(operator, &path, &vec![i as u64; 100])
+            .await
+            .unwrap();
+        inputs.push(data);
+        paths.push(path);
+    }
+
+    let merged_path = format!("{}/merged", dir);
+    bwt_merge_disk(operator, &paths[0], &paths[1], &merged_path)
+        .await
+        .unwrap();
+    let merged = bwt_merge(&inputs[0], &inputs[1]);
+    assert_eq!(
+        operator
+            .read(format!("{}.bwt", merged_path).as_str())
+            .await
+            .unwrap(),
+        merged.0
+    );
+    assert_eq!(
+        verify_segment(operator, &merged_path).await.unwrap(),
+        vec![]
+    );
+    assert_eq!(
+        read_doc_ids(operator, &merged_path).await.unwrap(),
+        [vec![0; 100], vec![1; 100]].concat()
+    );
+
+    let many_path = format!("{}/many", dir);
+    let path_strs = paths.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+    bwt_merge_disk_many(operator, &path_strs, &many_path)
+        .await
+        .unwrap();
+    assert_eq!(
+        operator
+            .read(format!("{}.bwt", many_path).as_str())
+            .await
+            .unwrap(),
+        bwt_merge_many(&inputs).0
+    );
+    assert_eq!(verify_segment(operator, &many_path).await.unwrap(), vec![]);
+
+    // with the interleave kept on the service
+    let budgeted_path = format!("{}/budgeted", dir);
+    let options = MergeOptions {
+        read: ReadOptions {
+            buffer_size: 16,
+            buffer_count: 2,
+        },
+        memory_budget: Some(1024),
+        ..Default::default()
+    };
+    bwt_merge_disk_many_with_options(
+        operator,
+        &path_strs,
+        &budgeted_path,
+        &mut NoopObserver,
+        options,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        operator
+            .read(format!("{}.bwt", budgeted_path).as_str())
+            .await
+            .unwrap(),
+        bwt_merge_many(&inputs).0
+    );
+    let interleave_dir = format!("{}.interleave/", budgeted_path);
+    assert!(operator
+        .list(&interleave_dir)
+        .await
+        .unwrap_or_default()
+        .is_empty());
+
+    let deleted_path = format!("{}/deleted", dir);
+    bwt_delete_lines_in_memory(operator, &merged_path, &[0, 150], &deleted_path)
+        .await
+        .unwrap();
+    assert_eq!(
+        operator
+            .read(format!("{}.bwt", deleted_path).as_str())
+            .await
+            .unwrap(),
+        bwt_delete_lines(&merged, &[0, 150]).0
+    );
+    assert_eq!(
+        verify_segment(operator, &deleted_path).await.unwrap(),
+        vec![]
+    );
+
+    // a stopped merge leaves nothing behind
+    let stopped_path = format!("{}/stopped", dir);
+    assert!(bwt_merge_disk_observed(
+        operator,
+        &paths[0],
+        &paths[1],
+        &stopped_path,
+        &mut StopAfterFirst
+    )
+    .await
+    .is_err());
+    for ext in ["bwt", "index", "counts", "rank", "docs"] {
+        let path = format!("{}.{}", stopped_path, ext);
+        assert!(!operator.is_exist(path.as_str()).await.unwrap());
+    }
+
+    // migration renames through the operator, or copies on services without rename
+    let text_path = format!("{}/text", dir);
+    let data = &inputs[2];
+    operator
+        .write(format!("{}.bwt", text_path).as_str(), data.0.clone())
+        .await
+        .unwrap();
+    operator
+        .write(
+            format!("{}.index", text_path).as_str(),
+            ints_to_str(&data.1),
+        )
+        .await
+        .unwrap();
+    operator
+        .write(
+            format!("{}.counts", text_path).as_str(),
+            ints_to_str(&data.2),
+        )
+        .await
+        .unwrap();
+    assert!(migrate_text_segment(operator, &text_path).await.unwrap());
+    assert_eq!(verify_segment(operator, &text_path).await.unwrap(), vec![]);
+    assert!(!operator
+        .is_exist(format!("{}.counts.tmp", text_path).as_str())
+        .await
+        .unwrap());
+}
+
+#[tokio::test]
+async fn fs_operator_rooted() {
+    let root = format!("{}/fs_root", TEST_DIR);
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+
+    let operator = fs_operator(&root).unwrap();
+    run_on_operator(&operator, "segments").await;
+    assert!(std::path::Path::new(&format!("{}/segments/merged.bwt", root)).exists());
+}
+
+#[tokio::test]
+async fn memory_operator_merge() {
+    let operator = memory_operator().unwrap();
+    assert!(!operator.info().full_capability().rename);
+    run_on_operator(&operator, "segments").await;
+
+    let config = HashMap::from([("root".to_string(), "/other".to_string())]);
+    let operator = operator_from_config("memory", config).unwrap();
+    run_on_operator(&operator, "segments").await;
+}
+
+// Runs against an S3-compatible service, such as a local MinIO, when
+// BWT_TEST_S3_ENDPOINT and BWT_TEST_S3_BUCKET are set. Credentials come from
+// BWT_TEST_S3_ACCESS_KEY_ID and BWT_TEST_S3_SECRET_ACCESS_KEY.
+#[tokio::test]
+async fn s3_operator_merge() {
+    let (Ok(endpoint), Ok(bucket)) = (
+        std::env::var("BWT_TEST_S3_ENDPOINT"),
+        std::env::var("BWT_TEST_S3_BUCKET"),
+    ) else {
+        return;
+    };
+    let mut config = HashMap::from([
+        ("endpoint".to_string(), endpoint),
+        ("bucket".to_string(), bucket),
+        ("region".to_string(), "us-east-1".to_string()),
+        ("root".to_string(), "/bwt_disk_test".to_string()),
+    ]);
+    for (key, var) in [
+        ("access_key_id", "BWT_TEST_S3_ACCESS_KEY_ID"),
+        ("secret_access_key", "BWT_TEST_S3_SECRET_ACCESS_KEY"),
+    ] {
+        if let Ok(value) = std::env::var(var) {
+            config.insert(key.to_string(), value);
+        }
+    }
+    let operator = operator_from_config("s3", config).unwrap();
+    run_on_operator(&operator, "segments").await;
+}
+
+// Stops the merge the first time it reports progress writing the output
+struct StopOnOutput;
+
+impl MergeObserver for StopOnOutput {
+    fn on_progress(&mut self, phase: MergePhase, _done: usize, _total: usize) -> bool {
+        phase != MergePhase::Output
+    }
+}
+
+async fn merge_resumable(
+    paths: &[String],
+    output_path: &str,
+    observer: &mut dyn MergeObserver,
+) -> anyhow::Result<MergeStats> {
+    let checkpoint_path = format!("{}.checkpoint", output_path);
+    bwt_merge_disk_resumable(
+        &operator(),
+        &paths[0],
+        &paths[1],
+        output_path,
+        &checkpoint_path,
+        observer,
+    )
+    .await
+}
+
+fn read_checkpoint(output_path: &str) -> MergeCheckpoint {
+    let data = std::fs::read(format!("{}.checkpoint", output_path)).unwrap();
+    MergeCheckpoint::decode(&data).unwrap()
+}
+
+#[tokio::test]
+async fn resumable_merge() {
+    let dir = format!("{}/resumable", TEST_DIR);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // large enough for the output to be checkpointed partway
+    let mut rng = StdRng::seed_from_u64(36);
+    let paths = [format!("{}/input_0", dir), format!("{}/input_1", dir)];
+    for path in paths.iter() {
+        write_triplet(path, &run_bwt(&random_text(&mut rng, 100_000, 10))).await;
+        write_doc_ids(&operator(), path, &vec![5; 100_000])
+            .await
+            .unwrap();
+    }
+    let expected_path = format!("{}/expected", dir);
+    let expected = bwt_merge_disk(&operator(), &paths[0], &paths[1], &expected_path)
+        .await
+        .unwrap();
+
+    // stopped after the first pass
+    let output_path = format!("{}/merged", dir);
+    let err = merge_resumable(&paths, &output_path, &mut StopAfterFirst)
+        .await
+        .unwrap_err();
+    assert_eq!(err.downcast::<MergeStopped>().unwrap(), MergeStopped);
+    let checkpoint = read_checkpoint(&output_path);
+    assert_eq!((checkpoint.iteration, checkpoint.output), (1, None));
+
+    // stopped once the interleave converged and some output was written
+    let err = merge_resumable(&paths, &output_path, &mut StopOnOutput)
+        .await
+        .unwrap_err();
+    assert_eq!(err.downcast::<MergeStopped>().unwrap(), MergeStopped);
+    let checkpoint = read_checkpoint(&output_path);
+    assert_eq!(checkpoint.iteration, expected.interleave_iterations);
+    let progress = checkpoint.output.unwrap();
+    assert_eq!(progress.position, PROGRESS_INTERVAL);
+    // the partial output is only under temporary names
+    let bwt_len = std::fs::metadata(format!("{}.bwt.tmp", output_path))
+        .unwrap()
+        .len();
+    assert_eq!(bwt_len, progress.bwt_len);
+    assert!(!std::path::Path::new(&format!("{}.bwt", output_path)).exists());
+
+    let stats = merge_resumable(&paths, &output_path, &mut NoopObserver)
+        .await
+        .unwrap();
+    assert_eq!(stats.interleave_iterations, expected.interleave_iterations);
+    for ext in ["bwt", "index", "counts", "rank", "docs"] {
+        assert_eq!(
+            std::fs::read(format!("{}.{}", output_path, ext)).unwrap(),
+            std::fs::read(format!("{}.{}", expected_path, ext)).unwrap(),
+            "{}",
+            ext
+        );
+    }
+    assert!(!std::path::Path::new(&format!("{}.checkpoint", output_path)).exists());
+
+    // a checkpoint of other inputs is ignored, and a damaged one is an error
+    let other_path = format!("{}/other", dir);
+    write_triplet(&other_path, &run_bwt(&random_text(&mut rng, 100, 10))).await;
+    write_doc_ids(&operator(), &other_path, &vec![6; 100])
+        .await
+        .unwrap();
+    let other_paths = [paths[0].clone(), other_path];
+    merge_resumable(&paths, &output_path, &mut StopAfterFirst)
+        .await
+        .unwrap_err();
+    merge_resumable(&other_paths, &output_path, &mut StopAfterFirst)
+        .await
+        .unwrap_err();
+    assert_eq!(read_checkpoint(&output_path).iteration, 1);
+    assert!(!std::path::Path::new(&format!("{}.checkpoint.tmp", output_path)).exists());
+    std::fs::write(format!("{}.checkpoint", output_path), b"BWTK garbage").unwrap();
+    merge_resumable(&other_paths, &output_path, &mut NoopObserver)
+        .await
+        .unwrap_err();
+    std::fs::remove_file(format!("{}.checkpoint", output_path)).unwrap();
+    merge_resumable(&other_paths, &output_path, &mut NoopObserver)
+        .await
+        .unwrap();
+    assert_eq!(
+        verify_segment(&operator(), &output_path).await.unwrap(),
+        vec![]
+    );
+}
+
+#[tokio::test]
+async fn resumable_merge_memory() {
+    // without append, only the interleave is checkpointed
+    let operator = memory_operator().unwrap();
+    let mut rng = StdRng::seed_from_u64(37);
+    let mut inputs = Vec::new();
+    for i in 0..2 {
+        let data = run_bwt(&random_text(&mut rng, 200, 10));
+        let encoding = IdEncoding::fixed_for(200);
+        write_segment(&operator, &data, &format!("input_{}", i), encoding)
+            .await
+            .unwrap();
+        inputs.push(data);
+    }
+
+    assert!(bwt_merge_disk_resumable(
+        &operator,
+        "input_0",
+        "input_1",
+        "merged",
+        "merged.checkpoint",
+        &mut StopAfterFirst,
+    )
+    .await
+    .is_err());
+    let checkpoint = operator.read("merged.checkpoint").await.unwrap();
+    assert_eq!(MergeCheckpoint::decode(&checkpoint).unwrap().iteration, 1);
+
+    let stats = bwt_merge_disk_resumable(
+        &operator,
+        "input_0",
+        "input_1",
+        "merged",
+        "merged.checkpoint",
+        &mut NoopObserver,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        operator.read("merged.bwt").await.unwrap(),
+        bwt_merge(&inputs[0], &inputs[1]).0
+    );
+    assert!(stats.interleave_iterations > 1);
+    assert!(!operator.is_exist("merged.checkpoint").await.unwrap());
+}
+
+// Lines of a text containing the pattern
+fn naive_matching_lines(text: &[u8], pattern: &[u8]) -> Vec<usize> {
+    text.split(|&x| x == b'\n')
+        .take(text.iter().filter(|&&x| x == b'\n').count())
+        .enumerate()
+        .filter(|(_, line)| pattern.is_empty() || line.windows(pattern.len()).any(|x| x == pattern))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+#[tokio::test]
+async fn disk_fm_index() {
+    let dir = format!("{}/disk_fm_index", TEST_DIR);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut rng = StdRng::seed_from_u64(36);
+    let mut paths = Vec::new();
+    let mut inputs = Vec::new();
+    for (i, encoding) in [IdEncoding::Fixed(2), IdEncoding::Varint]
+        .into_iter()
+        .enumerate()
+    {
+        let text = random_text(&mut rng, 10000, 10);
+        let data = run_bwt_with_doc_ids(&text, (0..10000).map(|x| x / 3).collect());
+        let path = format!("{}/input_{}", dir, i);
+        write_segment(&operator(), &data, &path, encoding)
+            .await
+            .unwrap();
+        paths.push(path);
+        inputs.push(data);
+    }
+    let merged_path = format!("{}/merged", dir);
+    bwt_merge_disk(&operator(), &paths[0], &paths[1], &merged_path)
+        .await
+        .unwrap();
+    let merged = bwt_merge(&inputs[0], &inputs[1]);
+    paths.push(merged_path);
+    inputs.push(merged);
+
+    let patterns: &[&[u8]] = &[
+        b"", b"a", b"ab", b"cab", b"abcabc", b"\nab", b"c\n", b"z", b"az",
+    ];
+    for (path, data) in paths.iter().zip(inputs.iter()) {
+        let without_docs = (data.0.clone(), data.1.clone(), data.2, None);
+        let blocks = fm_index(data);
+        let index = DiskFMIndex::open(&operator(), path).await.unwrap();
+        assert!(index.memory_size() < data.0.len() / 8);
+        for &pattern in patterns.iter() {
+            assert_eq!(
+                line_ids(index.matching_lines(pattern).await.unwrap()),
+                get_matching_lines(&without_docs, &blocks, pattern),
+                "{} {:?}",
+                path,
+                pattern
+            );
+            assert_eq!(
+                index.matching_docs(pattern).await.unwrap(),
+                get_matching_lines(data, &blocks, pattern)
+            );
+        }
+        for _ in 0..20 {
+            let len = rng.gen_range(1..8);
+            let pattern = (0..len)
+                .map(|_| b"abc\n"[rng.gen_range(0..4)])
+                .collect::<Vec<u8>>();
+            assert_eq!(
+                line_ids(index.matching_lines(&pattern).await.unwrap()),
+                get_matching_lines(&without_docs, &blocks, &pattern)
+            );
+        }
+    }
+
+    // a BWT ending exactly on a rank checkpoint
+    let text = b"abc\n".repeat(4096);
+    let path = format!("{}/aligned", dir);
+    let data = run_bwt(&text);
+    assert_eq!(data.0.len() % RANK_INTERVAL, 0);
+    write_triplet(&path, &data).await;
+    let index = DiskFMIndex::open(&operator(), &path).await.unwrap();
+    for pattern in [&b"bc"[..], b"abc", b"", b"cc", b"d"] {
+        assert_eq!(
+            index
+                .matching_lines(pattern)
+                .await
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<usize>>(),
+            naive_matching_lines(&text, pattern),
+            "{:?}",
+            pattern
+        );
+    }
+
+    // segments written without a .rank need one before they can be queried
+    std::fs::remove_file(format!("{}.rank", path)).unwrap();
+    let err = DiskFMIndex::open(&operator(), &path).await.err().unwrap();
+    assert!(err.to_string().contains("write_rank_index"));
+    write_rank_index(&operator(), &path).await.unwrap();
+    assert_eq!(verify_segment(&operator(), &path).await.unwrap(), vec![]);
+    let index = DiskFMIndex::open(&operator(), &path).await.unwrap();
+    assert_eq!(index.matching_lines(b"ab").await.unwrap().len(), 4096);
+}
+
+#[tokio::test]
+async fn compressed_segments() {
+    let dir = format!("{}/compressed", TEST_DIR);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    // small blocks, so queries and merges cross many of them, and a level other than
+    // the default, to check that outputs keep it
+    let compression = BlockCompression {
+        level: 5,
+        block_size: 4096,
+    };
+
+    let mut rng = StdRng::seed_from_u64(40);
+    let mut inputs = Vec::new();
+    let mut paths = Vec::new();
+    for i in 0..3 {
+        let data = run_bwt(&random_text(&mut rng, 3000, 10));
+        let path = format!("{}/input_{}", dir, i);
+        let encoding = IdEncoding::fixed_for(data.2[b'\n' as usize]);
+        // the middle input is left uncompressed
+        if i == 1 {
+            write_segment(&operator(), &data, &path, encoding)
+                .await
+                .unwrap();
+        } else {
+            write_segment_compressed(&operator(), &data, &path, encoding, compression)
+                .await
+                .unwrap();
+        }
+        write_doc_ids(&operator(), &path, &vec![i as u64; 3000])
+            .await
+            .unwrap();
+        assert_eq!(verify_segment(&operator(), &path).await.unwrap(), vec![]);
+        inputs.push(data);
+        paths.push(path);
+    }
+    let compressed_len = std::fs::metadata(format!("{}.bwt", paths[0]))
+        .unwrap()
+        .len();
+    assert!(compressed_len < inputs[0].0.len() as u64 / 2);
+
+    // merges of compressed inputs are compressed, and read back the same once decompressed
+    let read_decompressed = |path: String| async move {
+        let plain_path = format!("{}_plain", path);
+        compress_segment(&operator(), &path, &plain_path, None)
+            .await
+            .unwrap();
+        read_triplet(&plain_path)
+    };
+    let merged_path = format!("{}/merged", dir);
+    bwt_merge_disk(&operator(), &paths[0], &paths[1], &merged_path)
+        .await
+        .unwrap();
+    let meta =
+        SegmentMeta::decode(&std::fs::read(format!("{}.counts", merged_path)).unwrap()).unwrap();
+    assert!(meta.compressed);
+    assert_eq!(
+        verify_segment(&operator(), &merged_path).await.unwrap(),
+        vec![]
+    );
+    let merged = bwt_merge(&inputs[0], &inputs[1]);
+    assert_eq!(read_decompressed(merged_path.clone()).await, merged);
+    assert_eq!(block_compression(&merged_path).await, compression);
+
+    // other output compression on request
+    let plain_path = format!("{}/merged_uncompressed", dir);
+    let options = MergeOptions {
+        compression: OutputCompression::Uncompressed,
+        ..Default::default()
+    };
+    bwt_merge_disk_with_options(
+        &operator(),
+        &paths[0],
+        &paths[1],
+        &plain_path,
+        &mut NoopObserver,
+        options,
+    )
+    .await
+    .unwrap();
+    assert_eq!(read_triplet(&plain_path), merged);
+    let other_compression = BlockCompression {
+        level: 1,
+        block_size: 1000,
+    };
+    let recompressed_merge_path = format!("{}/merged_recompressed", dir);
+    bwt_merge_disk_with_options(
+        &operator(),
+        &paths[0],
+        &paths[1],
+        &recompressed_merge_path,
+        &mut NoopObserver,
+        MergeOptions {
+            compression: OutputCompression::Blocks(other_compression),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        block_compression(&recompressed_merge_path).await,
+        other_compression
+    );
+    assert_eq!(read_decompressed(recompressed_merge_path).await, merged);
+
+    // resumable merges can't append to compressed output, so they must be asked not to
+    let checkpoint_path = format!("{}/checkpoint", dir);
+    let resumed_path = format!("{}/resumed", dir);
+    assert!(bwt_merge_disk_resumable(
+        &operator(),
+        &paths[0],
+        &paths[1],
+        &resumed_path,
+        &checkpoint_path,
+        &mut NoopObserver,
+    )
+    .await
+    .is_err());
+    bwt_merge_disk_resumable_with_options(
+        &operator(),
+        &paths[0],
+        &paths[1],
+        &resumed_path,
+        &checkpoint_path,
+        &mut NoopObserver,
+        options,
+    )
+    .await
+    .unwrap();
+    assert_eq!(read_triplet(&resumed_path), merged);
+
+    let many_path = format!("{}/merged_many", dir);
+    let path_strs = paths.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+    bwt_merge_disk_many(&operator(), &path_strs, &many_path)
+        .await
+        .unwrap();
+    assert_eq!(
+        read_decompressed(many_path.clone()).await,
+        bwt_merge_many(&inputs)
+    );
+    assert_eq!(block_compression(&many_path).await, compression);
+
+    // queries read only the blocks they need
+    let blocks = fm_index(&merged);
+    let index = DiskFMIndex::open(&operator(), &merged_path).await.unwrap();
+    let mut with_docs = merged.clone();
+    with_docs.3 = Some([vec![0; 3000], vec![1; 3000]].concat());
+    for pattern in [&b"a"[..], b"abc", b"cab\n", b"\nbb", b"z"] {
+        assert_eq!(
+            line_ids(index.matching_lines(pattern).await.unwrap()),
+            get_matching_lines(&merged, &blocks, pattern)
+        );
+        assert_eq!(
+            index.matching_docs(pattern).await.unwrap(),
+            get_matching_lines(&with_docs, &blocks, pattern)
+        );
+    }
+
+    // compressing a plain segment, and deleting lines from a compressed one
+    let recompressed_path = format!("{}/recompressed", dir);
+    compress_segment(
+        &operator(),
+        &paths[1],
+        &recompressed_path,
+        Some(compression),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        verify_segment(&operator(), &recompressed_path)
+            .await
+            .unwrap(),
+        vec![]
+    );
+    assert_eq!(read_decompressed(recompressed_path).await, inputs[1]);
+    let deleted_path = format!("{}/deleted", dir);
+    bwt_delete_lines_in_memory(&operator(), &paths[0], &[0, 5, 2999], &deleted_path)
+        .await
+        .unwrap();
+    assert_eq!(block_compression(&deleted_path).await, compression);
+    assert_eq!(
+        read_decompressed(deleted_path).await,
+        bwt_delete_lines(&inputs[0], &[0, 5, 2999])
+    );
+
+    // damaged compressed files are reported, not decoded
+    let bwt_path = format!("{}.bwt", paths[2]);
+    let mut bwt = std::fs::read(&bwt_path).unwrap();
+    let len = bwt.len();
+    bwt[len - 8] ^= 1;
+    std::fs::write(&bwt_path, &bwt).unwrap();
+    let index_path = format!("{}.index", paths[2]);
+    let index = std::fs::read(&index_path).unwrap();
+    std::fs::write(&index_path, &index[..index.len() / 2]).unwrap();
+    let problems = verify_segment(&operator(), &paths[2]).await.unwrap();
+    assert!(problems
+        .iter()
+        .any(|x| matches!(x, SegmentProblem::BwtChecksum { .. })));
+    assert!(problems
+        .iter()
+        .any(|x| matches!(x, SegmentProblem::Undecodable { file: ".bwt", .. })));
+    assert!(problems
+        .iter()
+        .any(|x| matches!(x, SegmentProblem::Undecodable { file: ".index", .. })));
+}
+
+#[tokio::test]
+async fn save_load_segments() {
+    let dir = format!("{}/save_load", TEST_DIR);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut rng = StdRng::seed_from_u64(43);
+    let mut inputs = Vec::new();
+    let mut paths = Vec::new();
+    for (i, num_lines) in [1, 255, 256, 1000].into_iter().enumerate() {
+        let data = run_bwt(&random_text(&mut rng, num_lines, 10));
+        let path = format!("{}/input_{}", dir, i);
+        save_segment(&operator(), &data, &path).await.unwrap();
+        assert_eq!(load_segment(&operator(), &path).await.unwrap(), data);
+        inputs.push(data);
+        paths.push(path);
+    }
+
+    // line ids wider than the number of lines still round-trip
+    let mut sparse = inputs[2].clone();
+    for line_id in sparse.1.iter_mut() {
+        *line_id += 1 << 40;
+    }
+    let sparse_path = format!("{}/sparse", dir);
+    save_segment(&operator(), &sparse, &sparse_path)
+        .await
+        .unwrap();
+    assert_eq!(
+        load_segment(&operator(), &sparse_path).await.unwrap(),
+        sparse
+    );
+
+    // compressed segments load decompressed
+    let compressed_path = format!("{}/compressed", dir);
+    compress_segment(
+        &operator(),
+        &paths[3],
+        &compressed_path,
+        Some(BlockCompression::default()),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        load_segment(&operator(), &compressed_path).await.unwrap(),
+        inputs[3]
+    );
+
+    // in-memory and disk merges mixed
+    let loaded = [
+        load_segment(&operator(), &paths[0]).await.unwrap(),
+        load_segment(&operator(), &paths[1]).await.unwrap(),
+    ];
+    let memory_path = format!("{}/memory_merged", dir);
+    save_segment(&operator(), &bwt_merge_many(&loaded), &memory_path)
+        .await
+        .unwrap();
+    let disk_path = format!("{}/disk_merged", dir);
+    bwt_merge_disk_many(
+        &operator(),
+        &[&memory_path, &paths[2], &paths[3]],
+        &disk_path,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        load_segment(&operator(), &disk_path).await.unwrap(),
+        bwt_merge_many(&inputs)
+    );
+    assert!(load_segment(&operator(), &format!("{}/missing", dir))
+        .await
+        .is_err());
+}
+
+#[test]
+fn save_load_segments_blocking() {
+    let dir = format!("{}/save_load_blocking", TEST_DIR);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut rng = StdRng::seed_from_u64(44);
+    let data = run_bwt(&random_text(&mut rng, 500, 10));
+    let path = format!("{}/segment", dir);
+    save_segment_blocking(&operator(), &data, &path).unwrap();
+    assert_eq!(load_segment_blocking(&operator(), &path).unwrap(), data);
+    assert_eq!(read_triplet(&path), data);
+}
+
+#[tokio::test]
+async fn mapped_segments() {
+    let dir = format!("{}/mapped", TEST_DIR);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut rng = StdRng::seed_from_u64(45);
+    let memory = memory_operator().unwrap();
+    let encodings = [
+        IdEncoding::Fixed(3),
+        IdEncoding::Varint,
+        IdEncoding::Fixed(1),
+    ];
+    for (i, encoding) in encodings.into_iter().enumerate() {
+        // the last segment is empty
+        let num_lines = if i == 2 { 0 } else { 5000 };
+        let data = run_bwt(&random_text(&mut rng, num_lines, 10));
+        let path = format!("{}/input_{}", dir, i);
+        write_segment(&operator(), &data, &path, encoding)
+            .await
+            .unwrap();
+        write_segment(&memory, &data, &path, encoding)
+            .await
+            .unwrap();
+
+        // local segments are mapped, with the same data as on disk
+        let meta =
+            SegmentMeta::decode(&std::fs::read(format!("{}.counts", path)).unwrap()).unwrap();
+        let mapped = MappedSegment::open(&operator(), &path, &meta)
+            .unwrap()
+            .unwrap();
+        assert_eq!(mapped.bwt(), &data.0[..]);
+        assert_eq!(mapped.encoding(), encoding);
+        let len = data.1.len();
+        assert_eq!(mapped.line_ids(0, len).unwrap(), data.1);
+        if len > 0 {
+            assert_eq!(
+                mapped.line_ids(len / 3, len / 2).unwrap(),
+                data.1[len / 3..len / 2]
+            );
+        }
+        assert!(mapped.line_ids(0, len + 1).is_err());
+        assert!(MappedSegment::open(&memory, &path, &meta)
+            .unwrap()
+            .is_none());
+
+        // queries on mapped segments match those read through opendal
+        let blocks = fm_index(&data);
+        let mapped_index = DiskFMIndex::open(&operator(), &path).await.unwrap();
+        let memory_index = DiskFMIndex::open(&memory, &path).await.unwrap();
+        assert!(mapped_index.is_mapped());
+        assert!(!memory_index.is_mapped());
+        for pattern in [&b"a"[..], b"abc", b"cab\n", b"\nbb", b"z", b""] {
+            let expected = if data.0.is_empty() {
+                Default::default()
+            } else {
+                get_matching_lines(&data, &blocks, pattern)
+            };
+            assert_eq!(
+                line_ids(mapped_index.matching_lines(pattern).await.unwrap()),
+                expected
+            );
+            assert_eq!(
+                line_ids(memory_index.matching_lines(pattern).await.unwrap()),
+                expected
+            );
+        }
+    }
+
+    // compressed segments aren't mapped
+    let compressed_path = format!("{}/compressed", dir);
+    compress_segment(
+        &operator(),
+        &format!("{}/input_0", dir),
+        &compressed_path,
+        Some(BlockCompression::default()),
+    )
+    .await
+    .unwrap();
+    let index = DiskFMIndex::open(&operator(), &compressed_path)
+        .await
+        .unwrap();
+    assert!(!index.is_mapped());
+    let local = local_path(&operator(), &compressed_path).unwrap();
+    assert!(local.is_absolute());
+    assert!(local_path(&memory, &compressed_path).is_none());
+
+    // merges read mapped inputs
+    let merged_path = format!("{}/merged", dir);
+    let inputs = [format!("{}/input_0", dir), format!("{}/input_1", dir)];
+    bwt_merge_disk(&operator(), &inputs[0], &inputs[1], &merged_path)
+        .await
+        .unwrap();
+    bwt_merge_disk(&memory, &inputs[0], &inputs[1], &merged_path)
+        .await
+        .unwrap();
+    assert_eq!(
+        load_segment(&operator(), &merged_path).await.unwrap(),
+        load_segment(&memory, &merged_path).await.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn append_to_segment() {
+    let dir = format!("{}/append", TEST_DIR);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut rng = StdRng::seed_from_u64(46);
+    let text = random_text(&mut rng, 300, 10);
+    let path = format!("{}/segment", dir);
+    let mut expected = run_bwt(&text);
+    save_segment(&operator(), &expected, &path).await.unwrap();
+
+    for num_lines in [1, 200] {
+        let batch = random_text(&mut rng, num_lines, 10);
+        let lines = batch
+            .split_inclusive(|&x| x == b'\n')
+            .map(|x| x[..x.len() - 1].to_vec())
+            .collect::<Vec<Vec<u8>>>();
+        append_lines(&operator(), &path, &lines).await.unwrap();
+        expected = bwt_merge(&expected, &run_bwt(&batch));
+        assert_eq!(load_segment(&operator(), &path).await.unwrap(), expected);
+        assert_eq!(verify_segment(&operator(), &path).await.unwrap(), vec![]);
+    }
+    // the new lines come after the existing ones
+    append_lines(&operator(), &path, &[b"zzz".to_vec()])
+        .await
+        .unwrap();
+    let index = DiskFMIndex::open(&operator(), &path).await.unwrap();
+    assert_eq!(
+        index.matching_lines(b"zzz").await.unwrap(),
+        [501].into_iter().collect()
+    );
+    let names = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|x| x.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<String>>();
+    assert!(names.iter().all(|x| !x.contains("append")), "{:?}", names);
+
+    // nothing changes when appending fails
+    let before = load_segment(&operator(), &path).await.unwrap();
+    assert!(append_lines(&operator(), &path, &[b"a\nb".to_vec()])
+        .await
+        .is_err());
+    assert!(
+        append_lines_with_docs(&operator(), &path, &[b"a".to_vec()], &[1])
+            .await
+            .is_err()
+    );
+    assert_eq!(load_segment(&operator(), &path).await.unwrap(), before);
+    assert!(
+        append_lines(&operator(), &format!("{}/missing", dir), &[b"a".to_vec()])
+            .await
+            .is_err()
+    );
+
+    // document ids, on a service without renames
+    let memory = memory_operator().unwrap();
+    let data = run_bwt(&b"abc\nbca\n".to_vec());
+    save_segment(&memory, &data, "docs").await.unwrap();
+    write_doc_ids(&memory, "docs", &[10, 20]).await.unwrap();
+    assert!(
+        append_lines_with_docs(&memory, "docs", &[b"cab".to_vec()], &[1, 2])
+            .await
+            .is_err()
+    );
+    append_lines_with_docs(&memory, "docs", &[b"cab".to_vec()], &[30])
+        .await
+        .unwrap();
+    assert_eq!(
+        read_doc_ids(&memory, "docs").await.unwrap(),
+        vec![10, 20, 30]
+    );
+    let index = DiskFMIndex::open(&memory, "docs").await.unwrap();
+    assert_eq!(
+        index.matching_docs(b"ab").await.unwrap(),
+        [10, 30].into_iter().collect()
+    );
+}
+
+#[tokio::test]
+async fn committed_outputs() {
+    let dir = format!("{}/committed", TEST_DIR);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut rng = StdRng::seed_from_u64(47);
+    let mut inputs = Vec::new();
+    let mut paths = Vec::new();
+    for i in 0..2 {
+        let data = run_bwt(&random_text(&mut rng, 200, 10));
+        let path = format!("{}/input_{}", dir, i);
+        save_segment(&operator(), &data, &path).await.unwrap();
+        inputs.push(data);
+        paths.push(path);
+    }
+    let merged_path = format!("{}/merged", dir);
+    bwt_merge_disk(&operator(), &paths[0], &paths[1], &merged_path)
+        .await
+        .unwrap();
+    let merged = bwt_merge(&inputs[0], &inputs[1]);
+    // only the committed files are left
+    let mut names = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|x| x.unwrap().file_name().into_string().unwrap())
+        .filter(|x| x.starts_with("merged"))
+        .collect::<Vec<String>>();
+    names.sort();
+    assert_eq!(
+        names,
+        ["merged.bwt", "merged.counts", "merged.index", "merged.rank"]
+    );
+    assert!(!recover_output(&operator(), &merged_path).await.unwrap());
+
+    // a crash while writing leaves the old segment as it was
+    let exts = ["bwt", "index", "counts", "rank"];
+    for ext in exts.iter() {
+        std::fs::copy(
+            format!("{}.{}", merged_path, ext),
+            format!("{}.{}.tmp", paths[0], ext),
+        )
+        .unwrap();
+    }
+    assert_eq!(
+        load_segment(&operator(), &paths[0]).await.unwrap(),
+        inputs[0]
+    );
+
+    // a crash after committing, with some files renamed, is refused by readers and
+    // left as it is until the commit is finished
+    std::fs::rename(format!("{}.bwt.tmp", paths[0]), format!("{}.bwt", paths[0])).unwrap();
+    let listed = exts
+        .iter()
+        .map(|ext| format!("{}.{}", paths[0], ext))
+        .collect::<Vec<String>>();
+    std::fs::write(format!("{}.commit", paths[0]), listed.join("\n")).unwrap();
+    assert!(load_segment(&operator(), &paths[0]).await.is_err());
+    assert!(verify_segment(&operator(), &paths[0]).await.is_err());
+    assert!(std::path::Path::new(&format!("{}.index.tmp", paths[0])).exists());
+    assert!(recover_output(&operator(), &paths[0]).await.unwrap());
+    assert_eq!(load_segment(&operator(), &paths[0]).await.unwrap(), merged);
+    assert_eq!(
+        verify_segment(&operator(), &paths[0]).await.unwrap(),
+        vec![]
+    );
+    for path in listed.iter() {
+        assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+    }
+    assert!(!std::path::Path::new(&format!("{}.commit", paths[0])).exists());
+
+    // the next write of a segment finishes an interrupted commit of it first
+    for ext in exts.iter() {
+        std::fs::copy(
+            format!("{}.{}", merged_path, ext),
+            format!("{}.{}.tmp", paths[1], ext),
+        )
+        .unwrap();
+    }
+    std::fs::rename(format!("{}.bwt.tmp", paths[1]), format!("{}.bwt", paths[1])).unwrap();
+    let listed = exts
+        .iter()
+        .map(|ext| format!("{}.{}", paths[1], ext))
+        .collect::<Vec<String>>();
+    std::fs::write(format!("{}.commit", paths[1]), listed.join("\n")).unwrap();
+    write_rank_index(&operator(), &paths[1]).await.unwrap();
+    assert!(!std::path::Path::new(&format!("{}.commit", paths[1])).exists());
+    assert_eq!(load_segment(&operator(), &paths[1]).await.unwrap(), merged);
+}
@@ -0,0 +1,41 @@
+use bit_vec::BitVec;
+use bwt_merge::checkpoint::{MergeCheckpoint, OutputProgress};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+#[test]
+fn checkpoint_round_trip() {
+    let mut rng = StdRng::seed_from_u64(36);
+    for len in [0, 1, 7, 8, 9, 1000] {
+        let mut interleave = BitVec::from_elem(len, false);
+        for i in 0..len {
+            interleave.set(i, rng.gen());
+        }
+        let output = if len % 2 == 0 {
+            None
+        } else {
+            Some(OutputProgress {
+                position: len / 2,
+                bwt_len: 1 << 33,
+                bwt_crc: rng.gen(),
+                index_len: 12345,
+                index_crc: rng.gen(),
+            })
+        };
+        let checkpoint = MergeCheckpoint {
+            inputs: rng.gen(),
+            iteration: len + 3,
+            interleave,
+            output,
+        };
+        let data = checkpoint.encode();
+        assert_eq!(MergeCheckpoint::decode(&data).unwrap(), checkpoint);
+
+        // damage is detected
+        let mut damaged = data.clone();
+        let last = damaged.len() - 5;
+        damaged[last] ^= 1;
+        assert!(MergeCheckpoint::decode(&damaged).is_err());
+        assert!(MergeCheckpoint::decode(&data[..data.len() - 1]).is_err());
+    }
+    assert!(MergeCheckpoint::decode(b"not a checkpoint").is_err());
+}
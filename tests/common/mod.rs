@@ -0,0 +1,13 @@
+use rand::{rngs::StdRng, Rng};
+
+// Sorted random strings over a small alphabet, so many share prefixes, some repeated
+pub fn random_strs(rng: &mut StdRng, n: usize, max_len: usize) -> Vec<Vec<u8>> {
+    let mut strs = (0..n)
+        .map(|_| {
+            let len = rng.gen_range(0..=max_len);
+            (0..len).map(|_| b"abc"[rng.gen_range(0..3)]).collect()
+        })
+        .collect::<Vec<Vec<u8>>>();
+    strs.sort();
+    strs
+}
@@ -0,0 +1,73 @@
+mod common;
+
+use bwt_merge::louds::{LoudsTrie, RankSelect};
+use bwt_merge::trie::BinaryTrieNode;
+use common::random_strs;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+#[test]
+fn rank_select() {
+    let mut rng = StdRng::seed_from_u64(50);
+    for (len, density) in [
+        (0, 0.5),
+        (1, 1.0),
+        (64, 0.5),
+        (512, 0.0),
+        (3000, 0.1),
+        (5000, 0.9),
+    ] {
+        let bits = (0..len).map(|_| rng.gen_bool(density)).collect::<Vec<_>>();
+        let rank_select = RankSelect::new(bits.iter().copied());
+        assert_eq!(rank_select.len(), len);
+
+        let mut ones = Vec::new();
+        for (i, &bit) in bits.iter().enumerate() {
+            assert_eq!(rank_select.get(i), bit);
+            assert_eq!(rank_select.rank1(i), ones.len());
+            if bit {
+                ones.push(i);
+            }
+        }
+        assert_eq!(rank_select.rank1(len), ones.len());
+        assert_eq!(rank_select.count_ones(), ones.len());
+        for (k, &position) in ones.iter().enumerate() {
+            assert_eq!(rank_select.select1(k), Some(position));
+        }
+        assert_eq!(rank_select.select1(ones.len()), None);
+
+        // the rank directory is rebuilt when deserializing
+        let serialized = bincode::serialize(&rank_select).unwrap();
+        let deserialized: RankSelect = bincode::deserialize(&serialized).unwrap();
+        assert_eq!(deserialized, rank_select);
+    }
+}
+
+#[test]
+fn louds_matches_boxed() {
+    let mut rng = StdRng::seed_from_u64(51);
+    for extra_bits in [0, 8] {
+        let strs = random_strs(&mut rng, 500, 7);
+        let inds: Vec<Vec<usize>> = (0..strs.len())
+            .map(|x| vec![x; rng.gen_range(0..3)])
+            .collect();
+        let boxed = BinaryTrieNode::build_extra(&strs, &inds, extra_bits);
+        let louds = LoudsTrie::from(&boxed);
+
+        let queries = random_strs(&mut rng, 200, 7);
+        for query in strs.iter().chain(queries.iter()) {
+            assert_eq!(louds.query(query), boxed.query(query));
+        }
+
+        let serialized = bincode::serialize(&louds).unwrap();
+        let deserialized: LoudsTrie<usize> = bincode::deserialize(&serialized).unwrap();
+        assert_eq!(deserialized, louds);
+        // a few bits per node besides the postings
+        let postings_size = bincode::serialize(&inds.concat()).unwrap().len();
+        assert_eq!(serialized.len() - postings_size, louds.structure_size());
+        assert!(louds.structure_size() * 8 < 4 * louds.num_nodes());
+    }
+
+    let louds = LoudsTrie::from(&BinaryTrieNode::<usize>::new());
+    assert_eq!(louds.num_nodes(), 1);
+    assert_eq!(louds.query(b"abc"), Vec::<usize>::new());
+}
@@ -0,0 +1,257 @@
+use std::collections::BTreeSet;
+
+use bwt_merge::bwt_disk::{fs_operator, memory_operator, verify_segment, DiskFMIndex};
+use bwt_merge::lsm::{CompactionPolicy, Manifest, SegmentDir, SegmentInfo};
+use opendal::Operator;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+// relative to the crate root, since the tests use an fs operator rooted at the current directory
+const TEST_DIR: &str = "target/test_data/lsm";
+
+fn random_lines(rng: &mut StdRng, n: usize) -> Vec<u8> {
+    let mut text = Vec::new();
+    for _ in 0..n {
+        for _ in 0..rng.gen_range(0..=10) {
+            text.push(b"abc"[rng.gen_range(0..3)]);
+        }
+        text.push(b'\n');
+    }
+    text
+}
+
+// Lines of the directory matching a pattern, from each segment on disk
+async fn matching_lines(dir: &SegmentDir, operator: &Operator, pattern: &[u8]) -> BTreeSet<usize> {
+    let mut lines = BTreeSet::new();
+    for (segment, offset) in dir.segments().iter().zip(dir.line_offsets()) {
+        let index = DiskFMIndex::open(operator, &dir.segment_path(segment.id))
+            .await
+            .unwrap();
+        lines.extend(
+            index
+                .matching_lines(pattern)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|x| x + offset),
+        );
+    }
+    lines
+}
+
+fn naive_matching_lines(text: &[u8], pattern: &[u8]) -> BTreeSet<usize> {
+    text.split(|&x| x == b'\n')
+        .take(text.iter().filter(|&&x| x == b'\n').count())
+        .enumerate()
+        .filter(|(_, line)| line.windows(pattern.len()).any(|x| x == pattern))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+#[test]
+fn manifest_round_trip() {
+    let manifest = Manifest {
+        generation: 7,
+        next_id: 12,
+        has_docs: Some(true),
+        segments: vec![
+            SegmentInfo {
+                id: 3,
+                bwt_len: 1 << 33,
+                num_lines: 100,
+            },
+            SegmentInfo {
+                id: 11,
+                bwt_len: 5,
+                num_lines: 1,
+            },
+        ],
+    };
+    let data = manifest.encode();
+    assert_eq!(Manifest::decode(&data).unwrap(), manifest);
+
+    let mut damaged = data.clone();
+    damaged[30] ^= 1;
+    assert!(Manifest::decode(&damaged)
+        .unwrap_err()
+        .to_string()
+        .contains("checksum"));
+    assert!(Manifest::decode(&data[..data.len() - 1]).is_err());
+
+    let empty = Manifest {
+        has_docs: None,
+        segments: Vec::new(),
+        ..manifest
+    };
+    assert_eq!(Manifest::decode(&empty.encode()).unwrap(), empty);
+}
+
+#[test]
+fn size_tiered_pick() {
+    let policy = CompactionPolicy {
+        fan_in: 3,
+        base_len: 100,
+    };
+    assert_eq!(policy.tier(0), 0);
+    assert_eq!(policy.tier(100), 0);
+    assert_eq!(policy.tier(101), 1);
+    assert_eq!(policy.tier(300), 1);
+    assert_eq!(policy.tier(301), 2);
+
+    let segments = |lens: &[usize]| {
+        lens.iter()
+            .enumerate()
+            .map(|(i, &bwt_len)| SegmentInfo {
+                id: i as u64,
+                bwt_len,
+                num_lines: 1,
+            })
+            .collect::<Vec<SegmentInfo>>()
+    };
+    assert_eq!(policy.pick(&segments(&[50, 60])), None);
+    assert_eq!(policy.pick(&segments(&[50, 60, 70])), Some(0..3));
+    // runs must be adjacent and in the same tier
+    assert_eq!(policy.pick(&segments(&[50, 60, 200, 70])), None);
+    // the lowest tier goes first
+    assert_eq!(
+        policy.pick(&segments(&[200, 250, 280, 50, 60, 70])),
+        Some(3..6)
+    );
+    assert_eq!(
+        policy.pick(&segments(&[1000, 200, 250, 280, 50])),
+        Some(1..4)
+    );
+}
+
+async fn run_on_operator(operator: &Operator, path: &str) {
+    let policy = CompactionPolicy {
+        fan_in: 2,
+        base_len: 400,
+    };
+    let mut rng = StdRng::seed_from_u64(38);
+    let mut dir = SegmentDir::open(operator, path, policy).await.unwrap();
+    assert!(dir.segments().is_empty());
+
+    let mut text = Vec::new();
+    let mut doc_ids = Vec::new();
+    for batch in 0..12 {
+        let lines = random_lines(&mut rng, 60);
+        let batch_docs = vec![batch; 60];
+        dir.add_batch(lines.clone(), Some(&batch_docs))
+            .await
+            .unwrap();
+        text.extend(lines);
+        doc_ids.extend(batch_docs);
+        dir.compact().await.unwrap();
+
+        // no two adjacent segments of the same tier are left
+        let tiers = dir
+            .segments()
+            .iter()
+            .map(|x| policy.tier(x.bwt_len))
+            .collect::<Vec<usize>>();
+        assert!(tiers.windows(2).all(|x| x[0] != x[1]), "{:?}", tiers);
+    }
+    assert!(dir.segments().len() < 12);
+    assert_eq!(
+        dir.segments().iter().map(|x| x.bwt_len).sum::<usize>(),
+        text.len()
+    );
+    for segment in dir.segments().iter() {
+        let segment_path = dir.segment_path(segment.id);
+        assert_eq!(
+            verify_segment(operator, &segment_path).await.unwrap(),
+            vec![]
+        );
+    }
+    for pattern in [&b"a"[..], b"abc", b"cab", b"bb", b"ccc"] {
+        assert_eq!(
+            matching_lines(&dir, operator, pattern).await,
+            naive_matching_lines(&text, pattern)
+        );
+    }
+
+    // the directory's searcher gives the same lines, and their document ids
+    let searcher = dir.searcher().await.unwrap();
+    assert_eq!(searcher.num_lines(), doc_ids.len());
+    for pattern in [&b"a"[..], b"abc", b"cab", b"bb", b"ccc"] {
+        let lines = naive_matching_lines(&text, pattern);
+        let docs = lines.iter().map(|&x| doc_ids[x]).collect::<BTreeSet<u64>>();
+        assert_eq!(searcher.matching_lines(pattern).await.unwrap(), lines);
+        assert_eq!(searcher.matching_docs(pattern).await.unwrap(), docs);
+    }
+
+    // a batch without document ids can't join segments that have them
+    assert!(dir.add_batch(b"abc\n".to_vec(), None).await.is_err());
+    assert!(dir.add_batch(b"abc".to_vec(), Some(&[1])).await.is_err());
+
+    // reopening finds the same segments, and removes files no manifest refers to
+    let stray = format!("{}/seg-99999999.bwt", path);
+    operator
+        .write(stray.as_str(), b"abc".to_vec())
+        .await
+        .unwrap();
+    let old_manifest = format!("{}/manifest-{:020}", path, 1);
+    operator
+        .write(old_manifest.as_str(), b"old".to_vec())
+        .await
+        .unwrap();
+    let manifest = dir.manifest().clone();
+    let dir = SegmentDir::open(operator, path, policy).await.unwrap();
+    assert_eq!(dir.manifest(), &manifest);
+    assert!(!operator.is_exist(stray.as_str()).await.unwrap());
+    assert!(!operator.is_exist(old_manifest.as_str()).await.unwrap());
+    let live = dir.segment_path(dir.segments()[0].id);
+    assert!(operator
+        .is_exist(format!("{}.docs", live).as_str())
+        .await
+        .unwrap());
+}
+
+#[tokio::test]
+async fn segment_dir_fs() {
+    let path = format!("{}/fs", TEST_DIR);
+    let _ = std::fs::remove_dir_all(&path);
+    run_on_operator(&fs_operator(".").unwrap(), &path).await;
+}
+
+#[tokio::test]
+async fn segment_dir_memory() {
+    run_on_operator(&memory_operator().unwrap(), "index").await;
+}
+
+#[tokio::test]
+async fn damaged_manifest() {
+    let path = format!("{}/damaged", TEST_DIR);
+    let _ = std::fs::remove_dir_all(&path);
+    let operator = fs_operator(".").unwrap();
+    let policy = CompactionPolicy::default();
+
+    let mut dir = SegmentDir::open(&operator, &path, policy).await.unwrap();
+    dir.add_batch(b"ab\nc\n".to_vec(), None).await.unwrap();
+    let committed = dir.manifest().clone();
+
+    // a manifest whose write didn't finish is ignored, along with its segment
+    let mut next = committed.clone();
+    next.generation += 1;
+    next.segments.push(SegmentInfo {
+        id: next.next_id,
+        bwt_len: 2,
+        num_lines: 1,
+    });
+    let data = next.encode();
+    let next_path = format!("{}/manifest-{:020}", path, next.generation);
+    std::fs::write(&next_path, &data[..data.len() / 2]).unwrap();
+    std::fs::write(format!("{}/seg-{:08}.bwt", path, next.next_id), b"a\n").unwrap();
+    // so is the interleave of a merge that didn't finish
+    let spill = format!("{}/seg-{:08}.interleave", path, next.next_id);
+    std::fs::create_dir_all(format!("{}/1", spill)).unwrap();
+    std::fs::write(format!("{}/1/0", spill), b"ab").unwrap();
+
+    let mut dir = SegmentDir::open(&operator, &path, policy).await.unwrap();
+    assert_eq!(dir.manifest(), &committed);
+    assert!(!std::path::Path::new(&next_path).exists());
+    assert!(!std::path::Path::new(&spill).exists());
+    dir.add_batch(b"abc\n".to_vec(), None).await.unwrap();
+    assert_eq!(dir.segments().len(), 2);
+    assert_eq!(dir.line_offsets(), vec![0, 2]);
+}
@@ -0,0 +1,232 @@
+use bwt_merge::bwt::run_bwt;
+use bwt_merge::bwt_disk::{
+    bwt_merge_disk, bwt_merge_disk_many, load_segment, memory_operator, save_segment, verify_merge,
+    verify_merge_with_options, VerifyMode,
+};
+use bwt_merge::sample::{sample_dataset, sample_sizes, SampleConfig, SampleInfo};
+use bwt_merge::segment::MergeMismatch;
+use opendal::Operator;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+// Numbered lines, so every line is distinct
+fn input_text(num_lines: usize) -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut text = Vec::new();
+    for i in 0..num_lines {
+        text.extend_from_slice(i.to_string().as_bytes());
+        for _ in 0..rng.gen_range(0..8) {
+            text.push(b"abc"[rng.gen_range(0..3)]);
+        }
+        text.push(b'\n');
+    }
+    text
+}
+
+// Whether the lines of part appear in lines, in the same order
+fn is_subsequence(part: &[&[u8]], lines: &[&[u8]]) -> bool {
+    let mut lines = lines.iter();
+    part.iter().all(|x| lines.any(|y| y == x))
+}
+
+async fn read_all(operator: &Operator, dir: &str, sample: &SampleInfo) -> Vec<Vec<u8>> {
+    let mut files = Vec::new();
+    for name in ["0.bwt", "0.index", "1.bwt", "1.index", "full.txt"] {
+        let path = format!("{}/{}_{}", dir, sample.size, name);
+        files.push(operator.read(path.as_str()).await.unwrap());
+    }
+    files
+}
+
+#[tokio::test]
+async fn seeded_samples() {
+    let operator = memory_operator().unwrap();
+    let text = input_text(500);
+    operator.write("input.txt", text.clone()).await.unwrap();
+    let lines = text
+        .split_inclusive(|&x| x == b'\n')
+        .collect::<Vec<&[u8]>>();
+
+    let config = SampleConfig {
+        sizes: vec![10, 100, 1000],
+        split: 0.3,
+        seed: 7,
+    };
+    let samples = sample_dataset(&operator, "input.txt", "a", &config)
+        .await
+        .unwrap();
+    // sizes are of each part, and past half the input take all of it
+    let expected = [(10, [6, 14]), (100, [60, 140]), (250, [150, 350])];
+    for (sample, (size, num_lines)) in samples.iter().zip(expected) {
+        assert_eq!(sample, &SampleInfo { size, num_lines });
+    }
+    assert_eq!(
+        sample_sizes(&operator, "a").await.unwrap(),
+        vec![10, 100, 250]
+    );
+
+    for sample in samples.iter() {
+        let full_path = format!("a/{}_full.txt", sample.size);
+        let full = operator.read(full_path.as_str()).await.unwrap();
+        let full_lines = full
+            .split_inclusive(|&x| x == b'\n')
+            .collect::<Vec<&[u8]>>();
+        assert_eq!(full_lines.len(), 2 * sample.size);
+        let (part0, part1) = full_lines.split_at(sample.num_lines[0]);
+        assert!(is_subsequence(part0, &lines));
+        assert!(is_subsequence(part1, &lines));
+
+        // the segments are of the two parts of the full text
+        for (i, part) in [part0, part1].into_iter().enumerate() {
+            let bwt_path = format!("a/{}_{}.bwt", sample.size, i);
+            let bwt = operator.read(bwt_path.as_str()).await.unwrap();
+            assert_eq!(bwt, run_bwt(&part.concat()).0);
+        }
+    }
+
+    // the same seed gives the same files, another seed different ones
+    let same = sample_dataset(&operator, "input.txt", "b", &config)
+        .await
+        .unwrap();
+    assert_eq!(same, samples);
+    let other = SampleConfig { seed: 8, ..config };
+    sample_dataset(&operator, "input.txt", "c", &other)
+        .await
+        .unwrap();
+    for sample in samples.iter() {
+        let files = read_all(&operator, "a", sample).await;
+        assert_eq!(read_all(&operator, "b", sample).await, files);
+        assert_ne!(read_all(&operator, "c", sample).await, files);
+    }
+}
+
+#[tokio::test]
+async fn sample_edge_cases() {
+    let operator = memory_operator().unwrap();
+    // the last line has no separator
+    operator
+        .write("input.txt", b"one\ntwo\n\nfour".to_vec())
+        .await
+        .unwrap();
+    let config = SampleConfig {
+        sizes: vec![usize::MAX],
+        ..Default::default()
+    };
+    let samples = sample_dataset(&operator, "input.txt", "out", &config)
+        .await
+        .unwrap();
+    assert_eq!(
+        samples,
+        vec![SampleInfo {
+            size: 2,
+            num_lines: [2, 2]
+        }]
+    );
+    let mut full = operator.read("out/2_full.txt").await.unwrap();
+    full.sort();
+    let mut expected = b"one\ntwo\n\nfour\n".to_vec();
+    expected.sort();
+    assert_eq!(full, expected);
+
+    for split in [-0.5, 1.5, 0.0, 1.0, 0.1] {
+        let config = SampleConfig {
+            split,
+            ..config.clone()
+        };
+        assert!(sample_dataset(&operator, "input.txt", "out", &config)
+            .await
+            .is_err());
+    }
+    assert!(sample_dataset(&operator, "missing.txt", "out", &config)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn merges_match_rebuilds() {
+    let operator = memory_operator().unwrap();
+    operator.write("input.txt", input_text(3000)).await.unwrap();
+    let config = SampleConfig {
+        sizes: vec![50, 1000, usize::MAX],
+        split: 0.4,
+        seed: 3,
+    };
+    let samples = sample_dataset(&operator, "input.txt", "in", &config)
+        .await
+        .unwrap();
+    let mut reordered = 0;
+    for sample in samples.iter() {
+        let inputs = [
+            format!("in/{}_0", sample.size),
+            format!("in/{}_1", sample.size),
+        ];
+        let text_path = format!("in/{}_full.txt", sample.size);
+        let full = operator.read(text_path.as_str()).await.unwrap();
+
+        // a merge of both parts at once matches position by position
+        let merged_path = format!("out/{}_merged", sample.size);
+        bwt_merge_disk_many(&operator, &[&inputs[0], &inputs[1]], &merged_path)
+            .await
+            .unwrap();
+        let (bwt_len, mismatch) = verify_merge(&operator, &text_path, &merged_path)
+            .await
+            .unwrap();
+        assert_eq!(mismatch, None);
+        assert_eq!(bwt_len, full.len());
+
+        // a pairwise merge only up to the order of rotations equal to the end of their lines
+        let pairwise_path = format!("out/{}_pairwise", sample.size);
+        bwt_merge_disk(&operator, &inputs[0], &inputs[1], &pairwise_path)
+            .await
+            .unwrap();
+        let report = verify_merge_with_options(
+            &operator,
+            &text_path,
+            &pairwise_path,
+            VerifyMode::AllowLineEndReorders,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.mismatch, None);
+        assert_eq!(report.bwt_len, full.len());
+        let (_, mismatch) = verify_merge(&operator, &text_path, &pairwise_path)
+            .await
+            .unwrap();
+        match report.reordered.first() {
+            Some(run) => {
+                let Some(MergeMismatch::Position { position, .. }) = mismatch else {
+                    panic!("expected a position mismatch, got {:?}", mismatch);
+                };
+                assert!(run.contains(&position));
+            }
+            None => assert_eq!(mismatch, None),
+        }
+        reordered += report.reordered.len();
+    }
+    assert!(reordered > 0);
+
+    // a merge of other inputs, and a merge with line ids out of place
+    let text_path = "in/1000_full.txt";
+    let (_, mismatch) = verify_merge(&operator, text_path, "out/50_merged")
+        .await
+        .unwrap();
+    assert!(matches!(mismatch, Some(MergeMismatch::BwtLength { .. })));
+
+    let mut data = load_segment(&operator, "out/1000_merged").await.unwrap();
+    let last = data.1.len() - 1;
+    let position = (1..last).find(|&i| data.1[i] != data.1[last]).unwrap();
+    data.1.swap(position, last);
+    save_segment(&operator, &data, "out/swapped").await.unwrap();
+    let (_, mismatch) = verify_merge(&operator, text_path, "out/swapped")
+        .await
+        .unwrap();
+    let Some(MergeMismatch::Position {
+        position: found,
+        merged,
+        rebuilt,
+    }) = mismatch
+    else {
+        panic!("expected a position mismatch, got {:?}", mismatch);
+    };
+    assert_eq!(found, position);
+    assert_ne!(merged, rebuilt);
+}
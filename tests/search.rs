@@ -0,0 +1,124 @@
+use std::collections::BTreeSet;
+
+use bwt_merge::bwt::{bwt_merge_many, fm_index, run_bwt, substring_search};
+use bwt_merge::bwt_disk::{fs_operator, write_doc_ids, write_segment};
+use bwt_merge::search::SegmentSearcher;
+use bwt_merge::segment::IdEncoding;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+// relative to the crate root, since the tests use an fs operator rooted at the current directory
+const TEST_DIR: &str = "target/test_data/search";
+
+fn random_text(rng: &mut StdRng, n: usize, max_len: usize) -> Vec<u8> {
+    let mut text = Vec::new();
+    for _ in 0..n {
+        for _ in 0..rng.gen_range(0..=max_len) {
+            text.push(b"abc"[rng.gen_range(0..3)]);
+        }
+        text.push(b'\n');
+    }
+    text
+}
+
+fn naive_matching_lines(text: &[u8], pattern: &[u8]) -> BTreeSet<usize> {
+    text.split(|&x| x == b'\n')
+        .take(text.iter().filter(|&&x| x == b'\n').count())
+        .enumerate()
+        .filter(|(_, line)| pattern.is_empty() || line.windows(pattern.len()).any(|x| x == pattern))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+#[tokio::test]
+async fn search_unmerged_segments() {
+    std::fs::create_dir_all(TEST_DIR).unwrap();
+    let operator = fs_operator(".").unwrap();
+    let mut rng = StdRng::seed_from_u64(39);
+
+    let mut texts = Vec::new();
+    let mut paths = Vec::new();
+    let mut doc_ids = Vec::new();
+    for (i, num_lines) in [200, 1, 50, 400].into_iter().enumerate() {
+        let text = random_text(&mut rng, num_lines, 12);
+        let data = run_bwt(&text);
+        let path = format!("{}/segment_{}", TEST_DIR, i);
+        let encoding = match i % 2 {
+            0 => IdEncoding::fixed_for(num_lines),
+            _ => IdEncoding::Varint,
+        };
+        write_segment(&operator, &data, &path, encoding)
+            .await
+            .unwrap();
+        let segment_docs = (0..num_lines)
+            .map(|_| rng.gen_range(0..100))
+            .collect::<Vec<u64>>();
+        write_doc_ids(&operator, &path, &segment_docs)
+            .await
+            .unwrap();
+        texts.push(text);
+        paths.push(path);
+        doc_ids.extend(segment_docs);
+    }
+    let paths = paths.iter().map(|x| x.as_str()).collect::<Vec<&str>>();
+    let searcher = SegmentSearcher::open(&operator, &paths).await.unwrap();
+    assert_eq!(searcher.num_segments(), 4);
+    assert_eq!(searcher.num_lines(), 651);
+    assert_eq!(searcher.locate_line(0), Some((0, 0)));
+    assert_eq!(searcher.locate_line(200), Some((1, 0)));
+    assert_eq!(searcher.locate_line(201), Some((2, 0)));
+    assert_eq!(searcher.locate_line(650), Some((3, 399)));
+    assert_eq!(searcher.locate_line(651), None);
+
+    // results match the concatenated text, and the merged segment
+    let concat = texts.concat();
+    let merged = bwt_merge_many(&texts.iter().map(run_bwt).collect::<Vec<_>>());
+    let merged_index = fm_index(&merged);
+    let segments = texts
+        .iter()
+        .map(|text| {
+            let data = run_bwt(text);
+            let index = fm_index(&data);
+            (data, index)
+        })
+        .collect::<Vec<_>>();
+    for pattern in [
+        &b""[..],
+        b"a",
+        b"bc",
+        b"abca",
+        b"cccc",
+        b"\n",
+        b"a\nb",
+        b"x",
+    ] {
+        // matches across lines are only found within a segment
+        let count = match pattern.contains(&b'\n') {
+            true => segments
+                .iter()
+                .map(|(data, index)| {
+                    substring_search(index, pattern, data.0.len())
+                        .map_or(0, |(start, end)| end - start)
+                })
+                .sum(),
+            false => substring_search(&merged_index, pattern, merged.0.len())
+                .map_or(0, |(start, end)| end - start),
+        };
+        assert_eq!(
+            searcher.count(pattern).await.unwrap(),
+            count,
+            "{:?}",
+            pattern
+        );
+        if pattern.contains(&b'\n') {
+            continue;
+        }
+        let lines = naive_matching_lines(&concat, pattern);
+        let docs = lines.iter().map(|&x| doc_ids[x]).collect::<BTreeSet<u64>>();
+        assert_eq!(searcher.matching_lines(pattern).await.unwrap(), lines);
+        assert_eq!(searcher.matching_docs(pattern).await.unwrap(), docs);
+    }
+
+    let empty = SegmentSearcher::open(&operator, &[]).await.unwrap();
+    assert_eq!(empty.num_lines(), 0);
+    assert!(empty.matching_lines(b"a").await.unwrap().is_empty());
+}
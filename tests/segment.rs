@@ -0,0 +1,149 @@
+use bwt_merge::segment::{
+    decode_index_header, Checksums, IdDecoder, IdEncoding, IndexWriter, RankBuilder, RankIndex,
+    SegmentMeta, INDEX_HEADER_SIZE, META_V1_SIZE, SEPARATOR,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+#[test]
+fn ids_round_trip_in_chunks() {
+    let mut rng = StdRng::seed_from_u64(32);
+    let ids = (0..1000)
+        .map(|_| {
+            let bits = rng.gen_range(1..40);
+            rng.gen_range(0..1usize << bits)
+        })
+        .collect::<Vec<usize>>();
+    let max_id = *ids.iter().max().unwrap();
+
+    for encoding in [IdEncoding::fixed_for(max_id), IdEncoding::Varint] {
+        let mut writer = IndexWriter::new(Vec::new(), encoding).unwrap();
+        for &id in ids.iter() {
+            writer.write_id(id).unwrap();
+        }
+        let data = writer.into_inner();
+        assert_eq!(decode_index_header(&data).unwrap(), encoding);
+
+        // ids split across chunks of every small size
+        for chunk_size in 1..=9 {
+            let mut decoder = IdDecoder::new(encoding);
+            let mut decoded = Vec::new();
+            for chunk in data[INDEX_HEADER_SIZE..].chunks(chunk_size) {
+                decoder.decode(chunk, &mut decoded).unwrap();
+            }
+            assert!(!decoder.has_partial());
+            assert_eq!(decoded, ids);
+        }
+    }
+}
+
+#[test]
+fn fixed_width() {
+    assert_eq!(IdEncoding::fixed_for(0), IdEncoding::Fixed(1));
+    assert_eq!(IdEncoding::fixed_for(255), IdEncoding::Fixed(1));
+    assert_eq!(IdEncoding::fixed_for(256), IdEncoding::Fixed(2));
+    assert_eq!(IdEncoding::fixed_for(usize::MAX), IdEncoding::Fixed(8));
+
+    let mut writer = IndexWriter::new(Vec::new(), IdEncoding::Fixed(1)).unwrap();
+    writer.write_id(255).unwrap();
+    assert!(writer.write_id(256).is_err());
+}
+
+#[test]
+fn meta_round_trip() {
+    let mut counts = [0; 256];
+    counts[SEPARATOR as usize] = 3;
+    counts[b'a' as usize] = 10;
+    let checksums = Checksums {
+        bwt: 0x12345678,
+        index: 0x9abcdef0,
+    };
+    let meta = SegmentMeta::new(SEPARATOR, counts, checksums);
+    assert_eq!(meta.bwt_len, 13);
+    assert_eq!(meta.num_lines, 3);
+    assert_eq!(SegmentMeta::decode(&meta.encode()).unwrap(), meta);
+
+    // version 1 segments have no checksums
+    let meta_v1 = SegmentMeta {
+        version: 1,
+        checksums: None,
+        ..meta.clone()
+    };
+    assert_eq!(meta_v1.encode().len(), META_V1_SIZE);
+    assert_eq!(SegmentMeta::decode(&meta_v1.encode()).unwrap(), meta_v1);
+
+    let compressed = SegmentMeta {
+        compressed: true,
+        ..meta.clone()
+    };
+    assert_eq!(
+        SegmentMeta::decode(&compressed.encode()).unwrap(),
+        compressed
+    );
+    // the flag byte was reserved in version 1, and unknown flags are rejected
+    let mut data = meta_v1.encode();
+    data[7] = 1;
+    assert_eq!(SegmentMeta::decode(&data).unwrap(), meta_v1);
+    let mut data = meta.encode();
+    data[7] = 2;
+    let len = data.len();
+    let checksum = crc32fast::hash(&data[..len - 4]);
+    data[len - 4..].copy_from_slice(&checksum.to_le_bytes());
+    assert!(SegmentMeta::decode(&data).is_err());
+
+    let mut data = meta.encode();
+    data[4] = 3;
+    assert!(SegmentMeta::decode(&data).is_err());
+    let mut data = meta.encode();
+    data[30] ^= 1;
+    assert!(SegmentMeta::decode(&data)
+        .unwrap_err()
+        .to_string()
+        .contains("checksum"));
+    assert!(SegmentMeta::decode(b"0\n3\n").is_err());
+}
+
+#[test]
+fn rank_round_trip() {
+    let mut rng = StdRng::seed_from_u64(37);
+    for len in [0, 1, 99, 100, 101, 1000] {
+        let bwt = (0..len)
+            .map(|_| b"ab\n"[rng.gen_range(0..3)])
+            .collect::<Vec<u8>>();
+        let mut counts = [0; 256];
+        for &chr in bwt.iter() {
+            counts[chr as usize] += 1;
+        }
+        let mut builder = RankBuilder::with_interval(&counts, 100);
+        builder.extend(&bwt);
+        let rank = builder.finish().unwrap();
+        assert_eq!(RankIndex::decode(&rank.encode()).unwrap(), rank);
+
+        for pos in (0..=len).step_by(100) {
+            for chr in [b'a', b'b', SEPARATOR] {
+                let expected = bwt[..pos].iter().filter(|&&x| x == chr).count();
+                match rank.checkpoint_rank(pos, chr) {
+                    Some(actual) => assert_eq!(actual, expected),
+                    None => assert_eq!(counts[chr as usize], 0),
+                }
+            }
+            assert_eq!(rank.checkpoint_rank(pos, b'z'), None);
+        }
+        assert_eq!(rank.start(b'a'), counts[SEPARATOR as usize]);
+    }
+
+    // characters that don't match the counts
+    let mut counts = [0; 256];
+    counts[b'a' as usize] = 2;
+    let mut builder = RankBuilder::with_interval(&counts, 100);
+    builder.extend(b"ab");
+    assert!(builder.finish().is_err());
+
+    let mut builder = RankBuilder::with_interval(&counts, 1);
+    builder.extend(b"aa");
+    let mut data = builder.finish().unwrap().encode();
+    data[30] ^= 1;
+    assert!(RankIndex::decode(&data)
+        .unwrap_err()
+        .to_string()
+        .contains("checksum"));
+}
@@ -0,0 +1,179 @@
+use std::io::{Cursor, SeekFrom};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bwt_merge::stream::{ByteStream, ReadOptions};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+// Reader that returns at most a few bytes per read, like a remote service might
+struct ShortReader {
+    data: Cursor<Vec<u8>>,
+    max_read: usize,
+}
+
+impl AsyncRead for ShortReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let len = buf.remaining().min(self.max_read);
+        let mut short_buf = ReadBuf::new(buf.initialize_unfilled_to(len));
+        let poll = Pin::new(&mut self.data).poll_read(cx, &mut short_buf);
+        let filled = short_buf.filled().len();
+        buf.advance(filled);
+        poll
+    }
+}
+
+impl AsyncSeek for ShortReader {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        Pin::new(&mut self.data).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Pin::new(&mut self.data).poll_complete(cx)
+    }
+}
+
+fn short_stream(data: &[u8], max_read: usize, buffer_size: usize) -> ByteStream<ShortReader> {
+    let reader = ShortReader {
+        data: Cursor::new(data.to_vec()),
+        max_read,
+    };
+    ByteStream::new(reader, buffer_size)
+}
+
+#[tokio::test]
+async fn bytes_across_short_reads() {
+    let data = (0..=255).cycle().take(1000).collect::<Vec<u8>>();
+    let mut stream = short_stream(&data, 3, 16);
+
+    for pass in 0..2 {
+        for (i, &expected) in data.iter().enumerate() {
+            assert_eq!(stream.position(), i as u64);
+            assert_eq!(stream.next_byte().await.unwrap(), expected);
+        }
+        assert!(stream.chunk().await.unwrap().is_empty());
+        let err = stream.next_byte().await.unwrap_err();
+        assert!(err.to_string().contains("ended early"), "pass {}", pass);
+        assert_eq!(stream.take_bytes_read(), data.len() as u64);
+        stream.rewind().await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn chunks_and_read_up_to() {
+    let data = (0..100).collect::<Vec<u8>>();
+    let mut stream = short_stream(&data, 7, 32);
+
+    let mut header = [0u8; 10];
+    assert_eq!(stream.read_up_to(&mut header).await.unwrap(), 10);
+    assert_eq!(header.to_vec(), data[..10]);
+
+    let mut rest = Vec::new();
+    loop {
+        let chunk = stream.chunk().await.unwrap();
+        if chunk.is_empty() {
+            break;
+        }
+        assert!(chunk.len() <= 7);
+        // consume part of each chunk, so the next call returns the remainder
+        let len = chunk.len().div_ceil(2);
+        rest.extend_from_slice(&chunk[..len]);
+        stream.consume(len);
+    }
+    assert_eq!(rest, data[10..]);
+
+    // reading past the end fills as much as there is
+    stream.rewind().await.unwrap();
+    let mut all = [0u8; 150];
+    assert_eq!(stream.read_up_to(&mut all).await.unwrap(), 100);
+    assert_eq!(all[..100].to_vec(), data);
+    assert_eq!(stream.read_up_to(&mut all).await.unwrap(), 0);
+}
+
+// Reader that fails after some bytes
+struct FailingReader {
+    remaining: usize,
+}
+
+impl AsyncRead for FailingReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.remaining == 0 {
+            return Poll::Ready(Err(std::io::Error::other("read failed")));
+        }
+        let len = buf.remaining().min(self.remaining);
+        buf.put_slice(&vec![7; len]);
+        self.remaining -= len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn prefetched_buffers() {
+    let data = (0..=255).cycle().take(1000).collect::<Vec<u8>>();
+    for buffer_count in 1..=4 {
+        let options = ReadOptions {
+            buffer_size: 64,
+            buffer_count,
+        };
+        let reader = ShortReader {
+            data: Cursor::new(data.to_vec()),
+            max_read: 5,
+        };
+        let mut stream = ByteStream::prefetching(reader, options);
+
+        // rewinding partway through stops reading ahead and starts again
+        for stop in [10, 500, data.len()] {
+            for &expected in data[..stop].iter() {
+                assert_eq!(stream.next_byte().await.unwrap(), expected);
+            }
+            stream.rewind().await.unwrap();
+        }
+        let mut all = vec![0; data.len() + 1];
+        assert_eq!(stream.read_up_to(&mut all).await.unwrap(), data.len());
+        assert_eq!(all[..data.len()], data[..]);
+        assert!(stream.chunk().await.unwrap().is_empty());
+    }
+    assert_eq!(ReadOptions::default().memory_size(), 2 << 20);
+
+    let options = ReadOptions {
+        buffer_size: 16,
+        buffer_count: 3,
+    };
+    let mut stream = ByteStream::prefetching(FailingReader { remaining: 40 }, options);
+    stream.skip(32).await.unwrap();
+    assert!(stream.skip(16).await.is_err());
+}
+
+#[tokio::test]
+async fn mapped_stream() {
+    let dir = "target/test_data/stream";
+    std::fs::create_dir_all(dir).unwrap();
+    let data = (0..1000).map(|x| (x % 251) as u8).collect::<Vec<u8>>();
+    let path = format!("{}/mapped", dir);
+    std::fs::write(&path, &data).unwrap();
+    let file = std::fs::File::open(&path).unwrap();
+    let map = unsafe { memmap2::Mmap::map(&file) }.unwrap();
+    let mut stream = ByteStream::<ShortReader>::mapped(map);
+
+    // the whole file is one chunk
+    assert_eq!(stream.chunk().await.unwrap(), &data[..]);
+    stream.skip(10).await.unwrap();
+    assert_eq!(stream.next_byte().await.unwrap(), data[10]);
+    assert_eq!(stream.position(), 11);
+    stream.skip(989).await.unwrap();
+    assert!(stream.chunk().await.unwrap().is_empty());
+    assert!(stream.next_byte().await.is_err());
+    assert_eq!(stream.take_bytes_read(), data.len() as u64);
+
+    stream.rewind().await.unwrap();
+    let mut all = vec![0; data.len()];
+    assert_eq!(stream.read_up_to(&mut all).await.unwrap(), data.len());
+    assert_eq!(all, data);
+}
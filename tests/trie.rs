@@ -1,4 +1,8 @@
-use bwt_merge::trie::{self, compress_hex_strs, hex_to_u8};
+mod common;
+
+use bwt_merge::trie::{self, compress_hex_strs, hex_to_u8, ArenaTrie, BinaryTrieNode};
+use common::random_strs;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
 #[test]
 fn build_and_query() {
@@ -201,3 +205,66 @@ fn hex_build() {
         }
     }
 }
+
+fn count_nodes<T: Clone>(trie: &BinaryTrieNode<T>) -> usize {
+    1 + [&trie.left, &trie.right]
+        .iter()
+        .filter_map(|x| x.as_ref())
+        .map(|x| count_nodes(x))
+        .sum::<usize>()
+}
+
+#[test]
+fn arena_matches_boxed() {
+    let mut rng = StdRng::seed_from_u64(49);
+    for extra_bits in [0, 3, 8] {
+        let strs = random_strs(&mut rng, 200, 5);
+        let inds: Vec<Vec<usize>> = (0..strs.len())
+            .map(|x| vec![x; rng.gen_range(0..3)])
+            .collect();
+        let boxed = BinaryTrieNode::build_extra(&strs, &inds, extra_bits);
+        let arena = ArenaTrie::build_extra(&strs, &inds, extra_bits);
+        assert_eq!(arena.num_nodes(), count_nodes(&boxed));
+        // nodes are in preorder either way, so the conversion gives the same trie
+        assert_eq!(ArenaTrie::from(&boxed), arena);
+
+        let queries = random_strs(&mut rng, 100, 5);
+        for query in strs.iter().chain(queries.iter()) {
+            assert_eq!(arena.query(query), boxed.query(query));
+        }
+
+        let serialized = bincode::serialize(&arena).unwrap();
+        let deserialized: ArenaTrie<usize> = bincode::deserialize(&serialized).unwrap();
+        assert_eq!(deserialized, arena);
+    }
+
+    let empty = ArenaTrie::<usize>::build(&[], &[]);
+    assert_eq!(empty.num_nodes(), 1);
+    assert_eq!(empty.query(b"abc"), Vec::<usize>::new());
+}
+
+#[test]
+fn arena_extend() {
+    let mut rng = StdRng::seed_from_u64(50);
+    let strs1 = random_strs(&mut rng, 150, 5);
+    let inds1: Vec<Vec<(usize, usize)>> = (0..strs1.len()).map(|x| vec![(1, x)]).collect();
+    let strs2 = random_strs(&mut rng, 150, 5);
+    let inds2: Vec<Vec<(usize, usize)>> = (0..strs2.len()).map(|x| vec![(2, x)]).collect();
+
+    let mut boxed = BinaryTrieNode::build(&strs1, &inds1);
+    boxed.extend(BinaryTrieNode::build(&strs2, &inds2));
+    let mut arena = ArenaTrie::build(&strs1, &inds1);
+    arena.extend(ArenaTrie::build(&strs2, &inds2));
+    assert_eq!(ArenaTrie::from(&boxed), arena);
+
+    for query in strs1.iter().chain(strs2.iter()) {
+        let res = arena.query(query);
+        assert_eq!(res, boxed.query(query));
+        for (i, s) in strs1.iter().enumerate().filter(|(_, s)| *s == query) {
+            assert!(res.contains(&(1, i)), "{:?} missing from {:?}", s, res);
+        }
+        for (i, s) in strs2.iter().enumerate().filter(|(_, s)| *s == query) {
+            assert!(res.contains(&(2, i)), "{:?} missing from {:?}", s, res);
+        }
+    }
+}
@@ -2,6 +2,7 @@
 
 use std::{io::Write, time::Instant};
 
+use bwt_merge::louds::LoudsTrie;
 use bwt_merge::trie::{self, hex_to_u8};
 use rand::{prelude::SliceRandom, rngs::ThreadRng, Rng};
 use serde::Serialize;
@@ -65,11 +66,11 @@ fn get_queries(
         .collect::<Vec<_>>();
 
     // create random strings that don't exist (with high probability)
-    let str_length = input_lines.get(0).unwrap().len();
+    let str_length = input_lines.first().unwrap().len();
     for _ in 0..QUERY_COUNT {
         let mut new_str = vec![0; str_length];
-        for i in 0..str_length {
-            new_str[i] = ALPHABET[rng.gen_range(0..ALPHABET.len())];
+        for chr in new_str.iter_mut() {
+            *chr = ALPHABET[rng.gen_range(0..ALPHABET.len())];
         }
         query_strs.push((new_str, -1));
     }
@@ -89,6 +90,12 @@ fn serialize_and_compress<T: Clone + Serialize>(trie: &trie::BinaryTrieNode<T>)
     encoder.write_all(&serialized).unwrap();
     let compressed = encoder.finish().unwrap();
     println!("compressed size: {}", compressed.len());
+
+    let louds = LoudsTrie::from(trie);
+    println!(
+        "louds trie size: {}",
+        bincode::serialize(&louds).unwrap().len()
+    );
 }
 
 #[ignore]
@@ -212,13 +219,12 @@ fn stress_merge(incl_newline: bool, extend: bool, compress_hex: bool) {
     let trie2 = trie::BinaryTrieNode::build(&input2, &inds2);
 
     let start = Instant::now();
-    let merged;
-    if extend {
+    let merged = if extend {
         trie1.extend(trie2);
-        merged = trie1;
+        trie1
     } else {
-        merged = trie::merge_tries(&trie1, &trie2);
-    }
+        trie::merge_tries(&trie1, &trie2)
+    };
     let duration = start.elapsed();
     println!("merge time: {:?}", duration);
 